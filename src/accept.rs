@@ -0,0 +1,144 @@
+//! Choosing the type of the response among the ones the client accepts
+
+use crate::http::{self, headers, Request, StatusCode};
+use alloc::{format, vec::Vec};
+
+/// Content negotiation with the `Accept` header of requests
+///
+/// ```
+/// # use valor_core::{http, ContentNegotiation};
+/// let mut req = http::Request::new(http::Method::Get, "http://example.com");
+/// req.insert_header("accept", "application/json;q=0.5, application/msgpack");
+///
+/// let available = ["application/json", "application/msgpack"];
+/// assert_eq!(req.negotiate(&available).unwrap(), "application/msgpack");
+/// assert_eq!(
+///     req.negotiate(&["text/html"]).unwrap_err().status(),
+///     http::StatusCode::NotAcceptable
+/// );
+/// ```
+pub trait ContentNegotiation {
+    /// The type of the `available` ones(in the order the handler prefers them)
+    /// the client wants the most, requests without `Accept` get the first one.
+    /// The quality of a type is the `q` of the most specific media range that
+    /// matches it(e.g. `text/html` over `text/*` over `*/*`) and a `q=0` means
+    /// the type is not accepted. When none is accepted it's an error with
+    /// `406 Not Acceptable`.
+    fn negotiate<'a>(&self, available: &[&'a str]) -> Result<&'a str, http::Error>;
+}
+
+impl ContentNegotiation for Request {
+    fn negotiate<'a>(&self, available: &[&'a str]) -> Result<&'a str, http::Error> {
+        let ranges = self
+            .header(headers::ACCEPT)
+            .into_iter()
+            .flat_map(|values| values.iter())
+            .flat_map(|value| value.as_str().split(','))
+            .filter_map(MediaRange::parse)
+            .collect::<Vec<_>>();
+        let not_acceptable = || {
+            let msg = format!("Only {} can be served", available.join(", "));
+            http::Error::from_str(StatusCode::NotAcceptable, msg)
+        };
+        if ranges.is_empty() {
+            return available.first().copied().ok_or_else(not_acceptable);
+        }
+
+        let mut best = None::<(&'a str, f32)>;
+        for &ty in available {
+            let q = ranges
+                .iter()
+                .filter(|range| range.matches(ty))
+                .max_by_key(|range| range.specificity())
+                .map_or(0.0, |range| range.q);
+            // ties go to the type the handler lists first
+            if q > 0.0 && best.map_or(true, |(_, best)| q > best) {
+                best = Some((ty, q));
+            }
+        }
+        best.map(|(ty, _)| ty).ok_or_else(not_acceptable)
+    }
+}
+
+// A type of the `Accept` header like `text/*;q=0.8`, parameters other than
+// the quality are not taken into account
+struct MediaRange<'a> {
+    ty: &'a str,
+    subtype: &'a str,
+    q: f32,
+}
+
+impl<'a> MediaRange<'a> {
+    fn parse(range: &'a str) -> Option<Self> {
+        let mut params = range.split(';');
+        let (ty, subtype) = params.next()?.trim().split_once('/')?;
+        let q = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok())?;
+        Some(MediaRange {
+            ty: ty.trim(),
+            subtype: subtype.trim(),
+            q: q.clamp(0.0, 1.0),
+        })
+    }
+
+    fn matches(&self, mime: &str) -> bool {
+        let essence = mime.split(';').next().unwrap_or_default().trim();
+        let (ty, subtype) = essence.split_once('/').unwrap_or((essence, ""));
+        (self.ty == "*" || self.ty.eq_ignore_ascii_case(ty))
+            && (self.subtype == "*" || self.subtype.eq_ignore_ascii_case(subtype))
+    }
+
+    fn specificity(&self) -> u8 {
+        match (self.ty, self.subtype) {
+            ("*", _) => 0,
+            (_, "*") => 1,
+            _ => 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    fn request(accept: Option<&str>) -> Request {
+        let mut req = Request::new(Method::Get, "http://example.com");
+        if let Some(accept) = accept {
+            req.insert_header("accept", accept);
+        }
+        req
+    }
+
+    #[test]
+    fn negotiate_types() {
+        let available = ["application/json", "application/msgpack", "text/html"];
+        let negotiate = |accept| request(accept).negotiate(&available).ok();
+
+        assert_eq!(negotiate(None), Some("application/json"));
+        assert_eq!(negotiate(Some("*/*")), Some("application/json"));
+        assert_eq!(negotiate(Some("text/*")), Some("text/html"));
+        assert_eq!(
+            negotiate(Some("application/*;q=0.5, application/MsgPack")),
+            Some("application/msgpack")
+        );
+        // the most specific range sets the quality
+        assert_eq!(
+            negotiate(Some("*/*;q=0.9, application/json;q=0")),
+            Some("application/msgpack")
+        );
+        assert_eq!(
+            negotiate(Some("text/html;level=1;q=0.7, application/json; q=0.3")),
+            Some("text/html")
+        );
+        assert_eq!(negotiate(Some("image/png, */*;q=0")), None);
+
+        let err = request(Some("image/png"))
+            .negotiate(&available)
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::NotAcceptable);
+        assert!(request(None).negotiate(&[]).is_err());
+    }
+}
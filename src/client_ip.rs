@@ -0,0 +1,201 @@
+//! Address of the client that made a request, also when it comes through proxies
+
+use crate::http::Request;
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// Range of IPv4 or IPv6 addresses like `10.0.0.0/8` or `2001:db8::/32`,
+/// a single address is a range of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                masked(u32::from(net).into(), 32, self.len)
+                    == masked(u32::from(ip).into(), 32, self.len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                masked(net.into(), 128, self.len) == masked(ip.into(), 128, self.len)
+            }
+            _ => false,
+        }
+    }
+}
+
+// IPv4 clients of dual stack sockets come as IPv4-mapped IPv6 addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::from([a, b, c, d]),
+            _ => ip,
+        },
+        v4 => v4,
+    }
+}
+
+fn masked(bits: u128, width: u8, len: u8) -> u128 {
+    let host_bits = u32::from(width - len);
+    bits.checked_shr(host_bits).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid address range {}", s);
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr = canonical(addr.parse::<IpAddr>().map_err(|_| invalid())?);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|l| *l <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Cidr { addr, len })
+    }
+}
+
+// Extension the runtime sets when the peer is a trusted proxy
+#[derive(Debug, Clone, Copy)]
+struct ForwardedClient(IpAddr);
+
+/// Address of the client that made the request
+pub trait ClientIp {
+    /// The address the runtime resolved from `X-Forwarded-For` when the request
+    /// comes from a trusted proxy or the one of the peer otherwise, there's none
+    /// for requests that don't come from a TCP connection like a unix socket.
+    fn client_ip(&self) -> Option<IpAddr>;
+}
+
+impl ClientIp for Request {
+    fn client_ip(&self) -> Option<IpAddr> {
+        self.ext::<ForwardedClient>()
+            .map(|client| client.0)
+            .or_else(|| peer_ip(self))
+    }
+}
+
+fn peer_ip(request: &Request) -> Option<IpAddr> {
+    let addr = request.peer_addr()?;
+    addr.parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| addr.parse::<IpAddr>())
+        .ok()
+        .map(canonical)
+}
+
+/// Sets the address of the client of requests coming from one of the proxies,
+/// see [`forwarded_client`]. Headers of other peers are ignored.
+pub(crate) fn resolve(request: &mut Request, proxies: &[Cidr]) {
+    if let Some(client) = forwarded_client(request, proxies) {
+        request.set_ext(ForwardedClient(client));
+    }
+}
+
+/// Address the runtime resolves the client of a request to with the same
+/// trusted proxies, for servers that need it without going through the runtime
+/// (e.g. to log requests answered in another thread)
+pub fn resolve_client_ip(request: &Request, proxies: &[Cidr]) -> Option<IpAddr> {
+    forwarded_client(request, proxies).or_else(|| peer_ip(request))
+}
+
+// It's the closest one to us in the forwarded list that is not a trusted proxy
+// as the ones before could be made up
+fn forwarded_client(request: &Request, proxies: &[Cidr]) -> Option<IpAddr> {
+    let trusted = |ip: IpAddr| proxies.iter().any(|r| r.contains(ip));
+    let peer = peer_ip(request).filter(|peer| trusted(*peer))?;
+    let forwarded = request
+        .header("x-forwarded-for")
+        .map(|values| {
+            values
+                .iter()
+                .map(|v| v.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    let mut client = peer;
+    for hop in forwarded.rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = canonical(ip);
+                if !trusted(client) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn match_ranges() {
+        let range: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(ip("10.1.2.3")));
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+        assert!(!range.contains(ip("10.2.0.1")));
+        assert!(!range.contains(ip("2001:db8::1")));
+
+        let range: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(range.contains(ip("2001:db8:ffff::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+        assert!("::1".parse::<Cidr>().unwrap().contains(ip("::1")));
+        for invalid in &["10.0.0.0/33", "::/129", "10.0.0", "10.0.0.0/x"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn client_behind_trusted_proxies() {
+        let proxies = ["10.0.0.0/8".parse().unwrap()];
+        let client = |peer: &str, forwarded: Option<&str>| {
+            let mut req = Request::new(Method::Get, "http://example.com");
+            req.set_peer_addr(Some(peer));
+            if let Some(forwarded) = forwarded {
+                req.insert_header("x-forwarded-for", forwarded);
+            }
+            resolve(&mut req, &proxies);
+            req.client_ip()
+        };
+        let forwarded = Some("1.1.1.1, 2.2.2.2, 10.0.0.2");
+        assert_eq!(client("10.0.0.1:80", forwarded), Some(ip("2.2.2.2")));
+        // untrusted peers can't pretend to be someone else
+        assert_eq!(client("3.3.3.3:80", forwarded), Some(ip("3.3.3.3")));
+        assert_eq!(client("10.0.0.1:80", None), Some(ip("10.0.0.1")));
+        assert_eq!(
+            client("[::ffff:10.0.0.1]:80", Some("x")),
+            Some(ip("10.0.0.1"))
+        );
+
+        let mut req = Request::new(Method::Get, "http://example.com");
+        assert_eq!(req.client_ip(), None);
+        req.set_peer_addr(Some("10.0.0.1:80"));
+        req.insert_header("x-forwarded-for", "2.2.2.2");
+        assert_eq!(resolve_client_ip(&req, &proxies), Some(ip("2.2.2.2")));
+    }
+}
@@ -0,0 +1,268 @@
+//! Reading the cookies of requests and setting the ones of responses
+
+use crate::http::{headers, Request, Response};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+};
+use core::{fmt, time::Duration};
+
+/// Cookies sent by the client in any of the `Cookie` headers
+///
+/// ```
+/// # use valor_core::{http, RequestCookies};
+/// let mut req = http::Request::new(http::Method::Get, "http://example.com");
+/// req.append_header("cookie", "theme=dark; session=\"abc\"");
+/// req.append_header("cookie", "lang=en");
+///
+/// assert_eq!(req.cookie_value("session"), Some("abc"));
+/// assert_eq!(req.cookies().len(), 3);
+/// ```
+pub trait RequestCookies {
+    /// Cookies by name, when a name is repeated the first one is kept
+    fn cookies(&self) -> BTreeMap<&str, &str>;
+
+    /// Value of the cookie with the given name without the surrounding quotes
+    fn cookie_value(&self, name: &str) -> Option<&str> {
+        self.cookies().get(name).copied()
+    }
+}
+
+impl RequestCookies for Request {
+    fn cookies(&self) -> BTreeMap<&str, &str> {
+        let mut cookies = BTreeMap::new();
+        let pairs = self
+            .header(headers::COOKIE)
+            .into_iter()
+            .flat_map(|values| values.iter())
+            .flat_map(|value| value.as_str().split(';'))
+            .filter_map(|cookie| cookie.split_once('='));
+        for (name, value) in pairs {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            cookies.entry(name.trim()).or_insert(value);
+        }
+        cookies
+    }
+}
+
+/// Cookies the client is asked to store with `Set-Cookie`
+///
+/// ```
+/// # use valor_core::{http, Cookie, ResponseCookies, SameSite};
+/// # use core::time::Duration;
+/// let mut res = http::Response::new(http::StatusCode::Ok);
+/// res.set_cookie(
+///     Cookie::new("session", "abc")
+///         .path("/")
+///         .http_only()
+///         .secure()
+///         .same_site(SameSite::Lax)
+///         .max_age(Duration::from_secs(3600)),
+/// );
+/// assert_eq!(
+///     res.header("set-cookie").unwrap(),
+///     "session=abc; Path=/; Max-Age=3600; HttpOnly; Secure; SameSite=Lax"
+/// );
+/// ```
+pub trait ResponseCookies {
+    /// Adds a `Set-Cookie` header keeping the cookies set before
+    fn set_cookie(&mut self, cookie: Cookie);
+}
+
+impl ResponseCookies for Response {
+    fn set_cookie(&mut self, cookie: Cookie) {
+        self.append_header(headers::SET_COOKIE, cookie.to_string());
+    }
+}
+
+/// A cookie with the attributes that tell the client how to handle it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    /// Unix timestamp in seconds
+    expires: Option<u64>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl Cookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Cookie {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    /// Cookie that replaces the one with the same name making it expire
+    pub fn removal(name: impl Into<String>) -> Self {
+        Cookie::new(name, "")
+            .max_age(Duration::from_secs(0))
+            .expires(0)
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Time in seconds since the unix epoch the cookie expires at
+    pub fn expires(mut self, timestamp: u64) -> Self {
+        self.expires = Some(timestamp);
+        self
+    }
+
+    pub fn http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    /// Browsers only accept `SameSite=None` for secure cookies
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // values that can't go as they are are quoted
+        let needs_quotes = self
+            .value
+            .bytes()
+            .any(|b| matches!(b, b' ' | b',' | b';' | b'\\') || b.is_ascii_control());
+        if needs_quotes {
+            write!(f, "{}=\"{}\"", self.name, self.value)?;
+        } else {
+            write!(f, "{}={}", self.name, self.value)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            f.write_str("; Expires=")?;
+            http_date(f, expires)?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => f.write_str("; SameSite=Strict")?,
+            Some(SameSite::Lax) => f.write_str("; SameSite=Lax")?,
+            Some(SameSite::None) => f.write_str("; SameSite=None")?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+// Writes the timestamp as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(f: &mut fmt::Formatter<'_>, timestamp: u64) -> fmt::Result {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (days, secs) = (timestamp / 86400, timestamp % 86400);
+    // civil date from the days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    write!(
+        f,
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_set_cookie() {
+        let cookie = Cookie::new("id", "a b")
+            .domain("example.com")
+            .expires(784_111_777)
+            .same_site(SameSite::Strict);
+        assert_eq!(
+            cookie.to_string(),
+            "id=\"a b\"; Domain=example.com; Expires=Sun, 06 Nov 1994 08:49:37 GMT; SameSite=Strict"
+        );
+        assert_eq!(
+            Cookie::removal("id").to_string(),
+            "id=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn read_cookies() {
+        let mut req = Request::new(crate::http::Method::Get, "http://example.com");
+        assert!(req.cookies().is_empty());
+        req.append_header("cookie", "a=1; b=\"two\";c=3");
+        req.append_header("cookie", "a=repeated; invalid; d=");
+        let cookies = req.cookies();
+        assert_eq!(cookies.get("a"), Some(&"1"));
+        assert_eq!(cookies.get("b"), Some(&"two"));
+        assert_eq!(cookies.get("c"), Some(&"3"));
+        assert_eq!(cookies.get("d"), Some(&""));
+        assert_eq!(cookies.len(), 4);
+    }
+}
@@ -0,0 +1,81 @@
+//! Headers that only make sense for a single connection and are not relayed
+
+use crate::http::{self, headers::HeaderName};
+use alloc::vec::Vec;
+
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Removes the hop-by-hop headers(RFC 7230 section 6.1) like `Connection`,
+/// `Transfer-Encoding` or any `Proxy-*` along with the ones the `Connection`
+/// header lists. The runtime strips them from the requests it gets and the
+/// responses it sends, requests to upgrade the connection keep them.
+///
+/// ```
+/// # use valor_core::{http, strip_hop_by_hop};
+/// let mut res = http::Response::new(http::StatusCode::Ok);
+/// res.insert_header("connection", "keep-alive, x-upstream-hop");
+/// res.insert_header("x-upstream-hop", "1");
+/// res.insert_header("proxy-authenticate", "Basic");
+/// res.insert_header("content-type", "text/plain");
+/// strip_hop_by_hop(res.as_mut());
+///
+/// assert_eq!(res.header_names().count(), 1);
+/// assert!(res.header("content-type").is_some());
+/// ```
+pub fn strip_hop_by_hop(headers: &mut http::Headers) {
+    let listed = headers
+        .get(http::headers::CONNECTION)
+        .map(|values| {
+            values
+                .iter()
+                .flat_map(|v| v.as_str().split(','))
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .filter_map(|name| name.parse::<HeaderName>().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let proxy = headers
+        .iter()
+        .map(|(name, _)| name)
+        .filter(|name| name.as_str().starts_with("proxy-"))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in listed.into_iter().chain(proxy) {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_listed_headers() {
+        let mut req = http::Request::new(http::Method::Get, "http://example.com");
+        req.insert_header("Connection", "X-Custom, , x-other ");
+        req.append_header("Connection", "close");
+        req.insert_header("x-custom", "a");
+        req.insert_header("x-other", "b");
+        req.insert_header("proxy-authorization", "Basic Zm9vOmJhcg==");
+        req.insert_header("proxy-connection", "keep-alive");
+        req.insert_header("keep-alive", "timeout=5");
+        req.insert_header("transfer-encoding", "chunked");
+        req.insert_header("te", "trailers");
+        req.insert_header("authorization", "Bearer abc");
+        strip_hop_by_hop(req.as_mut());
+
+        let names = req.header_names().map(|n| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["authorization"]);
+    }
+}
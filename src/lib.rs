@@ -10,20 +10,48 @@
 extern crate alloc;
 extern crate core;
 
+mod accept;
+#[cfg(feature = "std")]
+mod client_ip;
+mod cookie;
+mod hop_by_hop;
+#[cfg(feature = "std")]
+mod multipart;
+#[cfg(all(feature = "proxy", not(target_arch = "wasm32")))]
+mod pool;
 #[cfg(feature = "proxy")]
 mod proxy;
+mod query;
 #[cfg(feature = "runtime")]
 pub mod runtime;
+#[cfg(feature = "std")]
+mod sse;
+mod state;
 #[cfg(feature = "util")]
 mod util;
 mod vlugin;
 
 use core::fmt;
 
+pub use accept::ContentNegotiation;
 pub use async_trait::async_trait;
+#[cfg(feature = "std")]
+pub use client_ip::{resolve_client_ip, Cidr, ClientIp};
+pub use cookie::{Cookie, RequestCookies, ResponseCookies, SameSite};
+pub use hop_by_hop::strip_hop_by_hop;
 pub use http_types as http;
+#[cfg(feature = "std")]
+pub use multipart::{Multipart, Part, RequestMultipart};
+#[cfg(all(feature = "proxy", not(target_arch = "wasm32")))]
+pub use pool::{HttpPool, PoolConfig, PoolStats};
+#[cfg(feature = "proxy")]
+pub use proxy::Proxy;
+pub use query::QueryParams;
 #[cfg(feature = "serde")]
 pub use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
+pub use sse::{Disconnected, Event, EventSender, SseResponse};
+pub use state::RequestState;
 #[cfg(feature = "util")]
 pub use util::*;
 pub use vlugin::*;
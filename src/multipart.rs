@@ -0,0 +1,325 @@
+//! Streaming parser of `multipart/form-data` bodies, e.g. of file uploads
+
+use crate::http::{self, Body, Request, StatusCode};
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_lite::{io, ready, AsyncRead, AsyncReadExt};
+
+// bytes read from the body at once
+const CHUNK: usize = 8 * 1024;
+// limit of the headers of a part
+const MAX_HEADERS: usize = 16 * 1024;
+
+/// Parts of a `multipart/form-data` request that are read from the body as
+/// they arrive, the body of a part is read before getting the next one or it's
+/// skipped. The size of the whole body is as limited as any other request with
+/// the runtime's `max_body_size`, a body that goes over it while its parts are
+/// read is answered with `413 Payload Too Large`.
+///
+/// ```
+/// # use valor_core::{http, RequestMultipart};
+/// # use futures_lite::AsyncReadExt;
+/// # #[async_std::main] async fn main() -> Result<(), http::Error> {
+/// let mut req = http::Request::new(http::Method::Post, "http://example.com/upload");
+/// req.set_content_type("multipart/form-data; boundary=x".parse()?);
+/// req.set_body("--x\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nhello\r\n--x--\r\n");
+///
+/// let mut parts = req.multipart()?;
+/// while let Some(mut part) = parts.next_part().await? {
+///     assert_eq!(part.name(), "file");
+///     assert_eq!(part.filename(), Some("a.txt"));
+///     let mut content = String::new();
+///     part.read_to_string(&mut content).await?;
+///     assert_eq!(content, "hello");
+/// }
+/// # Ok(()) }
+/// ```
+pub trait RequestMultipart {
+    /// Takes the body of the request to read its parts, it fails with `400 Bad Request`
+    /// when the request isn't `multipart/form-data` or has no boundary
+    fn multipart(&mut self) -> Result<Multipart, http::Error>;
+}
+
+impl RequestMultipart for Request {
+    fn multipart(&mut self) -> Result<Multipart, http::Error> {
+        let bad_request = |msg| http::Error::from_str(StatusCode::BadRequest, msg);
+        let mime = self
+            .content_type()
+            .filter(|mime| mime.essence() == "multipart/form-data")
+            .ok_or_else(|| bad_request("Expected a multipart/form-data body"))?;
+        let boundary = mime
+            .param("boundary")
+            .map(|b| b.as_str().to_owned())
+            .filter(|b| !b.is_empty() && b.len() <= 70)
+            .ok_or_else(|| bad_request("Missing multipart boundary"))?;
+        Ok(Multipart::new(self.take_body(), &boundary))
+    }
+}
+
+/// Reader of the parts of a multipart body
+pub struct Multipart {
+    body: Body,
+    // the delimiter of parts is the boundary at the start of a line
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl Multipart {
+    fn new(body: Body, boundary: &str) -> Self {
+        Multipart {
+            body,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // the first boundary doesn't need a line break before it
+            buf: b"\r\n".to_vec(),
+            done: false,
+        }
+    }
+
+    /// The next part of the body, the rest of the previous one is skipped
+    pub async fn next_part(&mut self) -> Result<Option<Part<'_>>, http::Error> {
+        if self.done {
+            return Ok(None);
+        }
+        // skips what's before the delimiter
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                self.buf.drain(..pos);
+                break;
+            }
+            let keep = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            self.buf.drain(..keep);
+            self.fill().await?;
+        }
+        while self.buf.len() < self.delimiter.len() + 2 {
+            self.fill().await?;
+        }
+        match &self.buf[self.delimiter.len()..self.delimiter.len() + 2] {
+            b"--" => {
+                self.done = true;
+                return Ok(None);
+            }
+            b"\r\n" => {}
+            _ => return Err(malformed("Invalid multipart boundary")),
+        }
+
+        // headers end with an empty line, the one of the boundary when there are none
+        let start = self.delimiter.len();
+        let end = loop {
+            if let Some(pos) = find(&self.buf[start..], b"\r\n\r\n") {
+                break start + pos;
+            }
+            if self.buf.len() > MAX_HEADERS {
+                return Err(malformed("Headers of multipart part are too large"));
+            }
+            self.fill().await?;
+        };
+        let headers = std::str::from_utf8(&self.buf[start + 2..end.max(start + 2)])
+            .map_err(|_| malformed("Invalid headers of multipart part"))?;
+        let mut part = PartHeaders::default();
+        for line in headers.split("\r\n").filter(|l| !l.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| malformed("Invalid headers of multipart part"))?;
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-disposition") {
+                part.name = param(value, "name");
+                part.filename = param(value, "filename");
+            } else if name.eq_ignore_ascii_case("content-type") {
+                part.content_type = Some(value.to_owned());
+            }
+        }
+        let name = part
+            .name
+            .ok_or_else(|| malformed("Multipart part without a name"))?;
+        self.buf.drain(..end + 4);
+        Ok(Some(Part {
+            name,
+            filename: part.filename,
+            content_type: part.content_type,
+            multipart: self,
+        }))
+    }
+
+    // reads more of the body, the body ending before the last boundary is an error
+    async fn fill(&mut self) -> Result<(), http::Error> {
+        let mut chunk = [0; CHUNK];
+        let read = self.body.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(malformed("Multipart body ended before the last boundary"));
+        }
+        self.buf.extend_from_slice(&chunk[..read]);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct PartHeaders {
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+/// Field of a multipart form, it's the reader of its content
+pub struct Part<'a> {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    multipart: &'a mut Multipart,
+}
+
+impl Part<'_> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name of the file the content comes from, only for file inputs
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+impl AsyncRead for Part<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let multipart = &mut *self.get_mut().multipart;
+        loop {
+            // bytes that can't be the start of the delimiter are part of the content
+            let (available, ends) = match find(&multipart.buf, &multipart.delimiter) {
+                Some(pos) => (pos, true),
+                None => {
+                    let len = multipart.buf.len();
+                    (len.saturating_sub(multipart.delimiter.len() - 1), false)
+                }
+            };
+            if available > 0 || ends {
+                let len = available.min(out.len());
+                out[..len].copy_from_slice(&multipart.buf[..len]);
+                multipart.buf.drain(..len);
+                return Poll::Ready(Ok(len));
+            }
+            let mut chunk = [0; CHUNK];
+            let read = ready!(Pin::new(&mut multipart.body).poll_read(cx, &mut chunk))?;
+            if read == 0 {
+                let err = "Multipart body ended before the last boundary";
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, err)));
+            }
+            multipart.buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+fn malformed(msg: &'static str) -> http::Error {
+    http::Error::from_str(StatusCode::BadRequest, msg)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Value of a parameter like `name="file"` of a header
+fn param(header: &str, name: &str) -> Option<String> {
+    header.split(';').skip(1).find_map(|p| {
+        let (key, value) = p.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"').to_owned())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use async_std::test;
+
+    // body that arrives in small chunks so boundaries are split across reads
+    struct Trickle(&'static [u8]);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            out: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let len = self.0.len().min(out.len()).min(3);
+            out[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Poll::Ready(Ok(len))
+        }
+    }
+
+    fn request(body: &'static [u8]) -> Request {
+        let mut req = Request::new(Method::Post, "http://example.com/upload");
+        req.set_content_type("multipart/form-data; boundary=----b".parse().unwrap());
+        let body = io::BufReader::new(Trickle(body));
+        req.set_body(Body::from_reader(body, None));
+        req
+    }
+
+    #[test]
+    async fn read_parts() {
+        let mut req = request(
+            b"preamble\r\n------b\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            Hi\r\n\
+            ------b\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\
+            \r\n--not the boundary\r\n\
+            ------b\r\n\
+            Content-Disposition: form-data; name=\"skipped\"\r\n\r\n\
+            whatever\r\n\
+            ------b--\r\nepilogue",
+        );
+        let mut parts = req.multipart().unwrap();
+
+        let mut part = parts.next_part().await.unwrap().unwrap();
+        assert_eq!(part.name(), "title");
+        assert_eq!(part.filename(), None);
+        let mut content = String::new();
+        part.read_to_string(&mut content).await.unwrap();
+        assert_eq!(content, "Hi");
+
+        let mut part = parts.next_part().await.unwrap().unwrap();
+        assert_eq!(part.filename(), Some("a.bin"));
+        assert_eq!(part.content_type(), Some("application/octet-stream"));
+        let mut content = Vec::new();
+        part.read_to_end(&mut content).await.unwrap();
+        assert_eq!(content, b"\r\n--not the boundary");
+
+        let part = parts.next_part().await.unwrap().unwrap();
+        assert_eq!(part.name(), "skipped");
+        assert!(parts.next_part().await.unwrap().is_none());
+    }
+
+    #[test]
+    async fn reject_malformed_bodies() {
+        let mut req = Request::new(Method::Post, "http://example.com/upload");
+        req.set_content_type("multipart/form-data".parse().unwrap());
+        assert_eq!(
+            req.multipart().err().unwrap().status(),
+            StatusCode::BadRequest
+        );
+
+        let mut req = request(b"------bX\r\n");
+        let err = req.multipart().unwrap().next_part().await.err().unwrap();
+        assert_eq!(err.status(), StatusCode::BadRequest);
+
+        let mut req =
+            request(b"------b\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nno end");
+        let mut parts = req.multipart().unwrap();
+        let mut part = parts.next_part().await.unwrap().unwrap();
+        let mut content = Vec::new();
+        assert!(part.read_to_end(&mut content).await.is_err());
+    }
+}
@@ -0,0 +1,512 @@
+//! HTTP client shared by the plugins of a runtime that keeps the connections
+//! to upstream servers alive to reuse them
+
+use crate::http;
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use async_std::{
+    channel::{self, Receiver, Sender},
+    net::TcpStream,
+};
+use async_tls::{client::TlsStream, TlsConnector};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+use futures_lite::{
+    future,
+    io::{self, AsyncRead, AsyncWrite, BufReader},
+    ready,
+};
+use futures_timer::Delay;
+use hashbrown::HashMap;
+use std::{sync::Mutex, time::Instant};
+
+/// Limits of the connections of a [`HttpPool`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Connections to the same upstream requests use at once, the ones that
+    /// are done stay open for the next requests
+    pub max_connections_per_host: usize,
+    /// Time a connection can stay unused before it's closed
+    pub idle_timeout: Duration,
+    /// Time to get a connection to an upstream, the TLS handshake included
+    pub connect_timeout: Duration,
+    /// Time the upstream has to answer with the headers of its response
+    pub request_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_connections_per_host: 50,
+            idle_timeout: Duration::from_secs(90),
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Connection pooled client for plugins that call upstream servers, added to
+/// the runtime as a state(`Runtime::with_state`) it's shared by all of them
+/// and the proxy plugins use it instead of their own client.
+///
+/// A connection goes back to the pool once the body of its response is read
+/// to the end, responses that are dropped before close their connection.
+///
+/// ```
+/// # use valor_core::{http, HttpPool, RequestState};
+/// async fn users(req: http::Request) -> http::Result<http::Response> {
+///     let pool = req.state::<HttpPool>().expect("runtime has a pool");
+///     pool.send(http::Request::get("http://users.internal/list")).await
+/// }
+/// ```
+pub struct HttpPool {
+    config: PoolConfig,
+    tls: TlsConnector,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+    checkouts: AtomicU64,
+    created: AtomicU64,
+}
+
+/// Connections of the pool, the idle ones were opened for requests that
+/// finished and are kept open for the next ones
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub active: usize,
+    pub idle: usize,
+    /// Requests that took a connection since the pool was created
+    pub checkouts: u64,
+    /// Connections opened since the pool was created
+    pub created: u64,
+}
+
+// Connections to an origin
+struct Host {
+    // a turn for every request that can use a connection at once
+    turns: (Sender<()>, Receiver<()>),
+    active: AtomicUsize,
+    // most recently used last
+    idle: Mutex<Vec<(Stream, Instant)>>,
+}
+
+impl HttpPool {
+    pub fn new(config: PoolConfig) -> Result<Self, http::Error> {
+        Ok(HttpPool {
+            config,
+            tls: TlsConnector::default(),
+            hosts: Mutex::default(),
+            checkouts: AtomicU64::new(0),
+            created: AtomicU64::new(0),
+        })
+    }
+
+    /// Sends the request reusing an open connection to its host if there's
+    /// one free, failing to reach the upstream is a `502 Bad Gateway` and
+    /// not getting a connection or an answer in time a `504 Gateway Timeout`
+    pub async fn send(&self, request: http::Request) -> http::Result<http::Response> {
+        let url = request.url().clone();
+        let host = self.host(url.origin().ascii_serialization());
+        let checkout = self.checkout(&host).await?;
+
+        // an upstream can close an idle connection anytime, requests without
+        // a body are sent again with a new one
+        let safe = matches!(
+            request.method(),
+            http::Method::Get | http::Method::Head | http::Method::Options
+        );
+        let retry = safe && request.is_empty() == Some(true);
+        let template = if retry { Some(request.clone()) } else { None };
+        let stream = checkout.idle(self.config.idle_timeout);
+        let reused = stream.is_some();
+        let stream = match stream {
+            Some(stream) => stream,
+            None => self.connect(&url).await?,
+        };
+        match (self.exchange(stream, checkout, request).await, template) {
+            (Err(err), Some(request)) if reused && err.status() == http::StatusCode::BadGateway => {
+                let checkout = self.checkout(&host).await?;
+                let stream = self.connect(&url).await?;
+                self.exchange(stream, checkout, request).await
+            }
+            (res, _) => res,
+        }
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let hosts = self.hosts.lock().unwrap();
+        let (active, idle) = hosts.values().fold((0, 0), |(active, idle), host| {
+            host.evict(self.config.idle_timeout);
+            (
+                active + host.active.load(Ordering::Relaxed),
+                idle + host.idle.lock().unwrap().len(),
+            )
+        });
+        PoolStats {
+            active,
+            idle,
+            checkouts: self.checkouts.load(Ordering::Relaxed),
+            created: self.created.load(Ordering::Relaxed),
+        }
+    }
+
+    fn host(&self, origin: String) -> Arc<Host> {
+        let max = self.config.max_connections_per_host.max(1);
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(origin)
+            .or_insert_with(|| {
+                let turns = channel::bounded(max);
+                for _ in 0..max {
+                    let _ = turns.0.try_send(());
+                }
+                Arc::new(Host {
+                    turns,
+                    active: AtomicUsize::new(0),
+                    idle: Mutex::default(),
+                })
+            })
+            .clone()
+    }
+
+    // Waits for a turn to use a connection of the host
+    async fn checkout(&self, host: &Arc<Host>) -> http::Result<Checkout> {
+        let turn = within(self.config.connect_timeout, host.turns.1.recv()).await;
+        if !matches!(turn, Some(Ok(()))) {
+            return Err(gateway_timeout("No free connection to the upstream"));
+        }
+        host.active.fetch_add(1, Ordering::Relaxed);
+        self.checkouts.fetch_add(1, Ordering::Relaxed);
+        Ok(Checkout(host.clone()))
+    }
+
+    async fn connect(&self, url: &http::Url) -> http::Result<Stream> {
+        match within(self.config.connect_timeout, self.open(url)).await {
+            Some(Ok(stream)) => {
+                self.created.fetch_add(1, Ordering::Relaxed);
+                Ok(stream)
+            }
+            Some(Err(err)) => Err(bad_gateway(err)),
+            None => Err(gateway_timeout("Couldn't connect to the upstream in time")),
+        }
+    }
+
+    async fn open(&self, url: &http::Url) -> io::Result<Stream> {
+        let name = url.host_str().unwrap_or_default();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let tcp = TcpStream::connect((name, port)).await?;
+        tcp.set_nodelay(true)?;
+        match url.scheme() {
+            "https" => {
+                let tls = self.tls.connect(name, tcp).await?;
+                Ok(Stream::Tls(Box::new(tls)))
+            }
+            _ => Ok(Stream::Plain(tcp)),
+        }
+    }
+
+    // Sends the request over the connection, the turn of the request is
+    // over once its response is read to the end or dropped
+    async fn exchange(
+        &self,
+        stream: Stream,
+        checkout: Checkout,
+        request: http::Request,
+    ) -> http::Result<http::Response> {
+        let done = Arc::new(AtomicBool::new(false));
+        let conn = Conn {
+            stream: Some(stream),
+            checkout,
+            done: done.clone(),
+        };
+        let answer = async_h1::connect(conn, request);
+        let mut res = match within(self.config.request_timeout, answer).await {
+            Some(Ok(res)) => res,
+            Some(Err(err)) => return Err(bad_gateway(err)),
+            None => return Err(gateway_timeout("Upstream timed out")),
+        };
+        let len = res.len();
+        let body = Finished {
+            body: res.take_body(),
+            done,
+        };
+        res.set_body(http::Body::from_reader(BufReader::new(body), len));
+        Ok(res)
+    }
+}
+
+impl fmt::Debug for HttpPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpPool")
+            .field("config", &self.config)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Host {
+    // Closes the connections that were unused for longer than the timeout
+    fn evict(&self, timeout: Duration) {
+        self.idle
+            .lock()
+            .unwrap()
+            .retain(|(_, since)| since.elapsed() < timeout);
+    }
+}
+
+// The turn of a request to use a connection of the host, when the request
+// is done it's given to the next one
+struct Checkout(Arc<Host>);
+
+impl Checkout {
+    fn idle(&self, timeout: Duration) -> Option<Stream> {
+        self.0.evict(timeout);
+        self.0.idle.lock().unwrap().pop().map(|(stream, _)| stream)
+    }
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+        let _ = self.0.turns.0.try_send(());
+    }
+}
+
+// Connection used by a request, it's reused when its response was read to the
+// end, otherwise what's left of it would be read as the next response
+struct Conn {
+    stream: Option<Stream>,
+    checkout: Checkout,
+    done: Arc<AtomicBool>,
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        let done = self.done.load(Ordering::Acquire);
+        if let Some(stream) = self.stream.take().filter(|_| done) {
+            let idle = &self.checkout.0.idle;
+            idle.lock().unwrap().push((stream, Instant::now()));
+        }
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.stream.as_mut() {
+            Some(stream) => Pin::new(stream).poll_read(cx, buf),
+            None => Poll::Ready(Ok(0)),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.stream.as_mut() {
+            Some(stream) => Pin::new(stream).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stream.as_mut() {
+            Some(stream) => Pin::new(stream).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stream.as_mut() {
+            Some(stream) => Pin::new(stream).poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_close(cx),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_close(cx),
+        }
+    }
+}
+
+// Body of a response that tells when it was read to the end
+struct Finished {
+    body: http::Body,
+    done: Arc<AtomicBool>,
+}
+
+impl AsyncRead for Finished {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = ready!(Pin::new(&mut self.body).poll_read(cx, buf))?;
+        if read == 0 && !buf.is_empty() {
+            self.done.store(true, Ordering::Release);
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+// None when the future doesn't complete in time
+async fn within<T>(timeout: Duration, fut: impl Future<Output = T>) -> Option<T> {
+    future::or(async { Some(fut.await) }, async {
+        Delay::new(timeout).await;
+        None
+    })
+    .await
+}
+
+fn bad_gateway(err: impl fmt::Display) -> http::Error {
+    http::Error::from_str(http::StatusCode::BadGateway, err.to_string())
+}
+
+fn gateway_timeout(msg: &'static str) -> http::Error {
+    http::Error::from_str(http::StatusCode::GatewayTimeout, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::test;
+
+    #[test]
+    async fn reuse_connections() {
+        let mock = mockito::mock("GET", "/foo")
+            .with_body("foo")
+            .expect(2)
+            .create();
+        let pool = HttpPool::new(PoolConfig::default()).unwrap();
+        let url = mockito::server_url() + "/foo";
+
+        for _ in 0..2 {
+            let mut res = pool.send(http::Request::get(url.as_str())).await.unwrap();
+            assert_eq!(res.body_string().await.unwrap(), "foo");
+        }
+        let stats = PoolStats {
+            active: 0,
+            idle: 1,
+            checkouts: 2,
+            created: 1,
+        };
+        assert_eq!(pool.stats(), stats);
+        mock.assert();
+
+        let res = pool.send(http::Request::get("http://127.0.0.1:1")).await;
+        assert_eq!(res.unwrap_err().status(), http::StatusCode::BadGateway);
+        let stats = PoolStats {
+            checkouts: 3,
+            ..stats
+        };
+        assert_eq!(pool.stats(), stats);
+    }
+
+    #[test]
+    async fn close_idle_and_unfinished_connections() {
+        let _mock = mockito::mock("GET", "/foo").with_body("foo").create();
+        let pool = HttpPool::new(PoolConfig {
+            idle_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        })
+        .unwrap();
+        let url = mockito::server_url() + "/foo";
+
+        let mut res = pool.send(http::Request::get(url.as_str())).await.unwrap();
+        res.body_string().await.unwrap();
+        assert_eq!(pool.stats().idle, 1);
+        Delay::new(Duration::from_millis(60)).await;
+        assert_eq!(pool.stats().idle, 0);
+
+        // a response that wasn't read leaves its connection unusable
+        let res = pool.send(http::Request::get(url.as_str())).await.unwrap();
+        assert_eq!(pool.stats().active, 1);
+        drop(res);
+        let stats = pool.stats();
+        assert_eq!((stats.active, stats.idle, stats.created), (0, 0, 2));
+    }
+
+    #[test]
+    async fn time_out_answers_apart_from_connecting() {
+        // accepts connections but never answers
+        let server = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("http://{}/", server.local_addr().unwrap());
+        let _accept = async_std::task::spawn(async move { server.accept().await });
+        let pool = HttpPool::new(PoolConfig {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        })
+        .unwrap();
+
+        let started = Instant::now();
+        let err = pool
+            .send(http::Request::get(url.as_str()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), http::StatusCode::GatewayTimeout);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(pool.stats().created, 1);
+    }
+}
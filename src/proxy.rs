@@ -1,15 +1,61 @@
-use crate::{async_trait, http, Answer, Context, Error, Message, Vlugin};
+use crate::{
+    async_trait, http, strip_hop_by_hop, Answer, Context, Error, Message, RequestState, Vlugin,
+    VluginConfig,
+};
+use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
-use alloc::string::String;
-use core::convert::TryFrom;
+use alloc::string::{String, ToString};
+use core::{convert::TryFrom, time::Duration};
+use futures_lite::future;
+use futures_timer::Delay;
 #[cfg(target_arch = "wasm32")]
 use http_client::{h1::wasm::WasmClient as Client, HttpClient};
 #[cfg(not(target_arch = "wasm32"))]
 use http_client::{h1::H1Client as Client, HttpClient};
 
-struct Proxy {
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Plugin that forwards requests to an upstream HTTP server.
+/// Its client is shared by all the requests so connections can be reused.
+pub struct Proxy {
     client: Client,
     server: http::Url,
+    timeout: Duration,
+}
+
+impl Proxy {
+    /// Creates a proxy from a plugin configuration like
+    /// `{"upstream": "http://localhost:3000", "timeout_ms": 5000}`
+    pub fn from_config(config: Option<&VluginConfig>) -> Result<Self, http::Error> {
+        let upstream = config
+            .and_then(|c| c.get("upstream"))
+            .and_then(VluginConfig::as_str)
+            .ok_or_else(|| http::Error::from_str(http::StatusCode::BadRequest, "No upstream"))?;
+        let mut proxy = Proxy::try_from(upstream.to_owned())?;
+        if let Some(ms) = config
+            .and_then(|c| c.get("timeout_ms"))
+            .and_then(VluginConfig::as_u64)
+        {
+            proxy.timeout = Duration::from_millis(ms);
+        }
+        Ok(proxy)
+    }
+
+    // request to the upstream with the path relative to the plugin route
+    fn upstream_request(&self, mut req: http::Request) -> http::Request {
+        let url = req.url();
+        let mut upstream_url = self.server.clone();
+        upstream_url.set_path(&(self.server.path().trim_end_matches('/').to_owned() + url.path()));
+        upstream_url.set_query(url.query());
+        upstream_url.set_fragment(url.fragment());
+
+        let mut proxied_req = http::Request::new(req.method(), upstream_url);
+        // copy headers
+        proxied_req.as_mut().clone_from(req.as_ref());
+        strip_hop_by_hop(proxied_req.as_mut());
+        proxied_req.set_body(req.take_body());
+        proxied_req
+    }
 }
 
 impl TryFrom<String> for Proxy {
@@ -19,6 +65,7 @@ impl TryFrom<String> for Proxy {
         Ok(Proxy {
             client: Client::new(),
             server: url.parse()?,
+            timeout: DEFAULT_TIMEOUT,
         })
     }
 }
@@ -26,26 +73,40 @@ impl TryFrom<String> for Proxy {
 #[async_trait(?Send)]
 impl Vlugin for Proxy {
     async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
-        let mut req = match msg {
+        let req = match msg {
             Message::Http(req) => req,
             Message::Ping => return Err(Error::NotSupported),
         };
 
-        let url = req.url();
-        let mut upstream_url = self
-            .server
-            .join(url.path())
-            .map_err(|_| http::Error::from_str(http::StatusCode::InternalServerError, ""))?;
-        upstream_url.set_query(url.query());
-        upstream_url.set_fragment(url.fragment());
-
-        let mut proxied_req = http::Request::new(req.method(), upstream_url);
-        // copy headers
-        proxied_req.as_mut().clone_from(req.as_ref());
-
-        proxied_req.set_body(req.take_body());
+        let timeout = async {
+            Delay::new(self.timeout).await;
+            Err(http::Error::from_str(
+                http::StatusCode::GatewayTimeout,
+                "Upstream timed out",
+            ))
+        };
+        // the pool of the runtime keeps the connections to upstreams alive
+        #[cfg(not(target_arch = "wasm32"))]
+        let pool = req.state::<crate::HttpPool>();
+        let send = async {
+            let req = self.upstream_request(req);
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                if let Some(pool) = pool {
+                    return pool.send(req).await;
+                }
+            }
+            self.client
+                .send(req)
+                .await
+                .map_err(|err| http::Error::from_str(http::StatusCode::BadGateway, err.to_string()))
+        };
+        let mut res = future::or(send, timeout).await?;
 
-        Ok(self.client.send(proxied_req).await?.into())
+        strip_hop_by_hop(res.as_mut());
+        // the runtime sets the plugin that answered
+        res.remove_header("x-valor-plugin");
+        Ok(res.into())
     }
 
     fn context_mut(&mut self) -> &mut Context {
@@ -83,4 +144,55 @@ mod tests {
         mock.assert();
         Ok(())
     }
+
+    #[test]
+    async fn strip_hop_by_hop_headers() -> Result<(), Error> {
+        let mock = mockito::mock("GET", "/api/foo")
+            .match_header("x-request-id", "123")
+            .match_header("connection", mockito::Matcher::Missing)
+            .match_header("x-custom-hop", mockito::Matcher::Missing)
+            .create();
+
+        let p: Proxy = (mockito::server_url() + "/api/").try_into()?;
+
+        let mut req = http::Request::new(Method::Get, "foo:/foo");
+        req.insert_header("x-request-id", "123");
+        req.insert_header("connection", "x-custom-hop");
+        req.insert_header("x-custom-hop", "bar");
+        p.on_msg(req.into()).await?;
+
+        mock.assert();
+        Ok(())
+    }
+
+    #[test]
+    async fn send_with_shared_pool() -> Result<(), Error> {
+        let mock = mockito::mock("GET", "/pooled").create();
+        let p: Proxy = mockito::server_url().try_into()?;
+        let pool = alloc::sync::Arc::new(crate::HttpPool::new(Default::default())?);
+
+        let mut req = http::Request::new(Method::Get, "foo:/pooled");
+        req.set_ext(crate::state::State(pool.clone()));
+        let mut res: http::Response = p.on_msg(req.into()).await?.into();
+
+        assert_eq!(res.status(), http::StatusCode::Ok);
+        assert_eq!(pool.stats().active, 1);
+        // the connection is free again once the response was read
+        res.body_bytes().await?;
+        assert_eq!(pool.stats().idle, 1);
+        mock.assert();
+        Ok(())
+    }
+
+    #[test]
+    async fn unreachable_upstream_is_bad_gateway() {
+        let cfg = serde_json::json!({ "upstream": "http://127.0.0.1:1" });
+        let p = Proxy::from_config(Some(&cfg)).unwrap();
+
+        let req = http::Request::new(Method::Get, "foo:/foo");
+        match p.on_msg(req.into()).await {
+            Err(Error::Http(err)) => assert_eq!(err.status(), http::StatusCode::BadGateway),
+            _ => panic!("expected an error"),
+        }
+    }
 }
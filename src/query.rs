@@ -0,0 +1,62 @@
+//! Reading the parameters of the query string of requests
+
+use crate::http::Request;
+use alloc::{string::String, vec::Vec};
+
+/// Percent decoded parameters of the query string, to deserialize them all at
+/// once into a type there's `Request::query` that fails with `400 Bad Request`
+///
+/// ```
+/// # use valor_core::{http, QueryParams};
+/// let req = http::Request::new(http::Method::Get, "http://example.com/?page=2&tag=a%20b&tag=c");
+///
+/// assert_eq!(req.query_param("page").as_deref(), Some("2"));
+/// assert_eq!(req.query_params("tag"), ["a b", "c"]);
+/// assert_eq!(req.query_param("missing"), None);
+/// ```
+pub trait QueryParams {
+    /// First value of the parameter with the given name
+    fn query_param(&self, name: &str) -> Option<String>;
+
+    /// All the values of a parameter that is repeated, in order
+    fn query_params(&self, name: &str) -> Vec<String>;
+}
+
+impl QueryParams for Request {
+    fn query_param(&self, name: &str) -> Option<String> {
+        self.url()
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, val)| val.into_owned())
+    }
+
+    fn query_params(&self, name: &str) -> Vec<String> {
+        self.url()
+            .query_pairs()
+            .filter(|(key, _)| key == name)
+            .map(|(_, val)| val.into_owned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, StatusCode};
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn typed_query() {
+        #[derive(serde::Deserialize)]
+        struct Page {
+            page: u32,
+        }
+        let req = Request::new(Method::Get, "http://example.com/?page=2&q=%C3%B6+x");
+        assert_eq!(req.query::<Page>().unwrap().page, 2);
+        assert_eq!(req.query_param("q").as_deref(), Some("ö x"));
+
+        let req = Request::new(Method::Get, "http://example.com/?page=two");
+        let err = req.query::<Page>().err().unwrap();
+        assert_eq!(err.status(), StatusCode::BadRequest);
+    }
+}
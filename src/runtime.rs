@@ -1,12 +1,76 @@
+mod breaker;
+mod drain;
+mod health;
+mod lazy;
+mod limit;
+mod maintenance;
+mod metrics;
+mod middleware;
+#[cfg(all(feature = "std", feature = "serde"))]
+mod persist;
+mod problem;
+mod redirect;
 mod registry;
+mod stats;
+#[cfg(feature = "std")]
+mod subrequest;
+mod time;
+pub mod transform;
+mod version;
 mod vlugin_definition;
+mod websocket;
+
+pub use breaker::CircuitBreaker;
+pub use maintenance::MaintenancePage;
+pub use metrics::{Connections, OpenConnection};
+#[cfg(feature = "auth")]
+pub use middleware::{BasicAuth, User};
+pub use middleware::{Cache, CachePurge, Cors, ETag, Middleware, Next, Prefixes, SecurityHeaders};
+#[cfg(feature = "jwt")]
+pub use middleware::{Claims, Jwt};
+pub use redirect::RedirectHandler;
+#[cfg(feature = "serde")]
+pub use registry::RegistryAuth;
+pub use registry::{LoadInfo, Params};
+#[cfg(feature = "std")]
+pub use subrequest::Subrequests;
+pub use transform::Transform;
+pub use version::Version;
+pub use vlugin_definition::{
+    sort_by_dependencies, LoadBalancer, Mirror, Retry, VluginDef, VluginType,
+};
 
-pub use vlugin_definition::{VluginDef, VluginType};
+use crate::{
+    async_trait,
+    http::{self, StatusCode},
+    Answer, Context, Health, Message, RequestCookies, ResponseCookies, Upgraded, Vlugin,
+};
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    format,
+    rc::Rc,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{cell::RefCell, fmt, future::Future, pin::Pin, time::Duration};
+use drain::Drain;
+use registry::{Choice, PluginRegistry, RegistrationError};
 
-use crate::{async_trait, Answer, Context, Message, Vlugin};
-use alloc::{borrow::ToOwned, boxed::Box, rc::Rc, string::String};
-use core::{cell::RefCell, fmt, future::Future, pin::Pin};
-use registry::PluginRegistry;
+const REQ_ID_HEADER: &str = "x-request-id";
+const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+const SERVER_TIMING_HEADER: &str = "server-timing";
+const HEALTH_PLUGIN: &str = "health";
+const METRICS_PLUGIN: &str = "metrics";
+const REGISTRY_PLUGIN: &str = "registry";
+const MAINTENANCE_PLUGIN: &str = "maintenance";
+const BUILTINS: [&str; 4] = [
+    HEALTH_PLUGIN,
+    METRICS_PLUGIN,
+    REGISTRY_PLUGIN,
+    MAINTENANCE_PLUGIN,
+];
 
 /// The runtime is a "Vlugin" itself that serves as the main entry point for
 /// dispatching incoming messages to vlugins registered under a specific URL pattern.
@@ -36,6 +100,59 @@ pub struct Runtime<L> {
     cx: Context,
     registry: Rc<RefCell<PluginRegistry>>,
     loader: Rc<L>,
+    disabled_status: StatusCode,
+    middlewares: Vec<Rc<dyn Middleware>>,
+    // of every plugin or only the one with the given name
+    transforms: Vec<(Option<String>, Rc<dyn Transform>)>,
+    drain: Rc<Drain>,
+    limiter: Rc<limit::Limiter>,
+    request_timeout: Option<Duration>,
+    max_body_size: Option<usize>,
+    metrics: Option<Rc<metrics::Metrics>>,
+    request_ids: Option<Rc<dyn Fn() -> String>>,
+    request_id_header: Option<http::headers::HeaderName>,
+    problem_details: bool,
+    fallback: Option<(VluginDef, Rc<dyn Vlugin>)>,
+    sticky: Option<Sticky>,
+    namespaces: Option<Namespaces>,
+    auto_head: bool,
+    server_timing: bool,
+    allowed_hosts: Vec<String>,
+    maintenance_page: Option<Rc<MaintenancePage>>,
+    #[cfg(feature = "std")]
+    trusted_proxies: Vec<crate::Cidr>,
+    // adds the states of the runtime to requests
+    states: Vec<Rc<dyn Fn(&mut http::Request)>>,
+    spawn: Option<Rc<dyn Fn(BoxedFuture<'static, ()>)>>,
+    #[cfg(feature = "std")]
+    max_subrequest_depth: Option<u8>,
+}
+
+/// What keeps a client on the same variant of a route with weighted plugins,
+/// variants are otherwise chosen by the [`LoadBalancer`] of the route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sticky {
+    /// Value of the cookie with the given name, e.g. a session id
+    Cookie(String),
+    /// IP address of the client that made the request
+    ClientIp,
+    /// Cookie the runtime sets with the variant that served the client, later
+    /// requests go to that same variant while it's in rotation and when it's
+    /// unhealthy or gone they are rebalanced. Each route has its own cookie
+    /// named after the given one(e.g. `replica-6b1e04f2c9a3d571`) and every
+    /// response sets it again so it expires `ttl` after the last request.
+    Affinity { cookie: String, ttl: Duration },
+}
+
+/// Where the namespace of a request comes from, requests of a namespace match
+/// the plugins of that namespace first and then the ones without a namespace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Namespaces {
+    /// Value of the header with the given name, e.g. `x-tenant`
+    Header(String),
+    /// First segment of the path(e.g. `acme` of `/acme/api/users`), the
+    /// plugins of the namespace see the path without it
+    PathSegment,
 }
 
 impl<L: Loader> Runtime<L> {
@@ -45,104 +162,591 @@ impl<L: Loader> Runtime<L> {
             cx: Context::default(),
             registry: Rc::new(RefCell::new(PluginRegistry::new())),
             loader: loader.into(),
+            disabled_status: StatusCode::NotFound,
+            middlewares: Vec::new(),
+            transforms: Vec::new(),
+            drain: Rc::default(),
+            limiter: Rc::default(),
+            request_timeout: None,
+            max_body_size: None,
+            metrics: None,
+            request_ids: None,
+            request_id_header: None,
+            problem_details: false,
+            fallback: None,
+            sticky: None,
+            namespaces: None,
+            auto_head: true,
+            server_timing: false,
+            allowed_hosts: Vec::new(),
+            maintenance_page: None,
+            #[cfg(feature = "std")]
+            trusted_proxies: Vec::new(),
+            states: Vec::new(),
+            spawn: None,
+            #[cfg(feature = "std")]
+            max_subrequest_depth: None,
         }
     }
 
-    /// Uses the configured loader to load and register the provided plugin
-    pub async fn load_plugin(&self, mut plugin: VluginDef) -> Result<(), Error> {
-        let factory = self
-            .loader
-            .load(&plugin)
-            .await
-            .map_err(|_| Error::LoadVlugin(plugin.name.clone()))?;
-        let handler = factory(plugin.config.take())
-            .await
-            .map_err(|_| Error::InstantiateVlugin(plugin.name.clone()))?;
-        self.register_plugin(plugin, handler)?;
-        Ok(())
+    /// Shares a value like a pool of connections or a configuration with the
+    /// plugins and middlewares, they get it with [`RequestState::state`].
+    /// There's one state per type, adding another of a type replaces it.
+    ///
+    /// [`RequestState::state`]: crate::RequestState::state
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Self {
+        let state = Arc::new(state);
+        self.states.push(Rc::new(move |req: &mut http::Request| {
+            req.set_ext(crate::state::State(state.clone()));
+        }));
+        self
+    }
+
+    /// Adds a middleware that requests go through before reaching a plugin,
+    /// middlewares run in the order they are added, the first one is the outermost
+    pub fn with_middleware(mut self, middleware: impl Middleware) -> Self {
+        self.middlewares.push(Rc::new(middleware));
+        self
+    }
+
+    /// Edits the requests of every plugin and their responses, transforms run
+    /// in the order they are added after the middlewares and pipelines
+    pub fn with_transform(mut self, transform: impl Transform) -> Self {
+        self.transforms.push((None, Rc::new(transform)));
+        self
+    }
+
+    /// Same as [`Self::with_transform`] only for the plugin with the given name
+    pub fn with_plugin_transform(mut self, plugin: &str, transform: impl Transform) -> Self {
+        self.transforms
+            .push((Some(plugin.to_owned()), Rc::new(transform)));
+        self
+    }
+
+    /// Status used to answer requests for disabled plugins, it's `404` by default
+    /// as if the plugin didn't exist but a `503` might be more appropriate
+    pub fn with_disabled_status(mut self, status: StatusCode) -> Self {
+        self.disabled_status = status;
+        self
+    }
+
+    /// Time plugins have to load before giving up on them, it's 30 seconds by
+    /// default and plugins can override it with their `load_timeout_ms`.
+    /// Timeouts are only enforced with the `std` feature.
+    pub fn with_load_timeout(self, timeout: Duration) -> Self {
+        self.registry.borrow_mut().load_timeout = timeout;
+        self
+    }
+
+    /// Time a plugin that is unloaded or replaced has to finish the requests
+    /// it's handling, it's 30 seconds by default. New requests go to the
+    /// replacement(or get a `404`) right away.
+    pub fn with_plugin_drain_timeout(self, timeout: Duration) -> Self {
+        self.registry.borrow_mut().drain_timeout = timeout;
+        self
+    }
+
+    /// Time plugins have to answer a request, there's no limit by default and
+    /// plugins can set their own with `request_timeout_ms`. A plugin that takes
+    /// longer is answered with `503 Service Unavailable`.
+    /// Timeouts are only enforced with the `std` feature.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Requests plugins can handle at the same time, the ones over the limit
+    /// are answered with `503 Service Unavailable` unless there's room to wait
+    /// in the queue set with [`Self::with_concurrency_queue`]. The built-in
+    /// health and metrics plugins aren't limited to keep answering under load.
+    pub fn with_max_concurrency(self, limit: usize) -> Self {
+        self.limiter.set_max(limit);
+        self
+    }
+
+    /// Requests that wait for others to finish when the concurrency limit is
+    /// reached, there's no queue by default. The request timeout includes the
+    /// time spent waiting.
+    pub fn with_concurrency_queue(self, len: usize) -> Self {
+        self.limiter.set_queue(len);
+        self
+    }
+
+    /// Bytes a request body can have, bigger requests are answered with
+    /// `413 Payload Too Large`, requests with a bigger `Content-Length` before
+    /// reading the body. Bodies without a known length(only with the `std`
+    /// feature) fail to be read once they go over the limit and the request is
+    /// answered with `413` as well, they are streamed to the plugin meanwhile.
+    pub fn with_max_body_size(mut self, size: usize) -> Self {
+        self.max_body_size = Some(size);
+        self
+    }
+
+    /// Hosts requests can be sent to, like `example.com` or `*.example.com` for
+    /// any subdomain, requests for other hosts are answered with
+    /// `421 Misdirected Request` and the ones without a host with `400 Bad Request`.
+    /// Any host is accepted when the list is empty, as it is by default.
+    pub fn with_allowed_hosts(mut self, hosts: Vec<String>) -> Self {
+        self.allowed_hosts = hosts;
+        self
+    }
+
+    /// Whether `HEAD` requests for routes that only have a `GET` plugin are
+    /// answered by that plugin without the body, it's enabled by default.
+    /// Plugins that serve `HEAD` themselves are always preferred.
+    pub fn with_auto_head(mut self, enabled: bool) -> Self {
+        self.auto_head = enabled;
+        self
+    }
+
+    /// Tells clients how long it took to answer their requests with a
+    /// `Server-Timing: total;dur=<ms>` header, the time includes the middlewares
+    /// and errors, also of requests refused before them(e.g. for an unknown
+    /// host), are answered as responses to carry it. Browsers show it with
+    /// the timing of the request. Without `std` time can't be measured.
+    pub fn with_server_timing(mut self, enabled: bool) -> Self {
+        self.server_timing = enabled;
+        self
+    }
+
+    /// Proxies in front of the runtime whose `X-Forwarded-For` header tells the
+    /// address of the client, see [`ClientIp`](crate::ClientIp). The header of
+    /// requests from any other peer is ignored as anyone can send it.
+    #[cfg(feature = "std")]
+    pub fn with_trusted_proxies(mut self, proxies: Vec<crate::Cidr>) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    /// Takes a plugin out of rotation without unloading it or back in,
+    /// returns `false` if there was none with that `name`
+    pub fn set_plugin_disabled(&self, name: &str, disabled: bool) -> bool {
+        self.registry.borrow_mut().set_disabled(name, disabled)
+    }
+
+    /// Uses the configured loader to load and register the provided plugin, a
+    /// plugin it replaces gets the drain timeout to finish its requests in the
+    /// background
+    pub async fn load_plugin(&self, plugin: VluginDef) -> Result<(), Error> {
+        self.registry.borrow().check_registration(&plugin)?;
+        let timeout = self.registry.borrow().load_timeout;
+        let (handler, load) = load_vlugin(&*self.loader, &plugin, timeout).await;
+        let retiring = self.registry.borrow().retiring(&plugin.name);
+        let mut registry = self.registry.borrow_mut();
+        match handler {
+            Ok(handler) => {
+                registry.register_loaded(plugin, handler, load)?;
+                if let Some(retiring) = retiring {
+                    retiring.retire();
+                }
+                Ok(())
+            }
+            Err(err) => {
+                registry.record_failure(plugin, load);
+                Err(err)
+            }
+        }
+    }
+
+    /// How the last load of the plugin with that `name` went
+    pub fn load_info(&self, name: &str) -> Option<LoadInfo> {
+        self.registry.borrow().load_info(name).cloned()
+    }
+
+    /// Registers the plugin without loading it, its route is taken and the first
+    /// request for it loads the plugin(concurrent requests wait for the same
+    /// load) that answers it and the ones after. When the load fails or times
+    /// out the request is answered with `503 Service Unavailable` and the next
+    /// one tries to load it again. Plugins that aren't loaded yet don't accept
+    /// upgrades and are reported as healthy.
+    pub fn register_lazy(&self, plugin: VluginDef) -> Result<(), Error> {
+        let lazy =
+            lazy::LazyVlugin::new(plugin.clone(), self.loader.clone(), self.registry.clone());
+        // the plugin warms up once it's loaded
+        self.registry
+            .borrow_mut()
+            .register_loaded(plugin, lazy, LoadInfo::default())
+            .map(|_| ())
+            .map_err(Into::into)
+    }
+
+    /// The runtime is reported as not ready until [`Self::set_ready`] is called,
+    /// for runtimes that start handling requests before loading their plugins
+    pub fn with_startup(self) -> Self {
+        self.drain.set_starting(true);
+        self
+    }
+
+    /// Ends the startup, the plugins are loaded and traffic can be served
+    pub fn set_ready(&self) {
+        self.drain.set_starting(false);
+    }
+
+    /// Puts the runtime in maintenance or takes it out, meanwhile requests for
+    /// plugins are answered with `503 Service Unavailable` and the page set
+    /// with [`Self::with_maintenance_page`] while the built-in endpoints like
+    /// `_health` or `_plugins` keep working. The runtime is not ready during
+    /// maintenance so load balancers stop sending it traffic.
+    pub fn set_maintenance(&self, maintenance: bool) {
+        self.drain.set_maintenance(maintenance);
+    }
+
+    /// Whether the runtime is in maintenance, see [`Self::set_maintenance`]
+    pub fn in_maintenance(&self) -> bool {
+        self.drain.in_maintenance()
+    }
+
+    /// Answer to the requests made during maintenance instead of the default
+    /// plain text error, e.g. an HTML page for browsers
+    pub fn with_maintenance_page(mut self, page: MaintenancePage) -> Self {
+        self.maintenance_page = Some(Rc::new(page));
+        self
+    }
+
+    /// Expose the maintenance switch as an endpoint on `_maintenance` to start
+    /// (`POST`) or end(`DELETE`) it at runtime, it's protected like the registry
+    #[cfg(feature = "serde")]
+    pub fn with_maintenance_endpoint(self, auth: Option<RegistryAuth>) -> Result<Self, Error> {
+        let handler = maintenance::MaintenanceHandler::new(self.drain.clone(), auth);
+        self.register_handler(MAINTENANCE_PLUGIN, handler)?;
+        Ok(self)
+    }
+
+    /// Checks the health of the variants of every route(plugins with a weight),
+    /// requests skip the unhealthy ones until a later check finds them healthy.
+    /// A check that takes longer than the `timeout` counts as unhealthy.
+    pub async fn check_replicas(&self, timeout: Duration) {
+        let replicas = self.registry.borrow().replicas();
+        for (plugin, handler) in replicas {
+            let health = time::timeout(timeout, handler.health())
+                .await
+                .unwrap_or(Health::Unhealthy);
+            self.registry.borrow().set_probe(&plugin.name, health);
+        }
+    }
+
+    /// Checks the health of the replicas every `interval` until the runtime
+    /// shuts down, e.g. spawned alongside the server
+    #[cfg(feature = "std")]
+    pub async fn check_replicas_every(&self, interval: Duration) {
+        while !self.drain.is_draining() {
+            self.check_replicas(interval).await;
+            time::sleep(interval).await;
+        }
+    }
+
+    /// Starts shutting down the runtime, the returned future resolves once the
+    /// requests in flight finish. Meanwhile the health endpoint reports the
+    /// runtime is not ready so it stops receiving traffic.
+    pub fn shutdown(&self) -> impl Future<Output = ()> + '_ {
+        self.drain.drained()
+    }
+
+    /// Removes a loaded plugin, returns `false` if there was none with that `name`
+    /// and fails while other plugins depend on it.
+    /// It stops getting requests right away and the ones it's handling get up to
+    /// the drain timeout to finish in the background before it's dropped
+    pub fn unload_plugin(&self, name: &str) -> Result<bool, Error> {
+        let retiring = self.registry.borrow().retiring(name);
+        if !self.registry.borrow_mut().unregister(name)? {
+            return Ok(false);
+        }
+        if let Some(retiring) = retiring {
+            retiring.retire();
+        }
+        Ok(true)
     }
 
     /// Expose the plugin registry as an endpoint on `_plugins` to add more plugins dynamically
+    /// (`POST /_plugins`) or remove them (`DELETE /_plugins/{name}`).
+    /// Loading plugins runs arbitrary code so unless the endpoint is only reachable
+    /// from a trusted network it should be protected with a token.
     #[cfg(feature = "serde")]
-    pub fn with_registry(self) -> Result<Self, Error> {
-        self.register_plugin(
-            ("registry", "_plugins"),
-            PluginRegistry::get_handler(self.registry.clone(), self.loader.clone()),
+    pub fn with_registry(self, auth: Option<RegistryAuth>) -> Result<Self, Error> {
+        self.register_handler(
+            (REGISTRY_PLUGIN, "/_plugins"),
+            PluginRegistry::get_handler(self.registry.clone(), self.loader.clone(), auth),
         )?;
         Ok(self)
     }
 
-    /// Include the built-in health plugin that returns _Ok_ on `_health`
+    /// Saves the plugins added through the registry endpoint to the JSON file at `path`
+    /// every time the registry changes, they are loaded again with [`Self::restore_registry`].
+    /// Secret values of their configuration are redacted unless they are `${VAR}`
+    /// placeholders and a change that couldn't be saved is answered with a `Warning`.
+    #[cfg(all(feature = "std", feature = "serde"))]
+    pub fn with_persistent_registry(self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.registry.borrow_mut().persist_to = Some(path.into());
+        self
+    }
+
+    /// Loads the plugins saved by a persistent registry, the ones that fail to
+    /// load are skipped and returned with their error
+    #[cfg(all(feature = "std", feature = "serde"))]
+    pub async fn restore_registry(&self) -> Result<Vec<(String, Error)>, crate::Error> {
+        let path = self.registry.borrow().persist_to.clone();
+        let saved = match path {
+            Some(path) => persist::read(&path).map_err(http::Error::from)?,
+            None => return Ok(Vec::new()),
+        };
+        let disabled = saved
+            .iter()
+            .filter(|p| p.disabled)
+            .map(|p| p.plugin.name.clone())
+            .collect::<Vec<_>>();
+        let plugins = saved.into_iter().map(|p| p.plugin).collect();
+        let plugins = sort_by_dependencies(plugins)
+            .map_err(|e| http::Error::from_str(StatusCode::Conflict, e.to_string()))?;
+        let mut failed = Vec::new();
+        for plugin in plugins {
+            let name = plugin.name.clone();
+            match self.load_plugin(plugin).await {
+                Ok(()) => {
+                    self.registry.borrow_mut().set_persistent(&name);
+                    self.set_plugin_disabled(&name, disabled.contains(&name));
+                }
+                Err(err) => failed.push((name, err)),
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Include the built-in health plugin with a liveness probe on `_health/live`
+    /// and a readiness probe on `_health/ready`(or `_health`) that reports the
+    /// health of every enabled plugin
     pub fn with_health(self) -> Result<Self, Error> {
-        self.register_plugin("health", ())?;
+        let health = health::HealthHandler::new(self.registry.clone(), self.drain.clone());
+        self.register_handler(HEALTH_PLUGIN, health)?;
+        Ok(self)
+    }
+
+    /// Lets plugins be replaced with an older version, e.g. to roll back a
+    /// bad release, by default downgrades are rejected
+    pub fn with_downgrades(self) -> Self {
+        self.registry.borrow_mut().allow_downgrades = true;
+        self
+    }
+
+    /// Plugins that keep failing are answered with `503 Service Unavailable`
+    /// for a while without being called, see [`CircuitBreaker`]
+    pub fn with_circuit_breaker(self, breaker: CircuitBreaker) -> Self {
+        self.registry.borrow_mut().circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Keeps clients on the same variant of routes with weighted plugins,
+    /// requests without the sticky value get the one the load balancer of the
+    /// route chooses
+    pub fn with_sticky_variants(mut self, sticky: Sticky) -> Self {
+        self.sticky = Some(sticky);
+        self
+    }
+
+    /// Groups the plugins by their `namespace`, each namespace has its own
+    /// routes and its requests are told apart by a header or a path segment
+    pub fn with_namespaces(mut self, namespaces: Namespaces) -> Self {
+        self.namespaces = Some(namespaces);
+        self
+    }
+
+    /// Generates the `x-request-id` of requests that come without one,
+    /// otherwise they are rejected with `400 Bad Request`
+    pub fn with_request_ids(mut self, generate: impl Fn() -> String + 'static) -> Self {
+        self.request_ids = Some(Rc::new(generate));
+        self
+    }
+
+    /// Runs tasks in the background like the requests mirrored to shadow plugins
+    /// or the wait for removed plugins to finish their requests, e.g. with
+    /// `async_std::task::spawn_local`. Without it requests aren't mirrored and
+    /// removed plugins don't wait.
+    pub fn with_spawner(
+        mut self,
+        spawn: impl Fn(Pin<Box<dyn Future<Output = ()>>>) + 'static,
+    ) -> Self {
+        let spawn: Rc<dyn Fn(BoxedFuture<'static, ()>)> = Rc::new(spawn);
+        self.registry.borrow_mut().spawn = Some(spawn.clone());
+        self.spawn = Some(spawn);
+        self
+    }
+
+    /// Lets plugins handle requests with other plugins in-process with the
+    /// [`Subrequests`] extension of requests, subrequests nested deeper than
+    /// `max_depth` fail with `508 Loop Detected`
+    #[cfg(feature = "std")]
+    pub fn with_subrequests(mut self, max_depth: u8) -> Self {
+        self.max_subrequest_depth = Some(max_depth);
+        self
+    }
+
+    /// Reads the request id from a different header(e.g. `x-amzn-trace-id` set by
+    /// a load balancer) and echoes it in the same header of the response. Plugins
+    /// still get the id as `x-request-id`.
+    pub fn with_request_id_header(mut self, name: http::headers::HeaderName) -> Self {
+        self.request_id_header = Some(name);
+        self
+    }
+
+    /// Answers the errors of the runtime and the registry(e.g. no plugin matched)
+    /// as `application/problem+json` with the request id as the `instance`
+    /// instead of plain text, errors plugins fail with are answered the same way
+    pub fn with_problem_details(mut self) -> Self {
+        self.problem_details = true;
+        self
+    }
+
+    /// Include the built-in metrics plugin on `_metrics` that exposes counters
+    /// and duration histograms of the handled requests in the Prometheus format
+    pub fn with_metrics(mut self) -> Result<Self, Error> {
+        let metrics = Rc::new(metrics::Metrics::default());
+        let handler = metrics::MetricsHandler(metrics.clone(), self.limiter.clone());
+        self.register_handler(METRICS_PLUGIN, handler)?;
+        self.metrics = Some(metrics);
         Ok(self)
     }
 
-    /// Adds a plugin with its handler to the internal registry
+    /// Adds a plugin with its handler to the internal registry, the handler is used
+    /// as it is without involving the loader. Like the loaded ones the plugin is
+    /// listed by the registry endpoint.
     pub fn with_plugin<H>(self, plugin: impl Into<VluginDef>, handler: H) -> Result<Self, Error>
     where
         H: Vlugin + 'static,
     {
-        self.register_plugin(plugin, handler)?;
+        self.register_handler(plugin, handler)?;
         Ok(self)
     }
 
-    fn register_plugin<H>(&self, plugin: impl Into<VluginDef>, handler: H) -> Result<(), Error>
+    /// Handler of the requests no plugin matched instead of answering with
+    /// `404 Not Found`, e.g. to serve the `index.html` of a single page app or
+    /// to forward requests to a legacy backend. It goes through the middlewares
+    /// like any plugin and gets the full path, the plugin's name is set in
+    /// the `x-valor-plugin` header of its responses.
+    pub fn with_fallback<H>(mut self, plugin: impl Into<VluginDef>, handler: H) -> Self
+    where
+        H: Vlugin + 'static,
+    {
+        self.fallback = Some((plugin.into(), Rc::new(handler)));
+        self
+    }
+
+    /// Same as [`Self::with_plugin`] for a runtime that is already in use
+    pub fn register_handler<H>(&self, plugin: impl Into<VluginDef>, handler: H) -> Result<(), Error>
     where
         H: Vlugin + 'static,
     {
         let handler: Box<dyn Vlugin> = Box::new(handler);
-        let plugin = plugin.into();
-        let name = plugin.name.clone();
         self.registry
             .borrow_mut()
-            .register(plugin, handler)
-            .map_err(|_| Error::RegisterVlugin(name))
+            .register(plugin.into(), handler)
+            .map(|_| ())
+            .map_err(Into::into)
     }
 }
 
 #[async_trait(?Send)]
-impl<L> Vlugin for Runtime<L> {
+impl<L: 'static> Vlugin for Runtime<L> {
     /// Handles an incoming request by answering form a plugin that matches the URL pattern
     ///
-    /// It requires the request to specify a `x-request-id` header that is set back on
-    /// the response as `x-correlation-id`(e.g. used by valor_web to match requests and responses)
+    /// It requires the request to specify a `x-request-id` header, or a generator of ids to
+    /// create a new one, the id is passed down to plugins and set back on the response as
+    /// `x-correlation-id` unless the plugin set its own(e.g. used by valor_web to match
+    /// requests and responses)
+    ///
+    /// When the path matches but none of the plugins serves the request method
+    /// it answers with `405 Method Not Allowed` and the list of methods that are.
+    /// A `HEAD` is answered by the `GET` plugin of the route if there's none for it
+    /// and an `OPTIONS` with `204 No Content` and the `Allow` header.
+    /// Requests to upgrade to WebSocket get the handshake response when the
+    /// plugin accepts the upgrade, see [`Runtime::upgrade`].
+    ///
+    /// Requests pass through the middlewares before being dispatched to the plugin.
+    ///
+    /// A client that sends `Expect: 100-continue` waits for the interim response
+    /// before sending the body, the server sends it the first time the body is read
+    /// so plugins that answer without reading it(e.g. rejecting an upload) spare
+    /// the client from sending it. Plugins don't get the `Expect` header and other
+    /// expectations are answered with `417 Expectation Failed`.
     async fn on_msg(&self, msg: Message) -> Result<Answer, crate::Error> {
-        use crate::http::{Error, StatusCode::*};
         let mut request = match msg {
             Message::Http(req) => req,
             _ => return Err(crate::Error::NotSupported),
         };
-
-        let req_id = request
-            .header("x-request-id")
-            .ok_or_else(|| Error::from_str(BadRequest, "Missing request ID"))?
-            .as_str()
-            .to_owned();
-
-        let (plugin, handler) = self
-            .registry
-            .borrow()
-            .match_vlugin(request.url().path())
-            .ok_or_else(|| Error::from_str(NotFound, "No plugin matched"))?;
-
-        let without_prefix = request
-            .url()
-            .path()
-            .trim_start_matches('/')
-            .strip_prefix(plugin.prefix_or_name())
-            .expect("prefix")
-            .to_owned();
-        request.url_mut().set_path(&without_prefix);
-
-        handler.on_msg(request.into()).await.map(|out| match out {
-            Answer::Http(mut res) => {
-                res.append_header("x-correlation-id", req_id);
-                res.append_header("x-valor-plugin", plugin.name);
-                res.into()
+        let stopwatch = time::Stopwatch::start();
+        let id_header = self
+            .request_id_header
+            .clone()
+            .unwrap_or_else(|| REQ_ID_HEADER.into());
+        let req_id = match (request.header(&id_header), &self.request_ids) {
+            (Some(id), _) => id.as_str().to_owned(),
+            (None, Some(generate)) => generate(),
+            (None, None) => {
+                let err = http::Error::from_str(StatusCode::BadRequest, "Missing request ID");
+                return self.finish(Err(err.into()), "", &stopwatch).map(Into::into);
             }
-            _ => Answer::Pong,
-        })
+        };
+        request.insert_header(REQ_ID_HEADER, req_id.as_str());
+        if let Err(err) = self.check_host(&request) {
+            return self
+                .finish(Err(err.into()), &req_id, &stopwatch)
+                .map(Into::into);
+        }
+        // the handshake needs the headers of the connection
+        let upgrade = websocket::is_upgrade(&request);
+        if !upgrade {
+            crate::strip_hop_by_hop(request.as_mut());
+        }
+        // the server has the expectation, plugins just read the body or not
+        if let Some(expect) = request.remove_header(http::headers::EXPECT) {
+            if !expect.as_str().eq_ignore_ascii_case("100-continue") {
+                let detail = "Only 100-continue is supported";
+                let err = http::Error::from_str(StatusCode::ExpectationFailed, detail);
+                return self
+                    .finish(Err(err.into()), &req_id, &stopwatch)
+                    .map(Into::into);
+            }
+        }
+        #[cfg(feature = "std")]
+        crate::client_ip::resolve(&mut request, &self.trusted_proxies);
+        for add_state in &self.states {
+            add_state(&mut request);
+        }
+        #[cfg(feature = "std")]
+        let _subrequests = self.max_subrequest_depth.map(|max_depth| {
+            let runtime = self.clone();
+            let req_id = req_id.clone();
+            let dispatch: subrequest::Dispatch =
+                Rc::new(move |req: http::Request, subrequests: Subrequests| {
+                    let (runtime, req_id) = (runtime.clone(), req_id.clone());
+                    Box::pin(async move { runtime.subrequest(req, subrequests, &req_id).await })
+                        as BoxedFuture<'static, _>
+                });
+            subrequest::register(&mut request, max_depth, dispatch)
+        });
+        let _in_flight = self.drain.track();
+        if let Some(limit) = self.max_body_size {
+            if let Err(err) = limit_body(&mut request, limit) {
+                return self.finish(Err(err), &req_id, &stopwatch).map(Into::into);
+            }
+        }
+        let dispatch = |req: http::Request| Box::pin(self.dispatch(req)) as BoxedFuture<'_, _>;
+        let res = Next::new(&self.middlewares, &dispatch).run(request).await;
+        // the body can go over the limit while a middleware reads it
+        #[cfg(feature = "std")]
+        let res = res.map_err(body_error);
+        if let Some(metrics) = &self.metrics {
+            metrics.observe(&res, stopwatch.elapsed());
+        }
+        let mut res = self.finish(res, &req_id, &stopwatch)?;
+        if !upgrade || res.status() != StatusCode::SwitchingProtocols {
+            crate::strip_hop_by_hop(res.as_mut());
+        }
+        if self.request_id_header.is_some() && res.header(&id_header).is_none() {
+            res.insert_header(id_header, req_id.as_str());
+        }
+        if res.header(CORRELATION_ID_HEADER).is_none() {
+            res.insert_header(CORRELATION_ID_HEADER, req_id);
+        }
+        Ok(res.into())
     }
 
     fn context(&self) -> &Context {
@@ -154,44 +758,1034 @@ impl<L> Vlugin for Runtime<L> {
     }
 }
 
-impl<L> Clone for Runtime<L> {
-    fn clone(&self) -> Self {
-        Runtime {
-            cx: Context::default(),
-            registry: self.registry.clone(),
-            loader: self.loader.clone(),
+impl<L: 'static> Runtime<L> {
+    /// Handles the request like when it comes from the network going through the
+    /// middlewares and the matching plugin, errors are answered as responses with
+    /// their status. Useful to test plugins without a server.
+    ///
+    /// ```
+    /// # use valor_core::*;
+    /// # use runtime::Runtime;
+    /// # #[async_std::main] async fn main() {
+    /// let runtime = Runtime::new(()).with_health().unwrap();
+    ///
+    /// let mut request = http::Request::new(http::Method::Get, "http://localhost/_health");
+    /// request.insert_header("x-request-id", "1");
+    /// let mut res = runtime.handle(request).await;
+    ///
+    /// assert_eq!(res.status(), http::StatusCode::Ok);
+    /// let health: serde_json::Value = res.body_json().await.unwrap();
+    /// assert_eq!(health["status"], "healthy");
+    /// # }
+    /// ```
+    pub async fn handle(&self, request: http::Request) -> http::Response {
+        match self.on_msg(request.into()).await {
+            Ok(answer) => answer.into(),
+            Err(crate::Error::Http(err)) => problem::from_error(&err),
+            Err(_) => StatusCode::InternalServerError.into(),
         }
     }
-}
 
-#[derive(Debug)]
-pub enum Error {
-    InstantiateVlugin(String),
-    LoadVlugin(String),
-    VluginNotSupported(VluginType),
-    RegisterVlugin(String),
-}
+    /// Warms up the plugins registered with their handler(e.g. with
+    /// [`Self::with_plugin`]) that didn't yet, otherwise each does it right
+    /// before its first request. Loaded plugins warm up after loading.
+    pub async fn warm_up(&self) {
+        let cold = self.registry.borrow().take_all_cold();
+        for (plugin, handler) in cold {
+            self.warm_up_plugin(&plugin, &*handler).await;
+        }
+    }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Error::InstantiateVlugin(name) => write!(f, "Failed instantiating {}", name),
-            Error::LoadVlugin(name) => write!(f, "Failed loading {}", name),
-            Error::RegisterVlugin(name) => write!(f, "{} already registered", name),
-            Error::VluginNotSupported(ty) => write!(f, "Loader doesn't support {:?}", ty),
+    async fn warm_up_plugin(&self, plugin: &VluginDef, handler: &dyn Vlugin) {
+        let timeout = plugin
+            .load_timeout_ms
+            .map_or(self.registry.borrow().load_timeout, Duration::from_millis);
+        let (warmup_ms, error) = warm_up(handler, timeout).await;
+        self.registry
+            .borrow_mut()
+            .record_warmup(&plugin.name, warmup_ms, error);
+    }
+
+    // Requests for hosts that are not allowed could be routed or cached
+    // as if they were for one that is, the builtins answer any host since
+    // probes of load balancers often use an IP
+    fn check_host(&self, request: &http::Request) -> Result<(), http::Error> {
+        if self.allowed_hosts.is_empty() || self.for_builtin(request) {
+            return Ok(());
+        }
+        let host = request_host(request)
+            .ok_or_else(|| http::Error::from_str(StatusCode::BadRequest, "Missing host"))?;
+        if self
+            .allowed_hosts
+            .iter()
+            .any(|allowed| registry::host_matches(allowed, &host))
+        {
+            Ok(())
+        } else {
+            let detail = format!("{} is not served here", host);
+            Err(http::Error::from_str(
+                StatusCode::MisdirectedRequest,
+                detail,
+            ))
+        }
+    }
+
+    // Whether the path is under the prefix of one of the plugins of the runtime
+    fn for_builtin(&self, request: &http::Request) -> bool {
+        let path = request.url().path();
+        let registry = self.registry.borrow();
+        BUILTINS
+            .iter()
+            .filter_map(|name| registry.handler(name))
+            .any(|(plugin, _)| {
+                let prefix = "/".to_owned() + plugin.prefix_or_name();
+                middleware::under_prefix(path, &prefix)
+            })
+    }
+
+    // Last touches of every answer, also of requests refused before the
+    // middlewares, errors are answered as responses to carry the timing
+    fn finish(
+        &self,
+        res: Result<http::Response, crate::Error>,
+        req_id: &str,
+        stopwatch: &time::Stopwatch,
+    ) -> Result<http::Response, crate::Error> {
+        let res = match res {
+            Err(crate::Error::Http(err)) if self.server_timing => Ok(problem::from_error(&err)),
+            res => res,
+        };
+        let mut res = self.problem(res, req_id)?;
+        if let Some(elapsed) = stopwatch.elapsed().filter(|_| self.server_timing) {
+            let dur = elapsed.as_secs_f64() * 1000.0;
+            res.append_header(SERVER_TIMING_HEADER, format!("total;dur={:.1}", dur));
+        }
+        Ok(res)
+    }
+
+    // With problem details errors become responses with the details as body
+    fn problem(
+        &self,
+        res: Result<http::Response, crate::Error>,
+        req_id: &str,
+    ) -> Result<http::Response, crate::Error> {
+        if !self.problem_details {
+            return res;
+        }
+        let mut res = match res {
+            Ok(res) => res,
+            Err(crate::Error::Http(err)) => problem::from_error(&err),
+            Err(err) => return Err(err),
+        };
+        problem::to_json(&mut res, req_id)?;
+        Ok(res)
+    }
+
+    async fn dispatch(&self, mut request: http::Request) -> Result<http::Response, crate::Error> {
+        use crate::http::{headers, Error, Method, Response};
+
+        let req_id = request
+            .header(REQ_ID_HEADER)
+            .map_or_else(String::new, |id| id.as_str().to_owned());
+
+        let host = request_host(&request);
+        let host = host.as_deref();
+        let path = request.url().path().to_owned();
+        let namespace = self.namespace(&request);
+        let scopes = scopes(&namespace, &path);
+        let choice = self.variant_choice(&request, &req_id);
+        let auto_head = self.auto_head && request.method() == Method::Head;
+        let match_route = |choice| {
+            let registry = self.registry.borrow();
+            let method = request.method();
+            scopes.iter().find_map(|&(ns, path)| {
+                let matched = match registry.match_variant(ns, method, host, path, choice) {
+                    None if auto_head => registry
+                        .match_variant(ns, Method::Get, host, path, choice)
+                        .map(|get| (get, true)),
+                    matched => matched.map(|matched| (matched, false)),
+                };
+                matched.map(|(matched, head)| (matched, head, path))
+            })
+        };
+        let mut matched = match_route(choice);
+        // the affinity cookie is the one of the route so it's known once matched
+        let preferred = match (&self.sticky, &matched) {
+            (Some(Sticky::Affinity { cookie, .. }), Some((((plugin, _), _), _, _)))
+                if plugin.weight.is_some() =>
+            {
+                let variant = request.cookie_value(&affinity_cookie(cookie, plugin));
+                variant.map(ToOwned::to_owned)
+            }
+            _ => None,
+        };
+        if let Some(variant) = preferred.as_deref() {
+            let seed = fnv1a(req_id.as_bytes());
+            matched = match_route(Choice::Prefer(variant, seed));
+        }
+        let head = matches!(matched, Some((_, true, _)));
+        if head {
+            request.set_method(Method::Get);
+        }
+        let is_disabled = || {
+            let registry = self.registry.borrow();
+            scopes
+                .iter()
+                .any(|&(ns, path)| registry.is_disabled(ns, host, path))
+        };
+        let allowed_methods = || {
+            let registry = self.registry.borrow();
+            scopes
+                .iter()
+                .find_map(|&(ns, path)| registry.allowed_methods(ns, host, path))
+        };
+        let ((plugin, handler), params) = match matched {
+            Some((((plugin, handler), params), _, path)) => {
+                let path = registry::strip_route(plugin.prefix_or_name(), path);
+                request.url_mut().set_path(&path);
+                ((plugin, handler), params)
+            }
+            None if is_disabled() => {
+                return Err(Error::from_str(self.disabled_status, "Plugin is disabled").into())
+            }
+            None => match allowed_methods() {
+                Some(mut methods) if !methods.is_empty() => {
+                    if self.auto_head && methods.contains(&Method::Get) {
+                        methods.push(Method::Head);
+                    }
+                    // the runtime answers OPTIONS when no plugin serves it
+                    methods.push(Method::Options);
+                    let mut allow = Vec::new();
+                    for method in methods.iter().map(|m| m.as_ref()) {
+                        if !allow.contains(&method) {
+                            allow.push(method);
+                        }
+                    }
+                    let allow = allow.join(", ");
+                    if request.method() != Method::Options {
+                        return Ok(problem::method_not_allowed(request.method(), &allow));
+                    }
+                    let mut res = Response::new(StatusCode::NoContent);
+                    res.insert_header(headers::ALLOW, allow);
+                    return Ok(res);
+                }
+                _ => match &self.fallback {
+                    Some(fallback) => (fallback.clone(), Params::default()),
+                    None => {
+                        return Err(
+                            Error::from_str(StatusCode::NotFound, "No plugin matched").into()
+                        )
+                    }
+                },
+            },
+        };
+        request.set_ext(params);
+        // counted from the moment it's matched so removing the plugin waits for it
+        let active = self.registry.borrow().active(&plugin.name);
+        let _active = active.as_ref().map(|active| active.track());
+        if self.registry.borrow().take_cold(&plugin.name) {
+            self.warm_up_plugin(&plugin, &*handler).await;
+        }
+
+        if self.drain.in_maintenance() && !is_builtin(&plugin.name) {
+            return Ok(maintenance::response(self.maintenance_page.as_deref()));
+        }
+
+        let now = time::unix_ms();
+        let allowed = self.registry.borrow().allow_request(&plugin.name, now);
+        if let Err(retry_after) = allowed {
+            let detail = format!("{} is unavailable", plugin.name);
+            let mut res = problem::response(StatusCode::ServiceUnavailable, detail);
+            let retry_after = retry_after.as_secs().max(1);
+            res.insert_header(headers::RETRY_AFTER, retry_after.to_string());
+            res.append_header("x-valor-plugin", plugin.name);
+            return Ok(res);
+        }
+
+        if websocket::is_upgrade(&request) && handler.accepts_upgrade("websocket") {
+            let mut res = websocket::handshake(&request);
+            res.append_header("x-valor-plugin", plugin.name);
+            return Ok(res);
+        }
+
+        if let Some(mirror) = &plugin.mirror {
+            self.mirror(mirror, &mut request, &req_id).await?;
+        }
+
+        let timeout = plugin
+            .request_timeout_ms
+            .map(Duration::from_millis)
+            .or(self.request_timeout);
+        let stopwatch = time::Stopwatch::start();
+        // subrequests take no turn, the request they come from already has one
+        let _permit = if plugin.name == HEALTH_PLUGIN
+            || plugin.name == METRICS_PLUGIN
+            || is_subrequest(&request)
+        {
+            None
+        } else {
+            let permit = match (self.limiter.acquire(), timeout) {
+                (Some(acquire), Some(timeout)) => time::timeout(timeout, acquire).await,
+                (Some(acquire), None) => Some(acquire.await),
+                (None, _) => None,
+            };
+            if permit.is_none() {
+                let detail = "Too many requests in flight";
+                let mut res = problem::response(StatusCode::ServiceUnavailable, detail);
+                res.insert_header(headers::RETRY_AFTER, "1");
+                return Ok(res);
+            }
+            permit
+        };
+        if !plugin.pipeline.is_empty() {
+            request = match self.pipeline(&plugin, request, &req_id).await? {
+                Ok(request) => request,
+                Err(res) => return Ok(res),
+            };
+        }
+        let transforms = self
+            .transforms
+            .iter()
+            .filter(|(only, _)| only.as_ref().map_or(true, |name| *name == plugin.name))
+            .map(|(_, transform)| transform)
+            .collect::<Vec<_>>();
+        for transform in &transforms {
+            transform.request(&mut request).await?;
+        }
+        // time waiting for a turn is taken from the one to answer
+        let timeout = timeout.map(|t| {
+            let waited = stopwatch.elapsed().unwrap_or_default();
+            t.checked_sub(waited).unwrap_or_default()
+        });
+        // retried requests are replayed from a copy with the body buffered
+        let retry = plugin
+            .retry
+            .as_ref()
+            .filter(|r| r.any_method || is_idempotent(request.method()));
+        let replay = match retry {
+            Some(_) => {
+                let body = request.take_body().into_bytes().await?;
+                request.set_body(body.clone());
+                Some((self.copy_request(&request, &body), body))
+            }
+            None => None,
+        };
+        let handling = time::Stopwatch::start();
+        let mut attempt = 1;
+        // all the attempts share the time to answer
+        let time_left = || {
+            let elapsed = handling.elapsed().unwrap_or_default();
+            timeout.map(|t| t.checked_sub(elapsed).unwrap_or_default())
+        };
+        let answer = loop {
+            let answer = catch_panic(handler.on_msg(request.into()));
+            let answer = match time_left() {
+                Some(left) => time::timeout(left, answer).await,
+                None => Some(answer.await),
+            };
+            // a body over the limit is the client's fault
+            #[cfg(feature = "std")]
+            let answer = answer.map(|answer| answer.map(|answer| answer.map_err(body_error)));
+            let status = answer_status(&answer);
+            // being unhealthy is reported on purpose, it's not a failure
+            let failed = plugin.name != HEALTH_PLUGIN && status.is_server_error();
+            self.registry
+                .borrow()
+                .record_outcome(&plugin.name, failed, time::unix_ms());
+            match (retry, &replay) {
+                (Some(retry), Some((template, body)))
+                    if attempt < retry.attempts
+                        && retry.statuses.contains(&(status as u16))
+                        && time_left().map_or(true, |left| left > retry.backoff(attempt)) =>
+                {
+                    time::sleep(retry.backoff(attempt)).await;
+                    request = self.copy_request(template, body);
+                    attempt += 1;
+                }
+                _ => break answer,
+            }
+        };
+        // errors and timeouts count as much as the responses of the plugin
+        self.registry.borrow().record_stats(
+            &plugin.name,
+            answer_status(&answer),
+            handling.elapsed(),
+        );
+        let mut res: Response = match answer {
+            Some(Ok(answer)) => answer?.into(),
+            Some(Err(panic)) => {
+                let detail = format!("{} failed handling request {}", plugin.name, req_id);
+                let mut res = problem::response(StatusCode::InternalServerError, detail);
+                res.insert_ext(panic);
+                res
+            }
+            None => {
+                let detail = format!("{} timed out handling request {}", plugin.name, req_id);
+                let mut res = problem::response(StatusCode::ServiceUnavailable, detail);
+                let retry_after = timeout.map_or(1, |t| t.as_secs().max(1));
+                res.insert_header(headers::RETRY_AFTER, retry_after.to_string());
+                res
+            }
+        };
+        for transform in &transforms {
+            // still tells which plugin answered when the edit fails
+            if let Err(err) = transform.response(&mut res).await {
+                let mut res = match err {
+                    crate::Error::Http(err) => problem::from_error(&err),
+                    err => problem::response(StatusCode::InternalServerError, err.to_string()),
+                };
+                res.append_header("x-valor-plugin", plugin.name);
+                return Ok(res);
+            }
+        }
+        if head {
+            // the length is kept as the one of the body a GET would get
+            if let Some(len) = res.len() {
+                res.insert_header(headers::CONTENT_LENGTH, len.to_string());
+            }
+            res.take_body();
+        }
+        if plugin.weight.is_some() {
+            res.insert_header("x-valor-variant", plugin.name.as_str());
+            // set with every response so it lasts while the client is active
+            if let Some(Sticky::Affinity { cookie, ttl }) = &self.sticky {
+                let name = affinity_cookie(cookie, &plugin);
+                let affinity = crate::Cookie::new(name, plugin.name.as_str())
+                    .path("/")
+                    .http_only()
+                    .max_age(*ttl);
+                res.set_cookie(affinity);
+            }
+        }
+        if retry.is_some() {
+            res.insert_header("x-valor-attempts", attempt.to_string());
+        }
+        res.append_header("x-valor-plugin", plugin.name);
+        Ok(res)
+    }
+
+    // Request a plugin makes to another one, it gets the request id of the
+    // request it's made from and the states of the runtime but none of its
+    // other extensions as it skips the middlewares that set them
+    #[cfg(feature = "std")]
+    async fn subrequest(
+        &self,
+        mut request: http::Request,
+        subrequests: Subrequests,
+        req_id: &str,
+    ) -> http::Result<http::Response> {
+        request.set_ext(subrequests);
+        if request.header(REQ_ID_HEADER).is_none() {
+            request.insert_header(REQ_ID_HEADER, req_id);
+        }
+        for add_state in &self.states {
+            add_state(&mut request);
+        }
+        match self.dispatch(request).await {
+            Ok(res) => Ok(res),
+            Err(crate::Error::Http(err)) => Err(err),
+            Err(err) => Err(http::Error::from_str(
+                StatusCode::InternalServerError,
+                err.to_string(),
+            )),
+        }
+    }
+
+    // Passes the request through the stages of the pipeline of the plugin in
+    // order, the first stage that answers with a response stops it
+    async fn pipeline(
+        &self,
+        plugin: &VluginDef,
+        mut request: http::Request,
+        req_id: &str,
+    ) -> Result<Result<http::Request, http::Response>, crate::Error> {
+        for stage in &plugin.pipeline {
+            let handler = self.registry.borrow().handler(stage);
+            let handler = match handler {
+                Some((_, handler)) => handler,
+                // stages like an authentication can't be skipped
+                None => {
+                    let detail = format!(
+                        "{} of the pipeline of {} is unavailable",
+                        stage, plugin.name
+                    );
+                    let mut res = problem::response(StatusCode::ServiceUnavailable, detail);
+                    res.append_header("x-valor-plugin", plugin.name.as_str());
+                    return Ok(Err(res));
+                }
+            };
+            let mut res: http::Response = match catch_panic(handler.on_msg(request.into())).await {
+                Ok(Ok(Answer::Next(next))) => {
+                    request = next;
+                    continue;
+                }
+                Ok(answer) => answer?.into(),
+                Err(panic) => {
+                    let detail = format!("{} failed handling request {}", stage, req_id);
+                    let mut res = problem::response(StatusCode::InternalServerError, detail);
+                    res.insert_ext(panic);
+                    res
+                }
+            };
+            res.append_header("x-valor-plugin", stage.as_str());
+            return Ok(Err(res));
+        }
+        Ok(Ok(request))
+    }
+
+    // Copy of a request to handle it again with the extensions of the runtime
+    // and the ones of its middlewares
+    fn copy_request(&self, request: &http::Request, body: &[u8]) -> http::Request {
+        let mut copy = request.clone();
+        copy.set_body(body.to_vec());
+        copy_ext::<Params>(request, &mut copy);
+        #[cfg(feature = "std")]
+        copy_ext::<Subrequests>(request, &mut copy);
+        #[cfg(feature = "auth")]
+        copy_ext::<User>(request, &mut copy);
+        #[cfg(feature = "jwt")]
+        copy_ext::<Claims>(request, &mut copy);
+        #[cfg(feature = "std")]
+        crate::client_ip::resolve(&mut copy, &self.trusted_proxies);
+        for add_state in &self.states {
+            add_state(&mut copy);
+        }
+        copy
+    }
+
+    // Sends a copy of a share of the requests to the shadow plugin in the
+    // background, the body is buffered so both plugins can read it
+    async fn mirror(
+        &self,
+        mirror: &Mirror,
+        request: &mut http::Request,
+        req_id: &str,
+    ) -> Result<(), crate::Error> {
+        let spawn = match &self.spawn {
+            Some(spawn) => spawn,
+            None => return Ok(()),
+        };
+        let sample = fnv1a(format!("{}{}", mirror.plugin, req_id).as_bytes()) % 10_000;
+        if sample as f64 >= mirror.rate * 10_000.0 {
+            return Ok(());
+        }
+        let (shadow, handler) = match self.registry.borrow().handler(&mirror.plugin) {
+            Some(shadow) => shadow,
+            None => return Ok(()),
+        };
+        let body = request.take_body().into_bytes().await?;
+        let copy = self.copy_request(request, &body);
+        request.set_body(body);
+        let timeout = shadow
+            .request_timeout_ms
+            .map(Duration::from_millis)
+            .or(self.request_timeout);
+        // what the shadow answers or how it fails never reaches the client,
+        // the runtime waits for it when shutting down like for requests
+        let in_flight = self.drain.track_task();
+        let (shadow, req_id) = (shadow.name.clone(), req_id.to_owned());
+        spawn(Box::pin(async move {
+            let _in_flight = in_flight;
+            let answer = catch_panic(handler.on_msg(copy.into()));
+            let answer = match timeout {
+                Some(timeout) => time::timeout(timeout, answer).await,
+                None => Some(answer.await),
+            };
+            match answer {
+                Some(Ok(Ok(_))) => {}
+                Some(Ok(Err(err))) => {
+                    log::debug!("[{}] mirrored request {} failed: {}", shadow, req_id, err)
+                }
+                Some(Err(panic)) => {
+                    log::debug!(
+                        "[{}] panicked with mirrored request {}: {}",
+                        shadow,
+                        req_id,
+                        panic.0
+                    )
+                }
+                None => log::debug!("[{}] mirrored request {} timed out", shadow, req_id),
+            }
+        }));
+        Ok(())
+    }
+
+    /// Hands the connection of a request that was answered with
+    /// `101 Switching Protocols` to the plugin of its route, the server calls
+    /// it after sending the response with the request it got
+    pub async fn upgrade(
+        &self,
+        mut request: http::Request,
+        conn: Box<dyn Upgraded>,
+    ) -> Result<(), crate::Error> {
+        #[cfg(feature = "std")]
+        crate::client_ip::resolve(&mut request, &self.trusted_proxies);
+        for add_state in &self.states {
+            add_state(&mut request);
+        }
+        let host = request_host(&request);
+        let path = request.url().path().to_owned();
+        let namespace = self.namespace(&request);
+        let matched = {
+            let registry = self.registry.borrow();
+            scopes(&namespace, &path)
+                .into_iter()
+                .find_map(|(ns, path)| {
+                    registry
+                        .match_variant(
+                            ns,
+                            request.method(),
+                            host.as_deref(),
+                            path,
+                            Choice::Balanced(0),
+                        )
+                        .filter(|((_, handler), _)| handler.accepts_upgrade("websocket"))
+                        .map(|matched| (matched, path))
+                })
+        };
+        let (((plugin, handler), params), path) = matched
+            .ok_or_else(|| http::Error::from_str(StatusCode::NotFound, "No plugin matched"))?;
+        let path = registry::strip_route(plugin.prefix_or_name(), path);
+        request.url_mut().set_path(&path);
+        request.set_ext(params);
+        handler.on_upgrade(request, conn).await
+    }
+
+    // Namespace of the request and its path within the namespace
+    fn namespace(&self, request: &http::Request) -> Option<(String, String)> {
+        let path = request.url().path();
+        match self.namespaces.as_ref()? {
+            Namespaces::Header(name) => {
+                let namespace = request.header(name.as_str())?.as_str().trim();
+                (!namespace.is_empty()).then(|| (namespace.to_owned(), path.to_owned()))
+            }
+            Namespaces::PathSegment => {
+                let path = path.trim_start_matches('/');
+                let (namespace, rest) = path.split_once('/').unwrap_or((path, ""));
+                (!namespace.is_empty()).then(|| (namespace.to_owned(), "/".to_owned() + rest))
+            }
+        }
+    }
+
+    // The request ids are random enough for the random load balancer
+    fn variant_choice<'a>(&self, request: &'a http::Request, req_id: &str) -> Choice<'a> {
+        let seed = fnv1a(req_id.as_bytes());
+        let sticky = match &self.sticky {
+            Some(Sticky::Cookie(name)) => request.cookie_value(name).map(ToOwned::to_owned),
+            Some(Sticky::ClientIp) => client_ip(request),
+            // chosen by the cookie of the route once it's matched
+            Some(Sticky::Affinity { .. }) | None => None,
+        };
+        match sticky {
+            Some(sticky) => Choice::Sticky(fnv1a(sticky.as_bytes())),
+            None => Choice::Balanced(seed),
         }
     }
 }
 
 #[cfg(feature = "std")]
-impl std::error::Error for Error {}
+fn client_ip(request: &http::Request) -> Option<String> {
+    crate::ClientIp::client_ip(request).map(|ip| ip.to_string())
+}
 
-/// A Loader can fetch plugin handlers from various sources
-/// such as the network or the file system
-#[async_trait(?Send)]
-pub trait Loader: 'static {
-    /// Loads the given `plugin`
-    async fn load(&self, plugin: &VluginDef) -> Result<VluginFactory, Error>;
+#[cfg(not(feature = "std"))]
+fn client_ip(request: &http::Request) -> Option<String> {
+    let addr = request.peer_addr()?;
+    Some(addr.rsplit_once(':').map_or(addr, |(ip, _)| ip).to_owned())
+}
+
+// Where a request is matched, in its namespace and then among the plugins
+// without a namespace with the whole path
+fn scopes<'a>(
+    namespace: &'a Option<(String, String)>,
+    path: &'a str,
+) -> Vec<(Option<&'a str>, &'a str)> {
+    namespace
+        .iter()
+        .map(|(namespace, path)| (Some(namespace.as_str()), path.as_str()))
+        .chain(core::iter::once((None, path)))
+        .collect()
+}
+
+// Host the request was sent to, from the header or the absolute url
+fn request_host(request: &http::Request) -> Option<String> {
+    request
+        .header(http::headers::HOST)
+        .map(|h| h.as_str())
+        .or_else(|| request.url().host_str())
+        .map(ToOwned::to_owned)
+}
+
+// Name of the affinity cookie of the route of the variant, the variants of a
+// route share its namespace, host and prefix
+fn affinity_cookie(cookie: &str, variant: &VluginDef) -> String {
+    let route = format!(
+        "{}|{}|{}",
+        variant.namespace.as_deref().unwrap_or_default(),
+        variant.host.as_deref().unwrap_or_default(),
+        variant.prefix_or_name(),
+    );
+    format!("{}-{:016x}", cookie, fnv1a(route.as_bytes()))
+}
+
+// The clone of a request leaves its extensions behind
+fn copy_ext<T: Clone + Send + Sync + 'static>(from: &http::Request, to: &mut http::Request) {
+    if let Some(ext) = from.ext::<T>() {
+        to.set_ext(ext.clone());
+    }
+}
+
+// Compares secrets taking the same time wherever they differ
+#[cfg(any(feature = "auth", feature = "serde"))]
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Small and stable hash, the same value must get the same variant across restarts
+// and the same body the same entity tag
+fn fnv1a(val: &[u8]) -> u64 {
+    val.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Status a plugin answered with, failing or timing out are server errors
+fn answer_status(answer: &Option<Result<Result<Answer, crate::Error>, Panic>>) -> StatusCode {
+    match answer {
+        Some(Ok(Ok(Answer::Http(res)))) => res.status(),
+        Some(Ok(Ok(Answer::Pong))) => StatusCode::Ok,
+        Some(Ok(Ok(Answer::Next(_)))) => StatusCode::NotFound,
+        Some(Ok(Err(crate::Error::Http(err)))) => err.status(),
+        Some(Ok(Err(_))) | Some(Err(_)) => StatusCode::InternalServerError,
+        None => StatusCode::ServiceUnavailable,
+    }
+}
+
+// Plugins of the runtime itself that are not affected by maintenance
+fn is_builtin(plugin: &str) -> bool {
+    BUILTINS.contains(&plugin)
+}
+
+// Methods that have the same effect when repeated
+fn is_idempotent(method: http::Method) -> bool {
+    use http::Method::*;
+    matches!(method, Get | Head | Put | Delete | Options)
+}
+
+#[cfg(feature = "std")]
+fn is_subrequest(request: &http::Request) -> bool {
+    request
+        .ext::<Subrequests>()
+        .map_or(false, |s| s.depth() > 0)
+}
+
+#[cfg(not(feature = "std"))]
+fn is_subrequest(_request: &http::Request) -> bool {
+    false
+}
+
+/// Message of a plugin that panicked handling a request, it's set as an
+/// extension of the `500 Internal Server Error` response it's answered with
+#[derive(Debug, Clone)]
+pub struct Panic(pub String);
+
+// A panicking plugin shouldn't take the runtime down, panics can only be
+// caught with `std` otherwise they unwind as usual
+async fn catch_panic<F: Future>(fut: F) -> Result<F::Output, Panic> {
+    #[cfg(feature = "std")]
+    {
+        use futures_lite::FutureExt;
+        use std::panic::AssertUnwindSafe;
+        AssertUnwindSafe(fut)
+            .catch_unwind()
+            .await
+            .map_err(|payload| {
+                let msg = match payload.downcast::<String>() {
+                    Ok(msg) => *msg,
+                    Err(payload) => payload
+                        .downcast_ref::<&str>()
+                        .map_or_else(|| "unknown cause".into(), |msg| msg.to_string()),
+                };
+                Panic(msg)
+            })
+    }
+    #[cfg(not(feature = "std"))]
+    Ok(fut.await)
+}
+
+// Rejects requests with a body over the limit without reading it when its
+// length is known, otherwise reading the body fails past the limit
+fn limit_body(request: &mut http::Request, limit: usize) -> Result<(), http::Error> {
+    match request.len() {
+        Some(len) if len > limit => Err(http::Error::from_str(
+            StatusCode::PayloadTooLarge,
+            "Body is too large",
+        )),
+        Some(_) => Ok(()),
+        #[cfg(feature = "std")]
+        None => {
+            let body = LimitedBody {
+                body: request.take_body(),
+                left: limit,
+            };
+            let body = futures_lite::io::BufReader::new(body);
+            request.set_body(http::Body::from_reader(body, None));
+            Ok(())
+        }
+        #[cfg(not(feature = "std"))]
+        None => Ok(()),
+    }
+}
+
+// Body that fails with `BodyTooLarge` once more than what's left is read
+#[cfg(feature = "std")]
+struct LimitedBody {
+    body: http::Body,
+    left: usize,
+}
+
+#[cfg(feature = "std")]
+impl futures_lite::AsyncRead for LimitedBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> core::task::Poll<std::io::Result<usize>> {
+        use futures_lite::AsyncRead;
+        let read = futures_lite::ready!(Pin::new(&mut self.body).poll_read(cx, buf))?;
+        if read > self.left {
+            let err = std::io::Error::new(std::io::ErrorKind::InvalidData, BodyTooLarge);
+            return core::task::Poll::Ready(Err(err));
+        }
+        self.left -= read;
+        core::task::Poll::Ready(Ok(read))
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct BodyTooLarge;
+
+#[cfg(feature = "std")]
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Body is too large")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BodyTooLarge {}
+
+// Reading a body over the limit fails with an I/O error that is usually
+// turned into a `500`, it's answered with `413` instead
+#[cfg(feature = "std")]
+fn body_error(err: crate::Error) -> crate::Error {
+    let too_large = match &err {
+        crate::Error::Http(err) => err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|err| err.get_ref())
+            .map_or(false, |err| err.is::<BodyTooLarge>()),
+        _ => false,
+    };
+    if !too_large {
+        return err;
+    }
+    http::Error::from_str(StatusCode::PayloadTooLarge, "Body is too large").into()
+}
+
+impl<L> Clone for Runtime<L> {
+    fn clone(&self) -> Self {
+        Runtime {
+            cx: Context::default(),
+            registry: self.registry.clone(),
+            loader: self.loader.clone(),
+            disabled_status: self.disabled_status,
+            middlewares: self.middlewares.clone(),
+            transforms: self.transforms.clone(),
+            drain: self.drain.clone(),
+            limiter: self.limiter.clone(),
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            metrics: self.metrics.clone(),
+            request_ids: self.request_ids.clone(),
+            request_id_header: self.request_id_header.clone(),
+            problem_details: self.problem_details,
+            fallback: self.fallback.clone(),
+            sticky: self.sticky.clone(),
+            namespaces: self.namespaces.clone(),
+            auto_head: self.auto_head,
+            server_timing: self.server_timing,
+            allowed_hosts: self.allowed_hosts.clone(),
+            maintenance_page: self.maintenance_page.clone(),
+            #[cfg(feature = "std")]
+            trusted_proxies: self.trusted_proxies.clone(),
+            states: self.states.clone(),
+            spawn: self.spawn.clone(),
+            #[cfg(feature = "std")]
+            max_subrequest_depth: self.max_subrequest_depth,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InstantiateVlugin(String),
+    LoadVlugin(String),
+    VluginNotSupported(VluginType),
+    RegisterVlugin(String),
+    PrefixConflict(String, String),
+    VluginEntryNotFound(String),
+    IncompatibleVlugin(String, u32),
+    LoadTimeout(String),
+    MissingDependency(String, String),
+    DependencyCycle(Vec<String>),
+    /// The plugin can't be removed while the other one depends on it
+    RequiredBy(String, String),
+    InvalidVersion(String),
+    Downgrade(String, Version, Version),
+    /// A field of the plugin's definition has an invalid value
+    InvalidField(String, &'static str, &'static str),
+    /// The plugin's module is not signed with the trusted key
+    UntrustedVlugin(String),
+    /// The plugin refused its configuration
+    InvalidConfig(String, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InstantiateVlugin(name) => write!(f, "Failed instantiating {}", name),
+            Error::LoadVlugin(name) => write!(f, "Failed loading {}", name),
+            Error::RegisterVlugin(name) => write!(f, "Failed registering {}", name),
+            Error::VluginNotSupported(ty) => write!(f, "Loader doesn't support {:?}", ty),
+            Error::PrefixConflict(name, existing) => {
+                write!(f, "{} prefix conflicts with {}", name, existing)
+            }
+            Error::VluginEntryNotFound(name) => write!(f, "{} has no entry point", name),
+            Error::LoadTimeout(name) => write!(f, "Plugin load of {} timed out", name),
+            Error::MissingDependency(name, dep) => {
+                write!(f, "{} depends on {} that is not registered", name, dep)
+            }
+            Error::DependencyCycle(cycle) => write!(f, "Dependency cycle {}", cycle.join(" -> ")),
+            Error::RequiredBy(name, dependent) => write!(f, "{} depends on {}", dependent, name),
+            Error::InvalidVersion(version) => write!(f, "Invalid version {}", version),
+            Error::Downgrade(name, registered, attempted) => write!(
+                f,
+                "{} {} would downgrade the registered {}",
+                name, attempted, registered
+            ),
+            Error::UntrustedVlugin(name) => write!(f, "{} has no valid signature", name),
+            Error::InvalidConfig(name, reason) => {
+                write!(f, "Invalid configuration of {}: {}", name, reason)
+            }
+            Error::InvalidField(name, field, reason) => {
+                write!(f, "{} of plugin {:?} {}", field, name, reason)
+            }
+            Error::IncompatibleVlugin(name, version) => write!(
+                f,
+                "{} was built for ABI version {}, expected {}",
+                name,
+                version,
+                crate::VLUGIN_ABI_VERSION
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+impl From<RegistrationError> for Error {
+    fn from(err: RegistrationError) -> Self {
+        match err {
+            RegistrationError::PrefixConflict {
+                existing,
+                attempted,
+            } => Error::PrefixConflict(attempted, existing),
+            RegistrationError::InvalidPrefix(name) | RegistrationError::NotRegistered(name) => {
+                Error::RegisterVlugin(name)
+            }
+            RegistrationError::MissingDependency { plugin, missing } => {
+                Error::MissingDependency(plugin, missing)
+            }
+            RegistrationError::DependencyCycle(cycle) => Error::DependencyCycle(cycle),
+            RegistrationError::RequiredBy { plugin, dependent } => {
+                Error::RequiredBy(plugin, dependent)
+            }
+            RegistrationError::Downgrade {
+                name,
+                registered,
+                attempted,
+            } => Error::Downgrade(name, registered, attempted),
+            RegistrationError::InvalidField {
+                plugin,
+                field,
+                reason,
+            } => Error::InvalidField(plugin, field, reason),
+        }
+    }
+}
+
+/// A Loader can fetch plugin handlers from various sources
+/// such as the network or the file system
+#[async_trait(?Send)]
+pub trait Loader: 'static {
+    /// Loads the given `plugin`
+    async fn load(&self, plugin: &VluginDef) -> Result<VluginFactory, Error>;
+}
+
+// Loads and instantiates a plugin keeping track of when and how long it took,
+// a load that takes longer than the timeout is dropped
+async fn load_vlugin<L: Loader>(
+    loader: &L,
+    plugin: &VluginDef,
+    timeout: Duration,
+) -> (Result<Box<dyn Vlugin>, Error>, LoadInfo) {
+    let loaded_at = time::unix_ms();
+    let stopwatch = time::Stopwatch::start();
+    let timeout = plugin
+        .load_timeout_ms
+        .map_or(timeout, Duration::from_millis);
+    let load = async {
+        let factory = loader.load(plugin).await?;
+        // the definition keeps its configuration to list or persist it
+        factory(plugin.config.clone())
+            .await
+            .map_err(|err| match err {
+                // plugins reject their configuration with a bad request
+                crate::Error::Http(err) if err.status() == StatusCode::BadRequest => {
+                    Error::InvalidConfig(plugin.name.clone(), err.to_string())
+                }
+                _ => Error::InstantiateVlugin(plugin.name.clone()),
+            })
+    };
+    let handler = time::timeout(timeout, load)
+        .await
+        .unwrap_or_else(|| Err(Error::LoadTimeout(plugin.name.clone())));
+    let mut load = LoadInfo {
+        loaded_at,
+        duration_ms: stopwatch.elapsed_ms(),
+        error: handler.as_ref().err().map(ToString::to_string),
+        ..LoadInfo::default()
+    };
+    // warming up has its own time, a plugin that fails it is still usable
+    if let Ok(handler) = &handler {
+        let (warmup_ms, warmup_error) = warm_up(&**handler, timeout).await;
+        load.warmup_ms = warmup_ms;
+        load.warmup_error = warmup_error;
+    }
+    (handler, load)
+}
+
+// How long the warmup took and why it failed if it did
+async fn warm_up(handler: &dyn Vlugin, timeout: Duration) -> (Option<u64>, Option<String>) {
+    let stopwatch = time::Stopwatch::start();
+    let error = match time::timeout(timeout, handler.warmup()).await {
+        Some(Ok(())) => None,
+        Some(Err(err)) => Some(err.to_string()),
+        None => Some("Warmup timed out".into()),
+    };
+    (stopwatch.elapsed_ms(), error)
 }
 
 type BoxedFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
@@ -209,3 +1803,1966 @@ impl Loader for () {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{h, http, Health};
+    use async_std::{task, test};
+    use futures::future::join_all;
+
+    fn request(path: &str) -> Message {
+        let url = "http://example.com".to_owned() + path;
+        let mut req = http::Request::new(http::Method::Get, url.as_str());
+        req.insert_header("x-request-id", "123");
+        req.into()
+    }
+
+    #[test]
+    async fn match_while_registering() {
+        let runtime = Runtime::new(())
+            .with_plugin(
+                "foo",
+                h(|_: http::Request, _| async {
+                    task::yield_now().await;
+                    Ok(http::Response::new(http::StatusCode::Ok))
+                }),
+            )
+            .unwrap();
+
+        let requests = join_all((0..100).map(|_| runtime.on_msg(request("/_foo/bar"))));
+        let register = async {
+            task::yield_now().await;
+            runtime.register_handler("bar", ())
+        };
+        let (answers, registered) = futures::join!(requests, register);
+
+        assert!(registered.is_ok());
+        assert!(answers.iter().all(|a| a.is_ok()));
+        assert!(runtime.on_msg(request("/_bar")).await.is_ok());
+    }
+
+    #[test]
+    async fn plugins_get_the_request_id() {
+        let runtime = Runtime::new(())
+            .with_request_ids(|| "abc".into())
+            .with_plugin(
+                "foo",
+                h(|req: http::Request, _| async move {
+                    let id = req.header("x-request-id").unwrap().as_str();
+                    Ok(http::Response::from(id))
+                }),
+            )
+            .unwrap()
+            .with_plugin(
+                "bar",
+                h(|_: http::Request, _| async {
+                    let mut res = http::Response::new(http::StatusCode::Ok);
+                    res.insert_header("x-correlation-id", "own");
+                    Ok(res)
+                }),
+            )
+            .unwrap();
+
+        let req = http::Request::new(http::Method::Get, "http://example.com/_foo");
+        let mut res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.header("x-correlation-id").unwrap(), "abc");
+        assert_eq!(res.body_string().await.unwrap(), "abc");
+
+        let res: http::Response = runtime.on_msg(request("/_foo")).await.unwrap().into();
+        assert_eq!(res.header("x-correlation-id").unwrap(), "123");
+
+        let res: http::Response = runtime.on_msg(request("/_bar")).await.unwrap().into();
+        assert_eq!(res.header("x-correlation-id").unwrap(), "own");
+
+        let runtime = runtime.with_request_id_header("x-amzn-trace-id".parse().unwrap());
+        let mut req = http::Request::new(http::Method::Get, "http://example.com/_foo");
+        req.insert_header("x-amzn-trace-id", "Root=1-5759e988");
+        let mut res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.header("x-amzn-trace-id").unwrap(), "Root=1-5759e988");
+        assert_eq!(res.header("x-correlation-id").unwrap(), "Root=1-5759e988");
+        assert_eq!(res.body_string().await.unwrap(), "Root=1-5759e988");
+
+        let runtime = Runtime::new(()).with_health().unwrap();
+        let req = http::Request::new(http::Method::Get, "http://example.com/_health");
+        match runtime.on_msg(req.into()).await {
+            Err(crate::Error::Http(err)) => assert_eq!(err.status(), http::StatusCode::BadRequest),
+            _ => panic!("request without id is rejected"),
+        }
+    }
+
+    #[test]
+    async fn strip_hop_by_hop_headers() {
+        let runtime = Runtime::new(())
+            .with_plugin(
+                "relay",
+                h(|req: http::Request, _| async move {
+                    let mut res = http::Response::new(StatusCode::Ok);
+                    res.insert_header("connection", "x-upstream-conn, x-upstream-debug");
+                    res.insert_header("x-upstream-conn", "42");
+                    res.insert_header("x-upstream-debug", "on");
+                    res.insert_header("keep-alive", "timeout=5");
+                    res.insert_header("proxy-authenticate", "Basic");
+                    res.insert_header("x-custom", "kept");
+                    let hops = ["connection", "x-client-hop", "transfer-encoding"];
+                    let forwarded = hops.iter().filter(|h| req.header(**h).is_some()).count();
+                    res.set_body(forwarded.to_string());
+                    Ok(res)
+                }),
+            )
+            .unwrap();
+        let mut req: http::Request = request("/_relay").into();
+        req.insert_header("connection", "x-client-hop");
+        req.insert_header("x-client-hop", "1");
+        req.insert_header("transfer-encoding", "chunked");
+
+        let mut res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.body_string().await.unwrap(), "0");
+        for hop in &[
+            "connection",
+            "x-upstream-conn",
+            "x-upstream-debug",
+            "keep-alive",
+            "proxy-authenticate",
+        ] {
+            assert!(res.header(*hop).is_none(), "{} is stripped", hop);
+        }
+        assert_eq!(res["x-custom"], "kept");
+        assert_eq!(res["x-valor-plugin"], "relay");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn panicking_plugins_answer_with_error() {
+        let runtime = Runtime::new(())
+            .with_plugin(
+                "oops",
+                h(|_: http::Request, _| async {
+                    if true {
+                        panic!("oh no");
+                    }
+                    Ok(http::Response::new(http::StatusCode::Ok))
+                }),
+            )
+            .unwrap()
+            .with_plugin("foo", ())
+            .unwrap();
+
+        let answer = runtime.on_msg(request("/_oops")).await;
+        let mut res: http::Response = answer.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::InternalServerError);
+        assert_eq!(res.header("x-correlation-id").unwrap(), "123");
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "oops");
+        assert_eq!(res.ext::<Panic>().unwrap().0, "oh no");
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "oops failed handling request 123"
+        );
+        assert!(runtime.on_msg(request("/_foo")).await.is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn open_circuit_of_failing_plugin() {
+        let runtime = Runtime::new(())
+            .with_circuit_breaker(CircuitBreaker {
+                failures: 2,
+                open_for: Duration::from_secs(60),
+            })
+            .with_plugin(
+                "down",
+                h(|_: http::Request, _| async {
+                    Ok(http::Response::new(http::StatusCode::BadGateway))
+                }),
+            )
+            .unwrap()
+            .with_health()
+            .unwrap();
+        let status = |path| {
+            let runtime = &runtime;
+            async move {
+                let res: http::Response = runtime.on_msg(request(path)).await.unwrap().into();
+                res
+            }
+        };
+
+        assert_eq!(
+            status("/_down").await.status(),
+            http::StatusCode::BadGateway
+        );
+        assert_eq!(
+            status("/_down").await.status(),
+            http::StatusCode::BadGateway
+        );
+        let res = status("/_down").await;
+        assert_eq!(res.status(), http::StatusCode::ServiceUnavailable);
+        assert_eq!(res.header("retry-after").unwrap(), "60");
+
+        let mut res = status("/_health").await;
+        assert_eq!(res.status(), http::StatusCode::ServiceUnavailable);
+        let health: serde_json::Value = res.body_json().await.unwrap();
+        let plugins = health["plugins"].as_array().unwrap();
+        let down = plugins.iter().find(|p| p["name"] == "down").unwrap();
+        assert_eq!(down["circuit"], "open");
+    }
+
+    #[test]
+    async fn list_static_plugins() {
+        let runtime = Runtime::new(())
+            .with_plugin("foo", ())
+            .unwrap()
+            .with_registry(None)
+            .unwrap();
+        runtime.register_handler(("bar", "/bar/*path"), ()).unwrap();
+
+        let answer = runtime.on_msg(request("/_plugins")).await;
+        let mut res: http::Response = answer.unwrap().into();
+        let list: Vec<VluginDef> = res.body_json().await.unwrap();
+        let names = list.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["bar", "foo", "registry"]);
+        assert!(runtime.on_msg(request("/bar/baz")).await.is_ok());
+    }
+
+    #[test]
+    async fn answer_unmatched_with_fallback() {
+        let runtime = Runtime::new(())
+            .with_plugin("foo", ())
+            .unwrap()
+            .with_plugin(
+                VluginDef {
+                    methods: vec![http::Method::Post],
+                    ..VluginDef::from("bar")
+                },
+                (),
+            )
+            .unwrap();
+        let res = runtime.on_msg(request("/spa/page")).await;
+        assert!(
+            matches!(res, Err(crate::Error::Http(e)) if e.status() == http::StatusCode::NotFound)
+        );
+
+        let runtime = runtime.with_fallback(
+            "spa",
+            h(|req: http::Request, _| async move { Ok(http::Response::from(req.url().path())) }),
+        );
+        let mut res: http::Response = runtime.on_msg(request("/spa/page")).await.unwrap().into();
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "spa");
+        assert_eq!(res.header("x-correlation-id").unwrap(), "123");
+        assert_eq!(res.body_string().await.unwrap(), "/spa/page");
+
+        // matching plugins still answer
+        let res: http::Response = runtime.on_msg(request("/_foo")).await.unwrap().into();
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "foo");
+        // and methods not allowed are not a missing route
+        let res: http::Response = runtime.on_msg(request("/_bar")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::MethodNotAllowed);
+    }
+
+    #[test]
+    async fn answer_head_with_get_plugin() {
+        let get_only = |name: &str| VluginDef {
+            methods: vec![http::Method::Get],
+            ..VluginDef::from(name)
+        };
+        let runtime = Runtime::new(())
+            .with_plugin(
+                get_only("foo"),
+                h(|req: http::Request, _| async move {
+                    let mut res = http::Response::from(req.method().to_string());
+                    res.insert_header("x-foo", "bar");
+                    Ok(res)
+                }),
+            )
+            .unwrap()
+            .with_plugin(
+                "head",
+                h(|_: http::Request, _| async { Ok(http::Response::from("own")) }),
+            )
+            .unwrap();
+        let head = |path: &str| {
+            let url = "http://example.com".to_owned() + path;
+            let mut req = http::Request::new(http::Method::Head, url.as_str());
+            req.insert_header("x-request-id", "123");
+            Message::Http(req)
+        };
+
+        let mut get: http::Response = runtime.on_msg(request("/_foo")).await.unwrap().into();
+        let mut res: http::Response = runtime.on_msg(head("/_foo")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::Ok);
+        for (name, values) in get.iter() {
+            assert_eq!(res.header(name), Some(values), "{}", name);
+        }
+        let len = get.len().unwrap().to_string();
+        assert_eq!(res.header("content-length").unwrap(), len.as_str());
+        assert_eq!(get.body_string().await.unwrap(), "GET");
+        assert_eq!(res.body_string().await.unwrap(), "");
+
+        // plugins that serve HEAD get it as it is
+        let mut res: http::Response = runtime.on_msg(head("/_head")).await.unwrap().into();
+        assert_eq!(res.body_string().await.unwrap(), "own");
+
+        let runtime = runtime.with_auto_head(false);
+        let res: http::Response = runtime.on_msg(head("/_foo")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::MethodNotAllowed);
+        assert_eq!(res.header("allow").unwrap(), "GET, OPTIONS");
+    }
+
+    #[test]
+    async fn route_by_namespace() {
+        let plugin = |name: &str| VluginDef {
+            namespace: Some(name.into()),
+            ..VluginDef::from((name, "api"))
+        };
+        let path = || {
+            h(|req: http::Request, _| async move {
+                let res: http::Response = req.url().path().into();
+                Ok(res)
+            })
+        };
+        let tenants = |namespaces| {
+            Runtime::new(())
+                .with_namespaces(namespaces)
+                .with_plugin(plugin("acme"), path())
+                .unwrap()
+                .with_plugin(plugin("globex"), path())
+                .unwrap()
+                .with_plugin("foo", ())
+                .unwrap()
+        };
+        let send = |runtime: &Runtime<()>, path: &str, tenant: Option<&str>| {
+            let url = "http://example.com".to_owned() + path;
+            let mut req = http::Request::new(http::Method::Get, url.as_str());
+            req.insert_header("x-request-id", "123");
+            if let Some(tenant) = tenant {
+                req.insert_header("x-tenant", tenant);
+            }
+            let runtime = runtime.clone();
+            async move { runtime.on_msg(req.into()).await.map(http::Response::from) }
+        };
+
+        let runtime = tenants(Namespaces::PathSegment);
+        let mut res = send(&runtime, "/acme/api/users", None).await.unwrap();
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "acme");
+        assert_eq!(res.body_string().await.unwrap(), "/users");
+        let res = send(&runtime, "/globex/api", None).await.unwrap();
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "globex");
+        assert!(send(&runtime, "/initech/api", None).await.is_err());
+        assert!(send(&runtime, "/api", None).await.is_err());
+        // plugins without a namespace get the whole path
+        let res = send(&runtime, "/_foo", None).await.unwrap();
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "foo");
+
+        let runtime = tenants(Namespaces::Header("x-tenant".into()));
+        let mut res = send(&runtime, "/api/users", Some("acme")).await.unwrap();
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "acme");
+        assert_eq!(res.body_string().await.unwrap(), "/users");
+        let res = send(&runtime, "/api", Some("globex")).await.unwrap();
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "globex");
+        assert!(send(&runtime, "/api", None).await.is_err());
+    }
+
+    #[test]
+    async fn answer_options_with_allowed_methods() {
+        let plugin = |name: &str, methods| VluginDef {
+            methods,
+            prefix: Some("/api".into()),
+            ..VluginDef::from(name)
+        };
+        let runtime = Runtime::new(())
+            .with_plugin(plugin("read", vec![http::Method::Get]), ())
+            .unwrap()
+            .with_plugin(plugin("write", vec![http::Method::Post]), ())
+            .unwrap();
+        let options = |path: &str| {
+            let url = "http://example.com".to_owned() + path;
+            let mut req = http::Request::new(http::Method::Options, url.as_str());
+            req.insert_header("x-request-id", "123");
+            Message::Http(req)
+        };
+
+        let res: http::Response = runtime.on_msg(options("/api/items")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::NoContent);
+        assert_eq!(res.header("allow").unwrap(), "GET, POST, HEAD, OPTIONS");
+        assert!(res.header("x-valor-plugin").is_none());
+
+        let res = runtime.on_msg(options("/unknown")).await;
+        assert!(
+            matches!(res, Err(crate::Error::Http(e)) if e.status() == http::StatusCode::NotFound)
+        );
+
+        // plugins that serve OPTIONS answer it themselves
+        let runtime = runtime.with_plugin("any", ()).unwrap();
+        let res: http::Response = runtime.on_msg(options("/_any")).await.unwrap().into();
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "any");
+    }
+
+    #[test]
+    async fn sticky_weighted_variants() {
+        let variant = |name: &str| VluginDef {
+            weight: Some(50),
+            ..VluginDef::from((name, "api"))
+        };
+        let runtime = Runtime::new(())
+            .with_sticky_variants(Sticky::Cookie("session".into()))
+            .with_plugin(variant("blue"), ())
+            .unwrap()
+            .with_plugin(variant("green"), ())
+            .unwrap();
+        let variant = |session: &str| {
+            let url = "http://example.com/api";
+            let mut req = http::Request::new(http::Method::Get, url);
+            req.insert_header("x-request-id", session.to_owned() + "-id");
+            req.insert_header("cookie", format!("theme=dark; session={}", session));
+            let runtime = &runtime;
+            async move {
+                let res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+                let variant = res.header("x-valor-variant").unwrap().as_str().to_owned();
+                assert_eq!(res.header("x-valor-plugin").unwrap(), variant.as_str());
+                variant
+            }
+        };
+
+        let mut served = Vec::new();
+        for session in (0..20).map(|i| i.to_string()) {
+            let first = variant(&session).await;
+            assert_eq!(variant(&session).await, first);
+            served.push(first);
+        }
+        assert!(served.iter().any(|v| v == "blue"));
+        assert!(served.iter().any(|v| v == "green"));
+    }
+
+    #[test]
+    async fn affinity_cookie_keeps_replica() {
+        let variant = |name: &str, route: &str| VluginDef {
+            weight: Some(50),
+            ..VluginDef::from((name, route))
+        };
+        let runtime = Runtime::new(())
+            .with_sticky_variants(Sticky::Affinity {
+                cookie: "replica".into(),
+                ttl: Duration::from_secs(60),
+            })
+            .with_plugin(variant("blue", "/api"), ())
+            .unwrap()
+            .with_plugin(variant("green", "/api"), ())
+            .unwrap()
+            .with_plugin(variant("old", "/web"), ())
+            .unwrap()
+            .with_plugin(variant("new", "/web"), ())
+            .unwrap();
+        let api = affinity_cookie("replica", &variant("blue", "/api"));
+        let web = affinity_cookie("replica", &variant("old", "/web"));
+        assert_ne!(api, web);
+        let get = |i: usize, path: &str, cookies: &[(&str, &str)]| {
+            let url = "http://example.com".to_owned() + path;
+            let mut req = http::Request::new(http::Method::Get, url.as_str());
+            req.insert_header("x-request-id", i.to_string());
+            let cookies = cookies
+                .iter()
+                .map(|(name, val)| format!("{}={}", name, val))
+                .collect::<Vec<_>>();
+            if !cookies.is_empty() {
+                req.insert_header("cookie", cookies.join("; "));
+            }
+            let runtime = &runtime;
+            async move {
+                let res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+                let variant = res.header("x-valor-variant").unwrap().as_str().to_owned();
+                let cookie = res.header("set-cookie").map(|c| c.as_str().to_owned());
+                (variant, cookie.unwrap())
+            }
+        };
+        let set_cookie = |name: &str, variant: &str| {
+            format!("{}={}; Path=/; Max-Age=60; HttpOnly", name, variant)
+        };
+
+        let (first, cookie) = get(0, "/api", &[]).await;
+        assert_eq!(cookie, set_cookie(&api, &first));
+        let (page, _) = get(1, "/web", &[(&api, &first)]).await;
+        // each route keeps its own and the cookie is refreshed every time
+        for i in 2..10 {
+            let cookies = [
+                (api.as_str(), first.as_str()),
+                (web.as_str(), page.as_str()),
+            ];
+            let (variant, cookie) = get(i, "/api", &cookies).await;
+            assert_eq!((variant, cookie), (first.clone(), set_cookie(&api, &first)));
+            assert_eq!(get(i, "/web", &cookies).await.0, page);
+        }
+
+        // rebalanced when the replica is unhealthy or gone
+        runtime
+            .registry
+            .borrow()
+            .set_probe(&first, Health::Unhealthy);
+        let (other, cookie) = get(10, "/api", &[(&api, &first)]).await;
+        assert_ne!(other, first);
+        assert_eq!(cookie, set_cookie(&api, &other));
+        let (variant, cookie) = get(11, "/api", &[(&api, "gone")]).await;
+        assert_eq!(cookie, set_cookie(&api, &variant));
+    }
+
+    #[test]
+    async fn limit_requests_in_flight() {
+        let slow = || {
+            h(|_: http::Request, _| async {
+                task::sleep(Duration::from_millis(50)).await;
+                Ok(http::Response::new(StatusCode::Ok))
+            })
+        };
+        let runtime = Runtime::new(())
+            .with_max_concurrency(1)
+            .with_metrics()
+            .unwrap()
+            .with_plugin("slow", slow())
+            .unwrap();
+        let runtime = &runtime;
+        let status = |path: &'static str| async move {
+            let res: http::Response = runtime.on_msg(request(path)).await.unwrap().into();
+            res.status()
+        };
+        let metrics = async {
+            task::sleep(Duration::from_millis(10)).await;
+            let mut res: http::Response =
+                runtime.on_msg(request("/_metrics")).await.unwrap().into();
+            res.body_string().await.unwrap()
+        };
+        let (first, second, metrics) = futures::join!(status("/_slow"), status("/_slow"), metrics);
+        assert_eq!(first, StatusCode::Ok);
+        assert_eq!(second, StatusCode::ServiceUnavailable);
+        assert!(metrics.contains("valor_requests_in_flight 1\n"));
+
+        let runtime = runtime.clone().with_concurrency_queue(1);
+        let runtime = &runtime;
+        let status = |path: &'static str| async move {
+            let res: http::Response = runtime.on_msg(request(path)).await.unwrap().into();
+            res.status()
+        };
+        let statuses = futures::join!(status("/_slow"), status("/_slow"), status("/_slow"));
+        assert_eq!(
+            statuses,
+            (
+                StatusCode::Ok,
+                StatusCode::Ok,
+                StatusCode::ServiceUnavailable
+            )
+        );
+    }
+
+    #[test]
+    async fn share_state_with_handlers() {
+        use crate::RequestState;
+
+        struct Greeting(&'static str);
+        struct Exclaim;
+
+        #[async_trait(?Send)]
+        impl Middleware for Exclaim {
+            async fn handle(
+                &self,
+                req: http::Request,
+                next: Next<'_>,
+            ) -> Result<http::Response, crate::Error> {
+                let times = req.state::<usize>().map_or(0, |n| *n);
+                let mut res = next.run(req).await?;
+                let body = res.body_string().await? + &"!".repeat(times);
+                res.set_body(body);
+                Ok(res)
+            }
+        }
+
+        let runtime = Runtime::new(())
+            .with_state(Greeting("hi"))
+            .with_state(1_usize)
+            .with_state(2_usize)
+            .with_middleware(Exclaim)
+            .with_plugin(
+                "greet",
+                h(|req: http::Request, _| async move {
+                    let greeting = req.state::<Greeting>().map_or("", |g| g.0);
+                    assert!(req.state::<String>().is_none());
+                    Ok(http::Response::from(greeting))
+                }),
+            )
+            .unwrap();
+        let mut res: http::Response = runtime.on_msg(request("/_greet")).await.unwrap().into();
+        assert_eq!(res.body_string().await.unwrap(), "hi!!");
+    }
+
+    #[test]
+    async fn mirror_requests_to_shadow_plugin() {
+        let tasks = Rc::new(RefCell::new(Vec::new()));
+        let mirrored = Rc::new(RefCell::new(Vec::new()));
+        let echo = |mut req: http::Request, _: &Context| async move {
+            Ok(http::Response::from(req.body_string().await?))
+        };
+        let shadow = {
+            let mirrored = mirrored.clone();
+            h(move |mut req: http::Request, _| {
+                let mirrored = mirrored.clone();
+                async move {
+                    let body = req.body_string().await?;
+                    mirrored
+                        .borrow_mut()
+                        .push((req.url().path().to_owned(), body));
+                    Err::<http::Response, _>(
+                        http::Error::from_str(StatusCode::InternalServerError, "broken").into(),
+                    )
+                }
+            })
+        };
+        let mirror = |rate| VluginDef {
+            mirror: Some(Mirror {
+                plugin: "shadow".into(),
+                rate,
+            }),
+            ..VluginDef::from("api")
+        };
+        let runtime = Runtime::new(())
+            .with_spawner({
+                let tasks = tasks.clone();
+                move |task| tasks.borrow_mut().push(task)
+            })
+            .with_plugin(mirror(1.0), h(echo))
+            .unwrap()
+            .with_plugin("shadow", shadow)
+            .unwrap();
+        let post = |body: &str| {
+            let mut req = http::Request::new(http::Method::Post, "http://example.com/_api/users");
+            req.insert_header("x-request-id", "123");
+            req.set_body(body);
+            req
+        };
+
+        let mut res: http::Response = runtime.on_msg(post("hi").into()).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "hi");
+        let pending = tasks.borrow_mut().drain(..).collect::<Vec<_>>();
+        assert_eq!(pending.len(), 1);
+        // shutting down waits for the mirrored request
+        assert_eq!(runtime.drain.in_flight(), 1);
+        join_all(pending).await;
+        assert_eq!(runtime.drain.in_flight(), 0);
+        assert_eq!(*mirrored.borrow(), [("/users".to_owned(), "hi".to_owned())]);
+
+        runtime.register_handler(mirror(0.0), h(echo)).unwrap();
+        let res: http::Response = runtime.on_msg(post("hi").into()).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(tasks.borrow().is_empty());
+    }
+
+    #[test]
+    async fn retry_transient_failures() {
+        let calls = Rc::new(core::cell::Cell::new(0));
+        let flaky = {
+            let calls = calls.clone();
+            h(move |mut req: http::Request, _| {
+                let calls = calls.clone();
+                async move {
+                    calls.set(calls.get() + 1);
+                    let body = req.body_string().await?;
+                    if calls.get() < 3 {
+                        return Ok(http::Response::new(StatusCode::BadGateway));
+                    }
+                    Ok(http::Response::from(body))
+                }
+            })
+        };
+        let plugin = VluginDef {
+            retry: Some(Retry {
+                backoff_ms: 0,
+                ..Retry::default()
+            }),
+            ..VluginDef::from("flaky")
+        };
+        let runtime = Runtime::new(()).with_plugin(plugin, flaky).unwrap();
+        let send = |method, body: &str| {
+            let mut req = http::Request::new(method, "http://example.com/_flaky");
+            req.insert_header("x-request-id", "123");
+            req.set_body(body);
+            runtime.on_msg(req.into())
+        };
+
+        let mut res: http::Response = send(http::Method::Put, "hi").await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res["x-valor-attempts"], "3");
+        assert_eq!(res.body_string().await.unwrap(), "hi");
+
+        calls.set(0);
+        let res: http::Response = send(http::Method::Post, "hi").await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::BadGateway);
+        assert!(res.header("x-valor-attempts").is_none());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    async fn retries_keep_the_user() {
+        struct Ann;
+
+        #[async_trait(?Send)]
+        impl Middleware for Ann {
+            async fn handle(
+                &self,
+                mut req: http::Request,
+                next: Next<'_>,
+            ) -> Result<http::Response, crate::Error> {
+                req.set_ext(User("ann".into()));
+                next.run(req).await
+            }
+        }
+
+        let calls = Rc::new(core::cell::Cell::new(0));
+        let flaky = {
+            let calls = calls.clone();
+            h(move |req: http::Request, _| {
+                calls.set(calls.get() + 1);
+                let user = req.ext::<User>().map(|u| u.0.clone());
+                let first = calls.get() == 1;
+                async move {
+                    if first {
+                        return Ok(http::Response::new(StatusCode::BadGateway));
+                    }
+                    Ok(http::Response::from(user.unwrap_or_default()))
+                }
+            })
+        };
+        let plugin = VluginDef {
+            retry: Some(Retry {
+                backoff_ms: 0,
+                ..Retry::default()
+            }),
+            ..VluginDef::from("flaky")
+        };
+        let runtime = Runtime::new(())
+            .with_middleware(Ann)
+            .with_plugin(plugin, flaky)
+            .unwrap();
+        let answer = runtime.on_msg(request("/_flaky")).await;
+        let mut res: http::Response = answer.unwrap().into();
+        assert_eq!(res["x-valor-attempts"], "2");
+        assert_eq!(res.body_string().await.unwrap(), "ann");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn retries_share_the_timeout() {
+        let calls = Rc::new(core::cell::Cell::new(0));
+        let slow = {
+            let calls = calls.clone();
+            h(move |_: http::Request, _| {
+                calls.set(calls.get() + 1);
+                async {
+                    task::sleep(Duration::from_millis(30)).await;
+                    Ok(http::Response::new(StatusCode::BadGateway))
+                }
+            })
+        };
+        let plugin = VluginDef {
+            retry: Some(Retry {
+                attempts: 10,
+                backoff_ms: 0,
+                ..Retry::default()
+            }),
+            request_timeout_ms: Some(100),
+            ..VluginDef::from("slow")
+        };
+        let runtime = Runtime::new(()).with_plugin(plugin, slow).unwrap();
+        let res: http::Response = runtime.on_msg(request("/_slow")).await.unwrap().into();
+        assert!(res.status().is_server_error());
+        assert!(calls.get() < 5, "{} attempts", calls.get());
+    }
+
+    #[test]
+    async fn pipeline_of_plugins() {
+        let auth = h(|req: http::Request, _| async move {
+            Ok(match req.header("authorization") {
+                Some(_) => Answer::Next(req),
+                None => http::Response::new(StatusCode::Unauthorized).into(),
+            })
+        });
+        let transform = h(|mut req: http::Request, _| async move {
+            req.insert_header("x-transformed", "yes");
+            Ok(req)
+        });
+        let api = h(|req: http::Request, _| async move {
+            let transformed = req.header("x-transformed").map(|h| h.as_str().to_owned());
+            Ok(http::Response::from(transformed.unwrap_or_default()))
+        });
+        let runtime = Runtime::new(())
+            .with_plugin("auth", auth)
+            .unwrap()
+            .with_plugin("transform", transform)
+            .unwrap()
+            .with_plugin(
+                VluginDef {
+                    pipeline: vec!["auth".into(), "transform".into()],
+                    ..VluginDef::from("api")
+                },
+                api,
+            )
+            .unwrap();
+        let send = |authorization: Option<&str>| {
+            let mut req = http::Request::new(http::Method::Get, "http://example.com/_api");
+            req.insert_header("x-request-id", "123");
+            if let Some(authorization) = authorization {
+                req.insert_header("authorization", authorization);
+            }
+            runtime.on_msg(req.into())
+        };
+
+        let mut res: http::Response = send(Some("Bearer abc")).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "api");
+        assert_eq!(res.body_string().await.unwrap(), "yes");
+
+        let res: http::Response = send(None).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "auth");
+
+        runtime.registry.borrow_mut().set_disabled("auth", true);
+        let res: http::Response = send(Some("Bearer abc")).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn subrequests_to_other_plugins() {
+        let call = |path: &'static str| {
+            h(move |req: http::Request, _| async move {
+                let subrequests = req.ext::<Subrequests>().copied().ok_or_else(|| {
+                    http::Error::from_str(StatusCode::InternalServerError, "no subrequests")
+                })?;
+                let url = "http://example.com".to_owned() + path;
+                let mut res = subrequests
+                    .dispatch(http::Request::new(http::Method::Get, url.as_str()))
+                    .await?;
+                let body = res.body_string().await?;
+                Ok(http::Response::from(format!(
+                    "{} {}",
+                    subrequests.depth(),
+                    body
+                )))
+            })
+        };
+        let runtime = Runtime::new(())
+            .with_plugin(
+                "users",
+                h(|req: http::Request, _| async move {
+                    let id = req.header(REQ_ID_HEADER).unwrap().as_str().to_owned();
+                    Ok(http::Response::from(id + "/users" + req.url().path()))
+                }),
+            )
+            .unwrap()
+            .with_plugin("profile", call("/_users/1"))
+            .unwrap()
+            .with_plugin("loop", call("/_loop"))
+            .unwrap();
+
+        match runtime.on_msg(request("/_profile")).await {
+            Err(crate::Error::Http(err)) => assert_eq!(err.to_string(), "no subrequests"),
+            _ => panic!("subrequests are disabled by default"),
+        }
+
+        // subrequests don't wait for a turn of the one they come from
+        let runtime = runtime.with_subrequests(3).with_max_concurrency(1);
+        let mut res: http::Response = runtime.on_msg(request("/_profile")).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "profile");
+        assert_eq!(res.body_string().await.unwrap(), "0 123/users/1");
+
+        match runtime.on_msg(request("/_loop")).await {
+            Err(crate::Error::Http(err)) => assert_eq!(err.status(), StatusCode::LoopDetected),
+            _ => panic!("recursive subrequests stop at the depth limit"),
+        }
+    }
+
+    #[test]
+    async fn client_ip_behind_trusted_proxies() {
+        let runtime = Runtime::new(())
+            .with_trusted_proxies(vec!["10.0.0.0/8".parse().unwrap()])
+            .with_plugin(
+                "ip",
+                h(|req: http::Request, _| async move {
+                    let ip = crate::ClientIp::client_ip(&req).unwrap();
+                    Ok(http::Response::from(ip.to_string()))
+                }),
+            )
+            .unwrap();
+        let client = |peer: &str| {
+            let mut req = http::Request::new(http::Method::Get, "http://example.com/_ip");
+            req.insert_header("x-request-id", "123");
+            req.insert_header("x-forwarded-for", "1.1.1.1, 2.2.2.2");
+            req.set_peer_addr(Some(peer));
+            let runtime = &runtime;
+            async move {
+                let mut res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+                res.body_string().await.unwrap()
+            }
+        };
+        assert_eq!(client("10.0.0.1:4000").await, "2.2.2.2");
+        assert_eq!(client("3.3.3.3:4000").await, "3.3.3.3");
+    }
+
+    #[test]
+    async fn answer_errors_with_problem_details() {
+        let runtime = Runtime::new(())
+            .with_problem_details()
+            .with_registry(None)
+            .unwrap();
+        let mut res: http::Response = runtime.on_msg(request("/nope")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::NotFound);
+        assert_eq!(
+            res.content_type().unwrap().essence(),
+            "application/problem+json"
+        );
+        let problem: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(problem["status"], 404);
+        assert_eq!(problem["detail"], "No plugin matched");
+        assert_eq!(problem["instance"], "123");
+
+        let mut res: http::Response = runtime
+            .on_msg(request("/_plugins/foo"))
+            .await
+            .unwrap()
+            .into();
+        let problem: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(problem["title"], "Not Found");
+        assert_eq!(problem["detail"], "foo is not registered");
+    }
+
+    #[cfg(feature = "proxy")]
+    #[test]
+    async fn answer_upstream_errors_with_problem_details() {
+        let upstream = serde_json::json!({ "upstream": "http://127.0.0.1:1" });
+        let runtime = Runtime::new(())
+            .with_problem_details()
+            .with_plugin("api", crate::Proxy::from_config(Some(&upstream)).unwrap())
+            .unwrap();
+        let mut res: http::Response = runtime.on_msg(request("/_api")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::BadGateway);
+        assert_eq!(
+            res.content_type().unwrap().essence(),
+            "application/problem+json"
+        );
+        let problem: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(problem["status"], 502);
+        assert_eq!(problem["instance"], "123");
+    }
+
+    #[test]
+    async fn get_single_plugin() {
+        let runtime = Runtime::new(()).with_registry(None).unwrap();
+        let answer = runtime.on_msg(request("/_plugins/registry")).await;
+        let mut res: http::Response = answer.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::Ok);
+        let plugin: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(plugin["name"], "registry");
+        assert_eq!(plugin["status"], "active");
+
+        let answer = runtime.on_msg(request("/_plugins/foo")).await;
+        let res: http::Response = answer.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::NotFound);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn stats_of_a_plugin() {
+        let runtime = Runtime::new(())
+            .with_registry(None)
+            .unwrap()
+            .with_plugin("foo", ())
+            .unwrap()
+            .with_plugin(
+                "broken",
+                h(|_: http::Request, _| async {
+                    Ok(http::Response::new(http::StatusCode::BadGateway))
+                }),
+            )
+            .unwrap()
+            .with_plugin(
+                "failing",
+                h(|_: http::Request, _| async {
+                    Err(http::Error::from_str(http::StatusCode::BadGateway, "down"))
+                }),
+            )
+            .unwrap()
+            .with_plugin(
+                "spoofing",
+                h(|_: http::Request, _| async {
+                    let mut res = http::Response::new(http::StatusCode::Ok);
+                    res.insert_header("x-valor-plugin", "foo");
+                    Ok(res)
+                }),
+            )
+            .unwrap();
+        for path in &["/_foo", "/_foo/bar", "/_broken", "/_failing", "/_spoofing"] {
+            runtime.on_msg(request(path)).await.unwrap();
+        }
+        let stats = |name: &'static str| {
+            let runtime = &runtime;
+            async move {
+                let path = format!("/_plugins/{}/stats", name);
+                let mut res: http::Response = runtime.on_msg(request(&path)).await.unwrap().into();
+                assert_eq!(res.status(), http::StatusCode::Ok);
+                res.body_json::<serde_json::Value>().await.unwrap()
+            }
+        };
+
+        let foo = stats("foo").await;
+        assert_eq!(foo["requests"], 2);
+        assert_eq!(foo["errors"], 0);
+        assert!(foo["uptime_ms"].is_u64());
+        assert!(foo["latency_ms"]["p99"].as_f64().is_some());
+        let broken = stats("broken").await;
+        assert_eq!(
+            (&broken["requests"], &broken["errors"]),
+            (&1.into(), &1.into())
+        );
+        let failing = stats("failing").await;
+        assert_eq!(
+            (&failing["requests"], &failing["errors"]),
+            (&1.into(), &1.into())
+        );
+        assert_eq!(stats("spoofing").await["requests"], 1);
+
+        let mut req = http::Request::new(
+            http::Method::Delete,
+            "http://example.com/_plugins/foo/stats",
+        );
+        req.insert_header("x-request-id", "123");
+        let res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::NoContent);
+        let foo = stats("foo").await;
+        assert_eq!(foo["requests"], 0);
+        assert_eq!(foo["latency_ms"], serde_json::Value::Null);
+
+        let res: http::Response = runtime
+            .on_msg(request("/_plugins/nope/stats"))
+            .await
+            .unwrap()
+            .into();
+        assert_eq!(res.status(), http::StatusCode::NotFound);
+    }
+
+    #[test]
+    async fn registry_lists_allowed_methods() {
+        let runtime = Runtime::new(())
+            .with_problem_details()
+            .with_registry(None)
+            .unwrap();
+        let mut req = http::Request::new(http::Method::Patch, "http://example.com/_plugins/foo");
+        req.insert_header("x-request-id", "123");
+        let mut res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::MethodNotAllowed);
+        assert_eq!(res.header("allow").unwrap(), "GET, POST, PUT, DELETE");
+        let problem: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(problem["detail"], "PATCH is not allowed");
+
+        for (path, allow) in &[
+            ("/_plugins", "GET, POST, PUT"),
+            ("/_plugins/foo/stats", "GET, DELETE"),
+            ("/_plugins/foo/disable", "POST"),
+        ] {
+            let url = "http://example.com".to_owned() + path;
+            let mut req = http::Request::new(http::Method::Patch, url.as_str());
+            req.insert_header("x-request-id", "123");
+            let res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+            assert_eq!(res.header("allow").unwrap(), *allow, "{}", path);
+        }
+    }
+
+    // plugins that need an upstream in their configuration
+    struct Upstream;
+
+    #[async_trait(?Send)]
+    impl Loader for Upstream {
+        async fn load(&self, _plugin: &VluginDef) -> Result<VluginFactory, Error> {
+            Ok(Box::new(|cfg| {
+                Box::pin(async move {
+                    cfg.as_ref()
+                        .and_then(|c| c.get("upstream"))
+                        .ok_or_else(|| {
+                            http::Error::from_str(http::StatusCode::BadRequest, "No upstream")
+                        })?;
+                    Ok(Box::new(()) as Box<dyn Vlugin>)
+                })
+            }))
+        }
+    }
+
+    #[test]
+    async fn pass_config_to_plugins() {
+        let runtime = Runtime::new(Upstream).with_registry(None).unwrap();
+        let plugin = VluginDef {
+            config: Some(serde_json::json!({ "upstream": "http://example.com", "token": "abc" })),
+            ..VluginDef::from("foo")
+        };
+        runtime.load_plugin(plugin).await.unwrap();
+        let res = runtime.load_plugin("bar".into()).await;
+        assert!(
+            matches!(res, Err(Error::InvalidConfig(name, reason)) if name == "bar" && reason == "No upstream")
+        );
+
+        let answer = runtime.on_msg(request("/_plugins/foo")).await;
+        let mut res: http::Response = answer.unwrap().into();
+        let plugin: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(plugin["config"]["upstream"], "http://example.com");
+        assert_eq!(plugin["config"]["token"], "[redacted]");
+        let answer = runtime.on_msg(request("/_plugins")).await;
+        let mut res: http::Response = answer.unwrap().into();
+        let plugins: serde_json::Value = res.body_json().await.unwrap();
+        let foo = plugins
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|p| p["name"] == "foo");
+        assert!(foo.unwrap().get("config").is_none());
+    }
+
+    #[cfg(all(feature = "std", feature = "serde"))]
+    #[test]
+    async fn persist_plugins_of_the_registry() {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+        let unique = format!("valor-registry-{}", now.unwrap().as_nanos());
+        let dir = std::env::temp_dir().join(unique);
+        std::fs::create_dir_all(&dir).unwrap();
+        let native = |name: &str| VluginDef {
+            r#type: VluginType::Native { path: None },
+            ..VluginDef::from(name)
+        };
+        let runtime = |path: std::path::PathBuf| {
+            Runtime::new(())
+                .with_registry(None)
+                .unwrap()
+                .with_persistent_registry(path)
+                .with_plugin(native("configured"), ())
+                .unwrap()
+        };
+        let register = |runtime: &Runtime<()>, name: &str| {
+            let mut req = http::Request::new(http::Method::Post, "http://example.com/_plugins");
+            req.insert_header("x-request-id", "123");
+            let plugin = serde_json::json!({ "name": name, "type": "native" });
+            req.set_body(http::Body::from_json(&plugin).unwrap());
+            runtime.on_msg(req.into())
+        };
+
+        // only the plugins registered through the endpoint are saved
+        let path = dir.join("registry.json");
+        let res: http::Response = register(&runtime(path.clone()), "api")
+            .await
+            .unwrap()
+            .into();
+        assert_eq!(res.status(), http::StatusCode::Created);
+        assert!(res.header("warning").is_none());
+        let saved = persist::read(&path).unwrap();
+        let names = saved.iter().map(|p| p.plugin.name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["api"]);
+
+        // the plugin is registered when it can't be saved
+        let runtime = runtime(dir.join("missing").join("registry.json"));
+        let res: http::Response = register(&runtime, "api").await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::Created);
+        let warning = res.header("warning").unwrap().as_str();
+        assert!(warning.starts_with("199 valor \"Couldn't save the registry"));
+        let registered = runtime.registry.borrow().handlers();
+        assert!(registered.iter().any(|(p, _)| p.name == "api"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    async fn concurrent_registrations_of_same_name() {
+        let runtime = Runtime::new(()).with_registry(None).unwrap();
+        let register = |i: usize| {
+            let url = "http://example.com/_plugins";
+            let mut req = http::Request::new(http::Method::Post, url);
+            req.insert_header("x-request-id", "123");
+            let plugin = serde_json::json!({
+                "name": "foo",
+                "prefix": format!("/v{}", i),
+                "type": "static",
+            });
+            req.set_body(http::Body::from_json(&plugin).unwrap());
+            runtime.on_msg(req.into())
+        };
+        let answers = join_all((0..50).map(register)).await;
+
+        let statuses = answers
+            .into_iter()
+            .map(|a| http::Response::from(a.unwrap()).status())
+            .collect::<Vec<_>>();
+        let created = statuses.iter().filter(|s| **s == http::StatusCode::Created);
+        assert_eq!(created.count(), 1);
+        assert!(statuses[1..].iter().all(|s| *s == http::StatusCode::Ok));
+
+        let registry = runtime.registry.borrow();
+        assert_eq!(registry.handlers().len(), 2);
+        let (matched, _) = registry
+            .match_vlugin(http::Method::Get, None, "/v49")
+            .unwrap();
+        assert_eq!(matched.0.name, "foo");
+        assert!((0..49).all(|i| registry
+            .match_vlugin(http::Method::Get, None, &format!("/v{}", i))
+            .is_none()));
+    }
+
+    #[cfg(feature = "std")]
+    struct Hung;
+
+    #[cfg(feature = "std")]
+    #[async_trait(?Send)]
+    impl Loader for Hung {
+        async fn load(&self, _plugin: &VluginDef) -> Result<VluginFactory, Error> {
+            futures::future::pending().await
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn load_times_out() {
+        let runtime = Runtime::new(Hung).with_load_timeout(core::time::Duration::from_millis(10));
+        let res = runtime.load_plugin("foo".into()).await;
+        assert!(matches!(res, Err(Error::LoadTimeout(name)) if name == "foo"));
+
+        let mut plugin: VluginDef = "bar".into();
+        plugin.load_timeout_ms = Some(5);
+        let runtime = Runtime::new(Hung);
+        let res = runtime.load_plugin(plugin).await;
+        assert!(matches!(res, Err(Error::LoadTimeout(name)) if name == "bar"));
+    }
+
+    // plugins named `cold` fail to warm up
+    struct Warming;
+
+    struct Warm(RefCell<bool>, bool);
+
+    #[async_trait(?Send)]
+    impl Vlugin for Warm {
+        async fn on_msg(&self, _msg: Message) -> Result<Answer, crate::Error> {
+            let mut res = http::Response::new(http::StatusCode::Ok);
+            res.set_body(if *self.0.borrow() { "warm" } else { "cold" });
+            Ok(res.into())
+        }
+
+        async fn warmup(&self) -> Result<(), crate::Error> {
+            if self.1 {
+                let err = http::Error::from_str(StatusCode::BadGateway, "No connection");
+                return Err(err.into());
+            }
+            *self.0.borrow_mut() = true;
+            Ok(())
+        }
+
+        fn context(&self) -> &Context {
+            unreachable!()
+        }
+        fn context_mut(&mut self) -> &mut Context {
+            unreachable!()
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Loader for Warming {
+        async fn load(&self, plugin: &VluginDef) -> Result<VluginFactory, Error> {
+            let fails = plugin.name == "cold";
+            Ok(Box::new(move |_| {
+                Box::pin(async move {
+                    Ok(Box::new(Warm(RefCell::new(false), fails)) as Box<dyn Vlugin>)
+                })
+            }))
+        }
+    }
+
+    #[test]
+    async fn warm_up_plugins_after_loading() {
+        let runtime = Runtime::new(Warming).with_registry(None).unwrap();
+        runtime.load_plugin("warm".into()).await.unwrap();
+        runtime.load_plugin("cold".into()).await.unwrap();
+
+        let mut res: http::Response = runtime.on_msg(request("/_warm")).await.unwrap().into();
+        assert_eq!(res.body_string().await.unwrap(), "warm");
+        let load = runtime.load_info("warm").unwrap();
+        assert_eq!(load.warmup_error, None);
+        #[cfg(feature = "std")]
+        assert!(load.warmup_ms.is_some());
+
+        // failing to warm up is not fatal
+        let mut res: http::Response = runtime.on_msg(request("/_cold")).await.unwrap().into();
+        assert_eq!(res.body_string().await.unwrap(), "cold");
+        let answer = runtime.on_msg(request("/_plugins/cold")).await;
+        let mut res: http::Response = answer.unwrap().into();
+        let plugin: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(plugin["status"], "active");
+        assert_eq!(plugin["warmup_error"], "No connection");
+    }
+
+    #[test]
+    async fn warm_up_registered_handlers() {
+        let runtime = Runtime::new(())
+            .with_plugin("warm", Warm(RefCell::new(false), false))
+            .unwrap()
+            .with_plugin("later", Warm(RefCell::new(false), false))
+            .unwrap();
+        runtime.warm_up().await;
+        assert_eq!(runtime.load_info("warm").unwrap().warmup_error, None);
+
+        // registered after it's warmed up, it does before its first request
+        runtime
+            .register_handler("cold", Warm(RefCell::new(false), true))
+            .unwrap();
+        runtime
+            .register_handler("late", Warm(RefCell::new(false), false))
+            .unwrap();
+        let mut res: http::Response = runtime.on_msg(request("/_late")).await.unwrap().into();
+        assert_eq!(res.body_string().await.unwrap(), "warm");
+        runtime.on_msg(request("/_cold")).await.unwrap();
+        let load = runtime.load_info("cold").unwrap();
+        assert_eq!(load.warmup_error.as_deref(), Some("No connection"));
+    }
+
+    // plugins that fail to load the first time
+    #[cfg(feature = "std")]
+    #[derive(Default)]
+    struct Flaky(core::cell::Cell<u32>);
+
+    #[cfg(feature = "std")]
+    #[async_trait(?Send)]
+    impl Loader for Flaky {
+        async fn load(&self, plugin: &VluginDef) -> Result<VluginFactory, Error> {
+            self.0.set(self.0.get() + 1);
+            futures_timer::Delay::new(Duration::from_millis(10)).await;
+            if self.0.get() == 1 {
+                return Err(Error::LoadVlugin(plugin.name.clone()));
+            }
+            Ok(Box::new(|_| {
+                Box::pin(async { Ok(Box::new(()) as Box<dyn Vlugin>) })
+            }))
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn load_plugins_on_first_request() {
+        let loader = Rc::new(Flaky::default());
+        let runtime = Runtime::new(loader.clone());
+        runtime.register_lazy("foo".into()).unwrap();
+        assert_eq!(loader.0.get(), 0);
+
+        let status = |path| {
+            let runtime = &runtime;
+            async move {
+                let res: http::Response = runtime.on_msg(request(path)).await.unwrap().into();
+                res.status()
+            }
+        };
+        let (first, second) = futures::future::join(status("/_foo"), status("/_foo/bar")).await;
+        assert_eq!(first, http::StatusCode::ServiceUnavailable);
+        assert_eq!(second, http::StatusCode::ServiceUnavailable);
+        assert_eq!(loader.0.get(), 1);
+
+        // the route is not poisoned by the failure
+        assert_eq!(status("/_foo").await, http::StatusCode::Ok);
+        assert_eq!(status("/_foo").await, http::StatusCode::Ok);
+        assert_eq!(loader.0.get(), 2);
+    }
+
+    #[test]
+    async fn shutdown_waits_for_requests_in_flight() {
+        let runtime = Runtime::new(())
+            .with_health()
+            .unwrap()
+            .with_plugin(
+                "slow",
+                h(|_: http::Request, _| async {
+                    for _ in 0..3 {
+                        task::yield_now().await;
+                    }
+                    Ok(http::Response::new(http::StatusCode::Ok))
+                }),
+            )
+            .unwrap();
+
+        let done = core::cell::Cell::new(false);
+        let req = async {
+            runtime.on_msg(request("/_slow")).await.unwrap();
+            done.set(true);
+        };
+        let shutdown = async {
+            task::yield_now().await;
+            runtime.shutdown().await;
+            assert!(done.get());
+            for (path, status) in &[
+                ("/_health", http::StatusCode::ServiceUnavailable),
+                ("/_health/ready", http::StatusCode::ServiceUnavailable),
+                ("/_health/live", http::StatusCode::Ok),
+            ] {
+                let health: http::Response = runtime.on_msg(request(path)).await.unwrap().into();
+                assert_eq!(health.status(), *status, "{}", path);
+            }
+        };
+        futures::join!(req, shutdown);
+    }
+
+    #[test]
+    async fn unload_lets_requests_of_the_plugin_finish() {
+        let tasks = Rc::new(RefCell::new(Vec::new()));
+        let runtime = Runtime::new(())
+            .with_spawner({
+                let tasks = tasks.clone();
+                move |task| tasks.borrow_mut().push(task)
+            })
+            .with_registry(None)
+            .unwrap()
+            .with_plugin(
+                "slow",
+                h(|_: http::Request, _| async {
+                    for _ in 0..5 {
+                        task::yield_now().await;
+                    }
+                    Ok(http::Response::new(http::StatusCode::Ok))
+                }),
+            )
+            .unwrap();
+
+        let done = core::cell::Cell::new(false);
+        let req = async {
+            let res: http::Response = runtime.on_msg(request("/_slow")).await.unwrap().into();
+            assert_eq!(res.status(), http::StatusCode::Ok);
+            done.set(true);
+        };
+        let unload = async {
+            task::yield_now().await;
+            let mut res: http::Response = runtime
+                .on_msg(request("/_plugins?verbose=true"))
+                .await
+                .unwrap()
+                .into();
+            let list: Vec<serde_json::Value> = res.body_json().await.unwrap();
+            let slow = list.iter().find(|p| p["name"] == "slow").unwrap();
+            assert_eq!(slow["active_requests"], 1);
+
+            // answered right away while the request goes on
+            assert!(runtime.unload_plugin("slow").unwrap());
+            assert!(!runtime.unload_plugin("slow").unwrap());
+            assert!(!done.get());
+            let retiring = tasks.borrow_mut().drain(..).collect::<Vec<_>>();
+            assert_eq!(retiring.len(), 1);
+            join_all(retiring).await;
+            assert!(done.get());
+        };
+        futures::join!(req, unload);
+    }
+
+    #[test]
+    async fn not_ready_while_starting() {
+        let runtime = Runtime::new(()).with_startup().with_health().unwrap();
+        let status = |path| {
+            let runtime = &runtime;
+            async move {
+                let res: http::Response = runtime.on_msg(request(path)).await.unwrap().into();
+                res.status()
+            }
+        };
+        assert_eq!(
+            status("/_health/ready").await,
+            http::StatusCode::ServiceUnavailable
+        );
+        assert_eq!(status("/_health/live").await, http::StatusCode::Ok);
+        runtime.set_ready();
+        assert_eq!(status("/_health/ready").await, http::StatusCode::Ok);
+        assert_eq!(status("/_health").await, http::StatusCode::Ok);
+        assert_eq!(status("/_health/other").await, http::StatusCode::NotFound);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn server_timing_of_responses() {
+        let runtime = Runtime::new(())
+            .with_plugin("foo", ())
+            .unwrap()
+            .with_server_timing(true);
+        let res: http::Response = runtime.on_msg(request("/_foo")).await.unwrap().into();
+        let timing = res.header("server-timing").unwrap().as_str();
+        assert!(timing.starts_with("total;dur="), "{}", timing);
+        assert!(timing["total;dur=".len()..].parse::<f64>().is_ok());
+
+        // errors become responses with the header
+        let res: http::Response = runtime.on_msg(request("/bar")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::NotFound);
+        assert!(res.header("server-timing").is_some());
+        // also when refused before the middlewares
+        let mut req = http::Request::new(http::Method::Get, "http://localhost/_foo");
+        req.insert_header("x-request-id", "123");
+        req.insert_header("expect", "nothing");
+        let res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::ExpectationFailed);
+        assert!(res.header("server-timing").is_some());
+
+        let runtime = runtime.with_server_timing(false);
+        let res: http::Response = runtime.on_msg(request("/_foo")).await.unwrap().into();
+        assert!(res.header("server-timing").is_none());
+        assert!(runtime.on_msg(request("/bar")).await.is_err());
+    }
+
+    #[test]
+    async fn maintenance_mode() {
+        let page = MaintenancePage::new(r#"{"back":"soon"}"#, http::mime::JSON)
+            .retry_after(Duration::from_secs(600));
+        let runtime = Runtime::new(())
+            .with_plugin("foo", ())
+            .unwrap()
+            .with_health()
+            .unwrap()
+            .with_registry(Some(RegistryAuth::new("s3cret")))
+            .unwrap()
+            .with_maintenance_endpoint(Some(RegistryAuth::new("s3cret")))
+            .unwrap()
+            .with_maintenance_page(page);
+        let send = |method, path: &str, token: Option<&str>| {
+            let url = "http://example.com".to_owned() + path;
+            let mut req = http::Request::new(method, url.as_str());
+            req.insert_header("x-request-id", "123");
+            if let Some(token) = token {
+                req.insert_header("authorization", format!("Bearer {}", token));
+            }
+            let runtime = &runtime;
+            async move { runtime.handle(req).await }
+        };
+        use http::{Method::*, StatusCode};
+        assert_eq!(send(Get, "/_foo", None).await.status(), StatusCode::Ok);
+
+        let res = send(Post, "/_maintenance", None).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let mut res = send(Post, "/_maintenance", Some("s3cret")).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let state: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(state["maintenance"], true);
+        assert!(runtime.in_maintenance());
+
+        let mut res = send(Get, "/_foo", None).await;
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(res.header("retry-after").unwrap(), "600");
+        assert_eq!(res.content_type(), Some(http::mime::JSON));
+        assert_eq!(res.body_string().await.unwrap(), r#"{"back":"soon"}"#);
+        let mut res = send(Get, "/_health", None).await;
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        let health: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(health["status"], "maintenance");
+        assert_eq!(
+            send(Get, "/_health/live", None).await.status(),
+            StatusCode::Ok
+        );
+        assert_eq!(send(Get, "/_plugins", None).await.status(), StatusCode::Ok);
+
+        let res = send(Delete, "/_maintenance", Some("s3cret")).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(send(Get, "/_foo", None).await.status(), StatusCode::Ok);
+        assert_eq!(send(Get, "/_health", None).await.status(), StatusCode::Ok);
+
+        // without a page it's a plain error
+        let runtime = Runtime::new(()).with_plugin("foo", ()).unwrap();
+        runtime.set_maintenance(true);
+        let mut res: http::Response = runtime.on_msg(request("/_foo")).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert!(res.header("retry-after").is_none());
+        assert_eq!(res.body_string().await.unwrap(), "Under maintenance");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn slow_plugins_time_out() {
+        let runtime = Runtime::new(())
+            .with_request_timeout(core::time::Duration::from_millis(10))
+            .with_plugin(
+                "hung",
+                h(|_: http::Request, _| futures::future::pending::<Result<http::Response, _>>()),
+            )
+            .unwrap();
+
+        let res: http::Response = runtime.on_msg(request("/_hung")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::ServiceUnavailable);
+        assert_eq!(res.header("retry-after").unwrap(), "1");
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "hung");
+    }
+
+    #[test]
+    async fn reject_large_bodies() {
+        let runtime = Runtime::new(())
+            .with_max_body_size(4)
+            .with_plugin("foo", ())
+            .unwrap();
+
+        let mut req: http::Request = request("/_foo").into();
+        req.set_body("1234");
+        assert!(runtime.on_msg(req.into()).await.is_ok());
+
+        let mut req: http::Request = request("/_foo").into();
+        req.set_body("12345");
+        match runtime.on_msg(req.into()).await {
+            Err(crate::Error::Http(err)) => {
+                assert_eq!(err.status(), http::StatusCode::PayloadTooLarge)
+            }
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn reject_large_bodies_of_unknown_length() {
+        let runtime = Runtime::new(())
+            .with_max_body_size(4)
+            .with_plugin(
+                "upload",
+                h(|mut req: http::Request, _| async move {
+                    Ok(http::Response::from(req.body_string().await?))
+                }),
+            )
+            .unwrap();
+        let chunked = |body: &'static str| {
+            let mut req: http::Request = request("/_upload").into();
+            let reader = futures_lite::io::Cursor::new(body);
+            req.set_body(http::Body::from_reader(reader, None));
+            req
+        };
+
+        let mut res = runtime.handle(chunked("1234")).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "1234");
+        // the plugin fails reading the body
+        let res = runtime.handle(chunked("12345")).await;
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+    }
+
+    #[test]
+    async fn reject_hosts_not_allowed() {
+        let runtime = Runtime::new(())
+            .with_health()
+            .unwrap()
+            .with_plugin("foo", ())
+            .unwrap()
+            .with_allowed_hosts(vec!["example.com".into(), "*.example.org".into()]);
+        let send = |url: &str| {
+            let mut req = http::Request::new(http::Method::Get, url);
+            req.insert_header("x-request-id", "123");
+            runtime.on_msg(req.into())
+        };
+        let status = |res: Result<Answer, crate::Error>| match res {
+            Ok(answer) => http::Response::from(answer).status(),
+            Err(crate::Error::Http(err)) => err.status(),
+            Err(err) => panic!("unexpected error {:?}", err),
+        };
+
+        assert_eq!(
+            status(send("http://example.com/_foo").await),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            status(send("http://EXAMPLE.com:8080/_foo").await),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            status(send("http://api.example.org/_foo").await),
+            StatusCode::Ok
+        );
+        for url in &[
+            "http://evil.com/_foo",
+            "http://example.org/_foo",
+            "http://example.com.evil.com/_foo",
+        ] {
+            assert_eq!(
+                status(send(url).await),
+                StatusCode::MisdirectedRequest,
+                "{}",
+                url
+            );
+        }
+        // load balancers probe the health checks by IP
+        assert_eq!(
+            status(send("http://10.0.0.1/_health/live").await),
+            StatusCode::Ok
+        );
+        let mut req: http::Request = request("/_foo").into();
+        req.insert_header("host", "evil.com");
+        assert_eq!(
+            status(runtime.on_msg(req.into()).await),
+            StatusCode::MisdirectedRequest
+        );
+
+        let runtime = Runtime::new(()).with_plugin("foo", ()).unwrap();
+        let mut req: http::Request = request("/_foo").into();
+        req.insert_header("host", "evil.com");
+        assert_eq!(status(runtime.on_msg(req.into()).await), StatusCode::Ok);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    async fn expect_continue() {
+        struct Unread(Arc<core::sync::atomic::AtomicBool>);
+
+        impl futures_lite::AsyncRead for Unread {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                _: &mut core::task::Context<'_>,
+                _: &mut [u8],
+            ) -> core::task::Poll<std::io::Result<usize>> {
+                self.0.store(false, core::sync::atomic::Ordering::SeqCst);
+                core::task::Poll::Ready(Ok(0))
+            }
+        }
+
+        let runtime = Runtime::new(())
+            .with_max_body_size(4)
+            .with_plugin(
+                "upload",
+                h(|req: http::Request, _| async move {
+                    let expect = req.header("expect").map(|e| e.as_str().to_owned());
+                    Ok(http::Response::from(expect.unwrap_or_default()))
+                }),
+            )
+            .unwrap();
+        let upload = |expect: &str, len| {
+            let unread = Arc::new(core::sync::atomic::AtomicBool::new(true));
+            let mut req: http::Request = request("/_upload").into();
+            req.insert_header("expect", expect);
+            let reader = futures_lite::io::BufReader::new(Unread(unread.clone()));
+            req.set_body(http::Body::from_reader(reader, Some(len)));
+            (req, unread)
+        };
+
+        let (req, _) = upload("100-Continue", 4);
+        let mut res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "");
+
+        let (req, unread) = upload("100-continue", 1 << 20);
+        match runtime.on_msg(req.into()).await {
+            Err(crate::Error::Http(err)) => assert_eq!(err.status(), StatusCode::PayloadTooLarge),
+            _ => panic!("too large bodies are rejected"),
+        }
+        assert!(unread.load(core::sync::atomic::Ordering::SeqCst));
+
+        let (req, unread) = upload("something-else", 4);
+        match runtime.on_msg(req.into()).await {
+            Err(crate::Error::Http(err)) => assert_eq!(err.status(), StatusCode::ExpectationFailed),
+            _ => panic!("unknown expectations fail"),
+        }
+        assert!(unread.load(core::sync::atomic::Ordering::SeqCst));
+    }
+
+    struct Tag(&'static str);
+
+    #[async_trait(?Send)]
+    impl Middleware for Tag {
+        async fn handle(
+            &self,
+            mut req: http::Request,
+            next: Next<'_>,
+        ) -> Result<http::Response, crate::Error> {
+            req.append_header("x-tags", self.0);
+            let mut res = next.run(req).await?;
+            res.append_header("x-tags", self.0);
+            Ok(res)
+        }
+    }
+
+    struct Deny;
+
+    #[async_trait(?Send)]
+    impl Middleware for Deny {
+        async fn handle(
+            &self,
+            _req: http::Request,
+            _next: Next<'_>,
+        ) -> Result<http::Response, crate::Error> {
+            Ok(http::Response::new(http::StatusCode::Unauthorized))
+        }
+    }
+
+    #[test]
+    async fn middlewares_wrap_plugins_in_order() {
+        let runtime = Runtime::new(())
+            .with_middleware(Tag("outer"))
+            .with_middleware(Tag("inner"))
+            .with_plugin(
+                "foo",
+                h(|req: http::Request, _| async move {
+                    let tags = req
+                        .header("x-tags")
+                        .unwrap()
+                        .iter()
+                        .map(|t| t.as_str())
+                        .collect::<Vec<_>>();
+                    assert_eq!(tags, ["outer", "inner"]);
+                    Ok(http::Response::new(http::StatusCode::Ok))
+                }),
+            )
+            .unwrap();
+
+        let res: http::Response = runtime.on_msg(request("/_foo")).await.unwrap().into();
+        let tags = res
+            .header("x-tags")
+            .unwrap()
+            .iter()
+            .map(|t| t.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(tags, ["inner", "outer"]);
+    }
+
+    #[test]
+    async fn middleware_short_circuits() {
+        let runtime = Runtime::new(())
+            .with_middleware(Tag("outer"))
+            .with_middleware(Deny)
+            .with_plugin(
+                "foo",
+                h(|_: http::Request, _| async {
+                    Err::<http::Response, _>(crate::Error::NotSupported)
+                }),
+            )
+            .unwrap();
+
+        let res: http::Response = runtime.on_msg(request("/_foo")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::Unauthorized);
+        assert_eq!(res.header("x-tags").unwrap(), "outer");
+    }
+
+    struct Sick;
+
+    #[async_trait(?Send)]
+    impl Vlugin for Sick {
+        async fn on_msg(&self, _msg: Message) -> Result<Answer, crate::Error> {
+            Ok(Answer::Pong)
+        }
+        async fn health(&self) -> Health {
+            Health::Unhealthy
+        }
+        fn context(&self) -> &Context {
+            unreachable!()
+        }
+        fn context_mut(&mut self) -> &mut Context {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    async fn health_fails_with_critical_plugin() {
+        let runtime = Runtime::new(())
+            .with_health()
+            .unwrap()
+            .with_plugin("sick", Sick)
+            .unwrap();
+        let res: http::Response = runtime.on_msg(request("/_health")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::ServiceUnavailable);
+    }
+
+    #[test]
+    async fn skip_unhealthy_replicas() {
+        let replica = |name: &str| VluginDef {
+            prefix: Some("/api".into()),
+            weight: Some(50),
+            non_critical: true,
+            ..VluginDef::from(name)
+        };
+        let runtime = Runtime::new(())
+            .with_health()
+            .unwrap()
+            .with_plugin(replica("sick"), Sick)
+            .unwrap()
+            .with_plugin(replica("well"), ())
+            .unwrap();
+        let served_by = |i: usize| {
+            let mut req = http::Request::new(http::Method::Get, "http://example.com/api");
+            req.insert_header("x-request-id", i.to_string());
+            let runtime = runtime.clone();
+            async move {
+                let res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+                res.header("x-valor-plugin").unwrap().as_str().to_owned()
+            }
+        };
+        let mut sick = 0;
+        for i in 0..20 {
+            sick += (served_by(i).await == "sick") as usize;
+        }
+        assert!(sick > 0);
+
+        runtime.check_replicas(Duration::from_secs(1)).await;
+        for i in 0..20 {
+            assert_eq!(served_by(i).await, "well");
+        }
+        let mut res: http::Response = runtime.on_msg(request("/_health")).await.unwrap().into();
+        let health: serde_json::Value = res.body_json().await.unwrap();
+        let plugins = health["plugins"].as_array().unwrap();
+        let replica = |name| plugins.iter().find(|p| p["name"] == name).unwrap();
+        assert_eq!(replica("sick")["in_rotation"], false);
+        assert_eq!(replica("well")["in_rotation"], true);
+    }
+
+    #[test]
+    async fn health_degrades_with_non_critical_plugin() {
+        let mut sick: VluginDef = "sick".into();
+        sick.non_critical = true;
+        let runtime = Runtime::new(())
+            .with_health()
+            .unwrap()
+            .with_plugin(sick, Sick)
+            .unwrap();
+        let mut res: http::Response = runtime.on_msg(request("/_health")).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::Ok);
+        let report: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(report["status"], "degraded");
+    }
+
+    struct Chat(RefCell<String>);
+
+    #[async_trait(?Send)]
+    impl Vlugin for Rc<Chat> {
+        async fn on_msg(&self, _msg: Message) -> Result<Answer, crate::Error> {
+            Ok(http::Response::from("not upgraded").into())
+        }
+        fn accepts_upgrade(&self, protocol: &str) -> bool {
+            protocol == "websocket"
+        }
+        async fn on_upgrade(
+            &self,
+            req: http::Request,
+            mut conn: Box<dyn Upgraded>,
+        ) -> Result<(), crate::Error> {
+            use futures_lite::AsyncReadExt;
+            let mut msg = req.url().path().to_owned();
+            conn.read_to_string(&mut msg).await.unwrap();
+            self.0.replace(msg);
+            Ok(())
+        }
+        fn context(&self) -> &Context {
+            unreachable!()
+        }
+        fn context_mut(&mut self) -> &mut Context {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    async fn upgrade_to_websocket() {
+        let chat = Rc::new(Chat(RefCell::default()));
+        let runtime = Runtime::new(()).with_plugin("chat", chat.clone()).unwrap();
+        let mut req: http::Request = request("/_chat/room").into();
+        req.insert_header("upgrade", "websocket");
+        req.insert_header("connection", "Upgrade");
+        req.insert_header("sec-websocket-version", "13");
+        req.insert_header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==");
+
+        let res: http::Response = runtime.on_msg(req.clone().into()).await.unwrap().into();
+        assert_eq!(res.status(), http::StatusCode::SwitchingProtocols);
+        assert_eq!(res["sec-websocket-accept"], "s3pPLMBiTxaQ9kYGzzhZRrK+xOo=");
+        assert_eq!(res["x-valor-plugin"], "chat");
+
+        let conn = futures_lite::io::Cursor::new(b" hello".to_vec());
+        runtime.upgrade(req, Box::new(conn)).await.unwrap();
+        assert_eq!(*chat.0.borrow(), "/room hello");
+
+        // other requests to the route are messages as usual
+        let mut res: http::Response = runtime.on_msg(request("/_chat/room")).await.unwrap().into();
+        assert_eq!(res.body_string().await.unwrap(), "not upgraded");
+    }
+}
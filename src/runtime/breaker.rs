@@ -0,0 +1,131 @@
+use core::{cell::Cell, time::Duration};
+
+/// When the circuit breaker of a plugin trips, after `failures` consecutive
+/// failed requests(server errors or timeouts) the plugin is not called for
+/// the `open_for` period. Then a single probe request is let through, if it
+/// succeeds the circuit closes again otherwise it stays open for another period.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CircuitBreaker {
+    pub failures: u32,
+    pub open_for: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub(crate) enum Circuit {
+    Closed,
+    Open,
+    /// The open period is over and a probe request can be made
+    HalfOpen,
+}
+
+impl Circuit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Circuit::Closed => "closed",
+            Circuit::Open => "open",
+            Circuit::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Failures of a plugin, time is measured in unix milliseconds so the
+/// breaker never opens without `std`
+#[derive(Debug, Default)]
+pub(crate) struct Breaker {
+    failures: Cell<u32>,
+    opened_at: Cell<Option<u64>>,
+    probing: Cell<bool>,
+}
+
+impl Breaker {
+    pub fn circuit(&self, config: &CircuitBreaker, now: Option<u64>) -> Circuit {
+        match (self.opened_at.get(), now) {
+            (None, _) => Circuit::Closed,
+            (Some(at), Some(now)) if now.saturating_sub(at) >= ms(config.open_for) => {
+                Circuit::HalfOpen
+            }
+            _ => Circuit::Open,
+        }
+    }
+
+    /// Whether a request can be made, in the half open state the first request
+    /// is let through as a probe and the circuit is open while it's in flight.
+    /// If the probe never finishes another one is made after the open period.
+    pub fn allow(&self, config: &CircuitBreaker, now: Option<u64>) -> bool {
+        match self.circuit(config, now) {
+            Circuit::Closed => true,
+            Circuit::Open => false,
+            Circuit::HalfOpen => {
+                self.opened_at.set(now);
+                self.probing.set(true);
+                true
+            }
+        }
+    }
+
+    /// Time left until the circuit is half open
+    pub fn retry_after(&self, config: &CircuitBreaker, now: Option<u64>) -> Duration {
+        let elapsed = match (self.opened_at.get(), now) {
+            (Some(at), Some(now)) => now.saturating_sub(at),
+            _ => 0,
+        };
+        Duration::from_millis(ms(config.open_for).saturating_sub(elapsed))
+    }
+
+    pub fn record(&self, config: &CircuitBreaker, failed: bool, now: Option<u64>) {
+        if !failed {
+            self.failures.set(0);
+            self.opened_at.set(None);
+            self.probing.set(false);
+            return;
+        }
+        let failures = self.failures.get().saturating_add(1);
+        self.failures.set(failures);
+        if self.probing.replace(false) || failures >= config.failures {
+            self.opened_at.set(now);
+        }
+    }
+}
+
+fn ms(d: Duration) -> u64 {
+    d.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trip_and_recover() {
+        let config = CircuitBreaker {
+            failures: 2,
+            open_for: Duration::from_secs(10),
+        };
+        let breaker = Breaker::default();
+        let at = |s: u64| Some(s * 1000);
+
+        breaker.record(&config, true, at(0));
+        assert!(breaker.allow(&config, at(0)));
+        breaker.record(&config, true, at(1));
+        assert_eq!(breaker.circuit(&config, at(1)), Circuit::Open);
+        assert!(!breaker.allow(&config, at(5)));
+        assert_eq!(breaker.retry_after(&config, at(5)), Duration::from_secs(6));
+
+        // a single probe that fails opens the circuit again
+        assert_eq!(breaker.circuit(&config, at(11)), Circuit::HalfOpen);
+        assert!(breaker.allow(&config, at(11)));
+        assert!(!breaker.allow(&config, at(11)));
+        breaker.record(&config, true, at(12));
+        assert_eq!(breaker.circuit(&config, at(13)), Circuit::Open);
+
+        assert!(breaker.allow(&config, at(22)));
+        breaker.record(&config, false, at(22));
+        assert_eq!(breaker.circuit(&config, at(22)), Circuit::Closed);
+        assert!(breaker.allow(&config, at(22)));
+    }
+}
@@ -0,0 +1,108 @@
+use alloc::{rc::Rc, vec::Vec};
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Keeps count of the requests in flight to be able to wait for them
+/// to finish when the runtime is shutting down or a plugin is removed
+#[derive(Default)]
+pub(crate) struct Drain {
+    // the runtime is still loading what it needs to serve traffic
+    starting: Cell<bool>,
+    maintenance: Cell<bool>,
+    draining: Cell<bool>,
+    in_flight: Cell<usize>,
+    waiting: RefCell<Vec<Waker>>,
+}
+
+impl Drain {
+    pub fn is_draining(&self) -> bool {
+        self.draining.get()
+    }
+
+    pub fn is_starting(&self) -> bool {
+        self.starting.get()
+    }
+
+    pub fn set_starting(&self, starting: bool) {
+        self.starting.set(starting);
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.get()
+    }
+
+    pub fn set_maintenance(&self, maintenance: bool) {
+        self.maintenance.set(maintenance);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.get()
+    }
+
+    /// Marks a request as in flight until the returned guard is dropped
+    pub fn track(&self) -> InFlight<'_> {
+        self.in_flight.set(self.in_flight.get() + 1);
+        InFlight(self)
+    }
+
+    /// Like [`Self::track`] for tasks that go on in the background after their
+    /// request is answered
+    pub fn track_task(self: &Rc<Self>) -> InFlightTask {
+        self.in_flight.set(self.in_flight.get() + 1);
+        InFlightTask(self.clone())
+    }
+
+    fn done(&self) {
+        let in_flight = self.in_flight.get() - 1;
+        self.in_flight.set(in_flight);
+        if in_flight == 0 {
+            for waker in self.waiting.borrow_mut().drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Starts draining, the returned future resolves when there are
+    /// no more requests in flight
+    pub fn drained(&self) -> Drained<'_> {
+        self.draining.set(true);
+        Drained(self)
+    }
+}
+
+pub(crate) struct InFlight<'a>(&'a Drain);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+pub(crate) struct InFlightTask(Rc<Drain>);
+
+impl Drop for InFlightTask {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+pub(crate) struct Drained<'a>(&'a Drain);
+
+impl Future for Drained<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0.in_flight.get() == 0 {
+            return Poll::Ready(());
+        }
+        let mut waiting = self.0.waiting.borrow_mut();
+        if !waiting.iter().any(|w| w.will_wake(cx.waker())) {
+            waiting.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
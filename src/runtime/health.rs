@@ -0,0 +1,107 @@
+use super::{breaker::Circuit, drain::Drain, registry::PluginRegistry};
+use crate::{async_trait, http, Answer, Context, Error, Health, Message, Vlugin};
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+use core::cell::RefCell;
+use serde_json::json;
+
+/// Built-in plugin with the liveness and readiness probes of the runtime.
+///
+/// `/live` always answers `200` while the runtime can handle requests.
+/// `/ready`(also the root) checks the health of all the enabled plugins,
+/// the runtime is unhealthy(`503`) when any critical plugin is, if only
+/// non critical plugins fail it is reported as degraded. Plugins with an
+/// open circuit breaker are unhealthy without being checked. Variants of a
+/// route(replicas with a weight) keep the result of their check and show if
+/// they are `in_rotation`, requests skip the unhealthy ones.
+/// While the runtime starts, shuts down or is in maintenance it's reported
+/// as not ready(`503`).
+pub(crate) struct HealthHandler {
+    registry: Rc<RefCell<PluginRegistry>>,
+    drain: Rc<Drain>,
+}
+
+impl HealthHandler {
+    pub fn new(registry: Rc<RefCell<PluginRegistry>>, drain: Rc<Drain>) -> Self {
+        HealthHandler { registry, drain }
+    }
+}
+
+#[async_trait(?Send)]
+impl Vlugin for HealthHandler {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        use http::StatusCode;
+
+        let req = match msg {
+            Message::Http(req) => req,
+            Message::Ping => return Ok(Answer::Pong),
+        };
+        match req.url().path().trim_matches('/') {
+            "" | "ready" => {}
+            "live" => {
+                let mut res = http::Response::new(StatusCode::Ok);
+                res.set_body(http::Body::from_json(&json!({"status": "alive"}))?);
+                return Ok(res.into());
+            }
+            _ => return Ok(http::Response::new(StatusCode::NotFound).into()),
+        }
+
+        // the registry is not borrowed while the checks run
+        let plugins = self.registry.borrow().handlers();
+        let mut report = Vec::with_capacity(plugins.len());
+        let (mut degraded, mut failed) = (false, false);
+        for (plugin, handler) in plugins {
+            let circuit = self.registry.borrow().circuit(&plugin.name);
+            let health = match circuit {
+                Some(Circuit::Open) => Health::Unhealthy,
+                _ => {
+                    let health = handler.health().await;
+                    self.registry.borrow().set_probe(&plugin.name, health);
+                    health
+                }
+            };
+            if health != Health::Healthy {
+                degraded = true;
+                failed |= !plugin.non_critical;
+            }
+            let mut status = json!({
+                "name": plugin.name,
+                "status": health.as_str(),
+                "critical": !plugin.non_critical,
+            });
+            if let Some(circuit) = circuit {
+                status["circuit"] = circuit.as_str().into();
+            }
+            if let Some(in_rotation) = self.registry.borrow().is_in_rotation(&plugin.name) {
+                status["in_rotation"] = in_rotation.into();
+            }
+            report.push(status);
+        }
+
+        let (starting, draining) = (self.drain.is_starting(), self.drain.is_draining());
+        let maintenance = self.drain.in_maintenance();
+        let mut res = http::Response::new(if failed || starting || draining || maintenance {
+            StatusCode::ServiceUnavailable
+        } else {
+            StatusCode::Ok
+        });
+        let status = match (starting, draining, maintenance, degraded) {
+            (true, _, _, _) => "starting",
+            (_, true, _, _) => "not_ready",
+            (_, _, true, _) => "maintenance",
+            (_, _, _, true) => "degraded",
+            _ => "healthy",
+        };
+        res.set_body(http::Body::from_json(&json!({
+            "status": status,
+            "plugins": report,
+        }))?);
+        Ok(res.into())
+    }
+
+    fn context(&self) -> &Context {
+        unreachable!()
+    }
+    fn context_mut(&mut self) -> &mut Context {
+        unreachable!()
+    }
+}
@@ -0,0 +1,148 @@
+//! Plugins that hold their route but are loaded by the first request for it
+
+use super::{load_vlugin, problem, registry::PluginRegistry, Loader, VluginDef};
+use crate::{async_trait, http, Answer, Context, Error, Message, Vlugin};
+use alloc::{boxed::Box, format, rc::Rc, vec::Vec};
+use core::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    task::{self, Poll, Waker},
+};
+
+/// Stands in the registry for a plugin that is not loaded yet, the request that
+/// finds it loads the plugin and replaces it with the loaded one while requests
+/// that come meanwhile wait for that same load. A failed load is answered with
+/// `503 Service Unavailable` and the next request tries again.
+pub(crate) struct LazyVlugin<L> {
+    plugin: VluginDef,
+    loader: Rc<L>,
+    registry: Rc<RefCell<PluginRegistry>>,
+    state: RefCell<State>,
+    waiting: RefCell<Vec<Waker>>,
+}
+
+enum State {
+    Unloaded,
+    Loading,
+    Loaded(Rc<dyn Vlugin>),
+    Failed,
+}
+
+impl<L: Loader> LazyVlugin<L> {
+    pub fn new(plugin: VluginDef, loader: Rc<L>, registry: Rc<RefCell<PluginRegistry>>) -> Self {
+        LazyVlugin {
+            plugin,
+            loader,
+            registry,
+            state: RefCell::new(State::Unloaded),
+            waiting: RefCell::default(),
+        }
+    }
+
+    async fn load(&self) -> Option<Rc<dyn Vlugin>> {
+        loop {
+            let state = core::mem::replace(&mut *self.state.borrow_mut(), State::Loading);
+            match state {
+                State::Loaded(handler) => {
+                    *self.state.borrow_mut() = State::Loaded(handler.clone());
+                    return Some(handler);
+                }
+                State::Loading => {
+                    Loaded(self).await;
+                    match &*self.state.borrow() {
+                        State::Loaded(handler) => return Some(handler.clone()),
+                        State::Failed => return None,
+                        // the request that was loading it went away
+                        State::Unloaded | State::Loading => continue,
+                    }
+                }
+                State::Unloaded | State::Failed => break,
+            }
+        }
+
+        // a request that is dropped while loading lets the next one try
+        let mut guard = Guard(self, State::Unloaded);
+        let timeout = self.registry.borrow().load_timeout;
+        let (handler, load) = load_vlugin(&*self.loader, &self.plugin, timeout).await;
+        let handler = match handler {
+            Ok(handler) => {
+                let mut registry = self.registry.borrow_mut();
+                let name = &self.plugin.name;
+                match registry.replace(self.plugin.clone(), handler, load) {
+                    Ok(_) => registry.handler(name).map(|(_, handler)| handler),
+                    // e.g. it was unregistered while loading
+                    Err(err) => {
+                        let err = super::Error::from(err);
+                        log::warn!("[{}] loaded but couldn't be registered: {}", name, err);
+                        None
+                    }
+                }
+            }
+            // the error is listed with the plugin in the registry
+            Err(_) => {
+                self.registry
+                    .borrow_mut()
+                    .record_failure(self.plugin.clone(), load);
+                None
+            }
+        };
+        guard.1 = match &handler {
+            Some(handler) => State::Loaded(handler.clone()),
+            None => State::Failed,
+        };
+        handler
+    }
+}
+
+// Sets the state the load ended with and wakes the requests waiting for it
+struct Guard<'a, L>(&'a LazyVlugin<L>, State);
+
+impl<L> Drop for Guard<'_, L> {
+    fn drop(&mut self) {
+        *self.0.state.borrow_mut() = core::mem::replace(&mut self.1, State::Unloaded);
+        for waker in self.0.waiting.borrow_mut().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+// Resolves when the plugin is no longer loading
+struct Loaded<'a, L>(&'a LazyVlugin<L>);
+
+impl<L> Future for Loaded<'_, L> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<()> {
+        if !matches!(&*self.0.state.borrow(), State::Loading) {
+            return Poll::Ready(());
+        }
+        let mut waiting = self.0.waiting.borrow_mut();
+        if !waiting.iter().any(|w| w.will_wake(cx.waker())) {
+            waiting.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+#[async_trait(?Send)]
+impl<L: Loader> Vlugin for LazyVlugin<L> {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        match self.load().await {
+            Some(handler) => handler.on_msg(msg).await,
+            None => {
+                let detail = format!("{} couldn't be loaded", self.plugin.name);
+                let mut res = problem::response(http::StatusCode::ServiceUnavailable, detail);
+                res.insert_header(http::headers::RETRY_AFTER, "1");
+                Ok(res.into())
+            }
+        }
+    }
+
+    fn context(&self) -> &Context {
+        unreachable!()
+    }
+    fn context_mut(&mut self) -> &mut Context {
+        unreachable!()
+    }
+}
@@ -0,0 +1,221 @@
+use alloc::collections::VecDeque;
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Semaphore capping the requests plugins handle at the same time, the ones
+/// over the limit wait their turn in order while there's room in the queue
+pub(crate) struct Limiter {
+    max: Cell<usize>,
+    queue: Cell<usize>,
+    in_flight: Cell<usize>,
+    next_ticket: Cell<u64>,
+    waiting: RefCell<VecDeque<(u64, Option<Waker>)>>,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter {
+            max: Cell::new(usize::MAX),
+            queue: Cell::new(0),
+            in_flight: Cell::new(0),
+            next_ticket: Cell::new(0),
+            waiting: RefCell::default(),
+        }
+    }
+}
+
+impl Limiter {
+    pub fn set_max(&self, max: usize) {
+        self.max.set(max);
+        self.wake_next();
+    }
+
+    pub fn set_queue(&self, len: usize) {
+        self.queue.set(len);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.get()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.waiting.borrow().len()
+    }
+
+    /// Future resolving to the permit to handle a request, there's none when
+    /// the limit is reached and the queue is full
+    pub fn acquire(&self) -> Option<Acquire<'_>> {
+        let mut waiting = self.waiting.borrow_mut();
+        let state = if waiting.is_empty() && self.in_flight.get() < self.max.get() {
+            self.in_flight.set(self.in_flight.get() + 1);
+            State::Ready
+        } else if waiting.len() < self.queue.get() {
+            let ticket = self.next_ticket.get();
+            self.next_ticket.set(ticket + 1);
+            waiting.push_back((ticket, None));
+            State::Queued(ticket)
+        } else {
+            return None;
+        };
+        Some(Acquire {
+            limiter: self,
+            state,
+        })
+    }
+
+    // Only the first in the queue can take a free permit, when it does it wakes
+    // the next one in case there are more(e.g. the limit was raised)
+    fn wake_next(&self) {
+        if self.in_flight.get() >= self.max.get() {
+            return;
+        }
+        if let Some((_, Some(waker))) = self.waiting.borrow().front() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+pub(crate) struct Acquire<'a> {
+    limiter: &'a Limiter,
+    state: State,
+}
+
+enum State {
+    // the request is counted as in flight already
+    Ready,
+    Queued(u64),
+    Done,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Permit<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit<'a>> {
+        let this = self.get_mut();
+        let limiter = this.limiter;
+        let ticket = match this.state {
+            State::Ready => {
+                this.state = State::Done;
+                return Poll::Ready(Permit(limiter));
+            }
+            State::Queued(ticket) => ticket,
+            State::Done => panic!("permit acquired already"),
+        };
+        let mut waiting = limiter.waiting.borrow_mut();
+        let first = waiting.front().map(|(t, _)| *t) == Some(ticket);
+        if first && limiter.in_flight.get() < limiter.max.get() {
+            waiting.pop_front();
+            drop(waiting);
+            this.state = State::Done;
+            limiter.in_flight.set(limiter.in_flight.get() + 1);
+            limiter.wake_next();
+            return Poll::Ready(Permit(limiter));
+        }
+        if let Some((_, waker)) = waiting.iter_mut().find(|(t, _)| *t == ticket) {
+            *waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        match self.state {
+            State::Ready => drop(Permit(self.limiter)),
+            // a request that gave up waiting leaves the queue
+            State::Queued(ticket) => {
+                self.limiter
+                    .waiting
+                    .borrow_mut()
+                    .retain(|(t, _)| *t != ticket);
+                self.limiter.wake_next();
+            }
+            State::Done => {}
+        }
+    }
+}
+
+pub(crate) struct Permit<'a>(&'a Limiter);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.set(self.0.in_flight.get() - 1);
+        self.0.wake_next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use futures::{
+        executor::block_on,
+        task::{waker, ArcWake},
+        FutureExt,
+    };
+
+    #[test]
+    fn queue_over_the_limit() {
+        let limiter = Limiter::default();
+        limiter.set_max(1);
+        limiter.set_queue(1);
+
+        let first = block_on(limiter.acquire().unwrap());
+        let mut second = limiter.acquire().unwrap();
+        assert!(limiter.acquire().is_none());
+        assert_eq!((limiter.in_flight(), limiter.queued()), (1, 1));
+        assert!((&mut second).now_or_never().is_none());
+
+        drop(first);
+        let _second = block_on(second);
+        assert_eq!((limiter.in_flight(), limiter.queued()), (1, 0));
+
+        // giving up makes room in the queue
+        let waiting = limiter.acquire().unwrap();
+        assert!(limiter.acquire().is_none());
+        drop(waiting);
+        assert!(limiter.acquire().is_some());
+    }
+
+    struct Woken(AtomicBool);
+
+    impl ArcWake for Woken {
+        fn wake_by_ref(woken: &Arc<Self>) {
+            woken.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn wake_every_waiter_there_is_room_for() {
+        let limiter = Limiter::default();
+        limiter.set_max(1);
+        limiter.set_queue(2);
+
+        let _first = block_on(limiter.acquire().unwrap());
+        let mut queued = [limiter.acquire().unwrap(), limiter.acquire().unwrap()];
+        let woken = [
+            Arc::new(Woken(AtomicBool::new(false))),
+            Arc::new(Woken(AtomicBool::new(false))),
+        ];
+        for (acquire, woken) in queued.iter_mut().zip(&woken) {
+            let waker = waker(woken.clone());
+            let mut cx = Context::from_waker(&waker);
+            assert!(Pin::new(acquire).poll(&mut cx).is_pending());
+        }
+
+        limiter.set_max(3);
+        assert!(woken[0].0.load(Ordering::SeqCst));
+        assert!(!woken[1].0.load(Ordering::SeqCst));
+        let [second, third] = queued;
+        let _second = block_on(second);
+        // the first to take a permit passes the turn to the next
+        assert!(woken[1].0.load(Ordering::SeqCst));
+        let _third = block_on(third);
+        assert_eq!((limiter.in_flight(), limiter.queued()), (3, 0));
+    }
+}
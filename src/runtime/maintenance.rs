@@ -0,0 +1,116 @@
+//! Planned maintenance, while it lasts the runtime answers the requests for
+//! plugins with `503 Service Unavailable` and only its own endpoints work
+
+use super::problem;
+use crate::http;
+use alloc::{string::ToString, vec::Vec};
+use core::time::Duration;
+#[cfg(feature = "serde")]
+use {
+    super::drain::Drain,
+    crate::{async_trait, Answer, Context, Error, Message, Vlugin},
+    alloc::{boxed::Box, rc::Rc},
+    serde_json::json,
+};
+
+/// Response to the requests the runtime gets during maintenance, by default
+/// it's a plain text(or problem details) error
+#[derive(Debug, Clone)]
+pub struct MaintenancePage {
+    body: Vec<u8>,
+    content_type: http::Mime,
+    retry_after: Option<Duration>,
+}
+
+impl MaintenancePage {
+    /// Page with a body like an HTML document or a JSON object
+    pub fn new(body: impl Into<Vec<u8>>, content_type: http::Mime) -> Self {
+        MaintenancePage {
+            body: body.into(),
+            content_type,
+            retry_after: None,
+        }
+    }
+
+    /// Tells clients with `Retry-After` when the maintenance is expected to end
+    pub fn retry_after(mut self, after: Duration) -> Self {
+        self.retry_after = Some(after);
+        self
+    }
+}
+
+/// Maintenance response with the configured page or the default one
+pub(crate) fn response(page: Option<&MaintenancePage>) -> http::Response {
+    let page = match page {
+        Some(page) => page,
+        None => {
+            return problem::response(http::StatusCode::ServiceUnavailable, "Under maintenance")
+        }
+    };
+    let mut res = http::Response::new(http::StatusCode::ServiceUnavailable);
+    res.set_body(page.body.as_slice());
+    res.set_content_type(page.content_type.clone());
+    if let Some(after) = page.retry_after {
+        let secs = after.as_secs().max(1);
+        res.insert_header(http::headers::RETRY_AFTER, secs.to_string());
+    }
+    res
+}
+
+/// Built-in plugin to check(`GET`), start(`POST`) or end(`DELETE`) the
+/// maintenance of the runtime, changing it needs the token of the registry
+#[cfg(feature = "serde")]
+pub(crate) struct MaintenanceHandler {
+    drain: Rc<Drain>,
+    auth: Option<super::RegistryAuth>,
+}
+
+#[cfg(feature = "serde")]
+impl MaintenanceHandler {
+    pub fn new(drain: Rc<Drain>, auth: Option<super::RegistryAuth>) -> Self {
+        MaintenanceHandler { drain, auth }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[async_trait(?Send)]
+impl Vlugin for MaintenanceHandler {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        use http::{headers, Method, StatusCode};
+
+        let req = match msg {
+            Message::Http(req) => req,
+            Message::Ping => return Ok(Answer::Pong),
+        };
+        if !req.url().path().trim_matches('/').is_empty() {
+            return Ok(http::Response::new(StatusCode::NotFound).into());
+        }
+        if !self.auth.as_ref().map_or(true, |auth| auth.allows(&req)) {
+            let detail = "Missing or invalid registry token";
+            let mut res = problem::response(StatusCode::Unauthorized, detail);
+            res.insert_header(headers::WWW_AUTHENTICATE, "Bearer");
+            return Ok(res.into());
+        }
+        match req.method() {
+            Method::Get | Method::Head => {}
+            Method::Post => self.drain.set_maintenance(true),
+            Method::Delete => self.drain.set_maintenance(false),
+            method => {
+                let res = problem::method_not_allowed(method, "GET, HEAD, POST, DELETE");
+                return Ok(res.into());
+            }
+        }
+        let mut res = http::Response::new(StatusCode::Ok);
+        res.set_body(http::Body::from_json(
+            &json!({ "maintenance": self.drain.in_maintenance() }),
+        )?);
+        Ok(res.into())
+    }
+
+    fn context(&self) -> &Context {
+        unreachable!()
+    }
+    fn context_mut(&mut self) -> &mut Context {
+        unreachable!()
+    }
+}
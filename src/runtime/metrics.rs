@@ -0,0 +1,284 @@
+use super::limit::Limiter;
+use crate::{async_trait, http, Answer, Context, Error, Message, Vlugin};
+use alloc::{
+    borrow::ToOwned, boxed::Box, collections::BTreeMap, rc::Rc, string::String, sync::Arc,
+};
+use core::{
+    cell::RefCell,
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+// upper bounds in seconds of the buckets of the request duration histogram
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+// label of the requests that didn't get an answer from a plugin
+const NO_PLUGIN: &str = "none";
+
+/// Counters of the requests handled by the runtime
+#[derive(Default)]
+pub(crate) struct Metrics {
+    requests: RefCell<BTreeMap<(String, u16), u64>>,
+    durations: RefCell<BTreeMap<String, Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    /// Records the outcome of a request, the duration is only known with `std`
+    pub fn observe(&self, res: &Result<http::Response, Error>, duration: Option<Duration>) {
+        let (plugin, status) = match res {
+            Ok(res) => (
+                res.header("x-valor-plugin")
+                    .map_or(NO_PLUGIN, |p| p.as_str()),
+                res.status() as u16,
+            ),
+            Err(Error::Http(err)) => (NO_PLUGIN, err.status() as u16),
+            Err(_) => (NO_PLUGIN, 500),
+        };
+        *self
+            .requests
+            .borrow_mut()
+            .entry((plugin.to_owned(), status))
+            .or_default() += 1;
+
+        if let Some(duration) = duration {
+            let secs = duration.as_secs_f64();
+            let mut durations = self.durations.borrow_mut();
+            let histogram = durations.entry(plugin.to_owned()).or_default();
+            for (count, _) in histogram
+                .buckets
+                .iter_mut()
+                .zip(BUCKETS.iter())
+                .filter(|(_, le)| secs <= **le)
+            {
+                *count += 1;
+            }
+            histogram.sum += secs;
+            histogram.count += 1;
+        }
+    }
+
+    /// Metrics in the Prometheus text format
+    pub fn render(&self, limiter: &Limiter) -> String {
+        let mut out = String::new();
+        let requests = self.requests.borrow();
+
+        out.push_str("# HELP valor_requests_in_flight Requests plugins are handling.\n");
+        out.push_str("# TYPE valor_requests_in_flight gauge\n");
+        let _ = writeln!(out, "valor_requests_in_flight {}", limiter.in_flight());
+        out.push_str("# HELP valor_requests_queued Requests waiting for the concurrency limit.\n");
+        out.push_str("# TYPE valor_requests_queued gauge\n");
+        let _ = writeln!(out, "valor_requests_queued {}", limiter.queued());
+
+        out.push_str("# HELP valor_requests_total Requests handled by plugin and status.\n");
+        out.push_str("# TYPE valor_requests_total counter\n");
+        for ((plugin, status), count) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "valor_requests_total{{plugin=\"{}\",status=\"{}\"}} {}",
+                plugin, status, count
+            );
+        }
+
+        let mut classes = BTreeMap::<u16, u64>::new();
+        for ((_, status), count) in requests.iter() {
+            *classes.entry(status / 100).or_default() += count;
+        }
+        out.push_str("# HELP valor_responses_total Responses by status class.\n");
+        out.push_str("# TYPE valor_responses_total counter\n");
+        for (class, count) in classes {
+            let _ = writeln!(
+                out,
+                "valor_responses_total{{class=\"{}xx\"}} {}",
+                class, count
+            );
+        }
+
+        out.push_str("# HELP valor_request_duration_seconds Time plugins take to answer.\n");
+        out.push_str("# TYPE valor_request_duration_seconds histogram\n");
+        for (plugin, histogram) in self.durations.borrow().iter() {
+            let name = "valor_request_duration_seconds";
+            for (count, le) in histogram.buckets.iter().zip(BUCKETS.iter()) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{plugin=\"{}\",le=\"{}\"}} {}",
+                    name, plugin, le, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{plugin=\"{}\",le=\"+Inf\"}} {}",
+                name, plugin, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{{plugin=\"{}\"}} {}",
+                name, plugin, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{{plugin=\"{}\"}} {}",
+                name, plugin, histogram.count
+            );
+        }
+        out
+    }
+}
+
+/// Connections a server has open, added as a state of the runtime they are
+/// reported by the metrics. Clones count the same connections.
+///
+/// ```
+/// # use valor_core::runtime::{Connections, Runtime};
+/// let connections = Connections::default();
+/// let runtime = Runtime::new(()).with_state(connections.clone());
+///
+/// let conn = connections.track();
+/// assert_eq!(connections.open(), 1);
+/// drop(conn);
+/// assert_eq!(connections.open(), 0);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Connections(Arc<AtomicUsize>);
+
+impl Connections {
+    pub fn open(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Counts a connection as open until the guard is dropped
+    pub fn track(&self) -> OpenConnection {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self.0.clone())
+    }
+}
+
+/// Guard of a connection that is open
+#[derive(Debug)]
+pub struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn render_connections(out: &mut String, msg: &Message) {
+    use crate::RequestState;
+    let connections = match msg {
+        Message::Http(req) => match req.state::<Connections>() {
+            Some(connections) => connections,
+            None => return,
+        },
+        _ => return,
+    };
+    out.push_str("# HELP valor_connections_open Connections of clients that are open.\n");
+    out.push_str("# TYPE valor_connections_open gauge\n");
+    let _ = writeln!(out, "valor_connections_open {}", connections.open());
+}
+
+// Connections of the HTTP client plugins share when the runtime has one as a state
+#[cfg(all(feature = "proxy", not(target_arch = "wasm32")))]
+fn render_pool(out: &mut String, msg: &Message) {
+    use crate::RequestState;
+    let stats = match msg {
+        Message::Http(req) => match req.state::<crate::HttpPool>() {
+            Some(pool) => pool.stats(),
+            None => return,
+        },
+        _ => return,
+    };
+    out.push_str("# HELP valor_upstream_connections Connections of the shared HTTP client.\n");
+    out.push_str("# TYPE valor_upstream_connections gauge\n");
+    let _ = writeln!(
+        out,
+        "valor_upstream_connections{{state=\"active\"}} {}",
+        stats.active
+    );
+    let _ = writeln!(
+        out,
+        "valor_upstream_connections{{state=\"idle\"}} {}",
+        stats.idle
+    );
+    out.push_str("# HELP valor_upstream_checkouts_total Requests that took a connection of the shared HTTP client.\n");
+    out.push_str("# TYPE valor_upstream_checkouts_total counter\n");
+    let _ = writeln!(out, "valor_upstream_checkouts_total {}", stats.checkouts);
+    out.push_str("# HELP valor_upstream_connections_created_total Connections the shared HTTP client opened.\n");
+    out.push_str("# TYPE valor_upstream_connections_created_total counter\n");
+    let _ = writeln!(
+        out,
+        "valor_upstream_connections_created_total {}",
+        stats.created
+    );
+}
+
+#[cfg(not(all(feature = "proxy", not(target_arch = "wasm32"))))]
+fn render_pool(_out: &mut String, _msg: &Message) {}
+
+/// Built-in plugin exposing the metrics to be scraped by Prometheus
+pub(crate) struct MetricsHandler(pub Rc<Metrics>, pub Rc<Limiter>);
+
+#[async_trait(?Send)]
+impl Vlugin for MetricsHandler {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        let mut text = self.0.render(&self.1);
+        render_pool(&mut text, &msg);
+        render_connections(&mut text, &msg);
+        let mut res = http::Response::new(http::StatusCode::Ok);
+        res.set_body(text);
+        res.set_content_type("text/plain; version=0.0.4".parse::<http::Mime>()?);
+        Ok(res.into())
+    }
+
+    fn context(&self) -> &Context {
+        unreachable!()
+    }
+    fn context_mut(&mut self) -> &mut Context {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_prometheus_text() {
+        let metrics = Metrics::default();
+        let mut res = http::Response::new(http::StatusCode::Ok);
+        res.insert_header("x-valor-plugin", "foo");
+        metrics.observe(&Ok(res), Some(Duration::from_millis(20)));
+        let not_found = http::Error::from_str(http::StatusCode::NotFound, "");
+        metrics.observe(&Err(not_found.into()), None);
+
+        let text = metrics.render(&Limiter::default());
+        assert!(text.contains("valor_requests_in_flight 0\n"));
+        assert!(text.contains("valor_requests_total{plugin=\"foo\",status=\"200\"} 1\n"));
+        assert!(text.contains("valor_requests_total{plugin=\"none\",status=\"404\"} 1\n"));
+        assert!(text.contains("valor_responses_total{class=\"4xx\"} 1\n"));
+        assert!(text.contains("_bucket{plugin=\"foo\",le=\"0.01\"} 0\n"));
+        assert!(text.contains("_bucket{plugin=\"foo\",le=\"0.025\"} 1\n"));
+        assert!(text.contains("_bucket{plugin=\"foo\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("valor_request_duration_seconds_count{plugin=\"foo\"} 1\n"));
+    }
+
+    #[test]
+    fn render_open_connections() {
+        let connections = Connections::default();
+        let _open = (connections.track(), connections.track());
+        let mut req = http::Request::new(http::Method::Get, "http://example.com/_metrics");
+        req.set_ext(crate::state::State(Arc::new(connections.clone())));
+
+        let mut text = String::new();
+        render_connections(&mut text, &Message::Http(req));
+        assert!(text.contains("valor_connections_open 2\n"));
+    }
+}
@@ -0,0 +1,178 @@
+#[cfg(feature = "auth")]
+mod basic_auth;
+mod cache;
+mod cors;
+mod etag;
+#[cfg(feature = "jwt")]
+mod jwt;
+mod security;
+
+#[cfg(feature = "auth")]
+pub use basic_auth::{BasicAuth, User};
+pub use cache::{Cache, CachePurge};
+pub use cors::Cors;
+pub use etag::ETag;
+#[cfg(feature = "jwt")]
+pub use jwt::{Claims, Jwt};
+pub use security::SecurityHeaders;
+
+use super::BoxedFuture;
+use crate::{
+    async_trait,
+    http::{Request, Response},
+    Error,
+};
+use alloc::{borrow::ToOwned, boxed::Box, rc::Rc, string::String, vec::Vec};
+
+/// Middlewares take care of cross-cutting concerns like authentication or
+/// logging, they wrap the dispatching of requests to plugins being able to
+/// modify the request and the response or answer right away without
+/// calling the `next` one.
+///
+/// Data meant for the plugin or the middlewares after, like the authenticated
+/// user, travels with the request as a typed extension set with
+/// `req.set_ext(value)` that is read with `req.ext::<T>()`, there's one value
+/// per type. The runtime passes its own the same way, e.g. the [`Params`] of
+/// the route.
+///
+/// [`Params`]: super::Params
+///
+/// ```
+/// # use valor_core::*;
+/// # use runtime::{Middleware, Next, Runtime};
+/// struct Cors;
+///
+/// #[async_trait(?Send)]
+/// impl Middleware for Cors {
+///     async fn handle(&self, req: http::Request, next: Next<'_>) -> Result<http::Response, Error> {
+///         let mut res = next.run(req).await?;
+///         res.insert_header("access-control-allow-origin", "*");
+///         Ok(res)
+///     }
+/// }
+///
+/// let runtime = Runtime::new(()).with_middleware(Cors);
+/// ```
+#[async_trait(?Send)]
+pub trait Middleware: 'static {
+    async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response, Error>;
+}
+
+type Endpoint<'a> = dyn Fn(Request) -> BoxedFuture<'a, Result<Response, Error>> + 'a;
+
+/// The rest of the middleware chain that ends with the plugin matching the request
+pub struct Next<'a> {
+    middlewares: &'a [Rc<dyn Middleware>],
+    endpoint: &'a Endpoint<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middlewares: &'a [Rc<dyn Middleware>], endpoint: &'a Endpoint<'a>) -> Self {
+        Next {
+            middlewares,
+            endpoint,
+        }
+    }
+
+    /// Passes the request down the chain
+    pub async fn run(self, req: Request) -> Result<Response, Error> {
+        match self.middlewares.split_first() {
+            Some((current, middlewares)) => {
+                let next = Next {
+                    middlewares,
+                    endpoint: self.endpoint,
+                };
+                current.handle(req, next).await
+            }
+            None => (self.endpoint)(req).await,
+        }
+    }
+}
+
+/// Path prefixes a middleware is limited to, without any it covers every path.
+/// A prefix covers its own path and the ones under it, `/_api` covers
+/// `/_api/orders` but not `/_apis`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prefixes(Vec<String>);
+
+impl Prefixes {
+    pub fn push(&mut self, prefix: &str) {
+        self.0.push(prefix.trim_end_matches('/').to_owned());
+    }
+
+    pub fn covers(&self, path: &str) -> bool {
+        self.0.is_empty() || self.0.iter().any(|prefix| under_prefix(path, prefix))
+    }
+}
+
+// Whether the path is the one of the prefix or one under it
+pub(crate) fn under_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix.trim_end_matches('/'))
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{h, http, runtime::Runtime};
+    use async_std::test;
+
+    #[derive(Debug, PartialEq)]
+    struct Trace(&'static str);
+
+    struct Tracer;
+
+    #[async_trait(?Send)]
+    impl Middleware for Tracer {
+        async fn handle(&self, mut req: Request, next: Next<'_>) -> Result<Response, Error> {
+            req.set_ext(Trace("span-1"));
+            next.run(req).await
+        }
+    }
+
+    struct Check;
+
+    #[async_trait(?Send)]
+    impl Middleware for Check {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response, Error> {
+            assert_eq!(req.ext::<Trace>(), Some(&Trace("span-1")));
+            next.run(req).await
+        }
+    }
+
+    #[test]
+    async fn pass_extensions_to_the_plugin() {
+        let runtime = Runtime::new(())
+            .with_middleware(Tracer)
+            .with_middleware(Check)
+            .with_plugin(
+                "foo",
+                h(|req: http::Request, _| async move {
+                    let trace = req.ext::<Trace>().map_or("", |t| t.0);
+                    Ok(http::Response::from(trace))
+                }),
+            )
+            .unwrap();
+        let mut req = http::Request::new(http::Method::Get, "http://example.com/_foo");
+        req.insert_header("x-request-id", "123");
+        let mut res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.body_string().await.unwrap(), "span-1");
+    }
+
+    #[test]
+    async fn prefixes_cover_paths_under_them() {
+        let mut prefixes = Prefixes::default();
+        assert!(prefixes.covers("/_anything"));
+
+        prefixes.push("/_api/");
+        prefixes.push("/_admin");
+        for path in &["/_api", "/_api/", "/_api/orders/1", "/_admin/users"] {
+            assert!(prefixes.covers(path), "{}", path);
+        }
+        for path in &["/", "/_apis", "/_administrator", "/_public/_api"] {
+            assert!(!prefixes.covers(path), "{}", path);
+        }
+        assert!(under_prefix("/_api/orders", "/_api/"));
+        assert!(!under_prefix("/_apiary", "/_api/"));
+    }
+}
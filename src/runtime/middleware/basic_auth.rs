@@ -0,0 +1,233 @@
+use super::{Middleware, Next, Prefixes};
+use crate::{
+    async_trait,
+    http::{headers, Request, Response, StatusCode},
+    runtime::problem,
+    Error,
+};
+use alloc::{borrow::ToOwned, boxed::Box, format, string::String, vec::Vec};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+
+/// Middleware that lets through only the requests of known users, others are
+/// challenged with `401 Unauthorized`. Passwords are stored as bcrypt(`$2b$...`)
+/// or argon2(`$argon2id$...`) hashes, verifying them is slow on purpose so
+/// hashes with a lower cost keep requests responsive.
+///
+/// ```
+/// # use valor_core::*;
+/// # use runtime::{BasicAuth, Runtime};
+/// let auth = BasicAuth::new("admin area")
+///     .user("admin", "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW")
+///     .prefix("/_registry")
+///     .attach_user();
+/// let runtime = Runtime::new(()).with_middleware(auth);
+/// ```
+pub struct BasicAuth {
+    realm: String,
+    // name and password hash
+    users: Vec<(String, String)>,
+    prefixes: Prefixes,
+    attach_user: bool,
+}
+
+/// Name of the user that made the request, added by [`BasicAuth`] as an
+/// extension of the request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User(pub String);
+
+impl BasicAuth {
+    pub fn new(realm: impl Into<String>) -> Self {
+        BasicAuth {
+            realm: realm.into(),
+            users: Vec::new(),
+            prefixes: Prefixes::default(),
+            attach_user: false,
+        }
+    }
+
+    pub fn user(mut self, name: impl Into<String>, password_hash: impl Into<String>) -> Self {
+        self.users.push((name.into(), password_hash.into()));
+        self
+    }
+
+    /// Only requests for paths under the prefix need credentials, without
+    /// prefixes all requests do
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefixes.push(&prefix.into());
+        self
+    }
+
+    /// Requests of authenticated users get their [`User`] extension
+    pub fn attach_user(mut self) -> Self {
+        self.attach_user = true;
+        self
+    }
+
+    // Name of the user the `Authorization` header has valid credentials of
+    fn authenticate(&self, authorization: &str) -> Option<&str> {
+        let (scheme, credentials) = authorization.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let credentials = base64::decode(credentials.trim()).ok()?;
+        let credentials = String::from_utf8(credentials).ok()?;
+        let (name, password) = credentials.split_once(':')?;
+
+        // all names are compared so the time doesn't tell which exist
+        let mut found = None;
+        for (user, hash) in &self.users {
+            if crate::runtime::constant_time_eq(user.as_bytes(), name.as_bytes()) && found.is_none()
+            {
+                found = Some((user, hash));
+            }
+        }
+        match found {
+            Some((user, hash)) => verify(password, hash).then(|| user.as_str()),
+            None => {
+                // unknown users take as long as a wrong password
+                if let Some((_, hash)) = self.users.first() {
+                    verify(password, hash);
+                }
+                None
+            }
+        }
+    }
+
+    fn challenge(&self) -> Response {
+        let mut res = problem::response(StatusCode::Unauthorized, "Invalid credentials");
+        let realm = self.realm.replace('"', "'");
+        res.insert_header(
+            headers::WWW_AUTHENTICATE,
+            format!("Basic realm=\"{}\"", realm),
+        );
+        res
+    }
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).map_or(false, |hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+#[async_trait(?Send)]
+impl Middleware for BasicAuth {
+    async fn handle(&self, mut req: Request, next: Next<'_>) -> Result<Response, Error> {
+        if !self.prefixes.covers(req.url().path()) {
+            return next.run(req).await;
+        }
+        let user = req
+            .header(headers::AUTHORIZATION)
+            .and_then(|auth| self.authenticate(auth.as_str()))
+            .map(ToOwned::to_owned);
+        match user {
+            Some(user) => {
+                if self.attach_user {
+                    req.set_ext(User(user));
+                }
+                next.run(req).await
+            }
+            None => Ok(self.challenge()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{h, http, runtime::Runtime};
+    use async_std::test;
+
+    fn request(path: &str, credentials: Option<&str>) -> http::Request {
+        let url = "http://example.com".to_owned() + path;
+        let mut req = http::Request::new(http::Method::Get, url.as_str());
+        req.insert_header("x-request-id", "123");
+        if let Some(credentials) = credentials {
+            req.insert_header(
+                "authorization",
+                format!("Basic {}", base64::encode(credentials)),
+            );
+        }
+        req
+    }
+
+    #[test]
+    async fn challenge_unknown_users() {
+        let runtime = Runtime::new(())
+            .with_middleware(
+                BasicAuth::new("admin")
+                    .user("ann", bcrypt::hash("secret", 4).unwrap())
+                    .user(
+                        "bob",
+                        "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+                    )
+                    .prefix("/_private/")
+                    .attach_user(),
+            )
+            .with_plugin(
+                "private",
+                h(|req: http::Request, _| async move {
+                    let user = req.ext::<User>().map(|u| u.0.as_str()).unwrap_or("");
+                    Ok(http::Response::from(user))
+                }),
+            )
+            .unwrap()
+            .with_plugin("public", ())
+            .unwrap();
+        let runtime = &runtime;
+        let answer = |req: http::Request| async move {
+            let res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+            res
+        };
+
+        let res = answer(request("/_private/page", None)).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert_eq!(res["www-authenticate"], "Basic realm=\"admin\"");
+        for wrong in &["ann:wrong", "eve:secret", "ann", "bob:secret"] {
+            let res = answer(request("/_private", Some(wrong))).await;
+            assert_eq!(res.status(), StatusCode::Unauthorized, "{}", wrong);
+        }
+
+        let mut res = answer(request("/_private/page", Some("ann:secret"))).await;
+        assert_eq!(res.body_string().await.unwrap(), "ann");
+        let mut res = answer(request("/_private", Some("bob:U*U"))).await;
+        assert_eq!(res.body_string().await.unwrap(), "bob");
+
+        // other routes are not protected
+        let res = answer(request("/_public", None)).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res = runtime.on_msg(request("/_privateer", None).into()).await;
+        assert!(matches!(res, Err(Error::Http(e)) if e.status() == StatusCode::NotFound));
+    }
+
+    #[test]
+    async fn only_the_basic_scheme() {
+        let auth = BasicAuth::new("admin").user("ann", bcrypt::hash("secret", 4).unwrap());
+        let runtime = Runtime::new(())
+            .with_middleware(auth)
+            .with_plugin("foo", ())
+            .unwrap();
+        let status = |authorization: String| {
+            let mut req = request("/_foo", None);
+            req.insert_header("authorization", authorization);
+            let runtime = &runtime;
+            async move { runtime.handle(req).await.status() }
+        };
+        let ann = base64::encode("ann:secret");
+        assert_eq!(status(format!("basic {}", ann)).await, StatusCode::Ok);
+        assert_eq!(
+            status(format!("Bearer {}", ann)).await,
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            status("Basic not-base64".into()).await,
+            StatusCode::Unauthorized
+        );
+    }
+}
@@ -0,0 +1,508 @@
+use super::{under_prefix, Middleware, Next};
+use crate::{
+    async_trait,
+    http::{self, headers, Method, Request, Response, StatusCode},
+    runtime::{problem, time},
+    Answer, Context, Error, Message, Vlugin,
+};
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    collections::BTreeMap,
+    format,
+    rc::Rc,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{cell::RefCell, time::Duration};
+
+const CACHE_HEADER: &str = "x-cache";
+
+/// Middleware that keeps in memory the `200 OK` answers to `GET` requests for
+/// a while and serves them without reaching the plugin, responses are told
+/// apart by their URL and the request headers listed in their `Vary`.
+/// `Cache-Control: no-store` and `no-cache` of requests and responses are
+/// honored, responses that set cookies, have no known length(e.g. streams)
+/// or are bigger than the maximum entry size are not cached, neither are
+/// requests with credentials or cookies. Responses get the
+/// `X-Cache: HIT` or `MISS` header and cached ones their `Age`.
+/// It needs the `std` feature to know the time, without it nothing is cached.
+///
+/// ```
+/// # use valor_core::*;
+/// # use core::time::Duration;
+/// # use runtime::{Cache, Runtime, VluginDef};
+/// let cache = Cache::new(Duration::from_secs(60))
+///     .capacity(500)
+///     .max_entry_size(64 * 1024)
+///     .route("/_news", Duration::from_secs(5))
+///     .route("/_news/live", Duration::from_secs(0));
+/// // `DELETE /_cache` purges everything and `DELETE /_cache/_news` one path
+/// let purge = VluginDef {
+///     methods: vec![http::Method::Delete],
+///     ..VluginDef::from("cache")
+/// };
+/// let runtime = Runtime::new(())
+///     .with_middleware(cache.clone())
+///     .with_plugin(purge, cache.purge_handler())?;
+/// # Ok::<(), runtime::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct Cache {
+    ttl: Duration,
+    // path prefix and its time to live
+    routes: Vec<(String, Duration)>,
+    capacity: usize,
+    max_entry_size: usize,
+    store: Rc<RefCell<Store>>,
+}
+
+#[derive(Default)]
+struct Store {
+    // variants of the responses by method and url
+    entries: BTreeMap<String, Vec<Entry>>,
+    len: usize,
+    // increases with every use to know the least recently used entry
+    clock: u64,
+    // keys of the entries by their last use, the first is the next evicted
+    lru: BTreeMap<u64, String>,
+}
+
+struct Entry {
+    path: String,
+    // request headers named by `Vary` and their values
+    vary: Vec<(String, Option<String>)>,
+    headers: Vec<(headers::HeaderName, String)>,
+    body: Vec<u8>,
+    stored_ms: u64,
+    expires_ms: u64,
+    used: u64,
+}
+
+impl Cache {
+    /// Caches responses for the `ttl`, up to a thousand of them and of 1MiB
+    /// at most by default
+    pub fn new(ttl: Duration) -> Self {
+        Cache {
+            ttl,
+            routes: Vec::new(),
+            capacity: 1000,
+            max_entry_size: 1024 * 1024,
+            store: Rc::default(),
+        }
+    }
+
+    /// Responses kept at most, the least recently used one makes room for new ones
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Bytes of the body of the biggest response that is cached
+    pub fn max_entry_size(mut self, size: usize) -> Self {
+        self.max_entry_size = size;
+        self
+    }
+
+    /// Time to live of the responses for paths under the prefix, the longest
+    /// matching prefix wins and a zero `ttl` disables caching of the route
+    pub fn route(mut self, prefix: impl Into<String>, ttl: Duration) -> Self {
+        let prefix = prefix.into();
+        self.routes
+            .push((prefix.trim_end_matches('/').to_owned(), ttl));
+        self
+    }
+
+    /// Plugin that purges the cache when requested with `DELETE`, the
+    /// path it's given selects the cached responses of that path only
+    pub fn purge_handler(&self) -> CachePurge {
+        CachePurge(self.store.clone())
+    }
+
+    fn ttl_of(&self, path: &str) -> Duration {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| under_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.ttl, |(_, ttl)| *ttl)
+    }
+
+    fn lookup(&self, key: &str, req: &Request, now: u64) -> Option<Response> {
+        let mut store = self.store.borrow_mut();
+        let store = &mut *store;
+        store.remove(key, |e| e.expires_ms <= now);
+        store.clock += 1;
+        let clock = store.clock;
+        let entry = store
+            .entries
+            .get_mut(key)?
+            .iter_mut()
+            .find(|e| e.matches(req))?;
+        store.lru.remove(&entry.used);
+        store.lru.insert(clock, key.to_owned());
+        entry.used = clock;
+        Some(entry.response(now))
+    }
+
+    fn store(&self, key: String, entry: Entry) {
+        let mut store = self.store.borrow_mut();
+        let store = &mut *store;
+        store.remove(&key, |e| e.vary == entry.vary);
+        store.clock += 1;
+        let entry = Entry {
+            used: store.clock,
+            ..entry
+        };
+        store.lru.insert(entry.used, key.clone());
+        store.entries.entry(key).or_default().push(entry);
+        store.len += 1;
+        while store.len > self.capacity {
+            store.evict_oldest();
+        }
+    }
+}
+
+impl Store {
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .lru
+            .iter()
+            .next()
+            .map(|(used, key)| (*used, key.clone()));
+        if let Some((used, key)) = oldest {
+            self.remove(&key, |e| e.used == used);
+        }
+    }
+
+    // removes the entries of the key the predicate selects
+    fn remove(&mut self, key: &str, remove: impl Fn(&Entry) -> bool) {
+        let lru = &mut self.lru;
+        if let Some(variants) = self.entries.get_mut(key) {
+            let before = variants.len();
+            variants.retain(|e| {
+                let removed = remove(e);
+                if removed {
+                    lru.remove(&e.used);
+                }
+                !removed
+            });
+            self.len -= before - variants.len();
+            if variants.is_empty() {
+                self.entries.remove(key);
+            }
+        }
+    }
+
+    fn purge(&mut self, path: Option<&str>) -> usize {
+        let before = self.len;
+        match path {
+            Some(path) => {
+                let keys = self
+                    .entries
+                    .iter()
+                    .filter(|(_, variants)| variants.iter().any(|e| e.path == path))
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>();
+                for key in keys {
+                    self.remove(&key, |e| e.path == path);
+                }
+            }
+            None => {
+                self.entries.clear();
+                self.lru.clear();
+                self.len = 0;
+            }
+        }
+        before - self.len
+    }
+}
+
+impl Entry {
+    fn matches(&self, req: &Request) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| header_value(req.header(name.as_str())) == *value)
+    }
+
+    fn response(&self, now: u64) -> Response {
+        let mut res = Response::new(StatusCode::Ok);
+        for (name, value) in &self.headers {
+            res.append_header(name.clone(), value.as_str());
+        }
+        res.set_body(self.body.clone());
+        let age = now.saturating_sub(self.stored_ms) / 1000;
+        res.insert_header(headers::AGE, age.to_string());
+        res.insert_header(CACHE_HEADER, "HIT");
+        res
+    }
+}
+
+fn header_value(values: Option<&headers::HeaderValues>) -> Option<String> {
+    values.map(|values| {
+        values
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+// Whether the `Cache-Control` header has one of the directives
+fn cache_control_has(values: Option<&headers::HeaderValues>, directives: &[&str]) -> bool {
+    values.map_or(false, |values| {
+        values
+            .iter()
+            .flat_map(|v| v.as_str().split(','))
+            .map(|d| d.trim().split('=').next().unwrap_or(""))
+            .any(|d| directives.iter().any(|x| d.eq_ignore_ascii_case(x)))
+    })
+}
+
+#[async_trait(?Send)]
+impl Middleware for Cache {
+    async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response, Error> {
+        let ttl = self.ttl_of(req.url().path());
+        let now = match time::unix_ms() {
+            Some(now) if req.method() == Method::Get && ttl > Duration::ZERO => now,
+            _ => return next.run(req).await,
+        };
+        let cache_control = req.header(headers::CACHE_CONTROL);
+        // answers to requests with credentials or cookies are likely personal
+        if cache_control_has(cache_control, &["no-store"])
+            || req.header(headers::AUTHORIZATION).is_some()
+            || req.header(headers::COOKIE).is_some()
+        {
+            return next.run(req).await;
+        }
+        let key = format!("{} {}", req.method(), req.url());
+        if !cache_control_has(cache_control, &["no-cache"]) {
+            if let Some(res) = self.lookup(&key, &req, now) {
+                return Ok(res);
+            }
+        }
+
+        let path = req.url().path().to_owned();
+        // the request is gone once handled and `Vary` can name any header
+        let req_headers = req
+            .iter()
+            .map(|(name, values)| (name.as_str().to_owned(), header_value(Some(values))))
+            .collect::<Vec<_>>();
+
+        let mut res = next.run(req).await?;
+        let cacheable = res.status() == StatusCode::Ok
+            && res.len().map_or(false, |len| len <= self.max_entry_size)
+            && res.header(headers::SET_COOKIE).is_none()
+            && !cache_control_has(
+                res.header(headers::CACHE_CONTROL),
+                &["no-store", "no-cache", "private"],
+            );
+        let vary = header_value(res.header(headers::VARY)).unwrap_or_default();
+        if !cacheable || vary.trim() == "*" {
+            res.insert_header(CACHE_HEADER, "MISS");
+            return Ok(res);
+        }
+        let vary = vary
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let value = req_headers
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(&name))
+                    .and_then(|(_, v)| v.clone());
+                (name, value)
+            })
+            .collect();
+
+        let body = res.take_body().into_bytes().await?;
+        let headers = res
+            .iter()
+            .flat_map(|(name, values)| {
+                values
+                    .iter()
+                    .map(move |v| (name.clone(), v.as_str().to_owned()))
+            })
+            .collect();
+        res.set_body(body.clone());
+        let ttl_ms = ttl.as_millis() as u64;
+        self.store(
+            key,
+            Entry {
+                path,
+                vary,
+                headers,
+                body,
+                stored_ms: now,
+                expires_ms: now.saturating_add(ttl_ms),
+                used: 0,
+            },
+        );
+        res.insert_header(CACHE_HEADER, "MISS");
+        Ok(res)
+    }
+}
+
+/// Built-in plugin purging responses of a [`Cache`]
+pub struct CachePurge(Rc<RefCell<Store>>);
+
+#[async_trait(?Send)]
+impl Vlugin for CachePurge {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        let req = match msg {
+            Message::Http(req) => req,
+            _ => return Err(Error::NotSupported),
+        };
+        if req.method() != Method::Delete {
+            let detail = format!("{} is not allowed", req.method());
+            return Ok(problem::response(StatusCode::MethodNotAllowed, detail).into());
+        }
+        let path = req.url().path();
+        let path = (path != "/" && !path.is_empty()).then(|| path);
+        let purged = self.0.borrow_mut().purge(path);
+        let mut res = http::Response::new(StatusCode::NoContent);
+        res.insert_header("x-purged", purged.to_string());
+        Ok(res.into())
+    }
+
+    fn context(&self) -> &Context {
+        unreachable!()
+    }
+    fn context_mut(&mut self) -> &mut Context {
+        unreachable!()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{h, runtime::Runtime, VluginDef};
+    use async_std::test;
+    use core::cell::Cell;
+
+    fn request(path: &str) -> Request {
+        let url = "http://example.com".to_owned() + path;
+        let mut req = Request::new(Method::Get, url.as_str());
+        req.insert_header("x-request-id", "123");
+        req
+    }
+
+    // counts the times the plugin is reached
+    fn counter(cache: Cache) -> (Runtime<()>, Rc<Cell<u32>>) {
+        let calls = Rc::new(Cell::new(0));
+        let count = calls.clone();
+        let purge = VluginDef {
+            methods: vec![Method::Delete],
+            ..VluginDef::from("cache")
+        };
+        let runtime = Runtime::new(())
+            .with_middleware(cache.clone())
+            .with_plugin(purge, cache.purge_handler())
+            .unwrap()
+            .with_plugin(
+                "count",
+                h(move |req: Request, _| {
+                    count.set(count.get() + 1);
+                    let calls = count.get();
+                    async move {
+                        let mut res = Response::new(StatusCode::Ok);
+                        if req.url().path().starts_with("/lang") {
+                            res.insert_header("vary", "Accept-Language");
+                        }
+                        if req.url().path() == "/private" {
+                            res.insert_header("cache-control", "private");
+                        }
+                        res.set_body(calls.to_string());
+                        Ok(res)
+                    }
+                }),
+            )
+            .unwrap();
+        (runtime, calls)
+    }
+
+    async fn answer(runtime: &Runtime<()>, req: Request) -> Response {
+        runtime.on_msg(req.into()).await.unwrap().into()
+    }
+
+    #[test]
+    async fn serve_cached_responses() {
+        let (runtime, calls) = counter(Cache::new(Duration::from_secs(60)));
+
+        let mut res = answer(&runtime, request("/_count/a")).await;
+        assert_eq!(res[CACHE_HEADER], "MISS");
+        assert_eq!(res.body_string().await.unwrap(), "1");
+        let mut res = answer(&runtime, request("/_count/a")).await;
+        assert_eq!(res[CACHE_HEADER], "HIT");
+        assert_eq!(res["age"], "0");
+        assert_eq!(res["x-valor-plugin"], "count");
+        assert_eq!(res.body_string().await.unwrap(), "1");
+        assert_eq!(calls.get(), 1);
+
+        let mut req = request("/_count/a");
+        req.insert_header("cache-control", "no-cache");
+        answer(&runtime, req).await;
+        assert_eq!(calls.get(), 2);
+        answer(&runtime, request("/_count/private")).await;
+        answer(&runtime, request("/_count/private")).await;
+        assert_eq!(calls.get(), 4);
+
+        let lang = |lang: &str| {
+            let mut req = request("/_count/lang");
+            req.insert_header("accept-language", lang);
+            req
+        };
+        answer(&runtime, lang("en")).await;
+        answer(&runtime, lang("es")).await;
+        let mut res = answer(&runtime, lang("en")).await;
+        assert_eq!(res.body_string().await.unwrap(), "5");
+        assert_eq!(calls.get(), 6);
+
+        let mut purge = request("/_cache/_count/a");
+        purge.set_method(Method::Delete);
+        let res = answer(&runtime, purge).await;
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(res["x-purged"], "1");
+        answer(&runtime, request("/_count/a")).await;
+        assert_eq!(calls.get(), 7);
+    }
+
+    #[test]
+    async fn evict_least_recently_used() {
+        let cache = Cache::new(Duration::from_secs(60))
+            .capacity(2)
+            .route("/_count/fresh", Duration::from_secs(0));
+        let (runtime, calls) = counter(cache);
+        answer(&runtime, request("/_count/a")).await;
+        answer(&runtime, request("/_count/b")).await;
+        answer(&runtime, request("/_count/a")).await;
+        answer(&runtime, request("/_count/c")).await;
+        assert_eq!(calls.get(), 3);
+        // `b` was the one used the longest ago
+        answer(&runtime, request("/_count/a")).await;
+        answer(&runtime, request("/_count/b")).await;
+        assert_eq!(calls.get(), 4);
+
+        answer(&runtime, request("/_count/fresh")).await;
+        let res = answer(&runtime, request("/_count/fresh")).await;
+        assert!(res.header(CACHE_HEADER).is_none());
+        assert_eq!(calls.get(), 6);
+    }
+
+    #[test]
+    async fn skip_personal_and_big_responses() {
+        let (runtime, calls) = counter(Cache::new(Duration::from_secs(60)));
+        for _ in 0..2 {
+            let mut req = request("/_count/a");
+            req.insert_header("cookie", "session=123");
+            let res = answer(&runtime, req).await;
+            assert!(res.header(CACHE_HEADER).is_none());
+        }
+        assert_eq!(calls.get(), 2);
+
+        let (runtime, calls) = counter(Cache::new(Duration::from_secs(60)).max_entry_size(0));
+        answer(&runtime, request("/_count/a")).await;
+        let res = answer(&runtime, request("/_count/a")).await;
+        assert_eq!(res[CACHE_HEADER], "MISS");
+        assert_eq!(calls.get(), 2);
+    }
+}
@@ -0,0 +1,230 @@
+use super::{Middleware, Next};
+use crate::{
+    async_trait,
+    http::{Method, Request, Response, StatusCode},
+    Error,
+};
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+/// Middleware that handles Cross-Origin Resource Sharing, preflight requests are
+/// answered right away and actual requests get the allowed origin in the response.
+/// Requests from origins that are not allowed get no CORS headers.
+///
+/// ```
+/// # use valor_core::*;
+/// # use runtime::{Cors, Runtime};
+/// let cors = Cors::new()
+///     .allow_origin("https://example.com")
+///     .allow_headers(["content-type"])
+///     .allow_credentials(true)
+///     .max_age(3600);
+/// let runtime = Runtime::new(()).with_middleware(cors);
+/// ```
+pub struct Cors {
+    // any origin is allowed when empty
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl Cors {
+    /// Allows any origin to use the common methods without credentials
+    pub fn new() -> Self {
+        use Method::*;
+        Cors {
+            origins: Vec::new(),
+            methods: vec![Get, Head, Post, Put, Patch, Delete],
+            headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Restricts the allowed origins to the listed ones, `*` allows any
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Self {
+        let origin = origin.into();
+        if origin != "*" {
+            self.origins.push(origin);
+        }
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    pub fn allow_headers<H: Into<String>>(mut self, headers: impl IntoIterator<Item = H>) -> Self {
+        self.headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn allow_credentials(mut self, allow: bool) -> Self {
+        self.credentials = allow;
+        self
+    }
+
+    /// Seconds browsers can cache the result of a preflight request
+    pub fn max_age(mut self, seconds: u64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    fn is_allowed(&self, origin: &str) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|o| o == origin)
+    }
+
+    fn apply_origin(&self, res: &mut Response, origin: &str) {
+        // credentials can't be used with a wildcard origin
+        if self.origins.is_empty() && !self.credentials {
+            res.insert_header("access-control-allow-origin", "*");
+        } else {
+            res.insert_header("access-control-allow-origin", origin);
+            res.append_header("vary", "origin");
+        }
+        if self.credentials {
+            res.insert_header("access-control-allow-credentials", "true");
+        }
+    }
+
+    fn preflight(&self, origin: &str) -> Response {
+        let mut res = Response::new(StatusCode::NoContent);
+        self.apply_origin(&mut res, origin);
+        let methods = self.methods.iter().map(|m| m.as_ref()).collect::<Vec<_>>();
+        res.insert_header("access-control-allow-methods", methods.join(", "));
+        if !self.headers.is_empty() {
+            res.insert_header("access-control-allow-headers", self.headers.join(", "));
+        }
+        if let Some(max_age) = self.max_age {
+            res.insert_header("access-control-max-age", max_age.to_string());
+        }
+        res
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors::new()
+    }
+}
+
+#[async_trait(?Send)]
+impl Middleware for Cors {
+    async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response, Error> {
+        let origin = match req.header("origin").map(|o| o.last().as_str().to_owned()) {
+            Some(origin) => origin,
+            None => return next.run(req).await,
+        };
+        let is_preflight = req.method() == Method::Options
+            && req.header("access-control-request-method").is_some();
+
+        if !self.is_allowed(&origin) {
+            if is_preflight {
+                return Ok(Response::new(StatusCode::Forbidden));
+            }
+            return next.run(req).await;
+        }
+        if is_preflight {
+            return Ok(self.preflight(&origin));
+        }
+
+        let mut res = next.run(req).await?;
+        self.apply_origin(&mut res, &origin);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http, runtime::Runtime};
+    use async_std::test;
+
+    fn request(method: http::Method, origin: &str) -> http::Request {
+        let mut req = http::Request::new(method, "http://example.com/_foo");
+        req.insert_header("x-request-id", "123");
+        req.insert_header("origin", origin);
+        req
+    }
+
+    fn runtime(cors: Cors) -> Runtime<()> {
+        Runtime::new(())
+            .with_middleware(cors)
+            .with_plugin("foo", ())
+            .unwrap()
+    }
+
+    #[test]
+    async fn answer_preflight_requests() {
+        let runtime = runtime(
+            Cors::new()
+                .allow_origin("https://a.com")
+                .allow_methods([Method::Get])
+                .max_age(60),
+        );
+        let mut req = request(Method::Options, "https://a.com");
+        req.insert_header("access-control-request-method", "GET");
+        let res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(res["access-control-allow-origin"], "https://a.com");
+        assert_eq!(res["access-control-allow-methods"], "GET");
+        assert_eq!(res["access-control-max-age"], "60");
+        assert!(res.header("x-valor-plugin").is_none());
+    }
+
+    #[test]
+    async fn preflight_of_plugins_without_options() {
+        let plugin = crate::runtime::VluginDef {
+            methods: vec![Method::Get],
+            ..crate::runtime::VluginDef::from("foo")
+        };
+        let runtime = Runtime::new(())
+            .with_middleware(Cors::new())
+            .with_plugin(plugin, ())
+            .unwrap();
+
+        let mut req = request(Method::Options, "https://a.com");
+        req.insert_header("access-control-request-method", "GET");
+        let res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert!(res.header("access-control-allow-methods").is_some());
+
+        // other OPTIONS requests are answered by the runtime with CORS headers
+        let req = request(Method::Options, "https://a.com");
+        let res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(res["allow"], "GET, HEAD, OPTIONS");
+        assert_eq!(res["access-control-allow-origin"], "*");
+    }
+
+    #[test]
+    async fn wildcard_origin() {
+        let runtime = runtime(Cors::new());
+        let req = request(Method::Get, "https://b.com");
+        let res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res["access-control-allow-origin"], "*");
+    }
+
+    #[test]
+    async fn disallowed_origin_gets_no_cors_headers() {
+        let runtime = runtime(Cors::new().allow_origin("https://a.com"));
+
+        let req = request(Method::Get, "https://b.com");
+        let res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert!(res.header("access-control-allow-origin").is_none());
+
+        let mut req = request(Method::Options, "https://b.com");
+        req.insert_header("access-control-request-method", "GET");
+        let res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+    }
+}
@@ -0,0 +1,266 @@
+use super::{Middleware, Next};
+use crate::{
+    async_trait,
+    http::{headers, Method, Request, Response, StatusCode},
+    runtime::fnv1a,
+    Error,
+};
+use alloc::{borrow::ToOwned, boxed::Box, format, string::String, vec::Vec};
+
+// headers a `304 Not Modified` keeps from the response it replaces
+const KEPT_HEADERS: [&str; 8] = [
+    "cache-control",
+    "content-location",
+    "date",
+    "etag",
+    "expires",
+    "last-modified",
+    "vary",
+    "x-valor-plugin",
+];
+
+/// Middleware that tags `200 OK` responses to `GET` requests with an `ETag`
+/// computed from their body and answers `304 Not Modified` without a body to
+/// the conditional requests(`If-None-Match` or `If-Modified-Since`) of
+/// clients that have a fresh copy. Tags plugins set are kept and streamed
+/// responses without a known length are not tagged as it would mean buffering
+/// them, they are still answered with `304` when the plugin tags them.
+///
+/// ```
+/// # use valor_core::*;
+/// # use runtime::{ETag, Runtime};
+/// let runtime = Runtime::new(()).with_middleware(ETag::new().weak());
+/// ```
+pub struct ETag {
+    weak: bool,
+}
+
+impl ETag {
+    /// Strong tags that change when any byte of the body does
+    pub fn new() -> Self {
+        ETag { weak: false }
+    }
+
+    /// Weak tags(`W/"..."`) for bodies that are equivalent but might not be
+    /// byte for byte the same, e.g. after being compressed
+    pub fn weak(mut self) -> Self {
+        self.weak = true;
+        self
+    }
+
+    fn tag(&self, body: &[u8]) -> String {
+        let tag = format!("\"{:x}-{:016x}\"", body.len(), fnv1a(body));
+        if self.weak {
+            format!("W/{}", tag)
+        } else {
+            tag
+        }
+    }
+}
+
+impl Default for ETag {
+    fn default() -> Self {
+        ETag::new()
+    }
+}
+
+// `If-None-Match` uses the weak comparison, the `W/` prefix doesn't matter.
+// `*` matches any tag, also when the response has none
+fn none_match(if_none_match: &str, etag: Option<&str>) -> bool {
+    let etag = etag.and_then(|etag| entity_tags(etag).next());
+    entity_tags(if_none_match).any(|tag| tag == "*" || Some(tag) == etag)
+}
+
+// Opaque part of the tags of a list like `"a", W/"b,c"` or `*`, commas can be
+// quoted so the list isn't just split by them
+fn entity_tags(list: &str) -> impl Iterator<Item = &str> {
+    let mut rest = list;
+    core::iter::from_fn(move || loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return None;
+        }
+        if let Some(after) = rest.strip_prefix('*') {
+            rest = after;
+            return Some("*");
+        }
+        let tag = rest.strip_prefix("W/").unwrap_or(rest);
+        let quoted = tag
+            .strip_prefix('"')
+            .and_then(|tag| tag.find('"').map(|end| (&tag[..end], &tag[end + 1..])));
+        match quoted {
+            Some((tag, after)) => {
+                rest = after;
+                return Some(tag);
+            }
+            // a malformed tag is skipped up to the next one
+            None => rest = rest.find(',').map_or("", |next| &rest[next..]),
+        }
+    })
+}
+
+// Parts of an IMF-fixdate(`Sun, 06 Nov 1994 08:49:37 GMT`) ordered to compare dates
+fn http_date(date: &str) -> Option<(u16, u8, u8, u8, u8, u8)> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let parts = date.split_whitespace().collect::<Vec<_>>();
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, "GMT"] => (day, month, year, time),
+        _ => return None,
+    };
+    let month = MONTHS.iter().position(|m| m == month)? as u8 + 1;
+    let mut time = time.split(':').map(|t| t.parse::<u8>().ok());
+    Some((
+        year.parse().ok()?,
+        month,
+        day.parse().ok()?,
+        time.next()??,
+        time.next()??,
+        time.next()??,
+    ))
+}
+
+#[async_trait(?Send)]
+impl Middleware for ETag {
+    async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response, Error> {
+        let method = req.method();
+        if method != Method::Get && method != Method::Head {
+            return next.run(req).await;
+        }
+        // the tags can come in many headers
+        let if_none_match = req.header(headers::IF_NONE_MATCH).map(|values| {
+            values
+                .iter()
+                .map(|v| v.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        let if_modified_since = req
+            .header(headers::IF_MODIFIED_SINCE)
+            .map(|v| v.as_str().to_owned());
+
+        let mut res = next.run(req).await?;
+        if res.status() != StatusCode::Ok {
+            return Ok(res);
+        }
+        if method == Method::Get && res.header(headers::ETAG).is_none() && res.len().is_some() {
+            let body = res.take_body().into_bytes().await?;
+            res.insert_header(headers::ETAG, self.tag(&body));
+            res.set_body(body);
+        }
+
+        let etag = res.header(headers::ETAG).map(|v| v.as_str());
+        let fresh = match (if_none_match, if_modified_since) {
+            (Some(tags), _) => none_match(&tags, etag),
+            (None, Some(since)) => {
+                let modified = res.header(headers::LAST_MODIFIED);
+                match (
+                    modified.and_then(|m| http_date(m.as_str())),
+                    http_date(&since),
+                ) {
+                    (Some(modified), Some(since)) => modified <= since,
+                    _ => false,
+                }
+            }
+            (None, None) => false,
+        };
+        if !fresh {
+            return Ok(res);
+        }
+        let mut not_modified = Response::new(StatusCode::NotModified);
+        for name in KEPT_HEADERS.iter() {
+            for value in res.header(*name).iter().flat_map(|values| values.iter()) {
+                not_modified.append_header(*name, value.as_str());
+            }
+        }
+        Ok(not_modified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{h, http, runtime::Runtime};
+    use async_std::test;
+
+    #[test]
+    async fn answer_not_modified() {
+        let runtime = Runtime::new(())
+            .with_middleware(ETag::new())
+            .with_plugin(
+                "page",
+                h(|_: http::Request, _| async {
+                    let mut res = http::Response::from("hello");
+                    res.insert_header("last-modified", "Sun, 06 Nov 1994 08:49:37 GMT");
+                    res.insert_header("cache-control", "max-age=60");
+                    Ok(res)
+                }),
+            )
+            .unwrap();
+        let answer = |headers: &[(&str, &str)]| {
+            let mut req = http::Request::new(Method::Get, "http://example.com/_page");
+            req.insert_header("x-request-id", "123");
+            for (name, value) in headers {
+                req.insert_header(*name, *value);
+            }
+            let runtime = &runtime;
+            async move {
+                let res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+                res
+            }
+        };
+
+        let mut res = answer(&[]).await;
+        let etag = res["etag"].as_str().to_owned();
+        assert_eq!(etag, ETag::new().tag(b"hello"));
+        assert_eq!(res.body_string().await.unwrap(), "hello");
+
+        let tags = format!("\"x\", W/{}", etag);
+        let mut res = answer(&[("if-none-match", tags.as_str())]).await;
+        assert_eq!(res.status(), StatusCode::NotModified);
+        assert_eq!(res["etag"], etag.as_str());
+        assert_eq!(res["cache-control"], "max-age=60");
+        assert_eq!(res.body_string().await.unwrap(), "");
+
+        let res = answer(&[("if-none-match", "\"other\"")]).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        // every header with tags counts
+        let mut req = http::Request::new(Method::Get, "http://example.com/_page");
+        req.insert_header("x-request-id", "123");
+        req.append_header("if-none-match", etag.as_str());
+        req.append_header("if-none-match", "\"other\"");
+        let res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+        assert_eq!(res.status(), StatusCode::NotModified);
+        let res = answer(&[("if-modified-since", "Mon, 07 Nov 1994 00:00:00 GMT")]).await;
+        assert_eq!(res.status(), StatusCode::NotModified);
+        let res = answer(&[("if-modified-since", "Sat, 05 Nov 1994 00:00:00 GMT")]).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        // the tag takes precedence over the date
+        let res = answer(&[
+            ("if-none-match", "\"other\""),
+            ("if-modified-since", "Mon, 07 Nov 1994 00:00:00 GMT"),
+        ])
+        .await;
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn weak_tags() {
+        let tag = ETag::new().weak().tag(b"hello");
+        assert!(tag.starts_with("W/\""));
+        assert!(none_match(&ETag::new().tag(b"hello"), Some(tag.as_str())));
+        assert!(none_match("*", Some(tag.as_str())));
+        assert!(none_match("*", None));
+        assert!(!none_match(&ETag::new().tag(b"hell0"), Some(tag.as_str())));
+        assert!(!none_match("\"a\"", None));
+    }
+
+    #[test]
+    fn lists_of_tags() {
+        let tags = entity_tags(r#" "a", W/"b,c" ,bad, *,"d""#).collect::<Vec<_>>();
+        assert_eq!(tags, ["a", "b,c", "*", "d"]);
+        assert!(none_match(r#""x", W/"b,c""#, Some(r#""b,c""#)));
+        assert!(!none_match(r#""b", "c""#, Some(r#""b,c""#)));
+    }
+}
@@ -0,0 +1,436 @@
+use super::{Middleware, Next, Prefixes};
+use crate::{
+    async_trait,
+    http::{self, headers, Request, Response, StatusCode},
+    runtime::{problem, time},
+    Error,
+};
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use hashbrown::HashMap;
+#[cfg(target_arch = "wasm32")]
+use http_client::{h1::wasm::WasmClient as Client, HttpClient};
+#[cfg(not(target_arch = "wasm32"))]
+use http_client::{h1::H1Client as Client, HttpClient};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+
+// keys are not fetched again for unknown key ids more often than this
+const MIN_REFETCH: Duration = Duration::from_secs(30);
+
+/// Middleware that lets through only the requests with a valid bearer token,
+/// others are answered with `401 Unauthorized`. Tokens are signed with a
+/// shared secret(HS256) or with one of the keys of a JWKS(RS256), their
+/// expiration and not before times are always checked.
+///
+/// ```
+/// # use valor_core::*;
+/// # use runtime::{Jwt, Runtime};
+/// let jwt = Jwt::with_jwks("https://example.com/.well-known/jwks.json".parse().unwrap())
+///     .issuer("https://example.com")
+///     .audience("api")
+///     .prefix("/_orders");
+/// let runtime = Runtime::new(()).with_middleware(jwt);
+/// ```
+pub struct Jwt {
+    keys: Keys,
+    validation: Validation,
+    prefixes: Prefixes,
+}
+
+enum Keys {
+    Secret(DecodingKey),
+    Jwks(Jwks),
+}
+
+// Keys of a JWKS by key id fetched when they are too old
+struct Jwks {
+    url: http::Url,
+    client: Client,
+    refresh: Duration,
+    keys: RefCell<HashMap<String, DecodingKey>>,
+    fetched_at: Cell<Option<u64>>,
+    // requests that need the keys while they are fetched wait for that fetch
+    fetching: Cell<bool>,
+    waiting: RefCell<Vec<Waker>>,
+}
+
+/// Claims of the validated token of the request, added by [`Jwt`] as an
+/// extension of the request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claims(pub serde_json::Value);
+
+impl Jwt {
+    /// Validates tokens signed with HS256 and the `secret`
+    pub fn with_secret(secret: impl AsRef<[u8]>) -> Self {
+        Jwt {
+            keys: Keys::Secret(DecodingKey::from_secret(secret.as_ref())),
+            validation: validation(Algorithm::HS256),
+            prefixes: Prefixes::default(),
+        }
+    }
+
+    /// Validates tokens signed with RS256 and one of the keys served at `url`,
+    /// keys are fetched again every hour or when a token has an unknown key id
+    /// by one request while the ones that come meanwhile wait for it
+    pub fn with_jwks(url: http::Url) -> Self {
+        Jwt {
+            keys: Keys::Jwks(Jwks {
+                url,
+                client: Client::new(),
+                refresh: Duration::from_secs(3600),
+                keys: RefCell::default(),
+                fetched_at: Cell::new(None),
+                fetching: Cell::new(false),
+                waiting: RefCell::default(),
+            }),
+            validation: validation(Algorithm::RS256),
+            prefixes: Prefixes::default(),
+        }
+    }
+
+    /// How often the keys of the JWKS are fetched
+    pub fn refresh_every(mut self, period: Duration) -> Self {
+        if let Keys::Jwks(jwks) = &mut self.keys {
+            jwks.refresh = period;
+        }
+        self
+    }
+
+    /// Audience the tokens must be for, can be used multiple times
+    pub fn audience(mut self, aud: impl Into<String>) -> Self {
+        self.validation
+            .aud
+            .get_or_insert_with(Default::default)
+            .insert(aud.into());
+        self
+    }
+
+    /// Issuer the tokens must come from, can be used multiple times
+    pub fn issuer(mut self, iss: impl Into<String>) -> Self {
+        self.validation
+            .iss
+            .get_or_insert_with(Default::default)
+            .insert(iss.into());
+        self
+    }
+
+    /// Seconds of clock skew tolerated checking the times of the tokens
+    pub fn leeway(mut self, seconds: u64) -> Self {
+        self.validation.leeway = seconds;
+        self
+    }
+
+    /// Only requests for paths under the prefix need a token, without
+    /// prefixes all requests do
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefixes.push(&prefix.into());
+        self
+    }
+
+    async fn validate(&self, token: &str) -> Result<Claims, Response> {
+        let decoded = match &self.keys {
+            Keys::Secret(key) => decode(token, key, &self.validation),
+            Keys::Jwks(jwks) => {
+                let kid = decode_header(token)
+                    .map_err(|err| unauthorized(&err.to_string()))?
+                    .kid
+                    .unwrap_or_default();
+                let key = jwks.key(&kid).await?;
+                decode(token, &key, &self.validation)
+            }
+        };
+        decoded
+            .map(|data| Claims(data.claims))
+            .map_err(|err| unauthorized(&err.to_string()))
+    }
+}
+
+impl Jwks {
+    async fn key(&self, kid: &str) -> Result<DecodingKey, Response> {
+        let now = time::unix_ms().unwrap_or_default();
+        let age = self
+            .fetched_at
+            .get()
+            .map(|at| Duration::from_millis(now.saturating_sub(at)));
+        let known = self.keys.borrow().contains_key(kid);
+        let outdated = match age {
+            None => true,
+            Some(age) => age >= self.refresh || (!known && age >= MIN_REFETCH),
+        };
+        if outdated && self.fetching.get() {
+            Fetched(self).await;
+            if self.fetched_at.get().is_none() {
+                let detail = "Keys of the JWKS are unavailable";
+                return Err(problem::response(StatusCode::ServiceUnavailable, detail));
+            }
+        } else if outdated {
+            let _fetching = Fetching::start(self);
+            match self.fetch().await {
+                Ok(keys) => {
+                    self.keys.replace(keys);
+                    self.fetched_at.set(Some(now));
+                }
+                // the keys we had are still good to use
+                Err(_) if age.is_some() => {}
+                Err(detail) => {
+                    return Err(problem::response(StatusCode::ServiceUnavailable, detail))
+                }
+            }
+        }
+        self.keys
+            .borrow()
+            .get(kid)
+            .cloned()
+            .ok_or_else(|| unauthorized("Unknown key"))
+    }
+
+    async fn fetch(&self) -> Result<HashMap<String, DecodingKey>, String> {
+        let req = http::Request::new(http::Method::Get, self.url.clone());
+        let mut res = self.client.send(req).await.map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(alloc::format!("Fetching keys failed with {}", res.status()));
+        }
+        let jwks: serde_json::Value = res.body_json().await.map_err(|e| e.to_string())?;
+        let keys = jwks["keys"]
+            .as_array()
+            .map(|keys| keys.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter(|key| key["kty"] == "RSA")
+            .filter_map(|key| {
+                let kid = key["kid"].as_str().unwrap_or_default().to_owned();
+                let n = key["n"].as_str()?;
+                let e = key["e"].as_str()?;
+                Some((kid, DecodingKey::from_rsa_components(n, e).ok()?))
+            })
+            .collect();
+        Ok(keys)
+    }
+}
+
+// Marks the keys as being fetched until the fetch ends, also when the request
+// fetching them goes away, then wakes the requests waiting for them
+struct Fetching<'a>(&'a Jwks);
+
+impl<'a> Fetching<'a> {
+    fn start(jwks: &'a Jwks) -> Self {
+        jwks.fetching.set(true);
+        Fetching(jwks)
+    }
+}
+
+impl Drop for Fetching<'_> {
+    fn drop(&mut self) {
+        self.0.fetching.set(false);
+        for waker in self.0.waiting.borrow_mut().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+// Resolves when the keys are no longer being fetched
+struct Fetched<'a>(&'a Jwks);
+
+impl Future for Fetched<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.0.fetching.get() {
+            return Poll::Ready(());
+        }
+        let mut waiting = self.0.waiting.borrow_mut();
+        if !waiting.iter().any(|w| w.will_wake(cx.waker())) {
+            waiting.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+fn validation(algorithm: Algorithm) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    validation.validate_nbf = true;
+    validation
+}
+
+fn unauthorized(detail: &str) -> Response {
+    let mut res = problem::response(StatusCode::Unauthorized, detail);
+    res.insert_header(headers::WWW_AUTHENTICATE, "Bearer error=\"invalid_token\"");
+    res
+}
+
+#[async_trait(?Send)]
+impl Middleware for Jwt {
+    async fn handle(&self, mut req: Request, next: Next<'_>) -> Result<Response, Error> {
+        if !self.prefixes.covers(req.url().path()) {
+            return next.run(req).await;
+        }
+        let token = req.header(headers::AUTHORIZATION).and_then(|auth| {
+            let (scheme, token) = auth.as_str().trim().split_once(' ')?;
+            scheme
+                .eq_ignore_ascii_case("bearer")
+                .then(|| token.trim().to_owned())
+        });
+        let token = match token {
+            Some(token) => token,
+            None => {
+                let mut res = problem::response(StatusCode::Unauthorized, "Missing token");
+                res.insert_header(headers::WWW_AUTHENTICATE, "Bearer");
+                return Ok(res);
+            }
+        };
+        match self.validate(&token).await {
+            Ok(claims) => {
+                req.set_ext(claims);
+                next.run(req).await
+            }
+            Err(res) => Ok(res),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{h, runtime::Runtime};
+    use async_std::test;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    fn request(path: &str, token: Option<&str>) -> http::Request {
+        let url = "http://example.com".to_owned() + path;
+        let mut req = http::Request::new(http::Method::Get, url.as_str());
+        req.insert_header("x-request-id", "123");
+        if let Some(token) = token {
+            req.insert_header("authorization", alloc::format!("Bearer {}", token));
+        }
+        req
+    }
+
+    #[test]
+    async fn validate_tokens() {
+        let runtime = Runtime::new(())
+            .with_middleware(
+                Jwt::with_secret("secret")
+                    .issuer("valor")
+                    .audience("api")
+                    .prefix("/_api"),
+            )
+            .with_plugin(
+                "api",
+                h(|req: http::Request, _| async move {
+                    let claims = req.ext::<Claims>().unwrap();
+                    Ok(http::Response::from(claims.0["sub"].as_str().unwrap()))
+                }),
+            )
+            .unwrap()
+            .with_plugin("public", ())
+            .unwrap();
+        let runtime = &runtime;
+        let answer = |req: http::Request| async move {
+            let res: http::Response = runtime.on_msg(req.into()).await.unwrap().into();
+            res
+        };
+        let exp = time::unix_ms().unwrap() / 1000 + 60;
+        let token = |secret: &str, claims: serde_json::Value| {
+            let key = EncodingKey::from_secret(secret.as_bytes());
+            encode(&Header::default(), &claims, &key).unwrap()
+        };
+
+        let valid = token(
+            "secret",
+            json!({"sub": "ann", "iss": "valor", "aud": "api", "exp": exp}),
+        );
+        let mut res = answer(request("/_api/orders", Some(&valid))).await;
+        assert_eq!(res.body_string().await.unwrap(), "ann");
+
+        let res = answer(request("/_api", None)).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert_eq!(res["www-authenticate"], "Bearer");
+
+        let invalid = [
+            token(
+                "wrong",
+                json!({"sub": "ann", "iss": "valor", "aud": "api", "exp": exp}),
+            ),
+            token(
+                "secret",
+                json!({"sub": "ann", "iss": "valor", "aud": "api", "exp": exp - 120}),
+            ),
+            token(
+                "secret",
+                json!({"sub": "ann", "iss": "other", "aud": "api", "exp": exp}),
+            ),
+            token(
+                "secret",
+                json!({"sub": "ann", "iss": "valor", "aud": "web", "exp": exp}),
+            ),
+            token(
+                "secret",
+                json!({"sub": "ann", "iss": "valor", "aud": "api", "exp": exp, "nbf": exp}),
+            ),
+            "not a token".into(),
+        ];
+        for token in &invalid {
+            let res = answer(request("/_api", Some(token))).await;
+            assert_eq!(res.status(), StatusCode::Unauthorized, "{}", token);
+            assert_eq!(res["www-authenticate"], "Bearer error=\"invalid_token\"");
+        }
+
+        // public routes need no token
+        let res = answer(request("/_public", None)).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[test]
+    async fn only_bearer_tokens() {
+        let runtime = Runtime::new(())
+            .with_middleware(Jwt::with_secret("secret"))
+            .with_plugin("foo", ())
+            .unwrap();
+        let mut req = request("/_foo", None);
+        req.insert_header("authorization", "Basic YW5uOnNlY3JldA==");
+        let res = runtime.handle(req).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        // no token at all is not an invalid one
+        assert_eq!(res["www-authenticate"], "Bearer");
+    }
+
+    #[test]
+    async fn fetch_and_cache_jwks() {
+        let mock = mockito::mock("GET", "/jwks.json")
+            .with_body(
+                json!({"keys": [
+                    {"kty": "RSA", "kid": "a", "n": "D5DGecCe_6k14V0zCTemzJwJlY8PtSPYwWSeSwp-9_UPVl8ia1MJRp1XBw4Fx6Tb0WaiBNOrq4unDaMA0FbfAGJBisRlJOYtoOrM1unhFyRcu6O9adHGGMZ4zDAZ41c5jvqAqM47AZ9ktQAPVWYMMe_V88FJLSMSs-uakDMv5jofRHFGEQ8EIvBUMf11u2WCSs4hBXQqtLPy9CmcPG585rnxZxSxWGE_jZOPcT9mk5uvtL7qc83HjjnzlEQLKYfOlDVAoNu6EGUUqFBolOY3qBuK_teDWoarpv6JxqHzU9QxQfvtGquU_vpUgzVUrGAaupBDNY42MLiyWRDBn5gPqQ", "e": "AQAB"},
+                    {"kty": "EC", "kid": "b", "x": "", "y": ""},
+                ]})
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+        let url = (mockito::server_url() + "/jwks.json").parse().unwrap();
+        let jwt = Jwt::with_jwks(url);
+        let jwks = match &jwt.keys {
+            Keys::Jwks(jwks) => jwks,
+            _ => unreachable!(),
+        };
+
+        // requests that come while fetching wait for the same fetch
+        let (first, second) = futures::future::join(jwks.key("a"), jwks.key("a")).await;
+        assert!(first.is_ok() && second.is_ok());
+        // unknown keys are not fetched again right away
+        let res = jwks.key("b").await.err().unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        mock.assert();
+    }
+}
@@ -0,0 +1,133 @@
+use super::{Middleware, Next};
+use crate::{
+    async_trait,
+    http::{headers::HeaderName, Request, Response},
+    Error,
+};
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+/// Middleware that adds the headers that make browsers safer with the responses,
+/// headers plugins already set are left as they are.
+///
+/// ```
+/// # use valor_core::*;
+/// # use runtime::{Runtime, SecurityHeaders};
+/// let headers = SecurityHeaders::new()
+///     .frame_options("SAMEORIGIN")
+///     .content_security_policy("default-src 'self'")
+///     .skip("strict-transport-security");
+/// let runtime = Runtime::new(()).with_middleware(headers);
+/// ```
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, String)>,
+}
+
+impl SecurityHeaders {
+    /// Strict defaults without a content security policy as it depends on the
+    /// content, HSTS is sent for a year including subdomains
+    pub fn new() -> Self {
+        SecurityHeaders {
+            headers: Vec::new(),
+        }
+        .hsts("max-age=31536000; includeSubDomains")
+        .set("x-content-type-options", "nosniff")
+        .frame_options("DENY")
+        .referrer_policy("strict-origin-when-cross-origin")
+    }
+
+    /// Value of `Strict-Transport-Security`
+    pub fn hsts(self, value: impl Into<String>) -> Self {
+        self.set("strict-transport-security", value)
+    }
+
+    /// Value of `X-Frame-Options`, `DENY` or `SAMEORIGIN`
+    pub fn frame_options(self, value: impl Into<String>) -> Self {
+        self.set("x-frame-options", value)
+    }
+
+    pub fn referrer_policy(self, value: impl Into<String>) -> Self {
+        self.set("referrer-policy", value)
+    }
+
+    pub fn content_security_policy(self, policy: impl Into<String>) -> Self {
+        self.set("content-security-policy", policy)
+    }
+
+    /// Stops adding the header, e.g. `x-frame-options` when pages are meant
+    /// to be embedded
+    pub fn skip(mut self, header: &str) -> Self {
+        self.headers
+            .retain(|(name, _)| !name.as_str().eq_ignore_ascii_case(header));
+        self
+    }
+
+    fn set(mut self, header: &str, value: impl Into<String>) -> Self {
+        let value = value.into();
+        match self
+            .headers
+            .iter_mut()
+            .find(|(name, _)| name.as_str() == header)
+        {
+            Some((_, v)) => *v = value,
+            None => self.headers.push((HeaderName::from(header), value)),
+        }
+        self
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders::new()
+    }
+}
+
+#[async_trait(?Send)]
+impl Middleware for SecurityHeaders {
+    async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response, Error> {
+        let mut res = next.run(req).await?;
+        for (name, value) in &self.headers {
+            if res.header(name).is_none() {
+                res.insert_header(name, value.as_str());
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{h, http, runtime::Runtime};
+    use async_std::test;
+
+    #[test]
+    async fn add_missing_headers() {
+        let runtime = Runtime::new(())
+            .with_middleware(
+                SecurityHeaders::new()
+                    .content_security_policy("default-src 'self'")
+                    .skip("Referrer-Policy"),
+            )
+            .with_plugin(
+                "foo",
+                h(|_: http::Request, _| async {
+                    let mut res = http::Response::new(http::StatusCode::Ok);
+                    res.insert_header("x-frame-options", "SAMEORIGIN");
+                    Ok(res)
+                }),
+            )
+            .unwrap();
+        let mut req = http::Request::new(http::Method::Get, "http://example.com/_foo");
+        req.insert_header("x-request-id", "123");
+        let res: Response = runtime.on_msg(req.into()).await.unwrap().into();
+
+        assert_eq!(
+            res["strict-transport-security"],
+            "max-age=31536000; includeSubDomains"
+        );
+        assert_eq!(res["x-content-type-options"], "nosniff");
+        assert_eq!(res["x-frame-options"], "SAMEORIGIN");
+        assert_eq!(res["content-security-policy"], "default-src 'self'");
+        assert!(res.header("referrer-policy").is_none());
+    }
+}
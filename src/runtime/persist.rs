@@ -0,0 +1,129 @@
+//! Saving the plugins of the registry to a file to restore them after a restart,
+//! the file has the same format read by `valor_bin` with the `plugins` list.
+//! Secrets of the configuration are never written, only their placeholders.
+
+use super::VluginDef;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    vec::Vec,
+};
+
+#[derive(Serialize, Deserialize)]
+struct Saved<P> {
+    plugins: Vec<P>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SavedPlugin<P> {
+    #[serde(flatten)]
+    pub plugin: P,
+    #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+    pub disabled: bool,
+}
+
+/// Replaces the file with the given plugins redacted, the new content is written
+/// to a temporary file that is renamed so readers never see a half written file
+pub(crate) fn save<'a>(
+    path: &Path,
+    plugins: impl Iterator<Item = (&'a VluginDef, bool)>,
+) -> io::Result<()> {
+    let saved = Saved {
+        plugins: plugins
+            .map(|(plugin, disabled)| SavedPlugin {
+                plugin: plugin.redacted(),
+                disabled,
+            })
+            .collect(),
+    };
+    let json = serde_json::to_vec_pretty(&saved)?;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&json)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Plugins previously saved to the file, no file means there are none
+pub(crate) fn read(path: &Path) -> io::Result<Vec<SavedPlugin<VluginDef>>> {
+    match fs::read(path) {
+        Ok(content) => {
+            let saved: Saved<_> = serde_json::from_slice(&content)?;
+            Ok(saved.plugins)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a directory of its own so tests running at the same time don't share files
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+        let unique = format!(
+            "valor-{}-{}-{}",
+            name,
+            std::process::id(),
+            now.unwrap().as_nanos()
+        );
+        let dir = std::env::temp_dir().join(unique);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn save_and_read_plugins() {
+        let dir = temp_dir("persist");
+        let path = dir.join("registry.json");
+        assert!(read(&path).unwrap().is_empty());
+
+        let (foo, bar) = (VluginDef::from("foo"), VluginDef::from("bar"));
+        save(&path, vec![(&foo, false), (&bar, true)].into_iter()).unwrap();
+        let saved = read(&path).unwrap();
+        assert_eq!(saved.len(), 2);
+        assert_eq!(saved[0].plugin, foo);
+        assert!(!saved[0].disabled);
+        assert_eq!(saved[1].plugin, bar);
+        assert!(saved[1].disabled);
+
+        save(&path, core::iter::empty()).unwrap();
+        assert!(read(&path).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn save_plugins_without_secrets() {
+        let dir = temp_dir("persist-secrets");
+        let path = dir.join("registry.json");
+        let plugin = VluginDef {
+            config: Some(serde_json::json!({
+                "upstream": "http://example.com",
+                "token": "s3cret",
+                "api_key": "${API_KEY}",
+            })),
+            ..VluginDef::from("foo")
+        };
+        save(&path, vec![(&plugin, false)].into_iter()).unwrap();
+
+        assert!(!String::from_utf8(fs::read(&path).unwrap())
+            .unwrap()
+            .contains("s3cret"));
+        let saved = read(&path).unwrap();
+        assert_eq!(
+            saved[0].plugin.config,
+            Some(serde_json::json!({
+                "upstream": "http://example.com",
+                "token": "[redacted]",
+                "api_key": "${API_KEY}",
+            }))
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
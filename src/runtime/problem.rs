@@ -0,0 +1,90 @@
+//! Errors answered by the runtime itself, as plain text by default or
+//! as `application/problem+json`(RFC 7807) when the runtime is configured so
+
+use crate::http::{self, headers, Body, Method, Response, StatusCode};
+use alloc::{format, string::String};
+use serde_json::json;
+
+/// Marks the responses of errors made by the runtime with what went wrong
+#[derive(Debug, Clone)]
+pub(crate) struct Problem {
+    pub detail: String,
+}
+
+/// Error response with the `detail` as plain text body
+pub(crate) fn response(status: StatusCode, detail: impl Into<String>) -> Response {
+    let detail = detail.into();
+    let mut res = Response::new(status);
+    res.set_body(detail.as_str());
+    res.insert_ext(Problem { detail });
+    res
+}
+
+/// `405 Method Not Allowed` listing in the `Allow` header the methods that are
+/// allowed for the path, `allow` is the comma separated list
+pub(crate) fn method_not_allowed(method: Method, allow: &str) -> Response {
+    let mut res = response(
+        StatusCode::MethodNotAllowed,
+        format!("{} is not allowed", method),
+    );
+    res.insert_header(headers::ALLOW, allow);
+    res
+}
+
+/// Error response of an error a plugin or the runtime failed with
+pub(crate) fn from_error(err: &http::Error) -> Response {
+    use alloc::string::ToString;
+    response(err.status(), err.to_string())
+}
+
+/// Replaces the plain text body of an error response of the runtime with
+/// the problem details, other responses are left as they are
+pub(crate) fn to_json(res: &mut Response, instance: &str) -> Result<(), http::Error> {
+    let detail = match res.ext::<Problem>() {
+        Some(problem) => problem.detail.clone(),
+        None => return Ok(()),
+    };
+    let status = res.status();
+    res.set_body(Body::from_json(&json!({
+        "type": "about:blank",
+        "title": status.canonical_reason(),
+        "status": status as u16,
+        "detail": detail,
+        "instance": instance,
+    }))?);
+    res.set_content_type("application/problem+json".parse::<http::Mime>()?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::test;
+
+    #[test]
+    async fn problem_details() {
+        let mut res = response(StatusCode::NotFound, "No plugin matched");
+        to_json(&mut res, "123").unwrap();
+        assert_eq!(
+            res.content_type().unwrap().essence(),
+            "application/problem+json"
+        );
+        let problem: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(
+            problem,
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "No plugin matched",
+                "instance": "123",
+            })
+        );
+
+        // responses of plugins are not touched
+        let mut res = Response::new(StatusCode::NotFound);
+        res.set_body("not here");
+        to_json(&mut res, "123").unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "not here");
+    }
+}
@@ -0,0 +1,275 @@
+//! Plugin that sends the requests for its route somewhere else
+
+use super::Params;
+use crate::{
+    async_trait,
+    http::{self, headers, StatusCode},
+    Answer, Context, Error, Message, Vlugin, VluginConfig,
+};
+use alloc::{boxed::Box, format, string::String};
+use core::convert::TryFrom;
+
+/// Plugin answering the requests for its route with a redirect to the
+/// `target` set in its configuration like
+/// `{"target": "/users/:id/profile", "status": 301, "keep_query": true}`.
+///
+/// Parameters of the prefix(`:id` or a catch-all like `*path`) are replaced
+/// in the target with the values captured from the request, the target can be
+/// a path of the same site(`/new`) or an absolute URL(`https://example.com/*path`).
+/// The status is one of `301`, `302`(the default), `307` or `308` and with
+/// `keep_query` the query of the request is added to the target.
+#[derive(Debug, Clone)]
+pub struct RedirectHandler {
+    target: String,
+    status: StatusCode,
+    keep_query: bool,
+}
+
+impl RedirectHandler {
+    pub fn new(target: impl Into<String>, status: StatusCode) -> Result<Self, http::Error> {
+        let target = target.into();
+        if target.is_empty() {
+            return Err(http::Error::from_str(StatusCode::BadRequest, "No target"));
+        }
+        if !matches!(status as u16, 301 | 302 | 307 | 308) {
+            let msg = format!("{} is not a redirect status", status as u16);
+            return Err(http::Error::from_str(StatusCode::BadRequest, msg));
+        }
+        Ok(RedirectHandler {
+            target,
+            status,
+            keep_query: false,
+        })
+    }
+
+    /// Adds the query of the requests to the location they are redirected to
+    pub fn keep_query(mut self) -> Self {
+        self.keep_query = true;
+        self
+    }
+
+    pub fn from_config(config: Option<&VluginConfig>) -> Result<Self, http::Error> {
+        let target = config
+            .and_then(|c| c.get("target"))
+            .and_then(VluginConfig::as_str)
+            .ok_or_else(|| http::Error::from_str(StatusCode::BadRequest, "No target"))?;
+        let status = match config.and_then(|c| c.get("status")) {
+            Some(status) => status
+                .as_u64()
+                .and_then(|s| u16::try_from(s).ok())
+                .and_then(|s| StatusCode::try_from(s).ok())
+                .ok_or_else(|| http::Error::from_str(StatusCode::BadRequest, "Invalid status"))?,
+            None => StatusCode::Found,
+        };
+        let redirect = RedirectHandler::new(target, status)?;
+        let keep_query = config
+            .and_then(|c| c.get("keep_query"))
+            .and_then(VluginConfig::as_bool)
+            .unwrap_or(false);
+        Ok(if keep_query {
+            redirect.keep_query()
+        } else {
+            redirect
+        })
+    }
+
+    /// Where the request is redirected to, a location that would point to
+    /// another site(`//evil.com`) when the target is a path is an error
+    pub fn location(&self, req: &http::Request) -> Result<String, http::Error> {
+        let params = req.ext::<Params>();
+        let (target, fragment) = match self.target.split_once('#') {
+            Some((target, fragment)) => (target, Some(fragment)),
+            None => (self.target.as_str(), None),
+        };
+
+        let mut location = String::with_capacity(target.len());
+        let mut rest = target;
+        while let Some(at) = rest.find(|c| c == ':' || c == '*') {
+            location.push_str(&rest[..at]);
+            let after = &rest[at + 1..];
+            // a name starts with a letter so ports or `https://` are left as they are
+            let len = match after.chars().next() {
+                Some(c) if c.is_ascii_alphabetic() || c == '_' => after
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(after.len()),
+                _ => 0,
+            };
+            match params
+                .and_then(|p| p.get(&after[..len]))
+                .filter(|_| len > 0)
+            {
+                Some(val) => encode_into(&mut location, val),
+                None => location.push_str(&rest[at..at + 1 + len]),
+            }
+            rest = &after[len..];
+        }
+        location.push_str(rest);
+
+        if let Some(query) = req
+            .url()
+            .query()
+            .filter(|q| self.keep_query && !q.is_empty())
+        {
+            location.push(if target.contains('?') { '&' } else { '?' });
+            location.push_str(query);
+        }
+        if let Some(fragment) = fragment {
+            location.push('#');
+            location.push_str(fragment);
+        }
+        if location.starts_with("//") || location.starts_with("/\\") {
+            let msg = format!("{} is not a path of this site", location);
+            return Err(http::Error::from_str(StatusCode::BadRequest, msg));
+        }
+        Ok(location)
+    }
+}
+
+// Values of the parameters are decoded, what a path can't have is encoded back
+// as well as leading slashes so a value can't make the path of another host
+fn encode_into(out: &mut String, val: &str) {
+    const HEX: &[u8] = b"0123456789ABCDEF";
+    let leading = val.len() - val.trim_start_matches('/').len();
+    for (i, b) in val.bytes().enumerate() {
+        match b {
+            b'/' if i < leading => {
+                out.push('%');
+                out.push(HEX[(b >> 4) as usize] as char);
+                out.push(HEX[(b & 0xf) as usize] as char);
+            }
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => out.push(b as char),
+            b'-' | b'.' | b'_' | b'~' | b'/' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' => {
+                out.push(b as char)
+            }
+            b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' => out.push(b as char),
+            _ => {
+                out.push('%');
+                out.push(HEX[(b >> 4) as usize] as char);
+                out.push(HEX[(b & 0xf) as usize] as char);
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Vlugin for RedirectHandler {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        let req = match msg {
+            Message::Http(req) => req,
+            Message::Ping => return Err(Error::NotSupported),
+        };
+        let mut res = http::Response::new(self.status);
+        res.insert_header(headers::LOCATION, self.location(&req)?);
+        Ok(res.into())
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        unreachable!()
+    }
+    fn context(&self) -> &Context {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{Runtime, VluginDef};
+    use alloc::{borrow::ToOwned, vec};
+    use async_std::test;
+    use serde_json::json;
+
+    #[test]
+    async fn redirect_to_target() {
+        let redirect = |name: &str, prefix: &str, config| {
+            let plugin = VluginDef {
+                prefix: Some(prefix.into()),
+                ..VluginDef::from(name)
+            };
+            (plugin, RedirectHandler::from_config(Some(&config)).unwrap())
+        };
+        let plugins = vec![
+            redirect("old", "/old", json!({ "target": "/new" })),
+            redirect(
+                "users",
+                "/users/:id",
+                json!({
+                    "target": "/people/:id/profile#top",
+                    "status": 301,
+                    "keep_query": true,
+                }),
+            ),
+            redirect(
+                "docs",
+                "/docs/*page",
+                json!({
+                    "target": "https://docs.example.com:8080/v2/*page?lang=en",
+                    "status": 308,
+                    "keep_query": true,
+                }),
+            ),
+        ];
+        let runtime = plugins
+            .into_iter()
+            .fold(Runtime::new(()), |runtime, (plugin, handler)| {
+                runtime.with_plugin(plugin, handler).unwrap()
+            });
+        let get = |path: &str| {
+            let url = "http://example.com".to_owned() + path;
+            let mut req = http::Request::new(http::Method::Get, url.as_str());
+            req.insert_header("x-request-id", "123");
+            let runtime = runtime.clone();
+            async move { http::Response::from(runtime.on_msg(req.into()).await.unwrap()) }
+        };
+        let location = |res: &http::Response| res.header("location").unwrap().as_str().to_owned();
+
+        let res = get("/old?page=2").await;
+        assert_eq!(res.status(), StatusCode::Found);
+        assert_eq!(location(&res), "/new");
+
+        let res = get("/users/jane%20doe?tab=posts").await;
+        assert_eq!(res.status(), StatusCode::MovedPermanently);
+        assert_eq!(location(&res), "/people/jane%20doe/profile?tab=posts#top");
+
+        let res = get("/docs/guide/intro").await;
+        assert_eq!(res.status(), StatusCode::PermanentRedirect);
+        assert_eq!(
+            location(&res),
+            "https://docs.example.com:8080/v2/guide/intro?lang=en"
+        );
+
+        // captures can't make the location point to another site
+        let plugin = VluginDef {
+            prefix: Some("/go/*path".into()),
+            ..VluginDef::from("go")
+        };
+        let runtime = runtime
+            .with_plugin(
+                plugin,
+                RedirectHandler::new("/*path", StatusCode::Found).unwrap(),
+            )
+            .unwrap();
+        let mut req = http::Request::new(http::Method::Get, "http://example.com/go//evil.com");
+        req.insert_header("x-request-id", "123");
+        let res = http::Response::from(runtime.on_msg(req.into()).await.unwrap());
+        assert!(!location(&res).starts_with("//"));
+        let mut encoded = String::new();
+        encode_into(&mut encoded, "//evil.com/a/b");
+        assert_eq!(encoded, "%2F%2Fevil.com/a/b");
+        let open = RedirectHandler::new("//evil.com", StatusCode::Found).unwrap();
+        let req = http::Request::new(http::Method::Get, "http://example.com/old");
+        assert!(open.location(&req).is_err());
+    }
+
+    #[test]
+    async fn reject_invalid_config() {
+        let invalid = |config| RedirectHandler::from_config(Some(&config)).is_err();
+        assert!(RedirectHandler::from_config(None).is_err());
+        assert!(invalid(json!({ "target": "" })));
+        assert!(invalid(json!({ "target": "/new", "status": 200 })));
+        assert!(invalid(json!({ "target": "/new", "status": "301" })));
+        // 65837 would be 301 if it was truncated
+        assert!(invalid(json!({ "target": "/new", "status": 65837 })));
+        assert!(!invalid(json!({ "target": "/new", "status": 307 })));
+    }
+}
@@ -1,66 +1,973 @@
-use super::VluginDef;
-use crate::Vlugin;
-use alloc::{borrow::ToOwned, rc::Rc, string::String};
+use super::{
+    breaker::{Breaker, Circuit, CircuitBreaker},
+    drain::Drain,
+    stats::{Snapshot, Stats},
+    LoadBalancer, Version, VluginDef,
+};
+use crate::{
+    http::{Method, StatusCode},
+    Health, Vlugin,
+};
+use alloc::{borrow::ToOwned, rc::Rc, string::String, vec::Vec};
+use core::{cell::Cell, time::Duration};
 use hashbrown::HashMap;
 use path_tree::PathTree;
 
 type PluginHandler = (VluginDef, Rc<dyn Vlugin>);
 
+struct Entry {
+    plugin: VluginDef,
+    handler: Rc<dyn Vlugin>,
+    // disabled plugins keep their prefix but don't handle requests
+    disabled: bool,
+    load: LoadInfo,
+    breaker: Breaker,
+    // requests the plugin is handling
+    active: Rc<Drain>,
+    stats: Stats,
+    // latest health check of a variant, unhealthy ones are skipped
+    probe: Cell<Option<Health>>,
+    // round-robin position among the variants of its route
+    turn: Cell<i64>,
+    // handlers registered as they are warm up before their first request,
+    // loaded ones already did after loading
+    cold: Cell<bool>,
+    // registered through the registry endpoint so a persistent registry saves
+    // it, the ones of the configuration are registered again when it starts
+    #[cfg(all(feature = "std", feature = "serde"))]
+    persistent: bool,
+}
+
+/// Information about the last time a plugin was loaded
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadInfo {
+    /// Unix timestamp in milliseconds
+    pub loaded_at: Option<u64>,
+    pub duration_ms: Option<u64>,
+    pub error: Option<String>,
+    /// Time the plugin took to warm up after it was loaded
+    pub warmup_ms: Option<u64>,
+    /// The plugin was registered but its warmup failed
+    pub warmup_error: Option<String>,
+}
+
+const DEFAULT_LOAD_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// name of the catch-all segment appended to every route to match sub paths
+const REST: &str = "__rest";
+
 /// Plugin to keep track of registered plugins
+///
+/// Matching only needs a shared borrow that is released before the plugin
+/// handles the request, so concurrent requests never wait on each other and
+/// registering a plugin never waits for requests in flight.
+/// Registering happens entirely within one exclusive borrow that updates the
+/// plugins and their routes together, it either fully applies or not at all.
 pub(crate) struct PluginRegistry {
-    pub(self) plugins: HashMap<String, PluginHandler>,
-    routes: PathTree<String>,
+    pub(self) plugins: HashMap<String, Entry>,
+    // plugins whose last load failed and are not registered
+    failed: HashMap<String, (VluginDef, LoadInfo)>,
+    // a route tree per namespace and host pattern, plugins sharing a route
+    // serve different methods
+    routes: HashMap<(Option<String>, Option<String>), PathTree<Vec<String>>>,
+    /// Time plugins have to load unless they define their own
+    pub load_timeout: Duration,
+    /// Time a removed or replaced plugin has to finish its requests
+    pub drain_timeout: Duration,
+    /// Runs the wait of removed or replaced plugins in the background
+    pub spawn: Option<Rc<dyn Fn(super::BoxedFuture<'static, ()>)>>,
+    /// Plugins can be replaced with older versions
+    pub allow_downgrades: bool,
+    /// Stops calling plugins that keep failing when set
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// File where the plugins are saved when the registry changes
+    #[cfg(all(feature = "std", feature = "serde"))]
+    pub persist_to: Option<std::path::PathBuf>,
+}
+
+/// How a variant of a route is chosen for a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Choice<'a> {
+    /// By the load balancer of the route, the random one uses the seed
+    Balanced(u64),
+    /// The same seed always gets the same variant
+    Sticky(u64),
+    /// The variant with this name while it gets requests, otherwise the load
+    /// balancer chooses
+    Prefer(&'a str, u64),
+}
+
+/// Outcome of a successful registration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Registration {
+    Created,
+    /// A plugin with the same name was registered and got replaced
+    Replaced,
 }
 
-#[derive(Debug)]
-pub(crate) struct RegistrationError;
+#[derive(Debug, PartialEq)]
+pub(crate) enum RegistrationError {
+    /// The prefix of the plugin being registered would be ambiguous
+    /// with the one of an already registered plugin
+    PrefixConflict { existing: String, attempted: String },
+    /// A catch-all segment(e.g. `*path`) can only be the last one
+    InvalidPrefix(String),
+    /// There is no plugin with the given name to replace
+    NotRegistered(String),
+    /// A plugin the one being registered depends on is not registered
+    MissingDependency { plugin: String, missing: String },
+    /// The plugins would depend on each other, the first one is repeated at the end
+    DependencyCycle(Vec<String>),
+    /// The plugin being removed is a dependency of another registered plugin
+    RequiredBy { plugin: String, dependent: String },
+    /// The plugin would replace a newer version of itself
+    Downgrade {
+        name: String,
+        registered: Version,
+        attempted: Version,
+    },
+    /// A field of the plugin's definition has an invalid value
+    InvalidField {
+        plugin: String,
+        field: &'static str,
+        reason: &'static str,
+    },
+}
 
 impl PluginRegistry {
     pub fn new() -> Self {
         PluginRegistry {
             plugins: HashMap::new(),
-            routes: PathTree::new(),
+            failed: HashMap::new(),
+            routes: HashMap::new(),
+            load_timeout: DEFAULT_LOAD_TIMEOUT,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            spawn: None,
+            allow_downgrades: false,
+            circuit_breaker: None,
+            #[cfg(all(feature = "std", feature = "serde"))]
+            persist_to: None,
         }
     }
 
-    pub fn match_vlugin(&self, path: &str) -> Option<PluginHandler> {
-        let (name, _) = self.routes.find(path)?;
-        let (plugin, handler) = self.plugins.get(name)?;
-        Some((plugin.clone(), handler.clone()))
+    /// Plugin without a namespace that handles requests to the `path` of the
+    /// `host`, the plugins of the exact host are tried first then the ones of
+    /// wildcard hosts from the most specific and last the plugins without a host
+    pub fn match_vlugin(
+        &self,
+        method: Method,
+        host: Option<&str>,
+        path: &str,
+    ) -> Option<(PluginHandler, Params)> {
+        self.match_variant(None, method, host, path, Choice::Balanced(0))
+    }
+
+    /// Same as [`Self::match_vlugin`] among the plugins of the `namespace`
+    /// choosing among weighted variants of the route as the `choice` says
+    pub fn match_variant(
+        &self,
+        namespace: Option<&str>,
+        method: Method,
+        host: Option<&str>,
+        path: &str,
+        choice: Choice<'_>,
+    ) -> Option<(PluginHandler, Params)> {
+        let (names, captures) = self.find_route(namespace, host, path)?;
+        let mut candidates = names
+            .iter()
+            .filter_map(|name| self.plugins.get(name))
+            .filter(|e| !e.disabled && e.plugin.serves(method));
+        let first = candidates.next()?;
+        let entry = match first.plugin.weight {
+            Some(weight) => {
+                let variants = core::iter::once((first, weight))
+                    .chain(candidates.filter_map(|e| Some((e, e.plugin.weight?))))
+                    .collect::<Vec<_>>();
+                // weights apply among the healthy variants while there are any
+                let now = super::time::unix_ms();
+                let healthy = variants
+                    .iter()
+                    .copied()
+                    .filter(|(e, _)| self.in_rotation(e, now))
+                    .collect::<Vec<_>>();
+                pick_variant(&healthy, choice)
+                    .or_else(|| pick_variant(&variants, choice))
+                    .unwrap_or(first)
+            }
+            None => first,
+        };
+        let params = captures
+            .into_iter()
+            .filter(|(key, _)| *key != REST)
+            .map(|(key, val)| (key.to_owned(), percent_decode(val)))
+            .collect();
+        Some((
+            (entry.plugin.clone(), entry.handler.clone()),
+            Params(params),
+        ))
+    }
+
+    /// Methods explicitly declared by the plugins that match the `path`
+    pub fn allowed_methods(
+        &self,
+        namespace: Option<&str>,
+        host: Option<&str>,
+        path: &str,
+    ) -> Option<Vec<Method>> {
+        let (names, _) = self.find_route(namespace, host, path)?;
+        let methods = names
+            .iter()
+            .filter_map(|name| self.plugins.get(name))
+            .filter(|e| !e.disabled)
+            .flat_map(|e| e.plugin.methods.iter().copied())
+            .collect();
+        Some(methods)
+    }
+
+    /// Plugins that are currently enabled
+    pub fn handlers(&self) -> Vec<PluginHandler> {
+        self.plugins
+            .values()
+            .filter(|e| !e.disabled)
+            .map(|e| (e.plugin.clone(), e.handler.clone()))
+            .collect()
+    }
+
+    /// The plugin with the given name if it's enabled
+    pub fn handler(&self, name: &str) -> Option<PluginHandler> {
+        self.plugins
+            .get(name)
+            .filter(|e| !e.disabled)
+            .map(|e| (e.plugin.clone(), e.handler.clone()))
     }
 
+    /// Adds a plugin to the registry, a plugin with the same name is replaced.
+    /// The handler warms up with [`Self::take_cold`] before its first request.
     pub fn register<H: Vlugin + 'static>(
         &mut self,
         plugin: VluginDef,
         handler: H,
-    ) -> Result<(), RegistrationError> {
-        if self.plugins.contains_key(&plugin.name) {
-            return Err(RegistrationError);
+    ) -> Result<Registration, RegistrationError> {
+        let name = plugin.name.clone();
+        let registration = self.register_loaded(plugin, handler, LoadInfo::default())?;
+        if let Some(entry) = self.plugins.get(&name) {
+            entry.cold.set(true);
         }
-        let prefix = "/".to_owned() + plugin.prefix_or_name();
+        Ok(registration)
+    }
 
-        self.routes.insert(&prefix, plugin.name.clone());
-        self.routes.insert(&(prefix + "/*"), plugin.name.clone());
-        self.plugins
-            .insert(plugin.name.clone(), (plugin, Rc::new(handler)));
+    /// Registers a plugin that was just loaded keeping track of how it went
+    pub fn register_loaded<H: Vlugin + 'static>(
+        &mut self,
+        plugin: VluginDef,
+        handler: H,
+        load: LoadInfo,
+    ) -> Result<Registration, RegistrationError> {
+        let route = plugin.prefix_or_name();
+        let segments = route.split('/').filter(|s| !s.is_empty());
+        if segments.rev().skip(1).any(|s| s.starts_with('*')) {
+            return Err(RegistrationError::InvalidPrefix(plugin.name));
+        }
+        self.check_registration(&plugin)?;
+
+        let conflict = self.plugins.values().map(|e| &e.plugin).find(|p| {
+            let (prefix, other_prefix) = (p.prefix_or_name(), plugin.prefix_or_name());
+            // same prefix is fine when each plugin serves different methods
+            p.name != plugin.name
+                && p.namespace == plugin.namespace
+                && same_host(p, &plugin)
+                && !are_variants(p, &plugin)
+                && same_route(prefix, other_prefix)
+                && (prefix != other_prefix || methods_overlap(p, &plugin))
+        });
+        if let Some(existing) = conflict {
+            return Err(RegistrationError::PrefixConflict {
+                existing: existing.name.clone(),
+                attempted: plugin.name,
+            });
+        }
+
+        let unchanged = |e: &Entry| {
+            e.plugin.prefix_or_name() == plugin.prefix_or_name()
+                && e.plugin.namespace == plugin.namespace
+                && same_host(&e.plugin, &plugin)
+                && e.plugin.methods == plugin.methods
+        };
+        let keeps_route = self.plugins.get(&plugin.name).map_or(false, unchanged);
+        let entry = Entry {
+            plugin,
+            handler: Rc::new(handler),
+            disabled: false,
+            load,
+            breaker: Breaker::default(),
+            active: Rc::default(),
+            stats: Stats::new(super::time::unix_ms()),
+            probe: Cell::new(None),
+            turn: Cell::new(0),
+            cold: Cell::new(false),
+            #[cfg(all(feature = "std", feature = "serde"))]
+            persistent: false,
+        };
+        self.failed.remove(&entry.plugin.name);
+        let previous = self.plugins.insert(entry.plugin.name.clone(), entry);
+        if !keeps_route {
+            self.rebuild_routes();
+        }
+        Ok(match previous {
+            Some(_) => Registration::Replaced,
+            None => Registration::Created,
+        })
+    }
+
+    /// Checks that don't need the plugin to be loaded so a plugin that can't
+    /// be registered is rejected before running any of its code
+    pub fn check_registration(&self, plugin: &VluginDef) -> Result<(), RegistrationError> {
+        if let Some((field, reason)) = plugin.invalid_field() {
+            return Err(RegistrationError::InvalidField {
+                plugin: plugin.name.clone(),
+                field,
+                reason,
+            });
+        }
+        self.check_dependencies(plugin)?;
+        self.check_version(plugin)
+    }
+
+    /// A registered plugin can be replaced with the same or a newer version
+    pub fn check_version(&self, plugin: &VluginDef) -> Result<(), RegistrationError> {
+        let registered = self
+            .plugins
+            .get(&plugin.name)
+            .and_then(|e| e.plugin.version.as_ref());
+        match (registered, &plugin.version) {
+            (Some(registered), Some(attempted))
+                if attempted < registered && !self.allow_downgrades =>
+            {
+                Err(RegistrationError::Downgrade {
+                    name: plugin.name.clone(),
+                    registered: registered.clone(),
+                    attempted: attempted.clone(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the dependencies of the plugin are registered and none of them
+    /// depends on the plugin
+    pub fn check_dependencies(&self, plugin: &VluginDef) -> Result<(), RegistrationError> {
+        if let Some(missing) = plugin
+            .depends_on
+            .iter()
+            .find(|dep| !self.plugins.contains_key(*dep))
+        {
+            return Err(RegistrationError::MissingDependency {
+                plugin: plugin.name.clone(),
+                missing: missing.clone(),
+            });
+        }
+        // only a plugin that replaces another one can close a cycle
+        let depends_on = |name: &str| {
+            if name == plugin.name {
+                Some(&plugin.depends_on)
+            } else {
+                self.plugins.get(name).map(|e| &e.plugin.depends_on)
+            }
+        };
+        let mut path = alloc::vec![plugin.name.clone()];
+        if reaches(&plugin.name, &plugin.depends_on, &depends_on, &mut path) {
+            return Err(RegistrationError::DependencyCycle(path));
+        }
         Ok(())
     }
 
+    /// Swaps the handler of an already registered plugin, requests that
+    /// were being handled by the old one finish normally.
+    pub fn replace<H: Vlugin + 'static>(
+        &mut self,
+        plugin: VluginDef,
+        handler: H,
+        load: LoadInfo,
+    ) -> Result<Registration, RegistrationError> {
+        if !self.plugins.contains_key(&plugin.name) {
+            return Err(RegistrationError::NotRegistered(plugin.name));
+        }
+        self.register_loaded(plugin, handler, load)
+    }
+
+    /// Keeps track of a plugin that couldn't be loaded, if there's a registered
+    /// plugin with that name it stays active and only the load info is updated
+    pub fn record_failure(&mut self, plugin: VluginDef, load: LoadInfo) {
+        match self.plugins.get_mut(&plugin.name) {
+            Some(entry) => entry.load = load,
+            None => {
+                self.failed.insert(plugin.name.clone(), (plugin, load));
+            }
+        }
+    }
+
+    /// Whether the plugin still has to warm up, from then on it's not cold
+    pub fn take_cold(&self, name: &str) -> bool {
+        self.plugins
+            .get(name)
+            .map_or(false, |e| !e.disabled && e.cold.replace(false))
+    }
+
+    /// Same as [`Self::take_cold`] for all the plugins that didn't warm up
+    pub fn take_all_cold(&self) -> Vec<PluginHandler> {
+        self.plugins
+            .values()
+            .filter(|e| !e.disabled && e.cold.replace(false))
+            .map(|e| (e.plugin.clone(), e.handler.clone()))
+            .collect()
+    }
+
+    /// Keeps how the warmup of a registered plugin went with its load info
+    pub fn record_warmup(&mut self, name: &str, warmup_ms: Option<u64>, error: Option<String>) {
+        if let Some(entry) = self.plugins.get_mut(name) {
+            entry.load.warmup_ms = warmup_ms;
+            entry.load.warmup_error = error;
+        }
+    }
+
+    /// How the last load of the plugin went, also when it failed
+    pub fn load_info(&self, name: &str) -> Option<&LoadInfo> {
+        self.plugins
+            .get(name)
+            .map(|e| &e.load)
+            .or_else(|| self.failed.get(name).map(|(_, load)| load))
+    }
+
+    /// Requests the plugin is handling, the runtime tracks them with the
+    /// returned counter until the plugin answers
+    pub fn active(&self, name: &str) -> Option<Rc<Drain>> {
+        self.plugins.get(name).map(|e| e.active.clone())
+    }
+
+    /// The registered plugin as it is before it gets replaced or removed to
+    /// wait for the requests it's handling
+    pub fn retiring(&self, name: &str) -> Option<Retiring> {
+        self.plugins.get(name).map(|e| Retiring {
+            handler: e.handler.clone(),
+            active: e.active.clone(),
+            timeout: self.drain_timeout,
+            spawn: self.spawn.clone(),
+        })
+    }
+
+    /// Removes the plugin with the given name freeing its prefix, plugins that
+    /// others depend on have to wait for those to be removed first
+    pub fn unregister(&mut self, name: &str) -> Result<bool, RegistrationError> {
+        if let Some(dependent) = self
+            .plugins
+            .values()
+            .find(|e| e.plugin.depends_on.iter().any(|dep| dep == name))
+        {
+            return Err(RegistrationError::RequiredBy {
+                plugin: name.into(),
+                dependent: dependent.plugin.name.clone(),
+            });
+        }
+        let removed = self.plugins.remove(name).is_some() | self.failed.remove(name).is_some();
+        if removed {
+            self.rebuild_routes();
+        }
+        Ok(removed)
+    }
+
+    /// Changes the share of requests a variant gets, returns `false` if
+    /// there's no plugin with that `name`
+    pub fn set_weight(&mut self, name: &str, weight: u32) -> bool {
+        self.plugins
+            .get_mut(name)
+            .map(|e| e.plugin.weight = Some(weight))
+            .is_some()
+    }
+
+    /// Takes a plugin out of rotation without unloading it or back in,
+    /// returns `false` if there's no plugin with that `name`
+    pub fn set_disabled(&mut self, name: &str, disabled: bool) -> bool {
+        self.plugins
+            .get_mut(name)
+            .map(|e| e.disabled = disabled)
+            .is_some()
+    }
+
+    /// Whether the circuit breaker of the plugin lets a request through,
+    /// otherwise it returns the time until it does
+    pub fn allow_request(&self, name: &str, now: Option<u64>) -> Result<(), Duration> {
+        match (self.circuit_breaker, self.plugins.get(name)) {
+            (Some(config), Some(e)) if !e.breaker.allow(&config, now) => {
+                Err(e.breaker.retry_after(&config, now))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts the outcome of a request towards tripping the circuit breaker
+    pub fn record_outcome(&self, name: &str, failed: bool, now: Option<u64>) {
+        if let (Some(config), Some(e)) = (self.circuit_breaker, self.plugins.get(name)) {
+            e.breaker.record(&config, failed, now);
+        }
+    }
+
+    /// Counts a request the plugin answered in its stats
+    pub fn record_stats(&self, name: &str, status: StatusCode, latency: Option<Duration>) {
+        if let Some(e) = self.plugins.get(name) {
+            e.stats.record(status, latency);
+        }
+    }
+
+    /// Requests, errors and latencies of the plugin since it was registered
+    pub fn stats(&self, name: &str) -> Option<Snapshot> {
+        let entry = self.plugins.get(name)?;
+        Some(entry.stats.snapshot(super::time::unix_ms()))
+    }
+
+    /// Starts counting the stats of the plugin from zero, returns `false` if
+    /// there's no plugin with that `name`
+    pub fn reset_stats(&self, name: &str) -> bool {
+        self.plugins
+            .get(name)
+            .map(|e| e.stats.reset(super::time::unix_ms()))
+            .is_some()
+    }
+
+    /// Plugins that are variants of a route(they have a weight) and get
+    /// their health checked to skip the unhealthy ones
+    pub fn replicas(&self) -> Vec<PluginHandler> {
+        self.plugins
+            .values()
+            .filter(|e| !e.disabled && e.plugin.weight.is_some())
+            .map(|e| (e.plugin.clone(), e.handler.clone()))
+            .collect()
+    }
+
+    /// Keeps the result of the latest health check of a variant
+    pub fn set_probe(&self, name: &str, health: Health) {
+        if let Some(e) = self.plugins.get(name).filter(|e| e.plugin.weight.is_some()) {
+            e.probe.set(Some(health));
+        }
+    }
+
+    /// Whether a variant gets requests, the unhealthy ones and the ones with an
+    /// open circuit are skipped. `None` if it's not a variant.
+    pub fn is_in_rotation(&self, name: &str) -> Option<bool> {
+        let entry = self
+            .plugins
+            .get(name)
+            .filter(|e| e.plugin.weight.is_some())?;
+        Some(self.in_rotation(entry, super::time::unix_ms()))
+    }
+
+    // a half open circuit is in rotation so a request can check if it recovered
+    fn in_rotation(&self, entry: &Entry, now: Option<u64>) -> bool {
+        let open = self.circuit_breaker.map_or(false, |config| {
+            entry.breaker.circuit(&config, now) == Circuit::Open
+        });
+        !open && entry.probe.get() != Some(Health::Unhealthy)
+    }
+
+    /// State of the circuit breaker of the plugin if there is one
+    pub fn circuit(&self, name: &str) -> Option<Circuit> {
+        let config = self.circuit_breaker?;
+        let entry = self.plugins.get(name)?;
+        Some(entry.breaker.circuit(&config, super::time::unix_ms()))
+    }
+
+    /// Whether the `path` would match a plugin if it wasn't disabled
+    pub fn is_disabled(&self, namespace: Option<&str>, host: Option<&str>, path: &str) -> bool {
+        self.find_route(namespace, host, path)
+            .map_or(false, |(names, _)| {
+                names
+                    .iter()
+                    .filter_map(|name| self.plugins.get(name))
+                    .any(|e| e.disabled)
+            })
+    }
+
+    // the route tree of the namespace with the most specific host pattern that has the path
+    fn find_route<'a, 'b>(
+        &'a self,
+        namespace: Option<&str>,
+        host: Option<&str>,
+        path: &'b str,
+    ) -> Option<(&'a Vec<String>, Vec<(&'a str, &'b str)>)> {
+        let host = host.map(normalize_host);
+        let mut trees = self
+            .routes
+            .iter()
+            .filter(|((ns, _), _)| ns.as_deref() == namespace)
+            .filter_map(|((_, pattern), tree)| {
+                let specificity = match (pattern, &host) {
+                    (None, _) => 0,
+                    (Some(pattern), Some(host)) => host_specificity(pattern, host)?,
+                    (Some(_), None) => return None,
+                };
+                Some((specificity, tree))
+            })
+            .collect::<Vec<_>>();
+        trees.sort_by(|(a, _), (b, _)| b.cmp(a));
+        trees.into_iter().find_map(|(_, tree)| tree.find(path))
+    }
+
+    // routes can't be removed from the tree so it's created again
+    fn rebuild_routes(&mut self) {
+        let mut by_prefix = HashMap::<_, Vec<_>>::new();
+        for Entry { plugin, .. } in self.plugins.values() {
+            let host = plugin.host.as_deref().map(normalize_host);
+            by_prefix
+                .entry((plugin.namespace.clone(), host, plugin.prefix_or_name()))
+                .or_default()
+                .push(plugin.name.clone());
+        }
+        let mut routes = HashMap::<_, PathTree<_>>::new();
+        for ((namespace, host, prefix), mut names) in by_prefix {
+            // variants are chosen in the same order every time
+            names.sort();
+            let routes = routes
+                .entry((namespace, host))
+                .or_insert_with(PathTree::new);
+            let prefix = "/".to_owned() + prefix;
+            if !has_catch_all(&prefix) {
+                routes.insert(&(prefix.clone() + "/*" + REST), names.clone());
+            }
+            routes.insert(&prefix, names);
+        }
+        self.routes = routes;
+    }
+
     #[cfg(feature = "serde")]
     pub fn get_handler<L: super::Loader>(
         registry: Rc<core::cell::RefCell<Self>>,
         loader: Rc<L>,
+        auth: Option<RegistryAuth>,
     ) -> impl crate::Vlugin {
-        RegistryHandler { registry, loader }
+        RegistryHandler {
+            registry,
+            loader,
+            auth,
+        }
+    }
+}
+
+/// A plugin that no longer gets requests but can still be handling some
+pub(crate) struct Retiring {
+    handler: Rc<dyn Vlugin>,
+    active: Rc<Drain>,
+    timeout: Duration,
+    spawn: Option<Rc<dyn Fn(super::BoxedFuture<'static, ()>)>>,
+}
+
+impl Retiring {
+    /// Waits in the background up to the drain timeout for the requests in
+    /// flight to finish, the handler is dropped after them. Without a spawner
+    /// it's dropped right away, the requests hold on to it until they finish.
+    pub fn retire(self) {
+        if let Some(spawn) = self.spawn.clone() {
+            spawn(Box::pin(async move {
+                super::time::timeout(self.timeout, self.active.drained()).await;
+                drop(self.handler);
+            }));
+        }
+    }
+}
+
+fn has_catch_all(route: &str) -> bool {
+    route
+        .rsplit('/')
+        .find(|s| !s.is_empty())
+        .map_or(false, |s| s.starts_with('*'))
+}
+
+// Hosts are case insensitive and the port is not part of the pattern
+fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let without_port = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    without_port.to_lowercase()
+}
+
+// How specific the host pattern matching the host is, exact hosts
+// are preferred over wildcards and longer wildcards over shorter ones
+fn host_specificity(pattern: &str, host: &str) -> Option<usize> {
+    match pattern.strip_prefix('*') {
+        Some(suffix) => {
+            let matches = suffix.starts_with('.') && host.len() > suffix.len();
+            (matches && host.ends_with(suffix)).then(|| suffix.len())
+        }
+        None => (pattern == host).then(|| usize::MAX),
+    }
+}
+
+/// Whether the host(that can have a port) matches the pattern of a host
+/// like `example.com` or `*.example.com`
+pub(crate) fn host_matches(pattern: &str, host: &str) -> bool {
+    host_specificity(&normalize_host(pattern), &normalize_host(host)).is_some()
+}
+
+fn same_host(a: &VluginDef, b: &VluginDef) -> bool {
+    a.host.as_deref().map(normalize_host) == b.host.as_deref().map(normalize_host)
+}
+
+fn are_variants(a: &VluginDef, b: &VluginDef) -> bool {
+    a.weight.is_some() && b.weight.is_some() && a.prefix_or_name() == b.prefix_or_name()
+}
+
+// Variant of the load balancer of the route unless the request sticks to one,
+// a preferred variant that is gone or has no weight is rebalanced
+fn pick_variant<'a>(variants: &[(&'a Entry, u32)], choice: Choice<'_>) -> Option<&'a Entry> {
+    let seed = match choice {
+        Choice::Sticky(seed) => return pick_weighted(variants, seed),
+        Choice::Prefer(name, seed) => {
+            let preferred = variants
+                .iter()
+                .find(|(e, weight)| e.plugin.name == name && *weight > 0);
+            if let Some((entry, _)) = preferred {
+                return Some(*entry);
+            }
+            seed
+        }
+        Choice::Balanced(seed) => seed,
+    };
+    let balancer = variants
+        .iter()
+        .find_map(|(e, _)| e.plugin.load_balancer)
+        .unwrap_or_default();
+    match balancer {
+        LoadBalancer::RoundRobin => round_robin(variants),
+        LoadBalancer::Random => pick_weighted(variants, seed),
+        LoadBalancer::LeastConnections => least_connections(variants),
+    }
+}
+
+// Smooth weighted round-robin, the turns of a variant with more weight are
+// spread among the turns of the others instead of coming one after another
+fn round_robin<'a>(variants: &[(&'a Entry, u32)]) -> Option<&'a Entry> {
+    let total = variants.iter().map(|(_, w)| i64::from(*w)).sum::<i64>();
+    let mut next: Option<&Entry> = None;
+    for (entry, weight) in variants.iter().filter(|(_, w)| *w > 0) {
+        entry.turn.set(entry.turn.get() + i64::from(*weight));
+        if next.map_or(true, |next| entry.turn.get() > next.turn.get()) {
+            next = Some(*entry);
+        }
+    }
+    let next = next?;
+    next.turn.set(next.turn.get() - total);
+    Some(next)
+}
+
+// Ties go to the variant listed first
+fn least_connections<'a>(variants: &[(&'a Entry, u32)]) -> Option<&'a Entry> {
+    variants
+        .iter()
+        .filter(|(_, w)| *w > 0)
+        .min_by(|(a, a_weight), (b, b_weight)| {
+            // (in flight + 1) / weight compared without dividing
+            let load = |e: &Entry, other_weight: u32| {
+                (e.active.in_flight() as u64 + 1) * u64::from(other_weight)
+            };
+            load(a, *b_weight).cmp(&load(b, *a_weight))
+        })
+        .map(|(entry, _)| *entry)
+}
+
+// Variant where the seed lands when laying the weights one after the other
+fn pick_weighted<'a>(variants: &[(&'a Entry, u32)], seed: u64) -> Option<&'a Entry> {
+    let total = variants.iter().map(|(_, w)| u64::from(*w)).sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+    let mut point = seed % total;
+    variants.iter().find_map(|(entry, weight)| {
+        let weight = u64::from(*weight);
+        if point < weight {
+            return Some(*entry);
+        }
+        point -= weight;
+        None
+    })
+}
+
+// Depth first search of the `target` through the dependencies
+fn reaches<'a>(
+    target: &str,
+    deps: &'a [String],
+    depends_on: &impl Fn(&str) -> Option<&'a Vec<String>>,
+    path: &mut Vec<String>,
+) -> bool {
+    for dep in deps {
+        path.push(dep.clone());
+        if dep == target {
+            return true;
+        }
+        // cycles that don't involve the target were already rejected
+        let next = depends_on(dep).map_or(&[][..], |d| &d[..]);
+        if reaches(target, next, depends_on, path) {
+            return true;
+        }
+        path.pop();
+    }
+    false
+}
+
+fn methods_overlap(a: &VluginDef, b: &VluginDef) -> bool {
+    a.methods.is_empty() || b.methods.is_empty() || a.methods.iter().any(|m| b.serves(*m))
+}
+
+#[derive(PartialEq)]
+enum Segment<'a> {
+    Static(&'a str),
+    Param,
+    CatchAll,
+}
+
+fn shape(route: &str) -> Vec<Segment<'_>> {
+    let mut shape = route
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.as_bytes()[0] {
+            b':' => Segment::Param,
+            b'*' => Segment::CatchAll,
+            _ => Segment::Static(s),
+        })
+        .collect::<Vec<_>>();
+    if shape.last() != Some(&Segment::CatchAll) {
+        shape.push(Segment::CatchAll);
+    }
+    shape
+}
+
+/// Two routes are the same when they have the same static segments and
+/// parameters in the same positions, regardless of how they are named.
+/// Routes without an explicit catch-all have an implicit one at the end.
+fn same_route(a: &str, b: &str) -> bool {
+    shape(a) == shape(b)
+}
+
+/// Values captured from the dynamic segments(e.g. `:id`) of the prefix
+/// a plugin is mounted on. The runtime sets them as an extension of the
+/// request passed down to the plugin.
+///
+/// ```
+/// # use valor_core::{http, runtime::Params};
+/// # fn handle(req: http::Request) {
+/// let id = req.ext::<Params>().and_then(|p| p.get("id"));
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    /// Value of the parameter named `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 }
 
+/// Removes from `path` as many segments as the route of the plugin has
+/// (not counting a catch-all), what is left is the path the plugin sees.
+pub(crate) fn strip_route(route: &str, path: &str) -> String {
+    let segments = route
+        .split('/')
+        .filter(|s| !s.is_empty() && !s.starts_with('*'))
+        .count();
+    path.trim_start_matches('/')
+        .splitn(segments + 1, '/')
+        .nth(segments)
+        .map(|rest| "/".to_owned() + rest)
+        .unwrap_or_default()
+}
+
+fn percent_decode(val: &str) -> String {
+    let hex = |b: u8| (b as char).to_digit(16);
+    let bytes = val.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(h), Some(l)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                out.push((h * 16 + l) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
 #[cfg(feature = "serde")]
 use alloc::boxed::Box;
 #[cfg(feature = "serde")]
 struct RegistryHandler<L> {
     registry: Rc<core::cell::RefCell<PluginRegistry>>,
     loader: Rc<L>,
+    auth: Option<RegistryAuth>,
+}
+
+/// Protects the registry endpoint, requests that modify the registry need an
+/// `Authorization: Bearer <token>` header with the token. Listing the plugins
+/// only needs it when `protect_list` is set.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone)]
+pub struct RegistryAuth {
+    pub token: String,
+    pub protect_list: bool,
+}
+
+#[cfg(feature = "serde")]
+impl RegistryAuth {
+    pub fn new(token: impl Into<String>) -> Self {
+        RegistryAuth {
+            token: token.into(),
+            protect_list: false,
+        }
+    }
+
+    pub(crate) fn allows(&self, request: &crate::http::Request) -> bool {
+        use crate::http::{headers, Method};
+
+        if !self.protect_list && matches!(request.method(), Method::Get | Method::Head) {
+            return true;
+        }
+        request
+            .header(headers::AUTHORIZATION)
+            .and_then(|auth| auth.as_str().strip_prefix("Bearer "))
+            .map_or(false, |token| {
+                super::constant_time_eq(token.trim().as_bytes(), self.token.as_bytes())
+            })
+    }
+}
+
+// A change to the registry is applied even if it couldn't be saved, the
+// client is told with a `Warning` header so it can retry later
+#[cfg(feature = "serde")]
+fn with_warning(mut res: crate::http::Response, warning: Option<String>) -> crate::http::Response {
+    if let Some(warning) = warning {
+        let warning = alloc::format!("199 valor \"{}\"", warning.replace('"', "'"));
+        res.insert_header("warning", warning);
+    }
+    res
 }
 
 #[cfg(feature = "serde")]
@@ -71,45 +978,186 @@ where
 {
     async fn on_msg(&self, msg: crate::Message) -> Result<crate::Answer, crate::Error> {
         use crate::{
-            http::{headers, mime, Error, Method::*, Response, StatusCode},
+            http::{self, headers, mime, Error, Method::*, Response, StatusCode},
             Message,
         };
-        use alloc::vec::Vec;
         use core::result::Result::Ok;
 
         let mut request = match msg {
             Message::Http(req) => req,
             Message::Ping => return Err(crate::Error::NotSupported),
         };
+        if !self
+            .auth
+            .as_ref()
+            .map_or(true, |auth| auth.allows(&request))
+        {
+            let detail = "Missing or invalid registry token";
+            let mut res = super::problem::response(StatusCode::Unauthorized, detail);
+            res.insert_header(headers::WWW_AUTHENTICATE, "Bearer");
+            return Ok(res.into());
+        }
+
+        let path = request.url().path().trim_matches('/').to_owned();
+        let toggle = path
+            .rsplit_once('/')
+            .filter(|(_, action)| *action == "enable" || *action == "disable");
 
-        match request.method() {
-            Get => {
+        match (request.method(), toggle) {
+            (Post, Some((name, action))) => {
+                let disabled = action == "disable";
+                let toggled = self.registry.borrow_mut().set_disabled(name, disabled);
+                let res = if toggled {
+                    let warning = self.registry.borrow().persist();
+                    with_warning(StatusCode::NoContent.into(), warning)
+                } else {
+                    not_registered(name)
+                };
+                Ok(res.into())
+            }
+            (Post, _) if path.ends_with("/weight") => {
+                #[derive(serde::Deserialize)]
+                struct Weight {
+                    weight: u32,
+                }
+                let name = path.trim_end_matches("/weight");
+                let Weight { weight } = request.body_json().await?;
+                let updated = self.registry.borrow_mut().set_weight(name, weight);
+                let res = if updated {
+                    let warning = self.registry.borrow().persist();
+                    with_warning(StatusCode::NoContent.into(), warning)
+                } else {
+                    not_registered(name)
+                };
+                Ok(res.into())
+            }
+            (Get, _) if path.ends_with("/stats") => {
+                let name = path.trim_end_matches("/stats");
+                match self.registry.borrow().stats(name) {
+                    Some(stats) => {
+                        let mut res = Response::new(StatusCode::Ok);
+                        res.set_body(http::Body::from_json(&stats)?);
+                        Ok(res.into())
+                    }
+                    None => Ok(not_registered(name).into()),
+                }
+            }
+            (Delete, _) if path.ends_with("/stats") => {
+                let name = path.trim_end_matches("/stats");
+                let res = if self.registry.borrow().reset_stats(name) {
+                    StatusCode::NoContent.into()
+                } else {
+                    not_registered(name)
+                };
+                Ok(res.into())
+            }
+            (Get, _) if !path.is_empty() => match self.registry.borrow().get(&path) {
+                Some(status) => {
+                    let mut res = Response::new(StatusCode::Ok);
+                    res.set_body(http::Body::from_json(&status)?);
+                    Ok(res.into())
+                }
+                None => Ok(not_registered(&path).into()),
+            },
+            (Get, _) => {
+                let query = ListQuery::from_url(request.url())?;
                 let reg = self.registry.borrow();
-                let plugins = reg.plugins.values().map(|(p, _)| p).collect::<Vec<_>>();
-                serde_json::to_vec(&plugins)
-                    .map(|list| {
-                        let mut res: Response = list.into();
-                        res.append_header(headers::CONTENT_TYPE, mime::JSON);
-                        res.into()
-                    })
-                    .map_err(|e| Error::new(StatusCode::InternalServerError, e).into())
-            }
-            Post => {
-                let mut plugin: VluginDef = request.body_json().await?;
+                let list = if query.verbose {
+                    query.list(reg.status(), |s| &s.plugin)
+                } else {
+                    let plugins = reg.plugins.values().map(PluginState::from).collect();
+                    query.list(plugins, |s| &s.plugin)
+                };
+                list.map(|list| {
+                    let mut res: Response = list.into();
+                    res.append_header(headers::CONTENT_TYPE, mime::JSON);
+                    res.into()
+                })
+                .map_err(|e| Error::new(StatusCode::InternalServerError, e).into())
+            }
+            (Post, _) => {
+                let plugin: VluginDef = request.body_json().await?;
+                // a plugin that can't be registered isn't loaded for nothing
+                let checked = self.registry.borrow().check_registration(&plugin);
+                if let Err(err) = checked {
+                    return Ok(error_response(err)?.into());
+                }
+                let (handler, load) = self.load(&plugin).await.map_err(load_error)?;
                 let name = plugin.name.clone();
-                let factory = self.loader.load(&plugin).await?;
-                let handler = factory(plugin.config.take()).await?;
-                self.registry
+                let retiring = self.registry.borrow().retiring(&name);
+                let registered = self
+                    .registry
                     .borrow_mut()
-                    .register(plugin, handler)
-                    .map_err(|_| Error::from_str(StatusCode::Conflict, name + " already exists"))?;
-                let res: Response = StatusCode::Created.into();
+                    .register_loaded(plugin, handler, load);
+                let res = match registered {
+                    Ok(registration) => {
+                        #[cfg(feature = "std")]
+                        self.registry.borrow_mut().set_persistent(&name);
+                        let warning = self.registry.borrow().persist();
+                        if let Some(retiring) = retiring {
+                            retiring.retire();
+                        }
+                        let status = match registration {
+                            Registration::Replaced => StatusCode::Ok,
+                            Registration::Created => StatusCode::Created,
+                        };
+                        with_warning(status.into(), warning)
+                    }
+                    Err(err) => error_response(err)?,
+                };
                 Ok(res.into())
             }
-            _ => {
-                let res: Response = StatusCode::MethodNotAllowed.into();
+            (Put, _) => {
+                let plugin: VluginDef = request.body_json().await?;
+                if !self.registry.borrow().plugins.contains_key(&plugin.name) {
+                    return Ok(not_registered(&plugin.name).into());
+                }
+                let checked = self.registry.borrow().check_registration(&plugin);
+                if let Err(err) = checked {
+                    return Ok(error_response(err)?.into());
+                }
+                // the old handler keeps serving requests while the new one loads
+                let (handler, load) = self.load(&plugin).await.map_err(load_error)?;
+                let name = plugin.name.clone();
+                let retiring = self.registry.borrow().retiring(&name);
+                let replaced = self.registry.borrow_mut().replace(plugin, handler, load);
+                let res = match replaced {
+                    Ok(_) => {
+                        #[cfg(feature = "std")]
+                        self.registry.borrow_mut().set_persistent(&name);
+                        let warning = self.registry.borrow().persist();
+                        // the old handler finishes its requests in the background
+                        if let Some(retiring) = retiring {
+                            retiring.retire();
+                        }
+                        with_warning(StatusCode::Ok.into(), warning)
+                    }
+                    Err(err) => error_response(err)?,
+                };
                 Ok(res.into())
             }
+            (Delete, _) => {
+                let retiring = self.registry.borrow().retiring(&path);
+                let removed = self.registry.borrow_mut().unregister(&path);
+                let removed = match removed {
+                    Ok(removed) => removed,
+                    Err(err) => return Ok(error_response(err)?.into()),
+                };
+                let res = if removed {
+                    let warning = self.registry.borrow().persist();
+                    if let Some(retiring) = retiring {
+                        retiring.retire();
+                    }
+                    with_warning(StatusCode::NoContent.into(), warning)
+                } else {
+                    not_registered(&path)
+                };
+                Ok(res.into())
+            }
+            (method, toggle) => {
+                let allow = allowed_methods(&path, toggle.is_some());
+                Ok(super::problem::method_not_allowed(method, allow).into())
+            }
         }
     }
 
@@ -121,24 +1169,435 @@ where
     }
 }
 
+/// Options to list plugins given in the query string, when any of the `limit`,
+/// `offset`, `prefix`, `name` or `namespace` parameters is given the plugins are returned
+/// as a page like `{"items": [], "total": 0, "offset": 0, "limit": 10}`
+/// otherwise as a plain list.
+#[cfg(feature = "serde")]
+#[derive(Debug, Default, PartialEq)]
+struct ListQuery {
+    verbose: bool,
+    paginated: bool,
+    offset: usize,
+    limit: Option<usize>,
+    prefix: Option<String>,
+    name: Option<String>,
+    namespace: Option<String>,
+}
+
+#[cfg(feature = "serde")]
+impl ListQuery {
+    fn from_url(url: &crate::http::Url) -> Result<Self, crate::http::Error> {
+        use crate::http::{Error, StatusCode};
+
+        let number = |key: &str, val: &str| {
+            val.parse::<usize>().map_err(|_| {
+                let msg = alloc::format!("{} must be a positive number", key);
+                Error::from_str(StatusCode::BadRequest, msg)
+            })
+        };
+        let mut query = ListQuery::default();
+        for (key, val) in url.query_pairs() {
+            match key.as_ref() {
+                "verbose" => query.verbose = val == "true",
+                "offset" => query.offset = number(&key, &val)?,
+                "limit" => query.limit = Some(number(&key, &val)?),
+                "prefix" => query.prefix = Some(val.trim_matches('/').to_owned()),
+                "name" => query.name = Some(val.into_owned()),
+                "namespace" => query.namespace = Some(val.into_owned()),
+                _ => continue,
+            }
+            query.paginated |= key != "verbose";
+        }
+        Ok(query)
+    }
+
+    // Plugins sorted by name so pages are consistent across calls
+    fn list<T: serde::Serialize>(
+        &self,
+        mut items: Vec<T>,
+        plugin: impl Fn(&T) -> &VluginDef,
+    ) -> serde_json::Result<Vec<u8>> {
+        items.retain(|item| {
+            let plugin = plugin(item);
+            self.name
+                .as_ref()
+                .map_or(true, |n| plugin.name.contains(n.as_str()))
+                && self
+                    .prefix
+                    .as_ref()
+                    .map_or(true, |p| plugin.prefix_or_name().starts_with(p.as_str()))
+                && self
+                    .namespace
+                    .as_ref()
+                    .map_or(true, |ns| plugin.namespace.as_ref() == Some(ns))
+        });
+        items.sort_by(|a, b| plugin(a).name.cmp(&plugin(b).name));
+        if !self.paginated {
+            return serde_json::to_vec(&items);
+        }
+        let total = items.len();
+        let page = items
+            .iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect::<Vec<_>>();
+        serde_json::to_vec(&serde_json::json!({
+            "items": page,
+            "total": total,
+            "offset": self.offset,
+            "limit": self.limit,
+        }))
+    }
+}
+
+/// How plugins are listed by the registry endpoint, the configuration
+/// is only part of the detailed listing
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct PluginState {
+    #[serde(flatten)]
+    plugin: VluginDef,
+    #[serde(skip_serializing_if = "core::ops::Not::not")]
+    disabled: bool,
+}
+
+#[cfg(feature = "serde")]
+impl From<&Entry> for PluginState {
+    fn from(entry: &Entry) -> Self {
+        PluginState {
+            plugin: VluginDef {
+                config: None,
+                ..entry.plugin.clone()
+            },
+            disabled: entry.disabled,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Active,
+    Failed,
+    Disabled,
+}
+
+/// Detailed listing of plugins including the ones that failed loading
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct PluginStatus<'a> {
+    // with the secrets of the configuration redacted
+    #[serde(flatten)]
+    plugin: VluginDef,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loaded_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    load_duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warmup_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warmup_error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit: Option<Circuit>,
+    /// Requests the plugin is handling at the moment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_requests: Option<usize>,
+}
+
+#[cfg(feature = "serde")]
+impl<'a> PluginStatus<'a> {
+    fn new(plugin: &'a VluginDef, status: Status, load: &'a LoadInfo) -> Self {
+        PluginStatus {
+            plugin: plugin.redacted(),
+            status,
+            loaded_at: load.loaded_at,
+            load_duration_ms: load.duration_ms,
+            error: load.error.as_deref(),
+            warmup_ms: load.warmup_ms,
+            warmup_error: load.warmup_error.as_deref(),
+            circuit: None,
+            active_requests: None,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl PluginRegistry {
+    /// Keeps the plugin among the ones a persistent registry saves
+    #[cfg(feature = "std")]
+    pub fn set_persistent(&mut self, name: &str) {
+        if let Some(entry) = self.plugins.get_mut(name) {
+            entry.persistent = true;
+        }
+    }
+
+    // Saves the plugins registered through the endpoint when the registry is
+    // persistent, when it fails the change stays and the reason is returned
+    fn persist(&self) -> Option<String> {
+        #[cfg(feature = "std")]
+        if let Some(path) = &self.persist_to {
+            let plugins = self
+                .plugins
+                .values()
+                .filter(|e| e.persistent && e.plugin.r#type != super::VluginType::Static)
+                .map(|e| (&e.plugin, e.disabled));
+            if let Err(err) = super::persist::save(path, plugins) {
+                return Some(alloc::format!("Couldn't save the registry: {}", err));
+            }
+        }
+        None
+    }
+
+    fn entry_status<'a>(&self, entry: &'a Entry) -> PluginStatus<'a> {
+        let status = if entry.disabled {
+            Status::Disabled
+        } else {
+            Status::Active
+        };
+        PluginStatus {
+            circuit: self.circuit(&entry.plugin.name),
+            active_requests: Some(entry.active.in_flight()),
+            ..PluginStatus::new(&entry.plugin, status, &entry.load)
+        }
+    }
+
+    /// Definition and status of the plugin with the given name
+    fn get(&self, name: &str) -> Option<PluginStatus<'_>> {
+        match self.plugins.get(name) {
+            Some(entry) => Some(self.entry_status(entry)),
+            None => self
+                .failed
+                .get(name)
+                .map(|(plugin, load)| PluginStatus::new(plugin, Status::Failed, load)),
+        }
+    }
+
+    fn status(&self) -> Vec<PluginStatus<'_>> {
+        let active = self.plugins.values().map(|e| self.entry_status(e));
+        let failed = self
+            .failed
+            .values()
+            .map(|(plugin, load)| PluginStatus::new(plugin, Status::Failed, load));
+        active.chain(failed).collect()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<L: super::Loader> RegistryHandler<L> {
+    async fn load(&self, plugin: &VluginDef) -> Result<(Box<dyn Vlugin>, LoadInfo), super::Error> {
+        let timeout = self.registry.borrow().load_timeout;
+        let (handler, load) = super::load_vlugin(&*self.loader, plugin, timeout).await;
+        match handler {
+            Ok(handler) => Ok((handler, load)),
+            Err(err) => {
+                self.registry
+                    .borrow_mut()
+                    .record_failure(plugin.clone(), load);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn load_error(err: super::Error) -> crate::http::Error {
+    use crate::http::{Error, StatusCode};
+    let status = match err {
+        super::Error::LoadTimeout(_) => StatusCode::GatewayTimeout,
+        super::Error::UntrustedVlugin(_) => StatusCode::Forbidden,
+        _ => StatusCode::UnprocessableEntity,
+    };
+    Error::from_str(status, err)
+}
+
+// Methods the registry answers for a path, the list of plugins, one of them
+// or one of their actions
+#[cfg(feature = "serde")]
+fn allowed_methods(path: &str, toggle: bool) -> &'static str {
+    match path {
+        "" => "GET, POST, PUT",
+        _ if toggle || path.ends_with("/weight") => "POST",
+        _ if path.ends_with("/stats") => "GET, DELETE",
+        _ => "GET, POST, PUT, DELETE",
+    }
+}
+
+#[cfg(feature = "serde")]
+fn not_registered(name: &str) -> crate::http::Response {
+    let detail = name.to_owned() + " is not registered";
+    super::problem::response(crate::http::StatusCode::NotFound, detail)
+}
+
+#[cfg(feature = "serde")]
+fn error_response(err: RegistrationError) -> Result<crate::http::Response, crate::Error> {
+    use crate::http::{Body, Error, Response, StatusCode};
+    match err {
+        RegistrationError::PrefixConflict {
+            existing,
+            attempted,
+        } => {
+            let mut res = Response::new(StatusCode::Conflict);
+            res.set_body(Body::from_json(&serde_json::json!({
+                "existing": existing,
+                "attempted": attempted,
+            }))?);
+            Ok(res)
+        }
+        RegistrationError::InvalidPrefix(name) => {
+            let msg = name + " has a catch-all that is not the last segment";
+            Err(Error::from_str(StatusCode::BadRequest, msg).into())
+        }
+        RegistrationError::NotRegistered(name) => {
+            Err(Error::from_str(StatusCode::NotFound, name + " is not registered").into())
+        }
+        RegistrationError::MissingDependency { plugin, missing } => {
+            let msg = alloc::format!("{} depends on {} that is not registered", plugin, missing);
+            Err(Error::from_str(StatusCode::FailedDependency, msg).into())
+        }
+        RegistrationError::DependencyCycle(cycle) => {
+            let msg = "Dependency cycle ".to_owned() + &cycle.join(" -> ");
+            Err(Error::from_str(StatusCode::Conflict, msg).into())
+        }
+        RegistrationError::RequiredBy { plugin, dependent } => {
+            let msg = alloc::format!("{} depends on {}", dependent, plugin);
+            Err(Error::from_str(StatusCode::Conflict, msg).into())
+        }
+        RegistrationError::Downgrade {
+            name,
+            registered,
+            attempted,
+        } => {
+            let mut res = Response::new(StatusCode::Conflict);
+            res.set_body(Body::from_json(&serde_json::json!({
+                "reason": alloc::format!("{} {} would downgrade the registered {}", name, attempted, registered),
+                "registered": registered,
+                "attempted": attempted,
+            }))?);
+            Ok(res)
+        }
+        RegistrationError::InvalidField {
+            plugin,
+            field,
+            reason,
+        } => {
+            let mut res = Response::new(StatusCode::BadRequest);
+            res.set_body(Body::from_json(&serde_json::json!({
+                "reason": alloc::format!("{} of plugin {:?} {}", field, plugin, reason),
+                "plugin": plugin,
+                "field": field,
+            }))?);
+            Ok(res)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http::Method::*;
 
     #[test]
-    fn register_multiple_times_gives_an_error() {
+    fn register_multiple_times_replaces_plugin() {
         let mut registry = PluginRegistry::new();
-        registry.register("foo".into(), ()).unwrap();
         let res = registry.register("foo".into(), ());
-        assert!(res.is_err());
+        assert_eq!(res, Ok(Registration::Created));
+        let res = registry.register(("foo", "/bar").into(), ());
+        assert_eq!(res, Ok(Registration::Replaced));
         assert_eq!(registry.plugins.len(), 1);
+        assert!(registry.match_vlugin(Get, None, "/_foo").is_none());
+        assert!(registry.match_vlugin(Get, None, "/bar").is_some());
+    }
+
+    #[test]
+    fn register_conflicting_prefix_gives_an_error() {
+        let mut registry = PluginRegistry::new();
+        registry.register(("foo", "/api").into(), ()).unwrap();
+        let res = registry.register(("bar", "/api/").into(), ());
+        assert_eq!(
+            res,
+            Err(RegistrationError::PrefixConflict {
+                existing: "foo".into(),
+                attempted: "bar".into()
+            })
+        );
+        assert_eq!(registry.plugins.len(), 1);
+    }
+
+    #[test]
+    fn register_prefix_with_different_params_names_conflicts() {
+        let mut registry = PluginRegistry::new();
+        registry.register(("foo", "/users/:id").into(), ()).unwrap();
+        let res = registry.register(("bar", "/users/:user").into(), ());
+        assert!(res.is_err());
+        registry
+            .register(("baz", "/users/:id/orders").into(), ())
+            .unwrap();
+        registry.register(("qux", "/users/me").into(), ()).unwrap();
+    }
+
+    #[test]
+    fn unregister_frees_the_prefix() {
+        let mut registry = PluginRegistry::new();
+        registry.register(("foo", "/api").into(), ()).unwrap();
+        assert_eq!(registry.unregister("foo"), Ok(true));
+        assert_eq!(registry.unregister("foo"), Ok(false));
+        assert!(registry.match_vlugin(Get, None, "/api").is_none());
+        registry.register(("bar", "/api").into(), ()).unwrap();
+        assert!(registry.match_vlugin(Get, None, "/api").is_some());
+    }
+
+    #[test]
+    fn replace_requires_registered_plugin() {
+        let mut registry = PluginRegistry::new();
+        let res = registry.replace("foo".into(), (), LoadInfo::default());
+        assert_eq!(res, Err(RegistrationError::NotRegistered("foo".into())));
+        registry.register("foo".into(), ()).unwrap();
+        registry.register("bar".into(), ()).unwrap();
+        registry
+            .replace("foo".into(), (), LoadInfo::default())
+            .unwrap();
+        assert!(registry.match_vlugin(Get, None, "/_foo").is_some());
+        assert!(registry.match_vlugin(Get, None, "/_bar").is_some());
+    }
+
+    #[test]
+    fn disabled_plugins_dont_match() {
+        let mut registry = PluginRegistry::new();
+        registry.register("foo".into(), ()).unwrap();
+        assert!(registry.set_disabled("foo", true));
+        assert!(registry.match_vlugin(Get, None, "/_foo").is_none());
+        assert!(registry.is_disabled(None, None, "/_foo/bar"));
+        assert!(registry.set_disabled("foo", false));
+        assert!(registry.match_vlugin(Get, None, "/_foo").is_some());
+        assert!(!registry.set_disabled("bar", true));
+    }
+
+    #[test]
+    fn failed_load_is_recorded_until_registered() {
+        let mut registry = PluginRegistry::new();
+        let load = LoadInfo {
+            error: Some("oops".into()),
+            ..LoadInfo::default()
+        };
+        registry.record_failure("foo".into(), load.clone());
+        assert_eq!(registry.failed["foo"].1, load);
+        registry.register("foo".into(), ()).unwrap();
+        assert!(registry.failed.is_empty());
+        registry.record_failure("foo".into(), load.clone());
+        assert!(registry.failed.is_empty());
+        assert_eq!(registry.plugins["foo"].load, load);
     }
 
     #[test]
     fn match_with_leading_slash() {
         let mut registry = PluginRegistry::new();
         registry.register("foo".into(), ()).unwrap();
-        let handler = registry.match_vlugin("/_foo/");
+        let handler = registry.match_vlugin(Get, None, "/_foo/");
         assert!(handler.is_some());
     }
 
@@ -146,7 +1605,7 @@ mod tests {
     fn match_without_leading_slash() {
         let mut registry = PluginRegistry::new();
         registry.register("foo".into(), ()).unwrap();
-        let handler = registry.match_vlugin("/_foo");
+        let handler = registry.match_vlugin(Get, None, "/_foo");
         assert!(handler.is_some());
     }
 
@@ -154,11 +1613,478 @@ mod tests {
     fn match_all_after_prefix() {
         let mut registry = PluginRegistry::new();
         registry.register("foo".into(), ()).unwrap();
-        let handler = registry.match_vlugin("/_foo/bar");
+        let handler = registry.match_vlugin(Get, None, "/_foo/bar");
         assert!(handler.is_some());
-        let handler = registry.match_vlugin("/_foo/bar/");
+        let handler = registry.match_vlugin(Get, None, "/_foo/bar/");
         assert!(handler.is_some());
-        let handler = registry.match_vlugin("/_foo/bar/baz");
+        let handler = registry.match_vlugin(Get, None, "/_foo/bar/baz");
         assert!(handler.is_some());
     }
+
+    #[test]
+    fn match_catch_all_prefix() {
+        let mut registry = PluginRegistry::new();
+        registry
+            .register(("assets", "/assets/*path").into(), ())
+            .unwrap();
+        let ((plugin, _), params) = registry
+            .match_vlugin(Get, None, "/assets/css/app.css")
+            .unwrap();
+        assert_eq!(plugin.name, "assets");
+        assert_eq!(params.get("path"), Some("css/app.css"));
+        assert!(registry.match_vlugin(Get, None, "/assets").is_none());
+    }
+
+    #[test]
+    fn match_prefers_static_over_catch_all() {
+        let mut registry = PluginRegistry::new();
+        registry
+            .register(("assets", "/assets/*path").into(), ())
+            .unwrap();
+        registry
+            .register(("logo", "/assets/logo").into(), ())
+            .unwrap();
+        let ((plugin, _), _) = registry.match_vlugin(Get, None, "/assets/logo").unwrap();
+        assert_eq!(plugin.name, "logo");
+        let ((plugin, _), _) = registry
+            .match_vlugin(Get, None, "/assets/img/logo")
+            .unwrap();
+        assert_eq!(plugin.name, "assets");
+    }
+
+    #[test]
+    fn register_catch_all_conflicts_with_plain_prefix() {
+        let mut registry = PluginRegistry::new();
+        registry.register(("foo", "/assets").into(), ()).unwrap();
+        assert!(registry
+            .register(("bar", "/assets/*path").into(), ())
+            .is_err());
+        let res = registry.register(("baz", "/*path/foo").into(), ());
+        assert_eq!(res, Err(RegistrationError::InvalidPrefix("baz".into())));
+    }
+
+    #[test]
+    fn register_rejects_invalid_fields() {
+        let mut registry = PluginRegistry::new();
+        let res = registry.register(("foo bar", "foo").into(), ());
+        assert_eq!(
+            res,
+            Err(RegistrationError::InvalidField {
+                plugin: "foo bar".into(),
+                field: "name",
+                reason: "can only have ASCII letters, digits, `_`, `-` or `.`",
+            })
+        );
+        let res = registry.register(("foo", "").into(), ());
+        assert!(matches!(
+            res,
+            Err(RegistrationError::InvalidField {
+                field: "prefix",
+                ..
+            })
+        ));
+        let res = registry.register(("foo", "foo").into(), ());
+        assert_eq!(
+            res,
+            Err(RegistrationError::InvalidField {
+                plugin: "foo".into(),
+                field: "prefix",
+                reason: "has to start with `/`",
+            })
+        );
+        assert!(registry.plugins.is_empty());
+    }
+
+    #[test]
+    fn match_by_method() {
+        let mut registry = PluginRegistry::new();
+        let mut getter: VluginDef = ("getter", "/api").into();
+        getter.methods = vec![Get, Head];
+        let mut poster: VluginDef = ("poster", "/api").into();
+        poster.methods = vec![Post];
+        registry.register(getter, ()).unwrap();
+        registry.register(poster, ()).unwrap();
+
+        let ((plugin, _), _) = registry.match_vlugin(Get, None, "/api/foo").unwrap();
+        assert_eq!(plugin.name, "getter");
+        let ((plugin, _), _) = registry.match_vlugin(Post, None, "/api").unwrap();
+        assert_eq!(plugin.name, "poster");
+        assert!(registry.match_vlugin(Delete, None, "/api").is_none());
+        let mut allowed = registry.allowed_methods(None, None, "/api").unwrap();
+        allowed.sort_by_key(|m| m.as_ref().to_owned());
+        assert_eq!(allowed, vec![Get, Head, Post]);
+    }
+
+    #[test]
+    fn match_by_host() {
+        let mut registry = PluginRegistry::new();
+        let plugin = |name: &str, host: Option<&str>| VluginDef {
+            host: host.map(Into::into),
+            ..VluginDef::from((name, "app"))
+        };
+        registry.register(plugin("any", None), ()).unwrap();
+        registry
+            .register(plugin("blog", Some("Blog.example.com")), ())
+            .unwrap();
+        registry
+            .register(plugin("sub", Some("*.example.com")), ())
+            .unwrap();
+        registry
+            .register(plugin("deep", Some("*.eu.example.com")), ())
+            .unwrap();
+
+        let matched = |host| {
+            let ((plugin, _), _) = registry.match_vlugin(Get, host, "/app/page").unwrap();
+            plugin.name
+        };
+        assert_eq!(matched(Some("blog.example.com:8080")), "blog");
+        assert_eq!(matched(Some("shop.example.com")), "sub");
+        assert_eq!(matched(Some("shop.eu.example.com")), "deep");
+        assert_eq!(matched(Some("example.com")), "any");
+        assert_eq!(matched(Some("other.org")), "any");
+        assert_eq!(matched(None), "any");
+
+        // hosts have their own routes
+        registry
+            .register(plugin("api", Some("api.example.com")), ())
+            .unwrap();
+        assert!(registry
+            .register(plugin("api2", Some("API.example.com")), ())
+            .is_err());
+        registry.unregister("any").unwrap();
+        assert!(registry
+            .match_vlugin(Get, Some("other.org"), "/app")
+            .is_none());
+        assert!(registry.match_vlugin(Get, None, "/app").is_none());
+    }
+
+    #[test]
+    fn match_by_namespace() {
+        let mut registry = PluginRegistry::new();
+        let plugin = |name: &str, namespace: Option<&str>| VluginDef {
+            namespace: namespace.map(Into::into),
+            ..VluginDef::from((name, "api"))
+        };
+        registry.register(plugin("acme", Some("acme")), ()).unwrap();
+        registry
+            .register(plugin("globex", Some("globex")), ())
+            .unwrap();
+        assert!(registry
+            .register(plugin("acme2", Some("acme")), ())
+            .is_err());
+
+        let matched = |namespace| {
+            registry
+                .match_variant(namespace, Get, None, "/api/users", Choice::Balanced(0))
+                .map(|((plugin, _), _)| plugin.name)
+        };
+        assert_eq!(matched(Some("acme")).as_deref(), Some("acme"));
+        assert_eq!(matched(Some("globex")).as_deref(), Some("globex"));
+        assert_eq!(matched(Some("initech")), None);
+        assert_eq!(matched(None), None);
+
+        registry.register(plugin("shared", None), ()).unwrap();
+        assert_eq!(matched(None).as_deref(), Some("shared"));
+        assert_eq!(matched(Some("acme")).as_deref(), Some("acme"));
+    }
+
+    #[test]
+    fn match_weighted_variants() {
+        let mut registry = PluginRegistry::new();
+        let variant = |name: &str, weight| VluginDef {
+            weight: Some(weight),
+            ..VluginDef::from((name, "api"))
+        };
+        registry.register(variant("stable", 90), ()).unwrap();
+        registry.register(variant("canary", 10), ()).unwrap();
+        assert!(registry.register(("other", "/api").into(), ()).is_err());
+
+        let matched = |registry: &PluginRegistry, seed| {
+            let ((plugin, _), _) = registry
+                .match_variant(None, Get, None, "/api", Choice::Sticky(seed))
+                .unwrap();
+            plugin.name
+        };
+        let canaries = (0..100)
+            .filter(|seed| matched(&registry, *seed) == "canary")
+            .count();
+        assert_eq!(canaries, 10);
+        assert_eq!(matched(&registry, 42), matched(&registry, 42));
+
+        registry.set_weight("canary", 0);
+        assert!((0..100).all(|seed| matched(&registry, seed) == "stable"));
+        registry.set_disabled("stable", true);
+        assert_eq!(matched(&registry, 7), "canary");
+    }
+
+    #[test]
+    fn balance_load_of_variants() {
+        let mut registry = PluginRegistry::new();
+        let variant = |name: &str, prefix: &str, weight, load_balancer| VluginDef {
+            weight: Some(weight),
+            load_balancer,
+            ..VluginDef::from((name, prefix))
+        };
+        registry.register(variant("a", "rr", 2, None), ()).unwrap();
+        registry.register(variant("b", "rr", 1, None), ()).unwrap();
+        let least = Some(LoadBalancer::LeastConnections);
+        registry.register(variant("c", "lc", 1, least), ()).unwrap();
+        registry.register(variant("d", "lc", 2, None), ()).unwrap();
+        let random = Some(LoadBalancer::Random);
+        registry
+            .register(variant("e", "rand", 1, random), ())
+            .unwrap();
+        registry
+            .register(variant("f", "rand", 1, random), ())
+            .unwrap();
+        let next = |path, seed| {
+            let ((plugin, _), _) = registry
+                .match_variant(None, Get, None, path, Choice::Balanced(seed))
+                .unwrap();
+            plugin.name
+        };
+
+        // round-robin by default
+        let turns = (0..6).map(|_| next("/rr", 0)).collect::<Vec<_>>();
+        assert_eq!(turns, ["a", "b", "a", "a", "b", "a"]);
+
+        let (c, d) = (registry.active("c").unwrap(), registry.active("d").unwrap());
+        assert_eq!(next("/lc", 0), "d");
+        let _d = (d.track(), d.track());
+        assert_eq!(next("/lc", 0), "c");
+        let _c = c.track();
+        // in proportion to their weight d has fewer in flight
+        assert_eq!(next("/lc", 0), "d");
+
+        assert_eq!(next("/rand", 7), next("/rand", 7));
+        let e = (0..100).filter(|seed| next("/rand", *seed) == "e").count();
+        assert_eq!(e, 50);
+        // sticky requests keep their variant whatever the load balancer
+        let sticky = registry
+            .match_variant(None, Get, None, "/rr", Choice::Sticky(0))
+            .map(|((plugin, _), _)| plugin.name);
+        for _ in 0..3 {
+            let again = registry
+                .match_variant(None, Get, None, "/rr", Choice::Sticky(0))
+                .map(|((plugin, _), _)| plugin.name);
+            assert_eq!(again, sticky);
+        }
+    }
+
+    #[test]
+    fn skip_unhealthy_replicas() {
+        let mut registry = PluginRegistry::new();
+        let replica = |name: &str, weight| VluginDef {
+            weight: Some(weight),
+            ..VluginDef::from((name, "api"))
+        };
+        registry.register(replica("a", 50), ()).unwrap();
+        registry.register(replica("b", 30), ()).unwrap();
+        registry.register(replica("c", 20), ()).unwrap();
+        registry.register("other".into(), ()).unwrap();
+        let share = |registry: &PluginRegistry, name: &str| {
+            (0..100)
+                .filter(|seed| {
+                    let ((plugin, _), _) = registry
+                        .match_variant(None, Get, None, "/api", Choice::Sticky(*seed))
+                        .unwrap();
+                    plugin.name == name
+                })
+                .count()
+        };
+        assert_eq!(registry.replicas().len(), 3);
+
+        registry.set_probe("a", Health::Unhealthy);
+        registry.set_probe("other", Health::Unhealthy);
+        assert_eq!(registry.is_in_rotation("a"), Some(false));
+        assert_eq!(registry.is_in_rotation("other"), None);
+        assert_eq!(share(&registry, "a"), 0);
+        // the weights are of the healthy ones
+        assert_eq!(share(&registry, "b"), 60);
+        assert_eq!(share(&registry, "c"), 40);
+
+        // with none healthy they all get requests
+        registry.set_probe("b", Health::Unhealthy);
+        registry.set_probe("c", Health::Unhealthy);
+        assert_eq!(share(&registry, "a"), 50);
+
+        // back in rotation when they recover
+        registry.set_probe("a", Health::Healthy);
+        assert_eq!(share(&registry, "a"), 100);
+    }
+
+    #[test]
+    fn register_after_dependencies() {
+        let mut registry = PluginRegistry::new();
+        let plugin = |name: &str, depends_on: &[&str]| VluginDef {
+            depends_on: depends_on.iter().map(|d| (*d).to_owned()).collect(),
+            ..VluginDef::from(name)
+        };
+        assert_eq!(
+            registry.register(plugin("app", &["auth"]), ()),
+            Err(RegistrationError::MissingDependency {
+                plugin: "app".into(),
+                missing: "auth".into()
+            })
+        );
+        registry.register(plugin("auth", &[]), ()).unwrap();
+        registry.register(plugin("app", &["auth"]), ()).unwrap();
+        assert_eq!(
+            registry.register(plugin("auth", &["app"]), ()),
+            Err(RegistrationError::DependencyCycle(vec![
+                "auth".into(),
+                "app".into(),
+                "auth".into()
+            ]))
+        );
+        assert!(registry.register(plugin("app", &["app"]), ()).is_err());
+
+        // dependencies go after the plugins that need them
+        assert_eq!(
+            registry.unregister("auth"),
+            Err(RegistrationError::RequiredBy {
+                plugin: "auth".into(),
+                dependent: "app".into()
+            })
+        );
+        assert_eq!(registry.unregister("app"), Ok(true));
+        assert_eq!(registry.unregister("auth"), Ok(true));
+    }
+
+    #[test]
+    fn reject_downgrades() {
+        let mut registry = PluginRegistry::new();
+        let plugin = |version: &str| VluginDef {
+            version: Some(version.parse().unwrap()),
+            ..VluginDef::from("foo")
+        };
+        registry.register(plugin("1.2.0"), ()).unwrap();
+        registry.register(plugin("2.0.0-rc.1"), ()).unwrap();
+        registry.register(plugin("2.0.0-rc.1"), ()).unwrap();
+        assert_eq!(
+            registry.register(plugin("1.9.0"), ()),
+            Err(RegistrationError::Downgrade {
+                name: "foo".into(),
+                registered: "2.0.0-rc.1".parse().unwrap(),
+                attempted: "1.9.0".parse().unwrap(),
+            })
+        );
+        // without versions there's nothing to compare
+        registry.register("foo".into(), ()).unwrap();
+        registry.register(plugin("1.0.0"), ()).unwrap();
+
+        registry.allow_downgrades = true;
+        registry.register(plugin("0.1.0"), ()).unwrap();
+    }
+
+    #[test]
+    fn register_overlapping_methods_conflicts() {
+        let mut registry = PluginRegistry::new();
+        let mut getter: VluginDef = ("getter", "/api").into();
+        getter.methods = vec![Get];
+        registry.register(getter, ()).unwrap();
+        let res = registry.register(("any", "/api").into(), ());
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn match_captures_params() {
+        let mut registry = PluginRegistry::new();
+        registry
+            .register(("orders", "/users/:id/orders/:order").into(), ())
+            .unwrap();
+        let (_, params) = registry
+            .match_vlugin(Get, None, "/users/42/orders/7/items")
+            .unwrap();
+        assert_eq!(params.len(), 2);
+        assert_eq!(params.get("id"), Some("42"));
+        assert_eq!(params.get("order"), Some("7"));
+    }
+
+    #[test]
+    fn params_are_percent_decoded() {
+        let mut registry = PluginRegistry::new();
+        registry
+            .register(("users", "/users/:name").into(), ())
+            .unwrap();
+        let (_, params) = registry
+            .match_vlugin(Get, None, "/users/j%C3%B6rg%20o")
+            .unwrap();
+        assert_eq!(params.get("name"), Some("jörg o"));
+    }
+
+    #[test]
+    fn strip_route_leaves_plugin_path() {
+        assert_eq!(strip_route("_foo", "/_foo"), "");
+        assert_eq!(strip_route("_foo", "/_foo/"), "/");
+        assert_eq!(strip_route("_foo", "/_foo/bar/baz"), "/bar/baz");
+        assert_eq!(strip_route("users/:id", "/users/42/orders"), "/orders");
+        assert_eq!(
+            strip_route("assets/*path", "/assets/css/app.css"),
+            "/css/app.css"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn paginate_plugin_list() {
+        let url = |query: &str| crate::http::Url::parse(&("http://a/_plugins?".to_owned() + query));
+        let plugins = ["foo", "bar", "baz", "qux"]
+            .iter()
+            .map(|n| VluginDef {
+                namespace: (*n == "qux").then(|| "acme".into()),
+                ..VluginDef::from(*n)
+            })
+            .collect::<Vec<_>>();
+        let list = |query: &str| {
+            let query = ListQuery::from_url(&url(query).unwrap()).unwrap();
+            let json = query.list(plugins.iter().collect(), |p| *p).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap()
+        };
+        let names = |items: &serde_json::Value| {
+            let items = items.as_array().unwrap().iter();
+            items
+                .map(|p| p["name"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(&list("")), ["bar", "baz", "foo", "qux"]);
+        assert_eq!(names(&list("verbose=false")), ["bar", "baz", "foo", "qux"]);
+        let page = list("limit=2&offset=1");
+        assert_eq!(names(&page["items"]), ["baz", "foo"]);
+        assert_eq!(page["total"], 4);
+        assert_eq!(page["limit"], 2);
+        let page = list("prefix=ba");
+        assert_eq!(names(&page["items"]), ["bar", "baz"]);
+        assert_eq!(page["total"], 2);
+        assert_eq!(names(&list("name=u")["items"]), ["qux"]);
+        assert_eq!(names(&list("namespace=acme")["items"]), ["qux"]);
+
+        assert!(ListQuery::from_url(&url("limit=-1").unwrap()).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn registry_auth_needs_the_token() {
+        let request = |method, auth: Option<&str>| {
+            let mut req = crate::http::Request::new(method, "http://example.com/_plugins");
+            if let Some(auth) = auth {
+                req.insert_header("authorization", auth);
+            }
+            req
+        };
+        let auth = RegistryAuth::new("s3cret");
+        assert!(auth.allows(&request(Get, None)));
+        assert!(!auth.allows(&request(Post, None)));
+        assert!(!auth.allows(&request(Delete, Some("Bearer s3cre"))));
+        assert!(!auth.allows(&request(Put, Some("Basic s3cret"))));
+        assert!(auth.allows(&request(Post, Some("Bearer s3cret"))));
+
+        let auth = RegistryAuth {
+            protect_list: true,
+            ..auth
+        };
+        assert!(!auth.allows(&request(Get, None)));
+        assert!(auth.allows(&request(Get, Some("Bearer s3cret"))));
+    }
 }
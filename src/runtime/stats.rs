@@ -0,0 +1,150 @@
+//! Requests, errors and latencies of each plugin
+
+use crate::http::StatusCode;
+use alloc::vec::Vec;
+use core::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+// latencies kept to compute the percentiles
+const SAMPLES: usize = 1024;
+
+/// Counters of the requests a plugin answered since it was registered or its
+/// stats were reset, the percentiles are of the latest 1024 requests. Time is
+/// only measured with `std`.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    requests: Cell<u64>,
+    errors: Cell<u64>,
+    since: Cell<Option<u64>>,
+    // microseconds, the oldest sample is overwritten once it's full
+    latencies: RefCell<Vec<u64>>,
+    next: Cell<usize>,
+}
+
+/// What the stats are at a point in time
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub(crate) struct Snapshot {
+    pub requests: u64,
+    /// Requests answered with a server error
+    pub errors: u64,
+    /// Unix timestamp in milliseconds of when counting started
+    pub since: Option<u64>,
+    pub uptime_ms: Option<u64>,
+    pub latency_ms: Option<Percentiles>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub(crate) struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Stats {
+    pub fn new(now: Option<u64>) -> Self {
+        Stats {
+            requests: Cell::new(0),
+            errors: Cell::new(0),
+            since: Cell::new(now),
+            latencies: RefCell::default(),
+            next: Cell::new(0),
+        }
+    }
+
+    pub fn record(&self, status: StatusCode, latency: Option<Duration>) {
+        self.requests.set(self.requests.get() + 1);
+        if status.is_server_error() {
+            self.errors.set(self.errors.get() + 1);
+        }
+        let latency = match latency {
+            Some(latency) => latency.as_micros() as u64,
+            None => return,
+        };
+        let mut latencies = self.latencies.borrow_mut();
+        if latencies.len() < SAMPLES {
+            latencies.push(latency);
+        } else {
+            latencies[self.next.get()] = latency;
+        }
+        self.next.set((self.next.get() + 1) % SAMPLES);
+    }
+
+    /// Starts counting again from `now`
+    pub fn reset(&self, now: Option<u64>) {
+        self.requests.set(0);
+        self.errors.set(0);
+        self.since.set(now);
+        self.latencies.borrow_mut().clear();
+        self.next.set(0);
+    }
+
+    pub fn snapshot(&self, now: Option<u64>) -> Snapshot {
+        let mut latencies = self.latencies.borrow().clone();
+        latencies.sort_unstable();
+        // nearest rank, the smallest sample with at least p of them below or equal
+        let rank = |p: f64| {
+            let i = (p * latencies.len() as f64).ceil() as usize;
+            latencies[i.max(1) - 1] as f64 / 1000.0
+        };
+        let latency_ms = (!latencies.is_empty()).then(|| Percentiles {
+            p50: rank(0.5),
+            p90: rank(0.9),
+            p99: rank(0.99),
+            max: rank(1.0),
+        });
+        let since = self.since.get();
+        Snapshot {
+            requests: self.requests.get(),
+            errors: self.errors.get(),
+            since,
+            uptime_ms: since.zip(now).map(|(since, now)| now.saturating_sub(since)),
+            latency_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_requests_and_latencies() {
+        let stats = Stats::new(Some(1_000));
+        for ms in 1..=100 {
+            stats.record(StatusCode::Ok, Some(Duration::from_millis(ms)));
+        }
+        stats.record(StatusCode::BadGateway, None);
+
+        let snapshot = stats.snapshot(Some(61_000));
+        assert_eq!(snapshot.requests, 101);
+        assert_eq!(snapshot.errors, 1);
+        assert_eq!(snapshot.uptime_ms, Some(60_000));
+        assert_eq!(
+            snapshot.latency_ms,
+            Some(Percentiles {
+                p50: 50.0,
+                p90: 90.0,
+                p99: 99.0,
+                max: 100.0,
+            })
+        );
+
+        // only the latest samples are kept
+        for _ in 0..SAMPLES {
+            stats.record(StatusCode::Ok, Some(Duration::from_millis(2)));
+        }
+        assert_eq!(stats.snapshot(None).latency_ms.unwrap().max, 2.0);
+
+        stats.reset(Some(70_000));
+        let snapshot = stats.snapshot(Some(70_500));
+        assert_eq!((snapshot.requests, snapshot.errors), (0, 0));
+        assert_eq!(snapshot.since, Some(70_000));
+        assert_eq!(snapshot.uptime_ms, Some(500));
+        assert_eq!(snapshot.latency_ms, None);
+    }
+}
@@ -0,0 +1,97 @@
+//! Requests plugins make to other plugins of the same runtime without going
+//! through the network
+
+use super::BoxedFuture;
+use crate::http::{self, StatusCode};
+use alloc::rc::Rc;
+use core::cell::{Cell, RefCell};
+use hashbrown::HashMap;
+
+pub(crate) type Dispatch =
+    Rc<dyn Fn(http::Request, Subrequests) -> BoxedFuture<'static, http::Result<http::Response>>>;
+
+thread_local! {
+    // runtimes of the requests being handled, extensions have to be `Send`
+    // so requests only have the key to find the runtime they came from
+    static RUNTIMES: RefCell<HashMap<u64, Dispatch>> = RefCell::default();
+    static NEXT_KEY: Cell<u64> = Cell::new(0);
+}
+
+/// Handle to make requests to other plugins of the runtime handling the
+/// request, it's an extension of requests when the runtime is created
+/// `with_subrequests`. Subrequests go straight to the plugin that matches
+/// skipping the middlewares, they get the request id and the states of the
+/// runtime but the plugin has to set the extensions of middlewares(e.g. the
+/// authenticated user) when the other one needs them.
+///
+/// ```
+/// # use valor_core::*;
+/// # use runtime::Subrequests;
+/// let profile = h(|req: http::Request, _| async move {
+///     let subrequests = req.ext::<Subrequests>().copied().expect("subrequests enabled");
+///     let user = http::Request::new(http::Method::Get, "http://localhost/_users/1");
+///     let mut res = subrequests.dispatch(user).await?;
+///     Ok(http::Response::from(res.body_string().await?))
+/// });
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Subrequests {
+    key: u64,
+    depth: u8,
+    max_depth: u8,
+}
+
+impl Subrequests {
+    /// Handles the request with the plugin of its route, the subrequests of
+    /// subrequests fail with `508 Loop Detected` over the depth limit
+    pub async fn dispatch(&self, request: http::Request) -> http::Result<http::Response> {
+        if self.depth >= self.max_depth {
+            let msg = "Too many nested subrequests";
+            return Err(http::Error::from_str(StatusCode::LoopDetected, msg));
+        }
+        let dispatch = RUNTIMES
+            .with(|runtimes| runtimes.borrow().get(&self.key).cloned())
+            .ok_or_else(|| {
+                let msg = "Subrequests can't outlive their request";
+                http::Error::from_str(StatusCode::InternalServerError, msg)
+            })?;
+        let nested = Subrequests {
+            depth: self.depth + 1,
+            ..*self
+        };
+        dispatch(request, nested).await
+    }
+
+    /// How many subrequests deep the request is, `0` for the ones from clients
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+}
+
+/// Keeps the runtime available for subrequests while the request is handled
+pub(crate) struct Registration(u64);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        RUNTIMES.with(|runtimes| runtimes.borrow_mut().remove(&self.0));
+    }
+}
+
+pub(crate) fn register(
+    request: &mut http::Request,
+    max_depth: u8,
+    dispatch: Dispatch,
+) -> Registration {
+    let key = NEXT_KEY.with(|next| {
+        let key = next.get();
+        next.set(key.wrapping_add(1));
+        key
+    });
+    RUNTIMES.with(|runtimes| runtimes.borrow_mut().insert(key, dispatch));
+    request.set_ext(Subrequests {
+        key,
+        depth: 0,
+        max_depth,
+    });
+    Registration(key)
+}
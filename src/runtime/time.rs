@@ -0,0 +1,73 @@
+//! Time related utilities, measuring time is only possible with the
+//! `std` feature, without it there's no time information available.
+
+use core::{future::Future, time::Duration};
+
+/// Milliseconds since the unix epoch
+pub(crate) fn unix_ms() -> Option<u64> {
+    #[cfg(feature = "std")]
+    {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_millis() as u64)
+    }
+    #[cfg(not(feature = "std"))]
+    None
+}
+
+/// Measures the time elapsed since it was started
+pub(crate) struct Stopwatch {
+    #[cfg(feature = "std")]
+    start: std::time::Instant,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Stopwatch {
+            #[cfg(feature = "std")]
+            start: std::time::Instant::now(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        #[cfg(feature = "std")]
+        {
+            Some(self.start.elapsed())
+        }
+        #[cfg(not(feature = "std"))]
+        None
+    }
+
+    pub fn elapsed_ms(&self) -> Option<u64> {
+        self.elapsed().map(|d| d.as_millis() as u64)
+    }
+}
+
+/// Resolves to `None` if the future doesn't complete before the timeout in
+/// which case the future is dropped. Without `std` it never times out.
+pub(crate) async fn timeout<F: Future>(timeout: Duration, fut: F) -> Option<F::Output> {
+    #[cfg(feature = "std")]
+    {
+        let fut = async { Some(fut.await) };
+        let delay = async {
+            futures_timer::Delay::new(timeout).await;
+            None
+        };
+        futures_lite::future::or(fut, delay).await
+    }
+    #[cfg(not(feature = "std"))]
+    {
+        let _ = timeout;
+        Some(fut.await)
+    }
+}
+
+/// Waits for the given time, without `std` it doesn't wait
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(feature = "std")]
+    futures_timer::Delay::new(duration).await;
+    #[cfg(not(feature = "std"))]
+    let _ = duration;
+}
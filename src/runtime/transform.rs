@@ -0,0 +1,213 @@
+//! Small edits of the requests plugins get and the responses they answer with
+
+use crate::{
+    async_trait,
+    http::{self, Request, Response},
+    Error,
+};
+use alloc::boxed::Box;
+
+// bytes of the biggest JSON body that is edited
+const MAX_JSON_BODY: usize = 1024 * 1024;
+
+/// Edits the request right before a plugin handles it and the response it
+/// answers with(e.g. adding a header or rewriting a field of a JSON body)
+/// without the whole chain of a [`Middleware`]. Transforms run inside the
+/// middlewares so caches and compression get the responses already edited.
+///
+/// [`Middleware`]: super::Middleware
+///
+/// ```
+/// # use valor_core::*;
+/// # use runtime::{transform, Runtime};
+/// let runtime = Runtime::new(())
+///     .with_transform(transform::on_response(|res: &mut http::Response| {
+///         res.insert_header("x-served-by", "valor");
+///     }))
+///     .with_plugin_transform(
+///         "users",
+///         transform::json_response(|user: &mut serde_json::Value| {
+///             user["email"] = "[redacted]".into();
+///         }),
+///     );
+/// ```
+#[async_trait(?Send)]
+pub trait Transform: 'static {
+    async fn request(&self, _req: &mut Request) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn response(&self, _res: &mut Response) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Transform of the requests with a closure
+pub fn on_request(edit: impl Fn(&mut Request) + 'static) -> impl Transform {
+    struct OnRequest<F>(F);
+
+    #[async_trait(?Send)]
+    impl<F: Fn(&mut Request) + 'static> Transform for OnRequest<F> {
+        async fn request(&self, req: &mut Request) -> Result<(), Error> {
+            (self.0)(req);
+            Ok(())
+        }
+    }
+
+    OnRequest(edit)
+}
+
+/// Transform of the responses with a closure
+pub fn on_response(edit: impl Fn(&mut Response) + 'static) -> impl Transform {
+    struct OnResponse<F>(F);
+
+    #[async_trait(?Send)]
+    impl<F: Fn(&mut Response) + 'static> Transform for OnResponse<F> {
+        async fn response(&self, res: &mut Response) -> Result<(), Error> {
+            (self.0)(res);
+            Ok(())
+        }
+    }
+
+    OnResponse(edit)
+}
+
+/// Edits the body of JSON responses, the ones of other types, streamed(without
+/// a known length), bigger than 1MiB or that are not valid JSON are left as
+/// they are
+pub fn json_response(edit: impl Fn(&mut serde_json::Value) + 'static) -> impl Transform {
+    struct JsonResponse<F>(F);
+
+    #[async_trait(?Send)]
+    impl<F: Fn(&mut serde_json::Value) + 'static> Transform for JsonResponse<F> {
+        async fn response(&self, res: &mut Response) -> Result<(), Error> {
+            let is_json = res
+                .content_type()
+                .map_or(false, |mime| mime.essence() == http::mime::JSON.essence());
+            let fits = res.len().map_or(false, |len| len <= MAX_JSON_BODY);
+            if !is_json || !fits {
+                return Ok(());
+            }
+            let body = res.take_body().into_bytes().await?;
+            let mut value = match serde_json::from_slice::<serde_json::Value>(&body) {
+                Ok(value) => value,
+                Err(_) => {
+                    res.set_body(body);
+                    return Ok(());
+                }
+            };
+            (self.0)(&mut value);
+            res.set_body(http::Body::from_json(&value)?);
+            Ok(())
+        }
+    }
+
+    JsonResponse(edit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{h, runtime::Runtime};
+    use async_std::test;
+    use serde_json::json;
+
+    #[test]
+    async fn transform_requests_and_responses() {
+        let runtime = Runtime::new(())
+            .with_transform(on_request(|req: &mut Request| {
+                req.insert_header("x-tenant", "acme");
+            }))
+            .with_transform(on_response(|res: &mut Response| {
+                res.insert_header("x-served-by", "valor");
+            }))
+            .with_plugin_transform(
+                "users",
+                json_response(|user: &mut serde_json::Value| {
+                    user["email"] = "[redacted]".into();
+                }),
+            )
+            .with_plugin(
+                "users",
+                h(|req: Request, _| async move {
+                    let tenant = req.header("x-tenant").map(|h| h.as_str().to_owned());
+                    let mut res = Response::new(http::StatusCode::Ok);
+                    res.set_body(http::Body::from_json(&json!({
+                        "tenant": tenant,
+                        "email": "jane@example.com",
+                    }))?);
+                    Ok(res)
+                }),
+            )
+            .unwrap()
+            .with_plugin("foo", ())
+            .unwrap();
+        let get = |path: &str| {
+            let mut req = Request::new(
+                http::Method::Get,
+                ("http://example.com".to_owned() + path).as_str(),
+            );
+            req.insert_header("x-request-id", "123");
+            let runtime = runtime.clone();
+            async move { Response::from(runtime.on_msg(req.into()).await.unwrap()) }
+        };
+
+        let mut res = get("/_users").await;
+        assert_eq!(res.header("x-served-by").unwrap(), "valor");
+        let user: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(user, json!({ "tenant": "acme", "email": "[redacted]" }));
+
+        // the ones without a plugin apply to all
+        let mut res = get("/_foo").await;
+        assert_eq!(res.header("x-served-by").unwrap(), "valor");
+        assert_eq!(res.body_string().await.unwrap(), "");
+    }
+
+    struct Failing;
+
+    #[async_trait(?Send)]
+    impl Transform for Failing {
+        async fn response(&self, _res: &mut Response) -> Result<(), Error> {
+            Err(http::Error::from_str(http::StatusCode::BadGateway, "broken").into())
+        }
+    }
+
+    #[test]
+    async fn leave_bodies_that_are_not_json() {
+        let runtime = Runtime::new(())
+            .with_plugin_transform(
+                "broken",
+                json_response(|value: &mut serde_json::Value| {
+                    value["edited"] = true.into();
+                }),
+            )
+            .with_plugin(
+                "broken",
+                h(|_: Request, _| async {
+                    let mut res = Response::new(http::StatusCode::Ok);
+                    res.set_body("{not json");
+                    res.set_content_type(http::mime::JSON);
+                    Ok(res)
+                }),
+            )
+            .unwrap()
+            .with_plugin_transform("failing", Failing)
+            .with_plugin("failing", ())
+            .unwrap();
+        let get = |path: &str| {
+            let url = "http://example.com".to_owned() + path;
+            let mut req = Request::new(http::Method::Get, url.as_str());
+            req.insert_header("x-request-id", "123");
+            let runtime = runtime.clone();
+            async move { Response::from(runtime.on_msg(req.into()).await.unwrap()) }
+        };
+
+        let mut res = get("/_broken").await;
+        assert_eq!(res.status(), http::StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "{not json");
+
+        let res = get("/_failing").await;
+        assert_eq!(res.status(), http::StatusCode::BadGateway);
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "failing");
+    }
+}
@@ -1,20 +1,126 @@
-use crate::VluginConfig;
-use alloc::{borrow::ToOwned, string::String};
+use super::{Error, Version};
+use crate::{http::Method, VluginConfig};
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use core::time::Duration;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 /// The format used to define and configure plugins
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct VluginDef {
     /// Name of the plugin
     pub name: String,
-    /// Url prefix where the plugin is mounted, defaults to the name
+    /// Version of the plugin, replacing a registered plugin with an older
+    /// version is rejected unless the runtime allows downgrades
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub version: Option<Version>,
+    /// Url prefix where the plugin is mounted starting with `/`, defaults to
+    /// the name. It can have parameters(e.g. `/users/:id`) and end with a
+    /// catch-all segment(e.g. `/assets/*path`) to claim only the sub paths.
     #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub prefix: Option<String>,
+    /// Host the plugin serves(e.g. `api.example.com`), a leading wildcard
+    /// like `*.example.com` matches any subdomain. Plugins without a host
+    /// serve requests for any host that no other plugin claims the path of.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub host: Option<String>,
+    /// Group of plugins(e.g. of a tenant) with routes of their own, the same
+    /// prefix can be used in every namespace. Requests of a namespace match its
+    /// plugins first and then the ones without a namespace.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub namespace: Option<String>,
+    /// HTTP methods the plugin handles, all of them when empty
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub methods: Vec<Method>,
+    /// Plugins on the same route that have a weight are variants of each other
+    /// and get a share of the requests in proportion to it(e.g. a canary with
+    /// `10` next to a stable plugin with `90`), a weight of `0` gets none.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub weight: Option<u32>,
+    /// How the variants of the route share its requests, the one of the first
+    /// variant that sets it applies to the route. Round-robin by default.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub load_balancer: Option<LoadBalancer>,
+    /// Plugin that gets a copy of the requests this one handles, e.g. to try a new
+    /// version with real traffic, its answers are ignored
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub mirror: Option<Mirror>,
+    /// Handle the request again when the plugin fails with a transient error,
+    /// e.g. a `502 Bad Gateway` of a proxy
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub retry: Option<Retry>,
+    /// Plugins the requests pass through in order before reaching this one(e.g.
+    /// an authentication and a transformation), a stage answers with
+    /// [`Answer::Next`] to hand the request to the next or with a response that
+    /// is sent instead
+    ///
+    /// [`Answer::Next`]: crate::Answer::Next
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub pipeline: Vec<String>,
+    /// Names of the plugins that have to be registered before this one, they
+    /// can't be removed while this one is registered
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub depends_on: Vec<String>,
     /// What kind of plugin
     #[cfg_attr(feature = "serde", serde(flatten))]
     pub r#type: VluginType,
+    /// Base64 ed25519 signature of the plugin's module, runtimes configured
+    /// with a public key refuse to load native or wasm plugins without a valid one
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub signature: Option<String>,
+    /// A failing health check of a non critical plugin degrades the
+    /// health of the runtime but doesn't make it fail
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "core::ops::Not::not")
+    )]
+    pub non_critical: bool,
+    /// Milliseconds the plugin has to load, overrides the runtime's default
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub load_timeout_ms: Option<u64>,
+    /// Milliseconds the plugin has to answer a request, overrides the runtime's default
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub request_timeout_ms: Option<u64>,
     /// Environment configuration to pass down to the plugin instance
     #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub config: Option<VluginConfig>, // NOTE this makes the core dependent on serde
@@ -27,6 +133,253 @@ impl VluginDef {
             .unwrap_or(&self.name)
             .trim_matches(&['/', ' '][..])
     }
+
+    /// Whether the plugin handles requests with the given `method`
+    pub fn serves(&self, method: Method) -> bool {
+        self.methods.is_empty() || self.methods.contains(&method)
+    }
+
+    /// Copy of the definition that is safe to show, the values of the
+    /// configuration with keys like `password` or `api_key` are replaced
+    /// unless they are `${VAR}` placeholders
+    pub fn redacted(&self) -> VluginDef {
+        VluginDef {
+            config: self.config.as_ref().map(redact),
+            ..self.clone()
+        }
+    }
+
+    /// Checks the fields whose type allows values that can't be registered,
+    /// the error says which field is wrong and why
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.invalid_field() {
+            Some((field, reason)) => Err(Error::InvalidField(self.name.clone(), field, reason)),
+            None => Ok(()),
+        }
+    }
+
+    // The first field with an invalid value and the reason
+    pub(crate) fn invalid_field(&self) -> Option<(&'static str, &'static str)> {
+        let invalid = |field, reason| Some((field, reason));
+        if self.name.is_empty() {
+            return invalid("name", "is empty");
+        }
+        if self.name.len() > MAX_NAME_LEN {
+            return invalid("name", "is longer than 64 characters");
+        }
+        if !self.name.bytes().all(is_name_char) {
+            return invalid(
+                "name",
+                "can only have ASCII letters, digits, `_`, `-` or `.`",
+            );
+        }
+        if let Some(prefix) = &self.prefix {
+            if prefix.trim_matches('/').is_empty() {
+                return invalid("prefix", "is empty");
+            }
+            if !prefix.starts_with('/') {
+                return invalid("prefix", "has to start with `/`");
+            }
+            let invalid_char =
+                |c: char| c.is_whitespace() || c.is_control() || c == '?' || c == '#';
+            if prefix.contains(invalid_char) || prefix.trim_matches('/').contains("//") {
+                return invalid("prefix", "is not a valid path");
+            }
+        }
+        if let Some(host) = &self.host {
+            let valid = |b: u8| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'*';
+            if host.is_empty() || !host.bytes().all(valid) {
+                return invalid("host", "is not a valid host name");
+            }
+        }
+        if let Some(namespace) = &self.namespace {
+            if namespace.is_empty() || !namespace.bytes().all(is_name_char) {
+                return invalid(
+                    "namespace",
+                    "can only have ASCII letters, digits, `_`, `-` or `.`",
+                );
+            }
+        }
+        if let Some(mirror) = &self.mirror {
+            if mirror.plugin.is_empty() || mirror.plugin == self.name {
+                return invalid("mirror", "has to be another plugin");
+            }
+            if !(0.0..=1.0).contains(&mirror.rate) {
+                return invalid("mirror", "rate has to be between 0 and 1");
+            }
+        }
+        if self.retry.as_ref().map_or(false, |r| r.attempts == 0) {
+            return invalid("retry", "attempts have to be at least 1");
+        }
+        if self.pipeline.iter().any(|stage| stage.is_empty()) {
+            return invalid("pipeline", "has an empty name");
+        }
+        if self.pipeline.contains(&self.name) {
+            return invalid("pipeline", "can't have the plugin itself");
+        }
+        if self.depends_on.iter().any(|dep| dep.is_empty()) {
+            return invalid("depends_on", "has an empty name");
+        }
+        None
+    }
+}
+
+const MAX_NAME_LEN: usize = 64;
+
+// parts of the keys of configuration values that are considered secret
+const SECRET_KEYS: [&str; 6] = ["auth", "credential", "key", "password", "secret", "token"];
+const REDACTED: &str = "[redacted]";
+
+fn redact(config: &VluginConfig) -> VluginConfig {
+    match config {
+        VluginConfig::Object(values) => values
+            .iter()
+            .map(|(key, value)| {
+                let lowercase = key.to_ascii_lowercase();
+                let secret = SECRET_KEYS.iter().any(|s| lowercase.contains(s));
+                let value = if secret && !is_placeholder(value) {
+                    REDACTED.into()
+                } else {
+                    redact(value)
+                };
+                (key.clone(), value)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        VluginConfig::Array(values) => values.iter().map(redact).collect(),
+        value => value.clone(),
+    }
+}
+
+// the secret is in the environment variable the placeholder names
+fn is_placeholder(value: &VluginConfig) -> bool {
+    value
+        .as_str()
+        .map_or(false, |s| s.starts_with("${") && s.ends_with('}'))
+}
+
+// names end up in urls, headers and metrics so they are kept simple
+fn is_name_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.'
+}
+
+/// Orders the plugins so the ones others depend on come first keeping the
+/// given order otherwise, dependencies that are not in the list are expected
+/// to be registered already. Plugins that depend on each other are an error.
+pub fn sort_by_dependencies(mut plugins: Vec<VluginDef>) -> Result<Vec<VluginDef>, Error> {
+    let mut sorted = Vec::<VluginDef>::with_capacity(plugins.len());
+    while !plugins.is_empty() {
+        let ready = plugins.iter().position(|p| {
+            p.depends_on
+                .iter()
+                .all(|dep| !plugins.iter().any(|other| other.name == *dep))
+        });
+        match ready {
+            Some(i) => sorted.push(plugins.remove(i)),
+            None => return Err(Error::DependencyCycle(find_cycle(&plugins))),
+        }
+    }
+    Ok(sorted)
+}
+
+// Follows the dependencies of plugins that all wait on others until a
+// plugin is seen twice
+fn find_cycle(plugins: &[VluginDef]) -> Vec<String> {
+    let mut path = Vec::<String>::new();
+    let mut current = &plugins[0];
+    loop {
+        if let Some(start) = path.iter().position(|name| *name == current.name) {
+            let mut cycle = path.split_off(start);
+            cycle.push(current.name.clone());
+            return cycle;
+        }
+        path.push(current.name.clone());
+        current = current
+            .depends_on
+            .iter()
+            .find_map(|dep| plugins.iter().find(|p| p.name == *dep))
+            .expect("plugins in a cycle depend on each other");
+    }
+}
+
+/// When to handle a request again, the body of requests that might be
+/// retried is buffered to replay it and the response says how many attempts
+/// it took in the `x-valor-attempts` header. Retries get the extensions of the
+/// runtime and its middlewares(e.g. the [`Params`](super::Params) or the
+/// authenticated user) and all attempts share the time the plugin has to
+/// answer, there's no retry when it's up.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retry {
+    /// Times the request is handled at most, the first one included
+    pub attempts: u32,
+    /// Status codes that are retried, failing to answer in time is a
+    /// `503 Service Unavailable` and panicking a `500 Internal Server Error`
+    pub statuses: Vec<u16>,
+    /// Milliseconds to wait before the first retry, it doubles for every other
+    pub backoff_ms: u64,
+    /// Retry requests of any method, otherwise only the idempotent
+    /// ones(`GET`, `HEAD`, `PUT`, `DELETE` and `OPTIONS`) are
+    pub any_method: bool,
+}
+
+impl Retry {
+    /// Time to wait after the given attempt failed
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor))
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 3,
+            statuses: alloc::vec![502, 503, 504],
+            backoff_ms: 100,
+            any_method: false,
+        }
+    }
+}
+
+/// How the variants of a route(plugins with a weight) are chosen, every
+/// variant gets a share of the requests in proportion to its weight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum LoadBalancer {
+    /// Variants take turns, the ones with more weight get more turns
+    RoundRobin,
+    /// A variant is chosen at random for every request
+    Random,
+    /// The variant with the fewest requests in flight for its weight
+    LeastConnections,
+}
+
+impl Default for LoadBalancer {
+    fn default() -> Self {
+        LoadBalancer::RoundRobin
+    }
+}
+
+/// Shadow plugin of a route that handles copies of its requests in the
+/// background, what it answers never reaches the client
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mirror {
+    /// Name of the shadow plugin
+    pub plugin: String,
+    /// Share of the requests that are mirrored, from `0.0` to `1.0`(all of them)
+    #[cfg_attr(feature = "serde", serde(default = "all_requests"))]
+    pub rate: f64,
+}
+
+#[cfg(feature = "serde")]
+fn all_requests() -> f64 {
+    1.0
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -45,14 +398,36 @@ pub enum VluginType {
     },
     /// Web script or WASM
     Web { url: String },
+    /// WebAssembly module run in a sandbox by the native runtime
+    Wasm { path: String },
+    /// Reverse proxy to the `upstream` server set in the configuration
+    Proxy,
+    /// Files of the local directory set as `root` in the configuration
+    Files,
+    /// Redirect to the `target` set in the configuration
+    Redirect,
 }
 
 impl From<&str> for VluginDef {
     fn from(name: &str) -> Self {
         VluginDef {
             name: name.into(),
-            prefix: Some("_".to_owned() + name),
+            version: None,
+            prefix: Some("/_".to_owned() + name),
+            host: None,
+            namespace: None,
+            methods: Vec::new(),
+            weight: None,
+            load_balancer: None,
+            mirror: None,
+            retry: None,
+            pipeline: Vec::new(),
+            depends_on: Vec::new(),
             r#type: VluginType::Static,
+            signature: None,
+            non_critical: false,
+            load_timeout_ms: None,
+            request_timeout_ms: None,
             config: None,
         }
     }
@@ -62,9 +437,144 @@ impl From<(&str, &str)> for VluginDef {
     fn from((name, prefix): (&str, &str)) -> Self {
         VluginDef {
             name: name.into(),
+            version: None,
             prefix: Some(prefix.into()),
+            host: None,
+            namespace: None,
+            methods: Vec::new(),
+            weight: None,
+            load_balancer: None,
+            mirror: None,
+            retry: None,
+            pipeline: Vec::new(),
+            depends_on: Vec::new(),
             r#type: VluginType::Static,
+            signature: None,
+            non_critical: false,
+            load_timeout_ms: None,
+            request_timeout_ms: None,
             config: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(name: &str, depends_on: &[&str]) -> VluginDef {
+        VluginDef {
+            depends_on: depends_on.iter().map(|d| (*d).to_owned()).collect(),
+            ..VluginDef::from(name)
+        }
+    }
+
+    #[test]
+    fn validate_fields() {
+        let field = |plugin: VluginDef| match plugin.validate() {
+            Err(Error::InvalidField(_, field, _)) => field,
+            res => panic!("expected an invalid field, got {:?}", res),
+        };
+        assert!(VluginDef::from("my-plugin_1.0").validate().is_ok());
+        assert!(VluginDef::from(("api", "/v1/users/:id")).validate().is_ok());
+        assert!(VluginDef::from(("api", "/assets/*path")).validate().is_ok());
+
+        assert_eq!(field(VluginDef::from(("", "foo"))), "name");
+        assert_eq!(field(VluginDef::from(("my plugin", "foo"))), "name");
+        assert_eq!(field(VluginDef::from(("über", "foo"))), "name");
+        assert_eq!(
+            field(VluginDef::from(("a".repeat(65).as_str(), "foo"))),
+            "name"
+        );
+        assert_eq!(field(VluginDef::from(("foo", "/"))), "prefix");
+        assert_eq!(field(VluginDef::from(("api", "assets/*path"))), "prefix");
+        assert_eq!(field(VluginDef::from(("foo", "/foo bar"))), "prefix");
+        assert_eq!(field(VluginDef::from(("foo", "/foo?bar"))), "prefix");
+        assert_eq!(field(VluginDef::from(("foo", "/foo//bar"))), "prefix");
+        let with_host = VluginDef {
+            host: Some("example.com/foo".into()),
+            ..VluginDef::from("foo")
+        };
+        assert_eq!(field(with_host), "host");
+        assert_eq!(field(plugin("foo", &[""])), "depends_on");
+        let mirror = |plugin: &str, rate| VluginDef {
+            mirror: Some(Mirror {
+                plugin: plugin.into(),
+                rate,
+            }),
+            ..VluginDef::from("foo")
+        };
+        assert!(mirror("bar", 0.5).validate().is_ok());
+        assert_eq!(field(mirror("foo", 0.5)), "mirror");
+        assert_eq!(field(mirror("bar", 1.5)), "mirror");
+        let pipeline = |stages: &[&str]| VluginDef {
+            pipeline: stages.iter().map(|s| (*s).to_owned()).collect(),
+            ..VluginDef::from("foo")
+        };
+        assert!(pipeline(&["auth", "transform"]).validate().is_ok());
+        assert_eq!(field(pipeline(&["auth", ""])), "pipeline");
+        assert_eq!(field(pipeline(&["foo"])), "pipeline");
+
+        let err = VluginDef::from(("", "foo")).validate().unwrap_err();
+        assert_eq!(err.to_string(), "name of plugin \"\" is empty");
+    }
+
+    #[test]
+    fn retry_with_growing_backoff() {
+        let retry = Retry::default();
+        let waits = (1..4)
+            .map(|a| retry.backoff(a).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(waits, [100, 200, 400]);
+        assert_eq!(retry.backoff(u32::MAX), Duration::from_millis(100 << 16));
+    }
+
+    #[test]
+    fn redact_secrets_of_config() {
+        let plugin = VluginDef {
+            config: Some(serde_json::json!({
+                "upstream": "http://example.com",
+                "Api-Key": "abc",
+                "db": { "user": "me", "password": "123" },
+                "backends": [{ "token": "xyz", "weight": 2 }],
+                "secret": "${SECRET}",
+            })),
+            ..VluginDef::from("foo")
+        };
+        assert_eq!(
+            plugin.redacted().config.unwrap(),
+            serde_json::json!({
+                "upstream": "http://example.com",
+                "Api-Key": "[redacted]",
+                "db": { "user": "me", "password": "[redacted]" },
+                "backends": [{ "token": "[redacted]", "weight": 2 }],
+                "secret": "${SECRET}",
+            })
+        );
+        assert_eq!(plugin.config.unwrap()["Api-Key"], "abc");
+    }
+
+    #[test]
+    fn sort_dependencies_first() {
+        let plugins = vec![
+            plugin("app", &["auth", "db"]),
+            plugin("auth", &["db", "health"]),
+            plugin("other", &[]),
+            plugin("db", &[]),
+        ];
+        let sorted = sort_by_dependencies(plugins).unwrap();
+        let names = sorted.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["other", "db", "auth", "app"]);
+
+        let plugins = vec![
+            plugin("other", &[]),
+            plugin("a", &["b"]),
+            plugin("b", &["c"]),
+            plugin("c", &["a"]),
+        ];
+        match sort_by_dependencies(plugins) {
+            Err(Error::DependencyCycle(cycle)) => assert_eq!(cycle, ["a", "b", "c", "a"]),
+            res => panic!("expected a cycle, got {:?}", res),
+        }
+    }
+}
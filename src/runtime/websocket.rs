@@ -0,0 +1,154 @@
+//! Handshake of requests that upgrade the connection to WebSocket(RFC 6455),
+//! the plugin gets the connection once the response is sent
+
+use super::problem;
+use crate::http::{headers, Request, Response, StatusCode};
+use alloc::{string::String, vec::Vec};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Whether the request asks to switch the connection to WebSocket
+pub(crate) fn is_upgrade(request: &Request) -> bool {
+    let has_token = |name: headers::HeaderName, token: &str| {
+        request.header(name).map_or(false, |values| {
+            values
+                .iter()
+                .flat_map(|v| v.as_str().split(','))
+                .any(|v| v.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    has_token(headers::UPGRADE, "websocket") && has_token(headers::CONNECTION, "upgrade")
+}
+
+/// Response that accepts the upgrade or an error when the handshake is not valid
+pub(crate) fn handshake(request: &Request) -> Response {
+    let version = request.header("sec-websocket-version").map(|v| v.as_str());
+    if version != Some("13") {
+        let mut res =
+            problem::response(StatusCode::UpgradeRequired, "Unsupported WebSocket version");
+        res.insert_header("sec-websocket-version", "13");
+        return res;
+    }
+    let key = match request.header("sec-websocket-key") {
+        Some(key) => key.as_str().trim(),
+        None => return problem::response(StatusCode::BadRequest, "Missing Sec-WebSocket-Key"),
+    };
+    let mut res = Response::new(StatusCode::SwitchingProtocols);
+    res.insert_header(headers::UPGRADE, "websocket");
+    res.insert_header(headers::CONNECTION, "Upgrade");
+    res.insert_header("sec-websocket-accept", accept_key(key));
+    res
+}
+
+fn accept_key(key: &str) -> String {
+    let mut input = Vec::with_capacity(key.len() + GUID.len());
+    input.extend_from_slice(key.as_bytes());
+    input.extend_from_slice(GUID.as_bytes());
+    base64(&sha1(&input))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip(&[a, b, c, d, e]) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, h) in digest.chunks_mut(4).zip(h.iter()) {
+        chunk.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    #[test]
+    fn accept_handshake() {
+        // example of the RFC
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRrK+xOo="
+        );
+        assert_eq!(base64(b"ab"), "YWI=");
+
+        let mut req = Request::new(Method::Get, "http://example.com/chat");
+        assert!(!is_upgrade(&req));
+        req.insert_header("upgrade", "WebSocket");
+        req.insert_header("connection", "keep-alive, Upgrade");
+        assert!(is_upgrade(&req));
+
+        assert_eq!(handshake(&req).status(), StatusCode::UpgradeRequired);
+        req.insert_header("sec-websocket-version", "13");
+        assert_eq!(handshake(&req).status(), StatusCode::BadRequest);
+        req.insert_header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==");
+        let res = handshake(&req);
+        assert_eq!(res.status(), StatusCode::SwitchingProtocols);
+        assert_eq!(res["sec-websocket-accept"], "s3pPLMBiTxaQ9kYGzzhZRrK+xOo=");
+    }
+}
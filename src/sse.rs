@@ -0,0 +1,303 @@
+//! Streaming of Server-Sent Events to browsers
+
+use crate::http::{self, headers, Body, Response, StatusCode};
+use core::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use futures_lite::{io, AsyncRead, FutureExt};
+use futures_timer::Delay;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const KEEP_ALIVE: &[u8] = b": keep-alive\n\n";
+
+/// Response with a `text/event-stream` body that stays open while there are
+/// senders of events, when no event is sent for a while a comment is sent to
+/// keep the connection alive. Events are written to the body as soon as they
+/// are sent and sending fails once the client is gone.
+///
+/// ```
+/// # use valor_core::{http, Event, SseResponse};
+/// # #[async_std::main] async fn main() {
+/// let (mut res, events) = SseResponse::new().open();
+/// events.send(&Event::new("hello").name("greeting")).unwrap();
+/// drop(events);
+///
+/// assert_eq!(res.content_type(), Some(http::mime::SSE));
+/// assert_eq!(res.body_string().await.unwrap(), "event: greeting\ndata: hello\n\n");
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SseResponse {
+    keep_alive: Option<Duration>,
+}
+
+impl SseResponse {
+    /// Keeps the connection alive with a comment every 15 seconds
+    pub fn new() -> Self {
+        SseResponse {
+            keep_alive: Some(Duration::from_secs(15)),
+        }
+    }
+
+    /// Period of the keep-alive comments, with `None` they are not sent
+    pub fn keep_alive(mut self, period: Option<Duration>) -> Self {
+        self.keep_alive = period;
+        self
+    }
+
+    /// The response the handler answers with and the sender of its events
+    pub fn open(self) -> (Response, EventSender) {
+        let shared = Arc::new(Mutex::new(Shared {
+            queue: Vec::new(),
+            senders: 1,
+            connected: true,
+            waker: None,
+        }));
+        let stream = EventStream {
+            shared: shared.clone(),
+            buf: Vec::new(),
+            pos: 0,
+            keep_alive: self.keep_alive.map(|period| (Delay::new(period), period)),
+        };
+        let mut res = Response::new(StatusCode::Ok);
+        res.set_content_type(http::mime::SSE);
+        res.insert_header(headers::CACHE_CONTROL, "no-cache");
+        res.set_body(Body::from_reader(io::BufReader::new(stream), None));
+        (res, EventSender { shared })
+    }
+}
+
+impl Default for SseResponse {
+    fn default() -> Self {
+        SseResponse::new()
+    }
+}
+
+/// A message of the event stream, multiline data is sent in multiple `data:` fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    name: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+    data: String,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Event {
+            name: None,
+            id: None,
+            retry: None,
+            data: data.into(),
+        }
+    }
+
+    /// Type of the event, the browser dispatches it to the listeners of that name
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Id the browser sends back as `Last-Event-ID` when it reconnects
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Time the browser waits before reconnecting when the connection is lost
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            writeln!(f, "event: {}", name)?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        for line in self.data.split('\n') {
+            writeln!(f, "data: {}", line.trim_end_matches('\r'))?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Sends the events of an [`SseResponse`], the stream ends when all the
+/// senders are dropped
+#[derive(Debug)]
+pub struct EventSender {
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// The client closed the connection of the event stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Client disconnected")
+    }
+}
+
+impl EventSender {
+    pub fn send(&self, event: &Event) -> Result<(), Disconnected> {
+        let mut shared = lock(&self.shared);
+        if !shared.connected {
+            return Err(Disconnected);
+        }
+        shared.queue.extend_from_slice(event.to_string().as_bytes());
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Whether the body is still being sent to the client
+    pub fn is_connected(&self) -> bool {
+        lock(&self.shared).connected
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        lock(&self.shared).senders += 1;
+        EventSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    // formatted events not read yet
+    queue: Vec<u8>,
+    senders: usize,
+    connected: bool,
+    waker: Option<Waker>,
+}
+
+// a panic while holding the lock leaves nothing half done
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// Body of the response, the server drops it when the client disconnects
+struct EventStream {
+    shared: Arc<Mutex<Shared>>,
+    buf: Vec<u8>,
+    pos: usize,
+    keep_alive: Option<(Delay, Duration)>,
+}
+
+impl AsyncRead for EventStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.buf.len() {
+                let n = out.len().min(this.buf.len() - this.pos);
+                out[..n].copy_from_slice(&this.buf[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            this.buf.clear();
+            this.pos = 0;
+            {
+                let mut shared = lock(&this.shared);
+                if !shared.queue.is_empty() {
+                    core::mem::swap(&mut shared.queue, &mut this.buf);
+                    if let Some((delay, period)) = &mut this.keep_alive {
+                        delay.reset(*period);
+                    }
+                    continue;
+                }
+                if shared.senders == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+                shared.waker = Some(cx.waker().clone());
+            }
+            match &mut this.keep_alive {
+                Some((delay, period)) if delay.poll(cx).is_ready() => {
+                    this.buf.extend_from_slice(KEEP_ALIVE);
+                    delay.reset(*period);
+                }
+                _ => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.connected = false;
+        shared.queue = Vec::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::test;
+    use futures_lite::AsyncReadExt;
+
+    #[test]
+    async fn stream_events() {
+        let event = Event::new("line 1\r\nline 2")
+            .name("update")
+            .id("7")
+            .retry(Duration::from_secs(3));
+        assert_eq!(
+            event.to_string(),
+            "event: update\nid: 7\nretry: 3000\ndata: line 1\ndata: line 2\n\n"
+        );
+
+        let (mut res, events) = SseResponse::new()
+            .keep_alive(Some(Duration::from_millis(50)))
+            .open();
+        let mut body = res.take_body();
+        let mut buf = [0; 64];
+
+        // nothing sent yet so the connection is kept alive
+        let n = body.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], KEEP_ALIVE);
+
+        // events are read as soon as they are sent
+        let sender = events.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(Duration::from_millis(5)).await;
+            sender.send(&Event::new("hi")).unwrap();
+        });
+        let n = body.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"data: hi\n\n");
+
+        drop(body);
+        assert!(!events.is_connected());
+        assert_eq!(events.send(&Event::new("bye")), Err(Disconnected));
+    }
+}
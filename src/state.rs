@@ -0,0 +1,38 @@
+//! Values shared by all the handlers of a runtime like a pool of connections
+
+use crate::http::Request;
+use alloc::sync::Arc;
+
+// Extension of requests with the state of a type
+pub(crate) struct State<T>(pub Arc<T>);
+
+/// Access to the state added to the runtime with `Runtime::with_state`, it's
+/// there for plugins and middlewares alike.
+///
+/// ```
+/// # use valor_core::{http, RequestState};
+/// struct Config {
+///     greeting: String,
+/// }
+///
+/// fn greet(req: &http::Request) -> String {
+///     match req.state::<Config>() {
+///         Some(config) => config.greeting.clone(),
+///         None => "hello".into(),
+///     }
+/// }
+///
+/// let req = http::Request::new(http::Method::Get, "http://example.com");
+/// assert_eq!(greet(&req), "hello");
+/// ```
+pub trait RequestState {
+    /// The state of the given type, `None` if the runtime has no state of
+    /// that type. The state is shared so getting it is cheap.
+    fn state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>>;
+}
+
+impl RequestState for Request {
+    fn state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.ext::<State<T>>().map(|state| state.0.clone())
+    }
+}
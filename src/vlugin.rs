@@ -6,6 +6,10 @@ use core::{
 };
 use hashbrown::HashMap;
 
+/// Version of the interface exported by native plugins, the runtime refuses
+/// to load plugins built against a different one
+pub const VLUGIN_ABI_VERSION: u32 = 4;
+
 /// Context allows plugins to pass state to the message handler
 /// and eventually to easily communicate with other plugins.
 #[derive(Default)]
@@ -111,8 +115,40 @@ pub trait Vlugin {
     }
 
     async fn on_msg(&self, msg: Message) -> Result<Answer, Error>;
+
+    /// Called by the runtime once the plugin is loaded or registered and before
+    /// it handles any request to do expensive initialization like warming up
+    /// connections so the first request isn't slow. A failed warmup doesn't keep
+    /// the plugin from being registered, the error is reported by the registry.
+    async fn warmup(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Reports if the plugin is in conditions of handling messages,
+    /// used by the runtime's health endpoint
+    async fn health(&self) -> Health {
+        Health::Healthy
+    }
+
+    /// Whether the plugin takes over the connections of requests that ask to
+    /// upgrade to the `protocol`(e.g. `websocket`), otherwise those requests
+    /// are handled with `on_msg` as any other
+    fn accepts_upgrade(&self, _protocol: &str) -> bool {
+        false
+    }
+
+    /// Called with the connection of an upgraded request once the runtime
+    /// answered the handshake, the plugin owns it until it returns
+    async fn on_upgrade(&self, _req: http::Request, _conn: Box<dyn Upgraded>) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
 }
 
+/// Bidirectional stream of a connection that switched protocols
+pub trait Upgraded: futures_lite::AsyncRead + futures_lite::AsyncWrite + Unpin {}
+
+impl<T: futures_lite::AsyncRead + futures_lite::AsyncWrite + Unpin> Upgraded for T {}
+
 #[async_trait(?Send)]
 impl<T> Vlugin for Box<T>
 where
@@ -126,6 +162,22 @@ where
         (&**self).on_msg(msg).await
     }
 
+    async fn warmup(&self) -> Result<(), Error> {
+        (&**self).warmup().await
+    }
+
+    async fn health(&self) -> Health {
+        (&**self).health().await
+    }
+
+    fn accepts_upgrade(&self, protocol: &str) -> bool {
+        (&**self).accepts_upgrade(protocol)
+    }
+
+    async fn on_upgrade(&self, req: http::Request, conn: Box<dyn Upgraded>) -> Result<(), Error> {
+        (&**self).on_upgrade(req, conn).await
+    }
+
     fn context_mut(&mut self) -> &mut Context {
         (&mut **self).context_mut()
     }
@@ -187,6 +239,22 @@ impl Vlugin for () {
     }
 }
 
+/// Result of a plugin health check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    Unhealthy,
+}
+
+impl Health {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Health::Healthy => "healthy",
+            Health::Unhealthy => "unhealthy",
+        }
+    }
+}
+
 /// Type of message supported by a handler
 #[derive(Debug)]
 pub enum Message {
@@ -215,11 +283,17 @@ impl From<Message> for http::Request {
     }
 }
 
-/// Type of valid outputs that a handler can return
+/// Type of valid outputs that a handler can return.
+/// Response bodies are not buffered by the runtime, handlers can stream them
+/// with a body created with `http::Body::from_reader` that is sent chunked
+/// when its length is unknown.
 #[derive(Debug)]
 pub enum Answer {
     Http(http::Response),
     Pong,
+    /// Hands the request, possibly modified, to the next stage of the
+    /// pipeline of the route, see `VluginDef::pipeline`
+    Next(http::Request),
 }
 
 impl From<Answer> for http::Response {
@@ -227,6 +301,8 @@ impl From<Answer> for http::Response {
         match out {
             Answer::Http(res) => res,
             Answer::Pong => http::StatusCode::Ok.into(),
+            // nothing came after the plugin that passed the request on
+            Answer::Next(_) => http::StatusCode::NotFound.into(),
         }
     }
 }
@@ -244,6 +320,12 @@ impl From<http::Response> for Answer {
     }
 }
 
+impl From<http::Request> for Answer {
+    fn from(req: http::Request) -> Self {
+        Answer::Next(req)
+    }
+}
+
 impl From<()> for Answer {
     fn from(_: ()) -> Self {
         Answer::Pong
@@ -0,0 +1,345 @@
+//! Log line of every request the server answers
+
+use kv_log_macro::{info, warn};
+use serde_json::json;
+use std::{
+    fmt::Write,
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) const DEFAULT_EXCLUDE: &str = "/_health";
+
+const DEFAULT: &str = "[{plugin}] {status} {method} {path}";
+const COMMON: &str = r#"{client_ip} - - [{time}] "{method} {path} {protocol}" {status} {bytes}"#;
+const COMBINED: &str = r#"{client_ip} - - [{time}] "{method} {path} {protocol}" {status} {bytes} "{referer}" "{user_agent}""#;
+
+/// How requests are logged, a template with placeholders like `{status}` or
+/// `{path}` or a JSON object with all the fields
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Format {
+    Template(Vec<Part>),
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Part {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Field {
+    Method,
+    Path,
+    Protocol,
+    Status,
+    Time,
+    Duration,
+    Plugin,
+    RequestId,
+    ClientIp,
+    UserAgent,
+    Referer,
+    Bytes,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "method" => Field::Method,
+            "path" => Field::Path,
+            "protocol" => Field::Protocol,
+            "status" => Field::Status,
+            "time" => Field::Time,
+            "duration" => Field::Duration,
+            "plugin" => Field::Plugin,
+            "id" => Field::RequestId,
+            "client_ip" => Field::ClientIp,
+            "user_agent" => Field::UserAgent,
+            "referer" => Field::Referer,
+            "bytes" => Field::Bytes,
+            _ => return None,
+        })
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        DEFAULT.parse().expect("valid template")
+    }
+}
+
+/// `default`, `common`, `combined`(like Apache and Nginx), `json` or a
+/// template like `{method} {path} -> {status} in {duration}ms`
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let template = match s {
+            "json" => return Ok(Format::Json),
+            "default" => DEFAULT,
+            "common" => COMMON,
+            "combined" => COMBINED,
+            template => template,
+        };
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("unclosed placeholder in {:?}", template))?;
+            let name = &rest[start + 1..end];
+            let field = Field::from_name(name)
+                .ok_or_else(|| format!("unknown placeholder {{{}}} in the access log", name))?;
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].into()));
+            }
+            parts.push(Part::Field(field));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.into()));
+        }
+        Ok(Format::Template(parts))
+    }
+}
+
+/// What is known of a request once it's answered
+#[derive(Debug)]
+pub(crate) struct Entry<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub protocol: Option<&'a str>,
+    pub status: u16,
+    pub time: SystemTime,
+    pub duration: Duration,
+    pub plugin: Option<&'a str>,
+    pub id: Option<&'a str>,
+    pub client_ip: Option<IpAddr>,
+    pub user_agent: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    pub bytes: Option<usize>,
+}
+
+/// Logs the requests that are not for an excluded path(e.g. the probes of
+/// `/_health`), server errors are logged as warnings
+#[derive(Debug, Clone)]
+pub(crate) struct AccessLog {
+    format: Format,
+    exclude: Vec<String>,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        AccessLog::new(Format::default(), &[DEFAULT_EXCLUDE.into()])
+    }
+}
+
+impl AccessLog {
+    pub fn new(format: Format, exclude: &[String]) -> Self {
+        AccessLog {
+            format,
+            exclude: exclude
+                .iter()
+                .map(|prefix| prefix.trim_end_matches('/').to_owned())
+                .collect(),
+        }
+    }
+
+    fn excludes(&self, path: &str) -> bool {
+        self.exclude.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    pub fn log(&self, entry: &Entry<'_>) {
+        if self.excludes(entry.path) {
+            return;
+        }
+        let line = self.line(entry);
+        let (id, status) = (entry.id.unwrap_or("-"), entry.status);
+        let dur = entry.duration.as_millis() as u64;
+        if status >= 500 {
+            warn!("{}", line, { id: id, status: status, dur: dur });
+        } else {
+            info!("{}", line, { id: id, status: status, dur: dur });
+        }
+    }
+
+    fn line(&self, entry: &Entry<'_>) -> String {
+        let parts = match &self.format {
+            Format::Template(parts) => parts,
+            Format::Json => {
+                return json!({
+                    "method": entry.method,
+                    "path": entry.path,
+                    "protocol": entry.protocol,
+                    "status": entry.status,
+                    "time": unix_millis(entry.time),
+                    "duration_ms": entry.duration.as_millis() as u64,
+                    "plugin": entry.plugin,
+                    "id": entry.id,
+                    "client_ip": entry.client_ip.map(|ip| ip.to_string()),
+                    "user_agent": entry.user_agent,
+                    "referer": entry.referer,
+                    "bytes": entry.bytes,
+                })
+                .to_string()
+            }
+        };
+        let mut line = String::new();
+        for part in parts {
+            let field = match part {
+                Part::Text(text) => {
+                    line.push_str(text);
+                    continue;
+                }
+                Part::Field(field) => field,
+            };
+            let _ = match field {
+                Field::Method => write!(line, "{}", entry.method),
+                Field::Path => write!(line, "{}", entry.path),
+                Field::Protocol => write!(line, "{}", entry.protocol.unwrap_or("-")),
+                Field::Status => write!(line, "{}", entry.status),
+                Field::Time => write_time(&mut line, entry.time),
+                Field::Duration => write!(line, "{}", entry.duration.as_millis()),
+                Field::Plugin => write!(line, "{}", entry.plugin.unwrap_or("-")),
+                Field::RequestId => write!(line, "{}", entry.id.unwrap_or("-")),
+                Field::ClientIp => match entry.client_ip {
+                    Some(ip) => write!(line, "{}", ip),
+                    None => write!(line, "-"),
+                },
+                Field::UserAgent => write_escaped(&mut line, entry.user_agent),
+                Field::Referer => write_escaped(&mut line, entry.referer),
+                Field::Bytes => match entry.bytes {
+                    Some(bytes) => write!(line, "{}", bytes),
+                    None => write!(line, "-"),
+                },
+            };
+        }
+        line
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Time like Apache and Nginx log it(e.g. `10/Oct/2000:13:55:36 +0000`) in UTC
+fn write_time(line: &mut String, time: SystemTime) -> std::fmt::Result {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // civil date of the days since the epoch
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    write!(
+        line,
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// Header values go between quotes, the ones in them are escaped so clients
+// can't break the line or forge fields
+fn write_escaped(line: &mut String, value: Option<&str>) -> std::fmt::Result {
+    let value = match value {
+        Some(value) => value,
+        None => return write!(line, "-"),
+    };
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            c if c.is_control() => write!(line, "\\x{:02x}", c as u32)?,
+            c => line.push(c),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> Entry<'static> {
+        Entry {
+            method: "GET",
+            path: "/blog/post",
+            protocol: Some("HTTP/1.1"),
+            status: 200,
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            duration: Duration::from_millis(12),
+            plugin: Some("blog"),
+            id: Some("123"),
+            client_ip: Some("10.0.0.1".parse().unwrap()),
+            user_agent: Some("curl/7.79.1"),
+            referer: None,
+            bytes: Some(512),
+        }
+    }
+
+    #[test]
+    fn format_lines() {
+        let log = |format: &str| AccessLog::new(format.parse().unwrap(), &[]).line(&entry());
+
+        assert_eq!(log("default"), "[blog] 200 GET /blog/post");
+        assert_eq!(
+            log("combined"),
+            r#"10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /blog/post HTTP/1.1" 200 512 "-" "curl/7.79.1""#
+        );
+        let forged = Entry {
+            user_agent: Some("x\" \"admin\n"),
+            ..entry()
+        };
+        assert_eq!(
+            AccessLog::new("{user_agent}".parse().unwrap(), &[]).line(&forged),
+            r#"x\" \"admin\x0a"#
+        );
+        assert_eq!(
+            log("{method} {path} -> {status} in {duration}ms ({id})"),
+            "GET /blog/post -> 200 in 12ms (123)"
+        );
+        let json: serde_json::Value = serde_json::from_str(&log("json")).unwrap();
+        assert_eq!(json["plugin"], "blog");
+        assert_eq!(json["duration_ms"], 12);
+        assert_eq!(json["referer"], serde_json::Value::Null);
+
+        assert!("{method} {size}".parse::<Format>().is_err());
+        assert!("{method".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn exclude_paths() {
+        let log = AccessLog::default();
+        assert!(log.excludes("/_health"));
+        assert!(log.excludes("/_health/ready"));
+        assert!(!log.excludes("/_healthz"));
+        assert!(!log.excludes("/blog"));
+
+        let log = AccessLog::new(Format::Json, &["/_metrics/".into(), "/static".into()]);
+        assert!(log.excludes("/_metrics"));
+        assert!(log.excludes("/static/app.js"));
+        assert!(!log.excludes("/_health"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Middleware compressing the responses of plugins
+
+use async_compression::futures::bufread::{BrotliEncoder, GzipEncoder};
+use async_std::io::BufReader;
+use async_trait::async_trait;
+use valor::http::{headers, Body, Request, Response};
+use valor::runtime::{Middleware, Next};
+
+// types of content that are already compressed
+const COMPRESSED: &[&str] = &[
+    "image/",
+    "video/",
+    "audio/",
+    "font/woff",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-brotli",
+    "application/octet-stream",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Compresses response bodies with brotli or gzip depending on what the
+/// client accepts, bodies smaller than `min_size` are sent as they are
+pub(crate) struct Compression {
+    min_size: usize,
+}
+
+impl Compression {
+    pub fn new(min_size: usize) -> Self {
+        Compression { min_size }
+    }
+
+    fn is_compressible(&self, res: &Response) -> bool {
+        let already_compressed = res.content_type().map_or(false, |mime| {
+            let mime = mime.essence();
+            mime != "image/svg+xml" && COMPRESSED.iter().any(|c| mime.starts_with(c))
+        });
+        // the encoder would hold back events until it has enough to compress
+        let is_event_stream = res
+            .content_type()
+            .map_or(false, |mime| mime.essence() == "text/event-stream");
+        res.header(headers::CONTENT_ENCODING).is_none()
+            && !already_compressed
+            && !is_event_stream
+            && res.len().map_or(true, |len| len >= self.min_size.max(1))
+    }
+}
+
+#[async_trait(?Send)]
+impl Middleware for Compression {
+    async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response, valor::Error> {
+        let encoding = req
+            .header(headers::ACCEPT_ENCODING)
+            .and_then(|h| preferred_encoding(h.as_str()));
+        let mut res = next.run(req).await?;
+        if !self.is_compressible(&res) {
+            return Ok(res);
+        }
+        res.append_header(headers::VARY, "accept-encoding");
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return Ok(res),
+        };
+
+        let body = res.take_body();
+        let mut body = match encoding {
+            Encoding::Brotli => Body::from_reader(BufReader::new(BrotliEncoder::new(body)), None),
+            Encoding::Gzip => Body::from_reader(BufReader::new(GzipEncoder::new(body)), None),
+        };
+        if let Some(mime) = res.content_type() {
+            body.set_mime(mime);
+        }
+        res.set_body(body);
+        res.remove_header(headers::CONTENT_LENGTH);
+        res.insert_header(headers::CONTENT_ENCODING, encoding.as_str());
+        Ok(res)
+    }
+}
+
+// Picks the supported encoding with the highest quality favoring brotli
+fn preferred_encoding(accept: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let encoding = match parts.next() {
+            Some("br") => Encoding::Brotli,
+            Some("gzip") | Some("x-gzip") => Encoding::Gzip,
+            Some("*") => Encoding::Brotli,
+            _ => continue,
+        };
+        let quality = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+            .unwrap_or(0.0);
+        if quality <= 0.0 {
+            continue;
+        }
+        match best {
+            Some((_, q)) if q > quality => {}
+            Some((Encoding::Brotli, q)) if q == quality => {}
+            _ => best = Some((encoding, quality)),
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_encoding() {
+        assert_eq!(
+            preferred_encoding("gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(preferred_encoding("gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            preferred_encoding("br;q=0.5, gzip;q=0.8"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(preferred_encoding("br;q=0, gzip;q=0"), None);
+        assert_eq!(preferred_encoding("identity"), None);
+        assert_eq!(preferred_encoding("*"), Some(Encoding::Brotli));
+    }
+}
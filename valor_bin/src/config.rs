@@ -0,0 +1,129 @@
+//! Settings read from a TOML or JSON configuration file
+
+use serde::{de, Deserialize, Deserializer};
+use std::{error::Error, fmt::Display, fs, path::Path, path::PathBuf, str::FromStr};
+use valor::runtime::VluginDef;
+
+/// Settings of the runtime that can also be given as command line flags,
+/// the precedence is defaults < file < command line.
+///
+/// ```toml
+/// bind = "127.0.0.1:8080"
+/// log_level = "info"
+/// request_timeout_ms = 5000
+///
+/// [[plugins]]
+/// name = "blog"
+/// type = "files"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub bind: Option<String>,
+    pub unix_socket: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_port: Option<u16>,
+    #[serde(deserialize_with = "parse")]
+    pub log_level: Option<femme::LevelFilter>,
+    #[serde(deserialize_with = "parse")]
+    pub log_format: Option<crate::LogFormat>,
+    pub log_file: Option<PathBuf>,
+    pub log_max_size: Option<u64>,
+    #[serde(deserialize_with = "parse")]
+    pub log_rotate: Option<crate::log_file::Interval>,
+    pub log_keep: Option<usize>,
+    pub request_timeout_ms: Option<u64>,
+    pub max_body_size: Option<usize>,
+    pub max_header_size: Option<usize>,
+    pub max_uri_length: Option<usize>,
+    pub idle_timeout_secs: Option<u64>,
+    pub keep_alive: Option<bool>,
+    pub header_read_timeout_secs: Option<u64>,
+    pub body_read_timeout_secs: Option<u64>,
+    #[serde(deserialize_with = "parse")]
+    pub request_id_header: Option<valor::http::headers::HeaderName>,
+    pub registry_token: Option<String>,
+    pub allowed_hosts: Vec<String>,
+    pub plugins: Vec<VluginDef>,
+}
+
+impl Config {
+    /// Reads the file as TOML when it has the `.toml` extension or JSON otherwise,
+    /// errors point to the setting that couldn't be read
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let is_toml = path.extension().map_or(false, |ext| ext == "toml");
+        let config = if is_toml {
+            serde_path_to_error::deserialize(&mut toml::Deserializer::new(&content))
+                .map_err(|e| invalid(path, e.path(), e.inner()))?
+        } else {
+            serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(&content))
+                .map_err(|e| invalid(path, e.path(), e.inner()))?
+        };
+        Ok(config)
+    }
+}
+
+fn invalid(file: &Path, field: &serde_path_to_error::Path, err: impl Display) -> String {
+    match field.to_string().as_str() {
+        "." => format!("invalid config {}: {}", file.display(), err),
+        field => format!("invalid config {}: `{}` {}", file.display(), field, err),
+    }
+}
+
+// Settings like the log level are written as the same strings the
+// command line accepts
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        fs::File::create(&path)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn read_toml_and_json() {
+        let toml = write(
+            "valor_config_test.toml",
+            "bind = \"127.0.0.1:9000\"\nlog_level = \"warn\"\n\n[[plugins]]\nname = \"foo\"\ntype = \"native\"\n",
+        );
+        let config = Config::from_file(&toml).unwrap();
+        assert_eq!(config.bind.as_deref(), Some("127.0.0.1:9000"));
+        assert_eq!(config.log_level, Some(femme::LevelFilter::Warn));
+        assert_eq!(config.plugins[0].name, "foo");
+
+        let json = write("valor_config_test.json", r#"{"max_body_size": 1024}"#);
+        let config = Config::from_file(&json).unwrap();
+        assert_eq!(config.max_body_size, Some(1024));
+        assert!(config.plugins.is_empty());
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let path = write("valor_config_bad.json", r#"{"tls_port": "https"}"#);
+        let err = Config::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("`tls_port`"), "{}", err);
+
+        let path = write("valor_config_bad.toml", "log_level = \"loud\"");
+        let err = Config::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("`log_level`"), "{}", err);
+    }
+}
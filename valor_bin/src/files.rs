@@ -0,0 +1,235 @@
+//! Plugin serving the files of a directory
+
+use async_std::{
+    fs::File,
+    io::{prelude::SeekExt, BufReader, ReadExt, SeekFrom},
+};
+use async_trait::async_trait;
+use std::{
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use valor::http::{
+    self,
+    conditional::{ETag, IfModifiedSince, LastModified},
+    headers, Body, Method, Mime, Request, Response, StatusCode,
+};
+use valor::{Answer, Context, Message, Vlugin, VluginConfig};
+
+const INDEX: &str = "index.html";
+
+/// Serves files under `root`, configured like `{"root": "./public", "index": true}`.
+/// When `index` is set requesting a directory serves its `index.html`
+/// otherwise it's forbidden.
+pub(crate) struct Files {
+    root: PathBuf,
+    index: bool,
+}
+
+impl Files {
+    pub fn from_config(config: Option<&VluginConfig>) -> Result<Self, http::Error> {
+        let root = config
+            .and_then(|c| c.get("root"))
+            .and_then(VluginConfig::as_str)
+            .ok_or_else(|| http::Error::from_str(StatusCode::BadRequest, "No root directory"))?;
+        let root = Path::new(root).canonicalize()?;
+        let index = config
+            .and_then(|c| c.get("index"))
+            .and_then(VluginConfig::as_bool)
+            .unwrap_or(false);
+        Ok(Files { root, index })
+    }
+
+    async fn serve(&self, req: &Request) -> http::Result<Response> {
+        let mut path = resolve(&self.root, req.url().path())
+            .ok_or_else(|| http::Error::from_str(StatusCode::NotFound, "Not found"))?;
+        if path.is_dir() {
+            if !self.index {
+                return Ok(StatusCode::Forbidden.into());
+            }
+            path.push(INDEX);
+        }
+        // symbolic links could point outside of the root
+        let path = path
+            .canonicalize()
+            .ok()
+            .filter(|p| p.starts_with(&self.root) && p.is_file())
+            .ok_or_else(|| http::Error::from_str(StatusCode::NotFound, "Not found"))?;
+
+        let mut file = File::open(&path).await?;
+        let meta = file.metadata().await?;
+        let len = meta.len();
+        let modified = meta.modified()?;
+        let secs = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let etag = format!("{:x}-{:x}", len, secs);
+
+        let mut res = Response::new(StatusCode::Ok);
+        ETag::new_weak(etag.clone()).apply(&mut res);
+        // HTTP dates have a precision of seconds
+        LastModified::new(UNIX_EPOCH + Duration::from_secs(secs)).apply(&mut res);
+        res.insert_header(headers::ACCEPT_RANGES, "bytes");
+        if not_modified(req, &etag, modified)? {
+            res.set_status(StatusCode::NotModified);
+            return Ok(res);
+        }
+
+        let (start, end) = match req
+            .header(headers::RANGE)
+            .map(|r| parse_range(r.as_str(), len))
+        {
+            None | Some(Range::Ignored) => (0, len),
+            Some(Range::Unsatisfiable) => {
+                res.set_status(StatusCode::RequestedRangeNotSatisfiable);
+                res.insert_header(headers::CONTENT_RANGE, format!("bytes */{}", len));
+                return Ok(res);
+            }
+            Some(Range::Bytes(start, end)) => {
+                res.set_status(StatusCode::PartialContent);
+                let range = format!("bytes {}-{}/{}", start, end - 1, len);
+                res.insert_header(headers::CONTENT_RANGE, range);
+                (start, end)
+            }
+        };
+
+        let mime = path
+            .extension()
+            .and_then(|ext| Mime::from_extension(ext.to_string_lossy()))
+            .unwrap_or(http::mime::BYTE_STREAM);
+        res.set_content_type(mime);
+        res.insert_header(headers::CONTENT_LENGTH, (end - start).to_string());
+        if req.method() == Method::Get {
+            file.seek(SeekFrom::Start(start)).await?;
+            let content = BufReader::new(file.take(end - start));
+            res.set_body(Body::from_reader(content, Some((end - start) as usize)));
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait(?Send)]
+impl Vlugin for Files {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, valor::Error> {
+        let req: Request = match msg {
+            Message::Http(req) => req,
+            Message::Ping => return Ok(Answer::Pong),
+        };
+        if !matches!(req.method(), Method::Get | Method::Head) {
+            let mut res = Response::new(StatusCode::MethodNotAllowed);
+            res.insert_header(headers::ALLOW, "GET, HEAD");
+            return Ok(res.into());
+        }
+        Ok(self.serve(&req).await?.into())
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        unreachable!()
+    }
+    fn context(&self) -> &Context {
+        unreachable!()
+    }
+}
+
+// Maps a (percent encoded) url path to a path under `root`, paths trying
+// to escape the root are rejected
+fn resolve(root: &Path, url_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(url_path)?;
+    let mut path = root.to_path_buf();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(segment) => path.push(segment),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.bytes();
+    while let Some(b) = rest.next() {
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        let hex = [rest.next()?, rest.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+    String::from_utf8(bytes).ok().filter(|s| !s.contains('\0'))
+}
+
+fn not_modified(req: &Request, etag: &str, modified: SystemTime) -> http::Result<bool> {
+    // If-None-Match takes precedence over If-Modified-Since
+    if let Some(tags) = req.header(headers::IF_NONE_MATCH) {
+        return Ok(tags.iter().flat_map(|t| t.as_str().split(',')).any(|t| {
+            let t = t.trim();
+            t == "*" || t.trim_start_matches("W/").trim_matches('"') == etag
+        }));
+    }
+    Ok(match IfModifiedSince::from_headers(req)? {
+        Some(since) => modified <= since.modified() + Duration::from_secs(1),
+        None => false,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum Range {
+    /// Byte range from the start(inclusive) to the end(exclusive)
+    Bytes(u64, u64),
+    Unsatisfiable,
+    // unsupported ranges like multiple ones are ignored serving the whole file
+    Ignored,
+}
+
+fn parse_range(header: &str, len: u64) -> Range {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Range::Ignored,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Range::Ignored,
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, (end + 1).min(len)),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len),
+        (Err(_), Ok(suffix)) if start.is_empty() => (len.saturating_sub(suffix), len),
+        _ => return Range::Ignored,
+    };
+    if range.0 >= range.1 {
+        return Range::Unsatisfiable;
+    }
+    Range::Bytes(range.0, range.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_rejects_traversal() {
+        let root = Path::new("/srv");
+        assert_eq!(resolve(root, "/a/b.txt"), Some("/srv/a/b.txt".into()));
+        assert_eq!(resolve(root, "/a/./b%20c"), Some("/srv/a/b c".into()));
+        assert_eq!(resolve(root, "/"), Some("/srv".into()));
+        assert_eq!(resolve(root, "/../etc/passwd"), None);
+        assert_eq!(resolve(root, "/a/%2e%2e/%2e%2e/etc"), None);
+        assert_eq!(resolve(root, "/a%00"), None);
+    }
+
+    #[test]
+    fn parse_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Range::Bytes(0, 10));
+        assert_eq!(parse_range("bytes=90-", 100), Range::Bytes(90, 100));
+        assert_eq!(parse_range("bytes=-10", 100), Range::Bytes(90, 100));
+        assert_eq!(parse_range("bytes=50-200", 100), Range::Bytes(50, 100));
+        assert_eq!(parse_range("bytes=100-", 100), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Range::Ignored);
+        assert_eq!(parse_range("items=0-1", 100), Range::Ignored);
+        assert_eq!(parse_range("bytes=5-1", 100), Range::Ignored);
+    }
+}
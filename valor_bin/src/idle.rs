@@ -0,0 +1,316 @@
+//! Connections that are closed when they go a while without activity or take
+//! too long to send a request so slow or gone clients don't keep their sockets open
+
+use async_std::{io, task};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use valor::http::Body;
+
+/// Handling of the connections after their requests are answered
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KeepAlive {
+    /// Connections are closed after every response when it's disabled
+    pub enabled: bool,
+    /// Time a connection can go without reading or writing a byte, it doesn't
+    /// run while a request is being handled
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Time clients have to send the parts of a request, the connection is answered
+/// with `408 Request Timeout` and closed when they take longer. It's how slow
+/// the client is, the time plugins take is not counted.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ReadTimeouts {
+    /// From the first byte of the request until its head is read
+    pub head: Option<Duration>,
+    /// Time a read of the body waits for the client to send more of it, the
+    /// time the plugin takes between reads is not counted
+    pub body: Option<Duration>,
+}
+
+/// Why the connection is closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Closed {
+    Idle,
+    /// The client took too long to send a request
+    Slow,
+}
+
+/// When the connection last read or wrote and how many of its requests are
+/// being handled
+#[derive(Debug)]
+pub(crate) struct Activity {
+    start: Instant,
+    // milliseconds since the start
+    last: AtomicU64,
+    busy: AtomicUsize,
+    // milliseconds since the start + 1 of when the head of a request started
+    // to come or a read of its body started waiting, 0 when none is
+    head_since: AtomicU64,
+    body_since: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            busy: AtomicUsize::new(0),
+            head_since: AtomicU64::new(0),
+            body_since: AtomicU64::new(0),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn touch(&self) {
+        self.last.store(self.now(), Ordering::Relaxed);
+    }
+
+    // bytes that come between requests are the head of the next one
+    fn touch_read(&self) {
+        self.touch();
+        if self.busy.load(Ordering::Relaxed) == 0 {
+            let since = self.now() + 1;
+            let _ =
+                self.head_since
+                    .compare_exchange(0, since, Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+
+    fn reading_for(&self, since: &AtomicU64) -> Option<Duration> {
+        match since.load(Ordering::Relaxed) {
+            0 => None,
+            since => Some(Duration::from_millis(self.now().saturating_sub(since - 1))),
+        }
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+
+    /// The connection is busy handling a request whose head was read while
+    /// the guard lives
+    pub fn busy(&self) -> Busy<'_> {
+        self.busy.fetch_add(1, Ordering::Relaxed);
+        self.head_since.store(0, Ordering::Relaxed);
+        Busy(self)
+    }
+
+    /// Resolves once the connection goes the `idle` timeout without activity
+    /// or the client is slower sending a request than the read timeouts allow
+    pub async fn closed(&self, idle: Option<Duration>, read: ReadTimeouts) -> Closed {
+        let timeouts = [idle, read.head, read.body];
+        let shortest = timeouts.iter().flatten().min().copied();
+        let shortest = match shortest {
+            Some(shortest) => shortest,
+            None => return async_std::future::pending().await,
+        };
+        loop {
+            let mut wait = shortest;
+            let reading = [
+                (read.head, self.reading_for(&self.head_since)),
+                (read.body, self.reading_for(&self.body_since)),
+            ];
+            for (timeout, elapsed) in reading.iter() {
+                if let (Some(timeout), Some(elapsed)) = (timeout, elapsed) {
+                    if elapsed >= timeout {
+                        return Closed::Slow;
+                    }
+                    wait = wait.min(*timeout - *elapsed);
+                }
+            }
+            if let Some(timeout) = idle {
+                let idle_for = self.idle_for();
+                if self.busy.load(Ordering::Relaxed) == 0 {
+                    if idle_for >= timeout {
+                        return Closed::Idle;
+                    }
+                    wait = wait.min(timeout - idle_for);
+                }
+            }
+            task::sleep(wait.max(Duration::from_millis(10))).await;
+        }
+    }
+}
+
+pub(crate) struct Busy<'a>(&'a Activity);
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.busy.fetch_sub(1, Ordering::Relaxed);
+        self.0.body_since.store(0, Ordering::Relaxed);
+        self.0.touch();
+    }
+}
+
+/// Body of a request that lets the connection know while it's being read
+pub(crate) struct TrackedBody {
+    body: Body,
+    activity: Arc<Activity>,
+}
+
+impl TrackedBody {
+    /// Body that reads the original one keeping its length and type
+    pub fn wrap(body: Body, activity: Arc<Activity>) -> Body {
+        let (len, mime) = (body.len(), body.mime().clone());
+        let tracked = TrackedBody { body, activity };
+        let mut body = Body::from_reader(io::BufReader::new(tracked), len);
+        body.set_mime(mime);
+        body
+    }
+}
+
+impl io::Read for TrackedBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = Pin::new(&mut self.body).poll_read(cx, buf);
+        let since = &self.activity.body_since;
+        match read {
+            // waiting for the client from the first time the read is pending
+            Poll::Pending => {
+                let now = self.activity.now() + 1;
+                let _ = since.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+            }
+            Poll::Ready(_) => since.store(0, Ordering::Relaxed),
+        }
+        read
+    }
+}
+
+impl Drop for TrackedBody {
+    fn drop(&mut self) {
+        self.activity.body_since.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Stream that keeps track of its activity, clones share it
+#[derive(Debug, Clone)]
+pub(crate) struct IdleStream<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> IdleStream<S> {
+    pub fn new(inner: S) -> Self {
+        IdleStream {
+            inner,
+            activity: Arc::new(Activity::new()),
+        }
+    }
+
+    pub fn activity(&self) -> Arc<Activity> {
+        self.activity.clone()
+    }
+}
+
+impl<S: io::Read + Unpin> io::Read for IdleStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = read {
+            if n > 0 {
+                self.activity.touch_read();
+            }
+        }
+        read
+    }
+}
+
+impl<S: io::Write + Unpin> io::Write for IdleStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = written {
+            if n > 0 {
+                self.activity.touch();
+            }
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{prelude::*, Cursor};
+
+    #[async_std::test]
+    async fn idle_without_activity() {
+        let mut stream = IdleStream::new(Cursor::new(b"ping".to_vec()));
+        let activity = stream.activity();
+        let timeout = Duration::from_millis(50);
+
+        task::sleep(Duration::from_millis(30)).await;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert!(activity.idle_for() < timeout);
+
+        // a request being handled keeps it active
+        let busy = activity.busy();
+        let closed = activity.closed(Some(timeout), ReadTimeouts::default());
+        let closed = async_std::future::timeout(Duration::from_millis(100), closed);
+        assert!(closed.await.is_err());
+        let started = Instant::now();
+        drop(busy);
+
+        let closed = activity.closed(Some(timeout), ReadTimeouts::default());
+        assert_eq!(closed.await, Closed::Idle);
+        assert!(started.elapsed() >= timeout);
+    }
+
+    #[async_std::test]
+    async fn slow_requests() {
+        let read = ReadTimeouts {
+            head: Some(Duration::from_millis(50)),
+            body: Some(Duration::from_millis(50)),
+        };
+        let mut stream = IdleStream::new(Cursor::new(b"GET / HTTP/1.1\r\n".to_vec()));
+        let activity = stream.activity();
+
+        // the head started to come but didn't end
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        let started = Instant::now();
+        assert_eq!(activity.closed(None, read).await, Closed::Slow);
+        assert!(started.elapsed() >= Duration::from_millis(40));
+
+        // the time the plugin takes between reads is not the client's
+        let busy = activity.busy();
+        let mut body = TrackedBody::wrap(Body::from("hello"), activity.clone());
+        let mut buf = [0; 2];
+        body.read_exact(&mut buf).await.unwrap();
+        let closed = activity.closed(None, read);
+        let closed = async_std::future::timeout(Duration::from_millis(100), closed);
+        assert!(closed.await.is_err());
+        assert_eq!(body.into_string().await.unwrap(), "llo");
+        drop(busy);
+    }
+}
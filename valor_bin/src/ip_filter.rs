@@ -0,0 +1,147 @@
+//! Middleware allowing or denying requests by the address of the client
+
+use async_trait::async_trait;
+use std::net::IpAddr;
+use valor::http::{Request, Response, StatusCode};
+use valor::runtime::{Middleware, Next, Prefixes};
+use valor::{Cidr, ClientIp};
+
+/// Answers with `403 Forbidden` the requests of clients in a denied range or
+/// not in any of the allowed ones when there are, denied ranges take precedence.
+/// Requests without a client address like the ones from a unix socket are only
+/// let through when there's no allowed range as they can't be in one. Behind trusted proxies the runtime resolves the client
+/// from the `X-Forwarded-For` header.
+#[derive(Default)]
+pub(crate) struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    prefixes: Prefixes,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        IpFilter {
+            allow,
+            deny,
+            ..Default::default()
+        }
+    }
+
+    /// Only requests for paths under the prefix are filtered, without
+    /// prefixes all requests are
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefixes.push(prefix);
+        self
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|r| r.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|r| r.contains(ip)))
+    }
+}
+
+#[async_trait(?Send)]
+impl Middleware for IpFilter {
+    async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response, valor::Error> {
+        if !self.prefixes.covers(req.url().path()) {
+            return next.run(req).await;
+        }
+        let allowed = match req.client_ip() {
+            Some(ip) => self.allows(ip),
+            None => self.allow.is_empty(),
+        };
+        if !allowed {
+            return Ok(Response::new(StatusCode::Forbidden));
+        }
+        next.run(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(ranges: &[&str]) -> Vec<Cidr> {
+        ranges.iter().map(|r| r.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let filter = IpFilter::new(cidrs(&["10.0.0.0/8", "fd00::/8"]), cidrs(&["10.0.0.66"]));
+        assert!(filter.allows(ip("10.0.0.1")));
+        assert!(filter.allows(ip("fd12::1")));
+        assert!(!filter.allows(ip("10.0.0.66")));
+        assert!(!filter.allows(ip("192.168.0.1")));
+
+        let filter = IpFilter::new(vec![], cidrs(&["192.168.0.0/24"]));
+        assert!(filter.allows(ip("8.8.8.8")));
+        assert!(!filter.allows(ip("192.168.0.5")));
+    }
+
+    #[async_std::test]
+    async fn forbid_only_under_prefix() {
+        let filter = IpFilter::new(cidrs(&["127.0.0.1"]), vec![]).prefix("/_plugins");
+        let runtime = valor::runtime::Runtime::new(())
+            .with_middleware(filter)
+            .with_plugin("plugins", ())
+            .unwrap()
+            .with_plugin("foo", ())
+            .unwrap();
+        let request = |path: &str, peer: &str| {
+            let url = "http://example.com".to_owned() + path;
+            let mut req = Request::new(valor::http::Method::Get, url.as_str());
+            req.insert_header("x-request-id", "1");
+            req.set_peer_addr(Some(peer));
+            req
+        };
+        assert_eq!(
+            runtime
+                .handle(request("/_plugins", "8.8.8.8:80"))
+                .await
+                .status(),
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            runtime
+                .handle(request("/_plugins", "127.0.0.1:80"))
+                .await
+                .status(),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            runtime
+                .handle(request("/_foo", "8.8.8.8:80"))
+                .await
+                .status(),
+            StatusCode::Ok
+        );
+        // an allowlist can't be passed without an address
+        let mut req = Request::new(valor::http::Method::Get, "http://example.com/_plugins");
+        req.insert_header("x-request-id", "1");
+        assert_eq!(runtime.handle(req).await.status(), StatusCode::Forbidden);
+    }
+
+    #[async_std::test]
+    async fn filter_clients_behind_trusted_proxies() {
+        let runtime = valor::runtime::Runtime::new(())
+            .with_trusted_proxies(cidrs(&["10.0.0.0/8"]))
+            .with_middleware(IpFilter::new(vec![], cidrs(&["203.0.113.0/24"])))
+            .with_plugin("foo", ())
+            .unwrap();
+        let status = |forwarded_for: &str| {
+            let mut req = Request::new(valor::http::Method::Get, "http://example.com/_foo");
+            req.insert_header("x-request-id", "1");
+            req.insert_header("x-forwarded-for", forwarded_for);
+            req.set_peer_addr(Some("10.0.0.2:1234"));
+            let runtime = &runtime;
+            async move { runtime.handle(req).await.status() }
+        };
+        // the proxy is allowed, the client it forwards is not
+        assert_eq!(status("203.0.113.7").await, StatusCode::Forbidden);
+        assert_eq!(status("198.51.100.1").await, StatusCode::Ok);
+    }
+}
@@ -0,0 +1,72 @@
+//! Limits of the head of requests that are checked before they reach the runtime
+
+use valor::http::{Request, StatusCode};
+
+pub(crate) const DEFAULT_MAX_HEADER_SIZE: usize = 8 * 1024;
+pub(crate) const DEFAULT_MAX_URI_LENGTH: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HeadLimits {
+    /// Bytes of all the header lines of a request
+    pub header_size: usize,
+    /// Bytes of the path and query of a request
+    pub uri_length: usize,
+}
+
+impl Default for HeadLimits {
+    fn default() -> Self {
+        HeadLimits {
+            header_size: DEFAULT_MAX_HEADER_SIZE,
+            uri_length: DEFAULT_MAX_URI_LENGTH,
+        }
+    }
+}
+
+impl HeadLimits {
+    /// Status to reject the request with when it's over a limit
+    pub fn check(&self, req: &Request) -> Result<(), StatusCode> {
+        let url = req.url();
+        let uri_length = url.path().len() + url.query().map_or(0, |q| q.len() + 1);
+        if uri_length > self.uri_length {
+            return Err(StatusCode::UriTooLong);
+        }
+        // as sent, with the `: ` separator and the line break
+        let header_size: usize = req
+            .iter()
+            .flat_map(|(name, values)| values.iter().map(move |v| (name, v)))
+            .map(|(name, value)| name.as_str().len() + value.as_str().len() + 4)
+            .sum();
+        if header_size > self.header_size {
+            return Err(StatusCode::RequestHeaderFieldsTooLarge);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use valor::http::Method;
+
+    #[test]
+    fn reject_requests_over_limits() {
+        let limits = HeadLimits {
+            header_size: 64,
+            uri_length: 16,
+        };
+        let mut req = Request::new(Method::Get, "http://localhost/foo?bar=baz");
+        req.insert_header("x-small", "1");
+        assert_eq!(limits.check(&req), Ok(()));
+
+        let req = Request::new(Method::Get, "http://localhost/foo?bar=bazbazbaz");
+        assert_eq!(limits.check(&req), Err(StatusCode::UriTooLong));
+
+        let mut req = Request::new(Method::Get, "http://localhost/foo");
+        req.insert_header("x-big", "a".repeat(40));
+        req.append_header("x-big", "a".repeat(40));
+        assert_eq!(
+            limits.check(&req),
+            Err(StatusCode::RequestHeaderFieldsTooLarge)
+        );
+    }
+}
@@ -1,12 +1,27 @@
+use crate::signature::TrustedKey;
 use async_trait::async_trait;
 use kv_log_macro::{debug, warn};
-use libloading::{library_filename, Library, Symbol};
-use std::{cell::RefCell, collections::HashMap, pin::Pin, rc::Rc};
-use valor::{runtime, Vlugin, VluginConfig};
+use libloading::{library_filename, Library};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    pin::Pin,
+    rc::{Rc, Weak},
+};
+use valor::{http, runtime, Answer, Context, Health, Message, Upgraded, Vlugin, VluginConfig};
+
+const ENTRY_SYMBOL: &[u8] = b"valor_plugin_entry";
+const ABI_VERSION_SYMBOL: &[u8] = b"valor_abi_version";
 
 #[derive(Default)]
 pub(crate) struct Loader {
-    plugins: RefCell<HashMap<String, Rc<Library>>>,
+    dylib: DylibLoader,
+    #[cfg(feature = "wasm")]
+    wasm: crate::wasm::WasmLoader,
+    // executable plugins have to be signed with it when set
+    trusted_key: Option<TrustedKey>,
 }
 
 #[async_trait(?Send)]
@@ -15,56 +30,301 @@ impl runtime::Loader for Loader {
         &self,
         plugin: &runtime::VluginDef,
     ) -> Result<runtime::VluginFactory, runtime::Error> {
-        match &plugin.r#type {
-            runtime::VluginType::Native { path } => {
-                let name = &plugin.name;
-                if let Some(factory) = self.get_factory(name) {
-                    return Ok(factory);
-                }
+        let factory = self.factory(plugin).await?;
+        // placeholders are expanded every time a plugin is instantiated
+        Ok(Box::new(move |cfg| {
+            match cfg.map(crate::substitute::expand).transpose() {
+                Ok(cfg) => factory(cfg),
+                Err(err) => invalid_config(err),
+            }
+        }))
+    }
+}
+
+// Instantiation that fails like plugins do when they reject their configuration
+fn invalid_config<'a>(err: String) -> FactoryFuture<'a> {
+    Box::pin(async move { Err(http::Error::from_str(http::StatusCode::BadRequest, err).into()) })
+}
+
+impl Loader {
+    #[cfg(feature = "wasm")]
+    pub fn with_wasm_limits(mut self, limits: crate::wasm::Limits) -> Self {
+        self.wasm = crate::wasm::WasmLoader::new(limits);
+        self
+    }
+
+    /// Native and wasm plugins are only loaded if their module is signed with the key
+    pub fn with_trusted_key(mut self, key: TrustedKey) -> Self {
+        self.trusted_key = Some(key);
+        self
+    }
 
+    async fn factory(
+        &self,
+        plugin: &runtime::VluginDef,
+    ) -> Result<runtime::VluginFactory<'_>, runtime::Error> {
+        match (&plugin.r#type, &self.trusted_key) {
+            (runtime::VluginType::Native { .. }, None) => {
+                runtime::Loader::load(&self.dylib, plugin).await
+            }
+            // the library is opened from a private copy of the bytes that were
+            // verified so the file can't be swapped in between, it's not
+            // searched in the library path either
+            (runtime::VluginType::Native { path }, Some(key)) => {
                 let path = path
                     .as_ref()
                     .map(Into::into)
-                    .unwrap_or_else(|| library_filename(name));
-                debug!("loading native plugin {}({})", name, path.to_string_lossy());
-                let lib = unsafe { Library::new(path) }.map_err(|e| {
+                    .unwrap_or_else(|| library_filename(&plugin.name));
+                let copy = VerifiedCopy::new(key, plugin, Path::new(&path))?;
+                let plugin = runtime::VluginDef {
+                    r#type: runtime::VluginType::Native {
+                        path: Some(copy.path().to_string_lossy().into()),
+                    },
+                    ..plugin.clone()
+                };
+                // the library stays mapped after its copy is removed
+                runtime::Loader::load(&self.dylib, &plugin).await
+            }
+            #[cfg(feature = "wasm")]
+            (runtime::VluginType::Wasm { .. }, None) => {
+                runtime::Loader::load(&self.wasm, plugin).await
+            }
+            #[cfg(feature = "wasm")]
+            (runtime::VluginType::Wasm { path }, Some(key)) => {
+                let module = std::fs::read(path).map_err(|e| {
                     warn!("{}", e);
-                    runtime::Error::LoadVlugin(name.to_owned())
+                    runtime::Error::LoadVlugin(plugin.name.clone())
                 })?;
-
-                {
-                    self.plugins.borrow_mut().insert(name.into(), Rc::new(lib));
-                }
-
-                self.get_factory(name)
-                    .ok_or(runtime::Error::LoadVlugin(name.to_owned()))
+                key.verify(plugin, &module)?;
+                self.wasm.load_module(plugin, &module)
             }
-            ty => Err(runtime::Error::VluginNotSupported(ty.to_owned())),
+            (runtime::VluginType::Proxy, _) => Ok(Box::new(|cfg| {
+                Box::pin(async move {
+                    let proxy = valor::Proxy::from_config(cfg.as_ref())?;
+                    Ok(Box::new(proxy) as Box<dyn Vlugin>)
+                })
+            })),
+            (runtime::VluginType::Files, _) => Ok(Box::new(|cfg| {
+                Box::pin(async move {
+                    let files = crate::files::Files::from_config(cfg.as_ref())?;
+                    Ok(Box::new(files) as Box<dyn Vlugin>)
+                })
+            })),
+            (runtime::VluginType::Redirect, _) => Ok(Box::new(|cfg| {
+                Box::pin(async move {
+                    let redirect = runtime::RedirectHandler::from_config(cfg.as_ref())?;
+                    Ok(Box::new(redirect) as Box<dyn Vlugin>)
+                })
+            })),
+            (ty, _) => Err(runtime::Error::VluginNotSupported(ty.to_owned())),
         }
     }
 }
 
-type Factory<'a> = fn(
-    Option<VluginConfig>,
-) -> Pin<
-    Box<dyn core::future::Future<Output = Result<Box<dyn Vlugin>, valor::Error>> + 'a>,
->;
+// Library that was verified written to a directory only the current user can
+// access, both are removed once dropped
+struct VerifiedCopy {
+    dir: PathBuf,
+    path: PathBuf,
+}
 
-impl Loader {
-    fn get_factory(&self, name: &str) -> Option<runtime::VluginFactory> {
-        let lib = self.plugins.borrow().get(name)?.clone();
-        let name = name.to_owned();
+impl VerifiedCopy {
+    fn new(
+        key: &TrustedKey,
+        plugin: &runtime::VluginDef,
+        path: &Path,
+    ) -> Result<Self, runtime::Error> {
+        let failed = |e: std::io::Error| {
+            warn!("{}", e);
+            runtime::Error::LoadVlugin(plugin.name.clone())
+        };
+        // read once, what is verified is what gets loaded
+        let module = std::fs::read(path).map_err(failed)?;
+        key.verify(plugin, &module)?;
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let dir = std::env::temp_dir().join(format!(
+            "valor-{}-{}-{}",
+            plugin.name,
+            std::process::id(),
+            nanos
+        ));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        // fails if the directory already exists so it can't be one prepared
+        // by someone else
+        builder.create(&dir).map_err(failed)?;
+        let copy = VerifiedCopy {
+            path: dir.join(library_filename(&plugin.name)),
+            dir,
+        };
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&copy.path).map_err(failed)?;
+        std::io::Write::write_all(&mut file, &module).map_err(failed)?;
+        file.sync_all().map_err(failed)?;
+        Ok(copy)
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for VerifiedCopy {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = std::fs::remove_dir(&self.dir);
+    }
+}
+
+/// Loads natively compiled plugins from dynamic libraries
+#[derive(Default)]
+pub(crate) struct DylibLoader {
+    // libraries are shared by the plugins loaded from the same path and
+    // unloaded once the last plugin using them is dropped
+    libs: RefCell<HashMap<OsString, Weak<Library>>>,
+}
+
+#[async_trait(?Send)]
+impl runtime::Loader for DylibLoader {
+    async fn load(
+        &self,
+        plugin: &runtime::VluginDef,
+    ) -> Result<runtime::VluginFactory, runtime::Error> {
+        let path = match &plugin.r#type {
+            runtime::VluginType::Native { path } => path,
+            ty => return Err(runtime::Error::VluginNotSupported(ty.to_owned())),
+        };
+        let name = &plugin.name;
+        let path: OsString = path
+            .as_ref()
+            .map(Into::into)
+            .unwrap_or_else(|| library_filename(name));
+        let lib = self.open(&path, name)?;
+
+        let version = unsafe { lib.get::<*const u32>(ABI_VERSION_SYMBOL) }
+            .map(|v| unsafe { **v })
+            .map_err(|_| runtime::Error::VluginEntryNotFound(name.to_owned()))?;
+        if version != valor::VLUGIN_ABI_VERSION {
+            return Err(runtime::Error::IncompatibleVlugin(name.to_owned(), version));
+        }
+        let entry = unsafe { lib.get::<Factory>(ENTRY_SYMBOL) }
+            .map(|f| *f)
+            .map_err(|_| runtime::Error::VluginEntryNotFound(name.to_owned()))?;
 
-        Some(Box::new(move |cfg| {
+        Ok(Box::new(move |cfg| {
             let lib = lib.clone();
-            let name = name.clone();
             Box::pin(async move {
-                let lib = lib.clone();
-                let factory: Symbol<'_, Factory> =
-                    unsafe { lib.get(b"instantiate_vlugin") }.expect("Plugin interface");
-                debug!("{} {:?}", name, factory);
-                factory(cfg).await
+                let handler = entry(cfg).await?;
+                Ok(Box::new(DylibVlugin { handler, _lib: lib }) as Box<dyn Vlugin>)
             })
         }))
     }
 }
+
+type FactoryFuture<'a> =
+    Pin<Box<dyn core::future::Future<Output = Result<Box<dyn Vlugin>, valor::Error>> + 'a>>;
+type Factory<'a> = fn(Option<VluginConfig>) -> FactoryFuture<'a>;
+
+impl DylibLoader {
+    fn open(&self, path: &OsStr, name: &str) -> Result<Rc<Library>, runtime::Error> {
+        if let Some(lib) = self.libs.borrow().get(path).and_then(Weak::upgrade) {
+            return Ok(lib);
+        }
+        debug!("loading native plugin {}({})", name, path.to_string_lossy());
+        let lib = unsafe { Library::new(path) }.map_err(|e| {
+            warn!("{}", e);
+            runtime::Error::LoadVlugin(name.to_owned())
+        })?;
+        let lib = Rc::new(lib);
+        let mut libs = self.libs.borrow_mut();
+        libs.retain(|_, lib| lib.strong_count() > 0);
+        libs.insert(path.to_owned(), Rc::downgrade(&lib));
+        Ok(lib)
+    }
+}
+
+// Plugin instance that keeps alive the library its code comes from, requests
+// in flight hold a reference to it so the library outlives them
+struct DylibVlugin {
+    // dropped before the library
+    handler: Box<dyn Vlugin>,
+    _lib: Rc<Library>,
+}
+
+#[async_trait(?Send)]
+impl Vlugin for DylibVlugin {
+    async fn on_create(&mut self) -> Result<(), valor::Error> {
+        self.handler.on_create().await
+    }
+
+    async fn on_msg(&self, msg: Message) -> Result<Answer, valor::Error> {
+        self.handler.on_msg(msg).await
+    }
+
+    async fn warmup(&self) -> Result<(), valor::Error> {
+        self.handler.warmup().await
+    }
+
+    async fn health(&self) -> Health {
+        self.handler.health().await
+    }
+
+    fn accepts_upgrade(&self, protocol: &str) -> bool {
+        self.handler.accepts_upgrade(protocol)
+    }
+
+    async fn on_upgrade(
+        &self,
+        req: http::Request,
+        conn: Box<dyn Upgraded>,
+    ) -> Result<(), valor::Error> {
+        self.handler.on_upgrade(req, conn).await
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        self.handler.context_mut()
+    }
+    fn context(&self) -> &Context {
+        self.handler.context()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey};
+
+    #[test]
+    fn load_the_verified_copy() {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let key: TrustedKey = base64::encode(public.as_bytes()).parse().unwrap();
+        let module = b"native plugin";
+        let signature = ExpandedSecretKey::from(&secret).sign(module, &public);
+        let plugin = runtime::VluginDef {
+            signature: Some(base64::encode(signature.to_bytes())),
+            ..runtime::VluginDef::from("foo")
+        };
+        let original = std::env::temp_dir().join(format!("valor-verify-{}", std::process::id()));
+        std::fs::write(&original, module).unwrap();
+
+        let copy = VerifiedCopy::new(&key, &plugin, &original).unwrap();
+        // replacing the original doesn't change what gets loaded
+        std::fs::write(&original, b"tampered plugin").unwrap();
+        assert_eq!(std::fs::read(copy.path()).unwrap(), module);
+        assert!(VerifiedCopy::new(&key, &plugin, &original).is_err());
+
+        let path = copy.path().to_owned();
+        drop(copy);
+        assert!(!path.exists());
+        std::fs::remove_file(original).unwrap();
+    }
+}
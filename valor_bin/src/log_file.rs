@@ -0,0 +1,268 @@
+//! Logger writing newline delimited JSON to a file that is rotated when it
+//! gets too big or a new hour or day starts
+
+use log::{kv, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) const DEFAULT_KEEP: usize = 5;
+
+/// Rotates the file every hour or day(UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Interval {
+    Hourly,
+    Daily,
+}
+
+impl Interval {
+    fn secs(self) -> u64 {
+        match self {
+            Interval::Hourly => 60 * 60,
+            Interval::Daily => 24 * 60 * 60,
+        }
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(Interval::Hourly),
+            "daily" => Ok(Interval::Daily),
+            _ => Err(format!("unknown rotation interval {}", s)),
+        }
+    }
+}
+
+/// When the log file is rotated, it's renamed to `<file>.1` and the older
+/// ones shifted(`<file>.1` to `<file>.2` and so on) keeping `keep` of them
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Rotation {
+    pub max_size: Option<u64>,
+    pub interval: Option<Interval>,
+    pub keep: usize,
+}
+
+/// Logger of all the workers, lines are written whole one at a time
+pub(crate) struct FileLogger {
+    level: LevelFilter,
+    file: Mutex<RotatingFile>,
+}
+
+impl FileLogger {
+    pub fn open(path: &Path, rotation: Rotation, level: LevelFilter) -> io::Result<Self> {
+        Ok(FileLogger {
+            level,
+            file: Mutex::new(RotatingFile::open(path, rotation, unix_secs())?),
+        })
+    }
+
+    /// Installs the logger for the `log` macros of the process
+    pub fn start(self) -> Result<(), log::SetLoggerError> {
+        let level = self.level;
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = serde_json::to_vec(&to_json(record)).unwrap_or_default();
+        line.push(b'\n');
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(err) = file.write_line(&line, unix_secs()) {
+            eprintln!("can't write to the log file: {}", err);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Same shape as the `ndjson` logs of the terminal
+fn to_json(record: &Record<'_>) -> Value {
+    struct Fields(Map<String, Value>);
+
+    impl<'kvs> kv::Visitor<'kvs> for Fields {
+        fn visit_pair(
+            &mut self,
+            key: kv::Key<'kvs>,
+            val: kv::Value<'kvs>,
+        ) -> Result<(), kv::Error> {
+            let val = if let Some(n) = val.to_u64() {
+                n.into()
+            } else if let Some(n) = val.to_i64() {
+                n.into()
+            } else if let Some(b) = val.to_bool() {
+                b.into()
+            } else {
+                val.to_string().into()
+            };
+            self.0.insert(key.to_string(), val);
+            Ok(())
+        }
+    }
+
+    let level = match record.level() {
+        log::Level::Trace => 10,
+        log::Level::Debug => 20,
+        log::Level::Info => 30,
+        log::Level::Warn => 40,
+        log::Level::Error => 50,
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let mut fields = Fields(Map::new());
+    fields.0.insert("level".into(), level.into());
+    fields.0.insert("time".into(), time.into());
+    fields
+        .0
+        .insert("msg".into(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut fields);
+    Value::Object(fields.0)
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    // interval the lines written to the file are from
+    period: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, rotation: Rotation, now: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.into(),
+            period: period(rotation, now),
+            rotation,
+            file,
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8], now: u64) -> io::Result<()> {
+        let too_big = self.rotation.max_size.map_or(false, |max| {
+            self.size > 0 && self.size + line.len() as u64 > max
+        });
+        if too_big || period(self.rotation, now) != self.period {
+            self.rotate(now)?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self, now: u64) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.rotation.keep > 0 {
+            let _ = fs::remove_file(rotated(self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.period = period(self.rotation, now);
+        Ok(())
+    }
+}
+
+fn period(rotation: Rotation, now: u64) -> u64 {
+    rotation
+        .interval
+        .map_or(0, |interval| now / interval.secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(path: &Path, suffix: &str) -> String {
+        let mut name = path.as_os_str().to_owned();
+        name.push(suffix);
+        fs::read_to_string(name).unwrap_or_default()
+    }
+
+    #[test]
+    fn rotate_by_size_and_time() {
+        let dir = std::env::temp_dir().join(format!("valor-log-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("valor.log");
+        let rotation = Rotation {
+            max_size: Some(10),
+            interval: Some(Interval::Hourly),
+            keep: 2,
+        };
+        let mut file = RotatingFile::open(&path, rotation, 0).unwrap();
+
+        for line in &["one\n", "two\n", "three\n", "four\n"] {
+            file.write_line(line.as_bytes(), 60).unwrap();
+        }
+        assert_eq!(read(&path, ""), "four\n");
+        assert_eq!(read(&path, ".1"), "three\n");
+        assert_eq!(read(&path, ".2"), "one\ntwo\n");
+
+        // a new hour starts a new file, the oldest ones are removed
+        file.write_line(b"five\n", 60 * 60).unwrap();
+        assert_eq!(read(&path, ""), "five\n");
+        assert_eq!(read(&path, ".1"), "four\n");
+        assert_eq!(read(&path, ".2"), "three\n");
+        assert_eq!(read(&path, ".3"), "");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_as_json() {
+        use kv::ToValue;
+        let fields = vec![("id", "123".to_value()), ("status", 404u16.to_value())];
+        let record = Record::builder()
+            .level(log::Level::Warn)
+            .args(format_args!("not found"))
+            .key_values(&fields)
+            .build();
+        let json = to_json(&record);
+        assert_eq!(json["level"], 40);
+        assert_eq!(json["msg"], "not found");
+        assert_eq!(json["id"], "123");
+        assert_eq!(json["status"], 404);
+    }
+}
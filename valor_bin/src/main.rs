@@ -1,130 +1,1781 @@
 //! ValorBin it's the native runtime that is able to load vlugins
 //! from a JSON configuration file and serve incoming HTTP requests.
 
+#[cfg(unix)]
+use async_std::os::unix::net::{UnixListener, UnixStream};
 use async_std::{
+    channel,
+    future::{self, FutureExt},
+    io::{self, WriteExt},
     net::{TcpListener, TcpStream},
-    stream::StreamExt,
+    stream::{Stream, StreamExt},
     task,
 };
+use async_tls::TlsAcceptor;
+use config::Config;
 use kv_log_macro::{error, info, warn};
 use loader::Loader;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
 use serde::Deserialize;
-use std::{fs::File, path::PathBuf, time::Instant};
+use std::{
+    env, fs,
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 use structopt::StructOpt;
 use uuid::Uuid;
 use valor::runtime;
-use valor::{http, Vlugin};
 
+mod access_log;
+mod compression;
+mod config;
+mod files;
+mod idle;
+mod ip_filter;
+mod limits;
 mod loader;
+mod log_file;
+mod rate_limit;
+mod remote;
+mod signature;
+mod substitute;
+mod tls;
+mod trace;
+#[cfg(feature = "wasm")]
+mod wasm;
 
 type Runtime = runtime::Runtime<Loader>;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "valor")]
 struct Opt {
+    /// TOML or JSON file with the settings, flags override its values
+    #[structopt(short, long)]
+    config: Option<PathBuf>,
+
+    /// Address to listen on as `host:port`, it's `0.0.0.0:8080` unless
+    /// only a unix socket is given
+    #[structopt(short, long)]
+    bind: Option<String>,
+
+    /// Path of a unix socket to listen on
+    #[cfg(unix)]
+    #[structopt(long)]
+    unix_socket: Option<PathBuf>,
+
     /// Enables the plugin registry endpoint
     #[structopt(short)]
     with_registry: bool,
 
+    /// Token required as `Authorization: Bearer <token>` to modify the registry
+    #[structopt(long, env = "VALOR_REGISTRY_TOKEN", hide_env_values = true)]
+    registry_token: Option<String>,
+
+    /// JSON file where the plugins added to the registry are saved to be
+    /// loaded again on restart, secrets of their config are saved redacted
+    /// so they should be given as `${VAR}` placeholders
+    #[structopt(long, requires = "with-registry")]
+    registry_file: Option<PathBuf>,
+
+    /// Listing the plugins of the registry also requires the token
+    #[structopt(long, requires = "registry-token")]
+    protect_registry_list: bool,
+
+    /// Answer errors as `application/problem+json` instead of plain text
+    #[structopt(long)]
+    problem_details: bool,
+
+    /// Plugins can be replaced with an older version of themselves
+    #[structopt(long)]
+    allow_downgrades: bool,
+
+    /// Answer `HEAD` requests only to plugins that serve `HEAD`, otherwise
+    /// a `GET` plugin answers them without the body
+    #[structopt(long)]
+    no_auto_head: bool,
+
+    /// Add a `Server-Timing` header with the time it took to answer to every response
+    #[structopt(long)]
+    server_timing: bool,
+
     /// Json file with the list of plugins to load at startup
-    #[structopt(short)]
+    #[structopt(short, conflicts_with = "plugin-url")]
     plugin_file: Option<PathBuf>,
+
+    /// Url of a manifest with the list of plugins to load at startup, it has
+    /// the format of the plugin file
+    #[structopt(long)]
+    plugin_url: Option<valor::http::Url>,
+
+    /// Fetch the plugin manifest again every given seconds applying its changes
+    #[structopt(long, requires = "plugin-url")]
+    plugin_poll_secs: Option<u64>,
+
+    /// File where the last plugin manifest fetched is kept to be used when
+    /// the server is unreachable at startup
+    #[structopt(long, requires = "plugin-url")]
+    plugin_cache: Option<PathBuf>,
+
+    /// Base64 ed25519 public key native and wasm plugins have to be signed
+    /// with, plugins without a valid `signature` are not loaded
+    #[structopt(long)]
+    plugin_key: Option<signature::TrustedKey>,
+
+    /// Exposes Prometheus metrics on `/_metrics`
+    #[structopt(long)]
+    metrics: bool,
+
+    /// Reload the plugins when the plugin file changes
+    #[structopt(long, requires = "plugin-file")]
+    watch: bool,
+
+    /// Load the plugins of the startup list on the first request for them
+    /// instead of at startup, for large lists of plugins
+    #[structopt(long)]
+    lazy_load: bool,
+
+    /// Origin allowed to make cross-origin requests, `*` allows any.
+    /// Can be used multiple times
+    #[structopt(long = "cors-origin")]
+    cors_origins: Vec<String>,
+
+    /// Add security headers like HSTS and `X-Frame-Options` to responses
+    #[structopt(long)]
+    security_headers: bool,
+
+    /// Content security policy sent with the security headers
+    #[structopt(long, requires = "security-headers")]
+    csp: Option<String>,
+
+    /// User allowed with basic authentication as `name:hash` where the hash
+    /// is the bcrypt or argon2 hash of the password. Can be used multiple times
+    #[structopt(long = "basic-auth")]
+    basic_auth: Vec<String>,
+
+    /// Path prefix that needs basic authentication, all do by default.
+    /// Can be used multiple times
+    #[structopt(long = "basic-auth-prefix", requires = "basic-auth")]
+    basic_auth_prefixes: Vec<String>,
+
+    /// Secret that bearer tokens are signed with(HS256)
+    #[structopt(
+        long,
+        env = "VALOR_JWT_SECRET",
+        hide_env_values = true,
+        conflicts_with = "jwks-url"
+    )]
+    jwt_secret: Option<String>,
+
+    /// Url of the keys that bearer tokens are signed with(RS256)
+    #[structopt(long)]
+    jwks_url: Option<valor::http::Url>,
+
+    /// Issuer bearer tokens must come from
+    #[structopt(long)]
+    jwt_issuer: Option<String>,
+
+    /// Audience bearer tokens must be for
+    #[structopt(long)]
+    jwt_audience: Option<String>,
+
+    /// Path prefix that needs a bearer token, all do by default.
+    /// Can be used multiple times
+    #[structopt(long = "jwt-prefix")]
+    jwt_prefixes: Vec<String>,
+
+    /// Range of client addresses allowed to make requests like `10.0.0.0/8`,
+    /// any is by default. Requests without an address(e.g. from a unix socket)
+    /// are refused when it's set. Can be used multiple times
+    #[structopt(long = "ip-allow")]
+    ip_allow: Vec<valor::Cidr>,
+
+    /// Range of client addresses that can't make requests. Can be used multiple times
+    #[structopt(long = "ip-deny")]
+    ip_deny: Vec<valor::Cidr>,
+
+    /// Path prefix the allowed and denied addresses apply to(e.g. `/_plugins`),
+    /// all paths by default. Can be used multiple times
+    #[structopt(long = "ip-filter-prefix")]
+    ip_filter_prefixes: Vec<String>,
+
+    /// Host requests can be sent to, e.g. `example.com` or `*.example.com`, other
+    /// hosts are answered with `421`. Any host is accepted when none is given.
+    /// Can be used multiple times
+    #[structopt(long = "allowed-host")]
+    allowed_hosts: Vec<String>,
+
+    /// Starts in maintenance, requests for plugins are answered with `503`
+    /// until it's ended with `DELETE /_maintenance`(needs the registry)
+    #[structopt(long)]
+    maintenance: bool,
+
+    /// File answered during maintenance, an `.html` or `.json` file is sent
+    /// with its content type or as plain text otherwise
+    #[structopt(long)]
+    maintenance_page: Option<PathBuf>,
+
+    /// Seconds clients are told to wait with `Retry-After` during maintenance
+    #[structopt(long, requires = "maintenance-page")]
+    maintenance_retry_after: Option<u64>,
+
+    /// Range of proxies whose `X-Forwarded-For` header tells the address of the
+    /// client used to rate limit and filter requests. Can be used multiple times
+    #[structopt(long = "trusted-proxy")]
+    trusted_proxies: Vec<valor::Cidr>,
+
+    /// Requests a client can make to each plugin per rate limit window,
+    /// unlimited by default
+    #[structopt(long)]
+    rate_limit: Option<u32>,
+
+    /// Seconds of the rate limit window, it can't be `0`
+    #[structopt(long, default_value = "60", requires = "rate-limit")]
+    rate_limit_window: std::num::NonZeroU64,
+
+    /// Requests a client can make at once, the same as the rate limit by default
+    #[structopt(long, requires = "rate-limit", conflicts_with = "rate-limit-redis")]
+    rate_limit_burst: Option<u32>,
+
+    /// Redis server(e.g. `redis://10.0.0.5/`) where the requests of clients are
+    /// counted so the rate limit applies to all the instances that share it.
+    /// Bursts are not limited apart with it so it can't have `--rate-limit-burst`
+    #[structopt(long, requires = "rate-limit")]
+    rate_limit_redis: Option<String>,
+
+    /// Milliseconds the Redis server has to count a request before it's
+    /// considered unreachable
+    #[structopt(long, default_value = "100", requires = "rate-limit-redis")]
+    rate_limit_redis_timeout_ms: u64,
+
+    /// Answer `503` when the Redis server can't be reached instead of letting
+    /// requests through
+    #[structopt(long, requires = "rate-limit-redis")]
+    rate_limit_fail_closed: bool,
+
+    /// Keeps clients on the same variant of weighted plugins by this cookie
+    #[structopt(long)]
+    sticky_cookie: Option<String>,
+
+    /// Keeps clients on the same variant of weighted plugins by their IP
+    #[structopt(long, conflicts_with = "sticky-cookie")]
+    sticky_ip: bool,
+
+    /// Name of a cookie the server sets with the variant of weighted plugins
+    /// that served the client to keep sending it there while it's healthy
+    #[structopt(long, conflicts_with_all = &["sticky-cookie", "sticky-ip"])]
+    affinity_cookie: Option<String>,
+
+    /// Seconds the affinity cookie lasts
+    #[structopt(long, default_value = "3600")]
+    affinity_ttl_secs: u64,
+
+    /// Header(e.g. `x-tenant`) with the namespace of a request, it's matched
+    /// against the plugins of that namespace first
+    #[structopt(long)]
+    namespace_header: Option<String>,
+
+    /// The first segment of the path is the namespace of a request
+    #[structopt(long, conflicts_with = "namespace-header")]
+    namespace_path: bool,
+
+    /// Consecutive failures after which a plugin is not called for a while
+    #[structopt(long)]
+    circuit_failures: Option<u32>,
+
+    /// Seconds a plugin with an open circuit is not called
+    #[structopt(long, default_value = "30", requires = "circuit-failures")]
+    circuit_open_secs: u64,
+
+    /// Compress responses with gzip or brotli when clients accept it
+    #[structopt(long)]
+    compress: bool,
+
+    /// Responses smaller than this many bytes are not compressed
+    #[structopt(long, default_value = "1024")]
+    compress_min_size: usize,
+
+    /// Tag responses with an `ETag` to answer `304 Not Modified` to clients
+    /// that already have them
+    #[structopt(long)]
+    etag: bool,
+
+    /// Use weak entity tags, e.g. when responses are compressed
+    #[structopt(long)]
+    weak_etag: bool,
+
+    /// Seconds responses to `GET` requests are kept in memory to answer the
+    /// same requests, nothing is cached by default
+    #[structopt(long)]
+    cache_ttl: Option<u64>,
+
+    /// Responses the cache keeps at most
+    #[structopt(long, default_value = "1000")]
+    cache_size: usize,
+
+    /// Bytes of the biggest response body the cache keeps
+    #[structopt(long, default_value = "1048576")]
+    cache_entry_max_size: usize,
+
+    /// Seconds responses under a path prefix are cached as `prefix=secs`, `0`
+    /// disables caching of the route. Can be used multiple times
+    #[structopt(long = "cache-route", parse(try_from_str = parse_cache_route))]
+    cache_routes: Vec<(String, u64)>,
+
+    /// Enables `DELETE /_cache` to purge cached responses, a path after it
+    /// (e.g. `/_cache/_foo/bar`) purges only the ones of that path
+    #[structopt(long)]
+    cache_purge: bool,
+
+    /// PEM encoded certificate chain to serve HTTPS
+    #[structopt(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM encoded private key of the certificate
+    #[structopt(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Port where HTTPS is served when a certificate is given(8443 by default),
+    /// plain HTTP keeps being served on the bind address
+    #[structopt(long)]
+    tls_port: Option<u16>,
+
+    /// Milliseconds plugins have to answer a request
+    #[structopt(long)]
+    request_timeout_ms: Option<u64>,
+
+    /// Maximum size in bytes of request bodies
+    #[structopt(long)]
+    max_body_size: Option<usize>,
+
+    /// Maximum bytes of the headers of a request(8192 by default), bigger ones
+    /// are answered with `431 Request Header Fields Too Large`. The HTTP parser
+    /// itself doesn't read heads bigger than 8192 bytes
+    #[structopt(long)]
+    max_header_size: Option<usize>,
+
+    /// Maximum bytes of the path and query of a request(8192 by default), longer
+    /// ones are answered with `414 URI Too Long`
+    #[structopt(long)]
+    max_uri_length: Option<usize>,
+
+    /// Seconds a connection can go without sending or receiving anything before
+    /// it's closed(60 by default), `0` keeps idle connections open
+    #[structopt(long)]
+    idle_timeout_secs: Option<u64>,
+
+    /// Closes the connections after every response instead of keeping them
+    /// open for more requests
+    #[structopt(long)]
+    no_keep_alive: bool,
+
+    /// Seconds clients have to send the head of a request once it starts(10 by
+    /// default), slower ones are answered with `408 Request Timeout` and the
+    /// connection is closed. `0` disables it
+    #[structopt(long)]
+    header_read_timeout_secs: Option<u64>,
+
+    /// Seconds a plugin reading the body of a request waits for the client to
+    /// send more of it(60 by default), `0` disables it. Unlike
+    /// `--request-timeout-ms` it's only the time the client takes
+    #[structopt(long)]
+    body_read_timeout_secs: Option<u64>,
+
+    /// Requests plugins can handle at the same time, unlimited by default.
+    /// Requests over the limit are answered with `503`
+    #[structopt(long)]
+    max_concurrency: Option<usize>,
+
+    /// Requests that wait for a turn when the concurrency limit is reached
+    /// instead of failing right away
+    #[structopt(long, default_value = "0")]
+    concurrency_queue: usize,
+
+    /// Connections to the same upstream the HTTP client shared by proxy
+    /// plugins uses at once at most
+    #[structopt(long, default_value = "50")]
+    upstream_max_connections: usize,
+
+    /// Seconds a connection to an upstream can stay unused before it's closed
+    #[structopt(long, default_value = "90")]
+    upstream_idle_secs: u64,
+
+    /// Seconds to connect to an upstream
+    #[structopt(long, default_value = "10")]
+    upstream_connect_timeout_secs: u64,
+
+    /// Seconds an upstream has to answer with the headers of its response
+    #[structopt(long, default_value = "30")]
+    upstream_timeout_secs: u64,
+
+    /// Threads accepting connections and reading and writing their requests,
+    /// e.g. one per core. Plugins run in the main thread with a single runtime
+    /// so rate limits, metrics, circuit breakers and the registry are shared
+    /// and plugins themselves only use one core
+    #[structopt(long, default_value = "1")]
+    workers: usize,
+
+    /// Seconds to wait for requests in flight to finish when shutting down
+    #[structopt(long, default_value = "30")]
+    grace_period: u64,
+
+    /// Seconds a plugin that is unloaded or replaced has to finish the
+    /// requests it's handling
+    #[structopt(long, default_value = "30")]
+    plugin_drain_timeout: u64,
+
+    /// Seconds between health checks of the variants of a route(plugins with a
+    /// weight), requests skip the unhealthy ones until they recover
+    #[structopt(long)]
+    replica_check_secs: Option<u64>,
+
+    /// Maximum memory in MiB a WASM plugin can use per request
+    #[cfg(feature = "wasm")]
+    #[structopt(long, default_value = "64")]
+    wasm_memory: usize,
+
+    /// Fuel a WASM plugin can consume per request before it's stopped
+    #[cfg(feature = "wasm")]
+    #[structopt(long, default_value = "100000000")]
+    wasm_fuel: u64,
+
+    /// Maximum level of the logs(trace, debug, info, warn or error),
+    /// falls back to the `RUST_LOG` environment variable
+    #[structopt(long)]
+    log_level: Option<femme::LevelFilter>,
+
+    /// Format of the logs, `pretty`(default) for humans or `ndjson`(newline
+    /// delimited JSON) for log collectors
+    #[structopt(long, possible_values = &["pretty", "ndjson"])]
+    log_format: Option<LogFormat>,
+
+    /// File the logs are written to as newline delimited JSON instead of the
+    /// terminal, they go to the terminal when it can't be opened
+    #[structopt(long)]
+    log_file: Option<PathBuf>,
+
+    /// Size in MiB the log file can grow to before it's rotated
+    #[structopt(long)]
+    log_max_size: Option<u64>,
+
+    /// Rotates the log file every hour or day(UTC)
+    #[structopt(long, possible_values = &["hourly", "daily"])]
+    log_rotate: Option<log_file::Interval>,
+
+    /// Rotated log files that are kept, `<file>.1` being the newest [default: 5]
+    #[structopt(long)]
+    log_keep: Option<usize>,
+
+    /// Line logged for every request, `default`, `common`, `combined`, `json` or a
+    /// template with the placeholders {method}, {path}, {protocol}, {status},
+    /// {time}, {duration}(ms), {plugin}, {id}, {client_ip}, {user_agent},
+    /// {referer} and {bytes}
+    #[structopt(long, default_value = "default")]
+    access_log_format: access_log::Format,
+
+    /// Path prefix of requests that are not logged, `/_health` when none is
+    /// given. Can be used multiple times
+    #[structopt(long = "access-log-exclude")]
+    access_log_exclude: Vec<String>,
+
+    /// Header with the id of requests, e.g. `x-amzn-trace-id` when behind a load
+    /// balancer that sets it. Requests without it are given a new id
+    #[structopt(long)]
+    request_id_header: Option<valor::http::headers::HeaderName>,
+
+    /// Plugins to load at startup from the config file
+    #[structopt(skip)]
+    plugins: Vec<runtime::VluginDef>,
+}
+
+impl Opt {
+    // Settings of the config file are used for the flags not given
+    fn with_config(mut self, config: Config) -> Self {
+        self.bind = self.bind.or(config.bind);
+        #[cfg(unix)]
+        {
+            self.unix_socket = self.unix_socket.or(config.unix_socket);
+        }
+        if self.tls_cert.is_none() && self.tls_key.is_none() {
+            self.tls_cert = config.tls_cert;
+            self.tls_key = config.tls_key;
+        }
+        self.tls_port = self.tls_port.or(config.tls_port);
+        self.log_level = self.log_level.or(config.log_level);
+        self.log_format = self.log_format.or(config.log_format);
+        self.log_file = self.log_file.or(config.log_file);
+        self.log_max_size = self.log_max_size.or(config.log_max_size);
+        self.log_rotate = self.log_rotate.or(config.log_rotate);
+        self.log_keep = self.log_keep.or(config.log_keep);
+        self.request_timeout_ms = self.request_timeout_ms.or(config.request_timeout_ms);
+        self.max_body_size = self.max_body_size.or(config.max_body_size);
+        self.max_header_size = self.max_header_size.or(config.max_header_size);
+        self.max_uri_length = self.max_uri_length.or(config.max_uri_length);
+        self.idle_timeout_secs = self.idle_timeout_secs.or(config.idle_timeout_secs);
+        self.no_keep_alive |= config.keep_alive == Some(false);
+        self.header_read_timeout_secs = self
+            .header_read_timeout_secs
+            .or(config.header_read_timeout_secs);
+        self.body_read_timeout_secs = self
+            .body_read_timeout_secs
+            .or(config.body_read_timeout_secs);
+        self.request_id_header = self.request_id_header.or(config.request_id_header);
+        self.registry_token = self.registry_token.or(config.registry_token);
+        if self.allowed_hosts.is_empty() {
+            self.allowed_hosts = config.allowed_hosts;
+        }
+        self.plugins = config.plugins;
+        self
+    }
+
+    fn access_log(&self) -> access_log::AccessLog {
+        let exclude = match self.access_log_exclude.as_slice() {
+            [] => vec![access_log::DEFAULT_EXCLUDE.into()],
+            exclude => exclude.to_vec(),
+        };
+        access_log::AccessLog::new(self.access_log_format.clone(), &exclude)
+    }
+
+    // What the listeners of the main thread serve, workers get a copy of it
+    fn server(&self, connections: runtime::Connections, runtime: Runtime) -> Server {
+        Server {
+            limits: self.head_limits(),
+            keep_alive: self.keep_alive(),
+            read_timeouts: self.read_timeouts(),
+            connections,
+            log: Rc::new(self.access_log()),
+            trusted_proxies: Rc::new(self.trusted_proxies.clone()),
+            runtime: Handler::Local(runtime),
+        }
+    }
+
+    fn keep_alive(&self) -> idle::KeepAlive {
+        let idle_timeout = self.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS);
+        idle::KeepAlive {
+            enabled: !self.no_keep_alive,
+            idle_timeout: Some(idle_timeout)
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+        }
+    }
+
+    fn read_timeouts(&self) -> idle::ReadTimeouts {
+        let secs = |secs: Option<u64>, default| {
+            Some(secs.unwrap_or(default))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+        };
+        idle::ReadTimeouts {
+            head: secs(
+                self.header_read_timeout_secs,
+                DEFAULT_HEADER_READ_TIMEOUT_SECS,
+            ),
+            body: secs(self.body_read_timeout_secs, DEFAULT_BODY_READ_TIMEOUT_SECS),
+        }
+    }
+
+    fn head_limits(&self) -> limits::HeadLimits {
+        limits::HeadLimits {
+            header_size: self
+                .max_header_size
+                .unwrap_or(limits::DEFAULT_MAX_HEADER_SIZE),
+            uri_length: self
+                .max_uri_length
+                .unwrap_or(limits::DEFAULT_MAX_URI_LENGTH),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LogFormat {
+    Pretty,
+    Ndjson,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "ndjson" => Ok(LogFormat::Ndjson),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct ConfigFile {
-    plugins: Vec<runtime::VluginDef>,
+    // plugins are parsed one by one to tell which one is malformed
+    plugins: Vec<serde_json::Value>,
 }
 
 #[async_std::main]
 async fn main() {
-    femme::with_level(femme::LevelFilter::Debug);
-    run(Opt::from_args())
+    let opt = Opt::from_args();
+    let opt = match &opt.config {
+        Some(path) => match Config::from_file(path) {
+            Ok(config) => opt.with_config(config),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => opt,
+    };
+    init_logger(&opt);
+    if let Err(e) = run(opt)
         //.catch_unwind()
         .await
-        .unwrap_or_else(|e| error!("{}", e));
+    {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn init_logger(opt: &Opt) {
+    let level = opt
+        .log_level
+        .or_else(|| env::var("RUST_LOG").ok()?.parse().ok())
+        .unwrap_or(femme::LevelFilter::Debug);
+    let failed = match &opt.log_file {
+        Some(path) => {
+            let rotation = log_file::Rotation {
+                max_size: opt.log_max_size.map(|mib| mib << 20),
+                interval: opt.log_rotate,
+                keep: opt.log_keep.unwrap_or(log_file::DEFAULT_KEEP),
+            };
+            match log_file::FileLogger::open(path, rotation, level) {
+                Ok(logger) => return logger.start().expect("only logger"),
+                Err(err) => Some((path, err)),
+            }
+        }
+        None => None,
+    };
+    match opt.log_format.unwrap_or(LogFormat::Pretty) {
+        LogFormat::Pretty => femme::pretty::start(level),
+        LogFormat::Ndjson => femme::ndjson::start(level),
+    }
+    if let Some((path, err)) = failed {
+        warn!(
+            "can't open the log file {}, logging to the terminal: {}",
+            path.display(),
+            err
+        );
+    }
 }
 
 async fn run(opt: Opt) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(("0.0.0.0", 8080))
-        .await
-        .expect("Bind address");
-    let addr = format!("http://{}", listener.local_addr()?);
-    info!("listening on {}", addr);
+    if opt.workers == 0 {
+        return Err("There has to be at least one worker".into());
+    }
+
+    let tls = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+        (None, None) => None,
+        _ => return Err("TLS needs both a certificate and a private key".into()),
+    };
 
-    let mut runtime = Runtime::new(Loader::default()).with_health()?;
+    let socket = unix_socket(&opt).map(Path::to_path_buf);
+    let listener = match (&opt.bind, &socket) {
+        (None, Some(_)) => None,
+        (bind, _) => {
+            let bind = bind.as_deref().unwrap_or(DEFAULT_BIND);
+            let listener = TcpListener::bind(bind)
+                .await
+                .map_err(|e| format!("can't listen on {}: {}", bind, e))?;
+            info!("listening on http://{}", listener.local_addr()?);
+            Some(listener)
+        }
+    };
+    #[cfg(unix)]
+    let unix_listener = match &socket {
+        Some(path) => Some(bind_unix(path).await?),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    let unix_listener: Option<TcpListener> = None;
+
+    let tls_listener = match &tls {
+        Some(_) => {
+            let ip = listener
+                .as_ref()
+                .and_then(|l| l.local_addr().ok())
+                .map_or(Ipv4Addr::UNSPECIFIED.into(), |addr| addr.ip());
+            let port = opt.tls_port.unwrap_or(DEFAULT_TLS_PORT);
+            let listener = TcpListener::bind((ip, port))
+                .await
+                .map_err(|e| format!("can't listen on port {}: {}", port, e))?;
+            info!("listening on https://{}", listener.local_addr()?);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let listeners = Arc::new(Listeners {
+        http: listener,
+        https: tls_listener,
+        unix: unix_listener,
+        tls,
+    });
+    let stop = shutdown_signal()?;
+    // failing to start fails before any worker is running
+    let connections = runtime::Connections::default();
+    let runtime = start(&opt, connections.clone()).await?;
+    let server = opt.server(connections, runtime.clone());
+
+    let (jobs, queue) = channel::unbounded();
+    task::spawn_local(handle_jobs(queue, runtime.clone()));
+    // workers keep their connections until the requests in flight are drained
+    let (drained, wait_drain) = channel::bounded::<()>(1);
+    let workers = (1..opt.workers)
+        .map(|i| {
+            let (listeners, stop, wait_drain) =
+                (listeners.clone(), stop.clone(), wait_drain.clone());
+            let server = server.remote(jobs.clone());
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || {
+                    task::block_on(async {
+                        let res = listen(&listeners, server(), &stop).await;
+                        // a failing worker stops the others
+                        if res.is_err() {
+                            stop.close();
+                        }
+                        wait_drain.recv().await.ok();
+                        res.map_err(|e| e.to_string())
+                    })
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    drop(jobs);
+    let res = listen(&listeners, server, &stop).await;
+    if res.is_err() {
+        stop.close();
+    }
+
+    info!("shutting down, waiting for requests in flight");
+    let grace_period = Duration::from_secs(opt.grace_period);
+    let drain = future::timeout(grace_period, runtime.shutdown()).await;
+    drained.close();
+    for worker in workers {
+        task::spawn_blocking(move || worker.join())
+            .await
+            .map_err(|_| "A worker panicked")??;
+    }
+    if let Some(path) = socket {
+        let _ = std::fs::remove_file(path);
+    }
+    res?;
+    drain.map_err(|_| "Grace period exceeded with requests in flight")?;
+    Ok(())
+}
+
+// Connections each worker accepts from, workers share the listeners
+struct Listeners {
+    http: Option<TcpListener>,
+    https: Option<TcpListener>,
+    #[cfg(unix)]
+    unix: Option<UnixListener>,
+    #[cfg(not(unix))]
+    unix: Option<TcpListener>,
+    tls: Option<TlsAcceptor>,
+}
+
+// Runtime with the plugins loaded ready to serve requests, plugins are single
+// threaded so it lives in the main thread and the workers send it their requests
+async fn start(
+    opt: &Opt,
+    connections: runtime::Connections,
+) -> Result<Runtime, Box<dyn std::error::Error>> {
+    let loader = Loader::default();
+    #[cfg(feature = "wasm")]
+    let loader = loader.with_wasm_limits(wasm::Limits {
+        memory: opt.wasm_memory << 20,
+        fuel: opt.wasm_fuel,
+    });
+    let loader = match opt.plugin_key {
+        Some(key) => loader.with_trusted_key(key),
+        None => loader,
+    };
+
+    let mut runtime = Runtime::new(loader)
+        .with_request_ids(|| Uuid::new_v4().to_string())
+        .with_spawner(|task| {
+            task::spawn_local(task);
+        })
+        .with_startup()
+        .with_health()?
+        .with_trusted_proxies(opt.trusted_proxies.clone())
+        .with_allowed_hosts(opt.allowed_hosts.clone())
+        .with_state(connections.clone())
+        .with_state(
+            valor::HttpPool::new(valor::PoolConfig {
+                max_connections_per_host: opt.upstream_max_connections,
+                idle_timeout: Duration::from_secs(opt.upstream_idle_secs),
+                connect_timeout: Duration::from_secs(opt.upstream_connect_timeout_secs),
+                request_timeout: Duration::from_secs(opt.upstream_timeout_secs),
+            })
+            .map_err(|e| e.to_string())?,
+        );
+    if let Some(header) = &opt.request_id_header {
+        runtime = runtime.with_request_id_header(header.clone());
+    }
+    if let Some(path) = &opt.maintenance_page {
+        let mut page = maintenance_page(path)?;
+        if let Some(secs) = opt.maintenance_retry_after {
+            page = page.retry_after(Duration::from_secs(secs));
+        }
+        runtime = runtime.with_maintenance_page(page);
+    }
+    runtime.set_maintenance(opt.maintenance);
+    if let Some(tracing) = trace::Tracing::from_env()? {
+        info!("exporting traces");
+        runtime = runtime.with_middleware(tracing);
+    }
+    if !opt.ip_allow.is_empty() || !opt.ip_deny.is_empty() {
+        let filter = ip_filter::IpFilter::new(opt.ip_allow.clone(), opt.ip_deny.clone());
+        let filter = opt
+            .ip_filter_prefixes
+            .iter()
+            .fold(filter, |filter, prefix| filter.prefix(prefix));
+        runtime = runtime.with_middleware(filter);
+    }
+    if !opt.cors_origins.is_empty() {
+        let cors = opt
+            .cors_origins
+            .iter()
+            .fold(runtime::Cors::new(), |cors, origin| {
+                cors.allow_origin(origin.as_str())
+            });
+        runtime = runtime.with_middleware(cors);
+    }
+    if opt.security_headers {
+        let headers = runtime::SecurityHeaders::new();
+        runtime = runtime.with_middleware(match &opt.csp {
+            Some(policy) => headers.content_security_policy(policy.as_str()),
+            None => headers,
+        });
+    }
+    if !opt.basic_auth.is_empty() {
+        let mut auth = runtime::BasicAuth::new("valor").attach_user();
+        for user in &opt.basic_auth {
+            let (name, hash) = user
+                .split_once(':')
+                .ok_or("Basic auth users are given as name:hash")?;
+            auth = auth.user(name, hash);
+        }
+        for prefix in &opt.basic_auth_prefixes {
+            auth = auth.prefix(prefix.as_str());
+        }
+        runtime = runtime.with_middleware(auth);
+    }
+    let jwt = match (&opt.jwt_secret, &opt.jwks_url) {
+        (Some(secret), _) => Some(runtime::Jwt::with_secret(secret)),
+        (None, Some(url)) => Some(runtime::Jwt::with_jwks(url.clone())),
+        (None, None) => None,
+    };
+    if let Some(mut jwt) = jwt {
+        if let Some(iss) = &opt.jwt_issuer {
+            jwt = jwt.issuer(iss.as_str());
+        }
+        if let Some(aud) = &opt.jwt_audience {
+            jwt = jwt.audience(aud.as_str());
+        }
+        for prefix in &opt.jwt_prefixes {
+            jwt = jwt.prefix(prefix.as_str());
+        }
+        runtime = runtime.with_middleware(jwt);
+    }
+    if let Some(requests) = opt.rate_limit {
+        let window = Duration::from_secs(opt.rate_limit_window.get());
+        let limit = match &opt.rate_limit_redis {
+            Some(url) => {
+                let store = rate_limit::RedisStore::new(url, requests, window)
+                    .map_err(|e| format!("invalid redis url {}: {}", url, e))?
+                    .timeout(Duration::from_millis(opt.rate_limit_redis_timeout_ms));
+                rate_limit::RateLimit::new(requests, store).fail_closed(opt.rate_limit_fail_closed)
+            }
+            None => {
+                let burst = opt.rate_limit_burst.unwrap_or(requests);
+                let store = rate_limit::MemoryStore::new(requests, window, burst);
+                rate_limit::RateLimit::new(requests, store)
+            }
+        };
+        runtime = runtime.with_middleware(limit);
+    }
+    if opt.compress {
+        runtime = runtime.with_middleware(compression::Compression::new(opt.compress_min_size));
+    }
+    if opt.etag || opt.weak_etag {
+        let etag = runtime::ETag::new();
+        runtime = runtime.with_middleware(if opt.weak_etag { etag.weak() } else { etag });
+    }
+    if let Some(ttl) = opt.cache_ttl {
+        let cache = opt.cache_routes.iter().fold(
+            runtime::Cache::new(Duration::from_secs(ttl))
+                .capacity(opt.cache_size)
+                .max_entry_size(opt.cache_entry_max_size),
+            |cache, (prefix, ttl)| cache.route(prefix.as_str(), Duration::from_secs(*ttl)),
+        );
+        if opt.cache_purge {
+            let purge = runtime::VluginDef {
+                methods: vec![valor::http::Method::Delete],
+                ..runtime::VluginDef::from("cache")
+            };
+            runtime = runtime.with_plugin(purge, cache.purge_handler())?;
+        }
+        runtime = runtime.with_middleware(cache);
+    }
+    if let Some(ms) = opt.request_timeout_ms {
+        runtime = runtime.with_request_timeout(Duration::from_millis(ms));
+    }
+    runtime = runtime.with_plugin_drain_timeout(Duration::from_secs(opt.plugin_drain_timeout));
+    if let Some(size) = opt.max_body_size {
+        runtime = runtime.with_max_body_size(size);
+    }
+    if let Some(limit) = opt.max_concurrency {
+        runtime = runtime
+            .with_max_concurrency(limit)
+            .with_concurrency_queue(opt.concurrency_queue);
+    }
+    if let Some(cookie) = &opt.sticky_cookie {
+        runtime = runtime.with_sticky_variants(runtime::Sticky::Cookie(cookie.clone()));
+    } else if opt.sticky_ip {
+        runtime = runtime.with_sticky_variants(runtime::Sticky::ClientIp);
+    } else if let Some(cookie) = &opt.affinity_cookie {
+        runtime = runtime.with_sticky_variants(runtime::Sticky::Affinity {
+            cookie: cookie.clone(),
+            ttl: Duration::from_secs(opt.affinity_ttl_secs),
+        });
+    }
+    if let Some(header) = &opt.namespace_header {
+        runtime = runtime.with_namespaces(runtime::Namespaces::Header(header.clone()));
+    } else if opt.namespace_path {
+        runtime = runtime.with_namespaces(runtime::Namespaces::PathSegment);
+    }
+    if opt.problem_details {
+        runtime = runtime.with_problem_details();
+    }
+    if opt.allow_downgrades {
+        runtime = runtime.with_downgrades();
+    }
+    if opt.no_auto_head {
+        runtime = runtime.with_auto_head(false);
+    }
+    runtime = runtime.with_server_timing(opt.server_timing);
+    if let Some(failures) = opt.circuit_failures {
+        runtime = runtime.with_circuit_breaker(runtime::CircuitBreaker {
+            failures,
+            open_for: Duration::from_secs(opt.circuit_open_secs),
+        });
+    }
+    if opt.metrics {
+        runtime = runtime.with_metrics()?;
+    }
     if opt.with_registry {
-        runtime = runtime.with_registry()?;
+        let auth = opt
+            .registry_token
+            .as_ref()
+            .map(|token| runtime::RegistryAuth {
+                token: token.clone(),
+                protect_list: opt.protect_registry_list,
+            });
+        if auth.is_none() {
+            warn!("the plugin registry is not protected, anyone can load plugins");
+        }
+        runtime = runtime
+            .with_registry(auth.clone())?
+            .with_maintenance_endpoint(auth)?;
+        if let Some(path) = &opt.registry_file {
+            runtime = runtime.with_persistent_registry(path);
+        }
     }
 
-    if opt.plugin_file.is_some() {
-        let file = File::open(opt.plugin_file.unwrap())?;
-        let config: ConfigFile = serde_json::from_reader(file)?;
-        for p in config.plugins {
+    // the plugin file or manifest replaces the plugins of the config file
+    let plugins = match (&opt.plugin_file, &opt.plugin_url) {
+        (Some(path), _) => read_plugins(path)?,
+        (None, Some(url)) => {
+            remote::Manifest::new(url.clone(), opt.plugin_cache.clone())
+                .load()
+                .await?
+        }
+        (None, None) => check_plugins(opt.plugins.clone())?,
+    };
+    for p in plugins.iter().cloned() {
+        if opt.lazy_load {
             runtime
-                .load_plugin(p)
-                .await
+                .register_lazy(p)
                 .unwrap_or_else(|err| warn!("{}", err));
+            continue;
         }
+        let name = p.name.clone();
+        match runtime.load_plugin(p).await {
+            Ok(()) => log_load(&runtime, &name),
+            Err(err) => warn!("{}", err),
+        }
+    }
+    let restored = runtime.restore_registry().await;
+    for (name, err) in restored.map_err(|e| format!("can't restore the registry: {}", e))? {
+        warn!("couldn't restore plugin {}: {}", name, err);
+    }
+    runtime.warm_up().await;
+    runtime.set_ready();
+    if let (Some(path), true) = (&opt.plugin_file, opt.watch) {
+        let changes = watch_file(path)?;
+        task::spawn_local(reload_on_change(
+            changes,
+            path.clone(),
+            plugins,
+            runtime.clone(),
+        ));
+    } else if let (Some(url), Some(secs)) = (&opt.plugin_url, opt.plugin_poll_secs) {
+        let manifest = remote::Manifest::new(url.clone(), opt.plugin_cache.clone());
+        task::spawn_local(remote::poll(
+            manifest,
+            Duration::from_secs(secs.max(1)),
+            plugins,
+            runtime.clone(),
+        ));
     }
 
-    let mut incoming = listener.incoming();
-    while let Some(Ok(stream)) = incoming.next().await {
+    if let Some(secs) = opt.replica_check_secs {
         let runtime = runtime.clone();
+        let interval = Duration::from_secs(secs.max(1));
+        task::spawn_local(async move { runtime.check_replicas_every(interval).await });
+    }
+    Ok(runtime)
+}
+
+// Serves the connections of every listener until the stop signal
+async fn listen(
+    listeners: &Listeners,
+    server: Server,
+    stop: &channel::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let http = listeners
+        .http
+        .as_ref()
+        .map(|l| serve(l.incoming(), None, server.clone(), stop.clone()));
+    let https = listeners.https.as_ref().map(|l| {
+        let tls = listeners.tls.clone();
+        serve(l.incoming(), tls, server.clone(), stop.clone())
+    });
+    let unix = listeners
+        .unix
+        .as_ref()
+        .map(|l| serve(l.incoming(), None, server.clone(), stop.clone()));
+    maybe(http)
+        .try_join(maybe(https))
+        .try_join(maybe(unix))
+        .await?;
+    Ok(())
+}
+
+fn parse_cache_route(route: &str) -> Result<(String, u64), String> {
+    route
+        .split_once('=')
+        .and_then(|(prefix, secs)| Some((prefix.to_owned(), secs.parse().ok()?)))
+        .ok_or_else(|| format!("Invalid cache route {}, expected prefix=secs", route))
+}
+
+fn unix_socket(opt: &Opt) -> Option<&Path> {
+    #[cfg(unix)]
+    return opt.unix_socket.as_deref();
+    #[cfg(not(unix))]
+    None
+}
+
+// Binds a unix socket replacing the one a previous run might have left behind
+#[cfg(unix)]
+async fn bind_unix(path: &Path) -> Result<UnixListener, Box<dyn std::error::Error>> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()).into());
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .await
+        .map_err(|e| format!("can't listen on {}: {}", path.display(), e))?;
+    // only the owner and its group can connect
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    info!("listening on unix:{}", path.display());
+    Ok(listener)
+}
+
+async fn maybe<F>(server: Option<F>) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: Future<Output = Result<(), Box<dyn std::error::Error>>>,
+{
+    match server {
+        Some(server) => server.await,
+        None => Ok(()),
+    }
+}
+
+// What every listener serves its connections with
+#[derive(Clone)]
+struct Server {
+    limits: limits::HeadLimits,
+    keep_alive: idle::KeepAlive,
+    read_timeouts: idle::ReadTimeouts,
+    connections: runtime::Connections,
+    log: Rc<access_log::AccessLog>,
+    // the access log resolves clients like the runtime in any thread
+    trusted_proxies: Rc<Vec<valor::Cidr>>,
+    runtime: Handler,
+}
+
+impl Server {
+    // Same server for a worker thread, it sends its requests to the runtime
+    fn remote(&self, jobs: channel::Sender<Job>) -> impl FnOnce() -> Server + Send {
+        let (limits, keep_alive, read_timeouts) =
+            (self.limits, self.keep_alive, self.read_timeouts);
+        let (connections, log) = (self.connections.clone(), (*self.log).clone());
+        let trusted_proxies = (*self.trusted_proxies).clone();
+        move || Server {
+            limits,
+            keep_alive,
+            read_timeouts,
+            connections,
+            log: Rc::new(log),
+            trusted_proxies: Rc::new(trusted_proxies),
+            runtime: Handler::Remote(jobs),
+        }
+    }
+}
+
+// What handles the requests of a thread, the runtime when it's the same thread
+// or a queue to the thread of the runtime. Plugins aren't `Send` so the work of
+// every request is done in the thread of the runtime whatever thread reads it
+#[derive(Clone)]
+enum Handler {
+    Local(Runtime),
+    Remote(channel::Sender<Job>),
+}
+
+// Request of a worker thread for the runtime
+enum Job {
+    Handle(valor::http::Request, channel::Sender<valor::http::Response>),
+    Upgrade(valor::http::Request, valor::http::upgrade::Connection),
+}
+
+impl Handler {
+    async fn handle(&self, req: valor::http::Request) -> valor::http::Response {
+        let jobs = match self {
+            Handler::Local(runtime) => return runtime.handle(req).await,
+            Handler::Remote(jobs) => jobs,
+        };
+        let (reply, res) = channel::bounded(1);
+        // the reply is dropped when the runtime is gone
+        let _ = jobs.send(Job::Handle(req, reply)).await;
+        res.recv()
+            .await
+            .unwrap_or_else(|_| valor::http::StatusCode::ServiceUnavailable.into())
+    }
+
+    async fn upgrade(
+        &self,
+        req: valor::http::Request,
+        conn: valor::http::upgrade::Connection,
+    ) -> Result<(), valor::Error> {
+        match self {
+            Handler::Local(runtime) => runtime.upgrade(req, Box::new(conn)).await,
+            Handler::Remote(jobs) => jobs.send(Job::Upgrade(req, conn)).await.map_err(|_| {
+                let status = valor::http::StatusCode::ServiceUnavailable;
+                valor::http::Error::from_str(status, "The runtime is gone").into()
+            }),
+        }
+    }
+}
+
+// Handles the requests of the worker threads until all of them are gone
+async fn handle_jobs(queue: channel::Receiver<Job>, runtime: Runtime) {
+    while let Ok(job) = queue.recv().await {
+        let handler = Handler::Local(runtime.clone());
+        task::spawn_local(async move {
+            match job {
+                Job::Handle(req, reply) => {
+                    let _ = reply.send(handler.handle(req).await).await;
+                }
+                Job::Upgrade(req, conn) => {
+                    if let Err(err) = handler.upgrade(req, conn).await {
+                        warn!("upgraded connection failed: {}", err);
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Accepts connections until the stop signal, connections are served over TLS
+// when there's an acceptor
+async fn serve<S>(
+    mut incoming: impl Stream<Item = io::Result<S>> + Unpin,
+    tls: Option<TlsAcceptor>,
+    server: Server,
+    stop: channel::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: Connection,
+{
+    loop {
+        let stopped = async {
+            stop.recv().await.ok();
+            None
+        };
+        let stream = match incoming.next().race(stopped).await {
+            Some(Ok(stream)) => stream,
+            Some(Err(_)) => return Err("Stream closed".into()),
+            None => return Ok(()),
+        };
+        let peer = stream.peer_addr();
+        let server = server.clone();
+        let tls = tls.clone();
         task::spawn_local(async move {
-            if let Err(err) = accept(stream, runtime).await {
+            let _open = server.connections.track();
+            let res = match tls {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let stream = tls::Stream::from(stream);
+                        accept(stream, peer, &server).await
+                    }
+                    Err(err) => {
+                        warn!("TLS handshake failed: {}", err);
+                        return;
+                    }
+                },
+                None => accept(stream, peer, &server).await,
+            };
+            if let Err(err) = res {
                 error!("{}", err);
             }
         });
     }
-    Err("Stream closed".into())
 }
 
-const REQ_ID_HEADER: &str = "x-request-id";
+// Notifies when the process is asked to terminate(SIGINT or SIGTERM),
+// the channel is closed so every receiver gets notified
+fn shutdown_signal() -> Result<channel::Receiver<()>, ctrlc::Error> {
+    let (tx, rx) = channel::bounded(1);
+    ctrlc::set_handler(move || {
+        tx.close();
+    })?;
+    Ok(rx)
+}
+
+fn read_plugins(path: &Path) -> Result<Vec<runtime::VluginDef>, Box<dyn std::error::Error>> {
+    parse_plugins(&fs::read(path)?)
+}
+
+fn maintenance_page(path: &Path) -> Result<runtime::MaintenancePage, Box<dyn std::error::Error>> {
+    use valor::http::mime;
+    let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") | Some("htm") => mime::HTML,
+        Some("json") => mime::JSON,
+        _ => mime::PLAIN,
+    };
+    Ok(runtime::MaintenancePage::new(fs::read(path)?, content_type))
+}
+
+fn parse_plugins(json: &[u8]) -> Result<Vec<runtime::VluginDef>, Box<dyn std::error::Error>> {
+    let config: ConfigFile = serde_json::from_slice(json)?;
+    let plugins = config
+        .plugins
+        .into_iter()
+        .enumerate()
+        .map(|(i, p)| serde_json::from_value(p).map_err(|e| format!("plugin {}: {}", i, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    check_plugins(plugins)
+}
+
+// Plugins of a list are checked before loading any of them, the error tells
+// the position of the invalid one
+fn check_plugins(
+    plugins: Vec<runtime::VluginDef>,
+) -> Result<Vec<runtime::VluginDef>, Box<dyn std::error::Error>> {
+    for (i, p) in plugins.iter().enumerate() {
+        p.validate().map_err(|e| format!("plugin {}: {}", i, e))?;
+    }
+    // dependencies are loaded first
+    Ok(runtime::sort_by_dependencies(plugins)?)
+}
+
+// Emits a signal every time the file is modified, editors often save files by
+// replacing them so it's the parent directory what's being watched
+fn watch_file(path: &Path) -> notify::Result<channel::Receiver<()>> {
+    let path = path.canonicalize()?;
+    let (tx, rx) = channel::unbounded();
+    let (fs_tx, fs_rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(fs_tx, Duration::from_millis(500))?;
+    watcher.watch(path.parent().unwrap_or(&path), RecursiveMode::NonRecursive)?;
 
-async fn accept(stream: TcpStream, runtime: Runtime) -> Result<(), valor::Error> {
-    async_h1::accept(stream.clone(), |mut req| async {
+    thread::spawn(move || {
+        let _watcher = watcher;
+        for event in fs_rx {
+            match event {
+                DebouncedEvent::Create(p) | DebouncedEvent::Write(p) if p == path => {}
+                DebouncedEvent::Rename(_, p) if p == path => {}
+                _ => continue,
+            }
+            if tx.try_send(()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+// Reloads the plugins every time the plugin file changes
+async fn reload_on_change(
+    changes: channel::Receiver<()>,
+    path: PathBuf,
+    mut loaded: Vec<runtime::VluginDef>,
+    runtime: Runtime,
+) {
+    while changes.recv().await.is_ok() {
+        // a burst of changes results in a single reload
+        while changes.try_recv().is_ok() {}
+
+        let plugins = match read_plugins(&path) {
+            Ok(plugins) => plugins,
+            Err(err) => {
+                warn!("can't reload {}: {}", path.to_string_lossy(), err);
+                continue;
+            }
+        };
+        info!("reloading plugins from {}", path.to_string_lossy());
+        apply_changes(&runtime, &loaded, &plugins).await;
+        loaded = plugins;
+    }
+}
+
+// Applies the difference between the plugins that were loaded and the ones
+// listed now, loading new or changed plugins and removing the ones that are gone
+async fn apply_changes(
+    runtime: &Runtime,
+    loaded: &[runtime::VluginDef],
+    plugins: &[runtime::VluginDef],
+) {
+    let mut gone = loaded
+        .iter()
+        .filter(|p| !plugins.iter().any(|n| n.name == p.name))
+        .collect::<Vec<_>>();
+    // plugins others depend on can go once those are gone
+    while !gone.is_empty() {
+        let mut kept = Vec::new();
+        for p in &gone {
+            if let Err(err) = runtime.unload_plugin(&p.name) {
+                kept.push((*p, err));
+            }
+        }
+        if kept.len() == gone.len() {
+            for (_, err) in kept {
+                warn!("{}", err);
+            }
+            break;
+        }
+        gone = kept.into_iter().map(|(p, _)| p).collect();
+    }
+    for p in plugins.iter().filter(|p| !loaded.contains(p)) {
+        match runtime.load_plugin(p.clone()).await {
+            Ok(()) => log_load(runtime, &p.name),
+            Err(err) => warn!("{}", err),
+        }
+    }
+}
+
+// Reports how long a plugin took to load and warm up
+fn log_load(runtime: &Runtime, name: &str) {
+    let load = match runtime.load_info(name) {
+        Some(load) => load,
+        None => return,
+    };
+    let (load_ms, warmup_ms) = (load.duration_ms.unwrap_or(0), load.warmup_ms.unwrap_or(0));
+    match load.warmup_error {
+        Some(err) => warn!("plugin {} failed to warm up: {}", name, err, { warmup_ms: warmup_ms }),
+        None => info!("loaded plugin {} in {}ms, warmed up in {}ms", name, load_ms, warmup_ms, {
+            load_ms: load_ms, warmup_ms: warmup_ms
+        }),
+    }
+}
+
+const DEFAULT_BIND: &str = "0.0.0.0:8080";
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_HEADER_READ_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_BODY_READ_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_TLS_PORT: u16 = 8443;
+
+/// Stream of an accepted connection
+trait Connection: io::Read + io::Write + Clone + Send + Sync + Unpin + 'static {
+    /// Address of the client when the connection comes from the network
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Connection for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+// Serves the requests of a connection until the client closes it, it's idle
+// for longer than the timeout or the client is too slow sending a request
+async fn accept<S>(stream: S, peer: Option<SocketAddr>, server: &Server) -> Result<(), valor::Error>
+where
+    S: io::Read + io::Write + Clone + Send + Sync + Unpin + 'static,
+{
+    let Server {
+        limits,
+        keep_alive,
+        read_timeouts,
+        log,
+        trusted_proxies,
+        runtime,
+        ..
+    } = server;
+    let stream = idle::IdleStream::new(stream);
+    let activity = stream.activity();
+    let mut conn = stream.clone();
+    let serve = async_h1::accept(stream, |mut req| async {
+        let _busy = activity.busy();
+        req.set_peer_addr(peer);
+        if read_timeouts.body.is_some() && req.is_empty() != Some(true) {
+            let body = idle::TrackedBody::wrap(req.take_body(), activity.clone());
+            req.set_body(body);
+        }
         let instant = Instant::now();
-        if req.header(REQ_ID_HEADER).is_none() {
-            let id = Uuid::new_v4().to_string();
-            req.insert_header(REQ_ID_HEADER, id);
+        // oversized requests are rejected before they reach any plugin
+        if let Err(status) = limits.check(&req) {
+            let client = peer.map_or_else(|| "-".into(), |addr| addr.ip().to_string());
+            warn!("rejected request from {}: {}", client, status.canonical_reason(), {
+                client: client.as_str(), status: status as u16
+            });
+            return Ok(valor::http::Response::new(status));
         }
 
         let method = req.method();
         let path = req.url().path().to_string();
+        let header = |name: &str| req.header(name).map(|h| h.as_str().to_owned());
+        let (user_agent, referer) = (header("user-agent"), header("referer"));
+        let protocol = req.version().map(|version| version.to_string());
+        let time = SystemTime::now();
+        let client_ip = valor::resolve_client_ip(&req, trusted_proxies);
+        // the plugin that accepts the upgrade gets the request with the connection
+        let upgrade = req.header("upgrade").map(|_| req.clone());
 
-        let res: http::Response = match runtime.on_msg(req.into()).await {
-            Ok(res) => res.into(),
-            Err(err) => match err {
-                valor::Error::Http(err) => err.status().into(),
-                err => return Err(err.into()),
-            },
-        };
+        let mut res = runtime.handle(req).await;
+        if let (Some(req), valor::http::StatusCode::SwitchingProtocols) = (upgrade, res.status()) {
+            let conn = res.recv_upgrade();
+            let runtime = runtime.clone();
+            task::spawn_local(async move {
+                if let Some(conn) = conn.await {
+                    if let Err(err) = runtime.upgrade(req, conn).await {
+                        warn!("upgraded connection failed: {}", err);
+                    }
+                }
+            });
+        }
 
-        let id = res
-            .header("x-correlation-id")
-            .map(|h| h.as_str())
-            .unwrap_or("-");
-        let plugin = res
-            .header("x-valor-plugin")
-            .map(|h| h.as_str())
-            .unwrap_or("unkown");
-        let status: u16 = res.status().into();
-
-        if !path.starts_with("/_health") {
-            if res.status().is_server_error() {
-                warn!("[{}] {} {} {}", plugin, status, method, path, {
-                    id: id, status: status, dur: instant.elapsed().as_millis() as u64
-                });
-            } else {
-                info!("[{}] {} {} {}", plugin, status, method, path, {
-                    id: id, status: status, dur: instant.elapsed().as_millis() as u64
-                });
-            }
+        let id = res.header("x-correlation-id").map(|h| h.as_str());
+        let plugin = res.header("x-valor-plugin").map(|h| h.as_str());
+        if let Some(panic) = res.ext::<runtime::Panic>() {
+            error!("[{}] panicked: {}", plugin.unwrap_or("-"), panic.0, {
+                id: id.unwrap_or("-")
+            });
         }
+        log.log(&access_log::Entry {
+            method: method.as_ref(),
+            path: &path,
+            protocol: protocol.as_deref(),
+            status: res.status().into(),
+            time,
+            duration: instant.elapsed(),
+            plugin,
+            id,
+            client_ip,
+            user_agent: user_agent.as_deref(),
+            referer: referer.as_deref(),
+            bytes: res.len(),
+        });
 
+        let upgraded = res.status() == valor::http::StatusCode::SwitchingProtocols;
+        if !keep_alive.enabled && !upgraded {
+            res.insert_header("connection", "close");
+        }
         Ok(res)
-    })
-    .await?;
+    });
+    // dropping the connection closes it
+    let served = async { serve.await.map(|_| None) };
+    let closed = activity.closed(keep_alive.idle_timeout, *read_timeouts);
+    let closed = async { Ok(Some(closed.await)) };
+    if let Some(idle::Closed::Slow) = served.race(closed).await? {
+        let client = peer.map_or_else(|| "-".into(), |addr| addr.ip().to_string());
+        warn!("closed slow connection from {}", client, { client: client.as_str() });
+        let timeout =
+            b"HTTP/1.1 408 Request Timeout\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+        // the client might be gone already
+        let _ = conn.write_all(timeout).await;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{prelude::*, Cursor};
+    use valor::http;
+
+    #[test]
+    fn report_invalid_plugins_with_their_position() {
+        let plugins = parse_plugins(
+            br#"{"plugins": [{"type": "proxy", "name": "api"}, {"type": "static", "name": "web"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(plugins.len(), 2);
+
+        let err =
+            parse_plugins(br#"{"plugins": [{"type": "proxy", "name": "api"}, {"name": "web"}]}"#)
+                .unwrap_err();
+        assert!(err.to_string().starts_with("plugin 1: "), "{}", err);
+        let err =
+            parse_plugins(br#"{"plugins": [{"type": "proxy", "name": "api", "version": "1.0"}]}"#)
+                .unwrap_err();
+        assert!(err.to_string().starts_with("plugin 0: "), "{}", err);
+        let err = parse_plugins(br#"{"plugins": [{"type": "proxy", "name": "a/b"}]}"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "plugin 0: name of plugin \"a/b\" can only have ASCII letters, digits, `_`, `-` or `.`"
+        );
+    }
+
+    #[async_std::test]
+    async fn stream_bodies_of_unknown_length() {
+        let runtime = Runtime::new(Loader::default())
+            .with_request_ids(|| "1".into())
+            .with_plugin(
+                "big",
+                valor::h(|_: http::Request, _| async {
+                    let mut res = http::Response::new(http::StatusCode::Ok);
+                    res.set_body(http::Body::from_reader(
+                        Cursor::new(vec![b'a'; 1 << 20]),
+                        None,
+                    ));
+                    Ok(res)
+                }),
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_stop, stopped) = channel::bounded(1);
+
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream
+                .write_all(b"GET /_big HTTP/1.1\r\nhost: localhost\r\n\r\n")
+                .await?;
+            let mut res = Vec::new();
+            let mut buf = [0; 8192];
+            while !res.ends_with(b"0\r\n\r\n") {
+                let n = stream.read(&mut buf).await?;
+                assert!(n > 0, "connection closed before the last chunk");
+                res.extend_from_slice(&buf[..n]);
+            }
+            let res = String::from_utf8_lossy(&res).to_lowercase();
+            assert!(res.contains("transfer-encoding: chunked"));
+            assert!(!res.contains("content-length"));
+            assert!(res.matches('a').count() >= 1 << 20);
+            Ok::<_, Box<dyn std::error::Error>>(())
+        };
+        serve(listener.incoming(), None, server(runtime, None), stopped)
+            .race(client)
+            .await
+            .unwrap();
+    }
+
+    fn server(runtime: Runtime, idle_timeout: Option<Duration>) -> Server {
+        Server {
+            limits: limits::HeadLimits::default(),
+            keep_alive: idle::KeepAlive {
+                enabled: true,
+                idle_timeout,
+            },
+            read_timeouts: idle::ReadTimeouts::default(),
+            connections: runtime::Connections::default(),
+            log: Rc::default(),
+            trusted_proxies: Rc::default(),
+            runtime: Handler::Local(runtime),
+        }
+    }
+
+    #[async_std::test]
+    async fn handle_requests_of_worker_threads() {
+        let runtime = Runtime::new(Loader::default())
+            .with_plugin("foo", ())
+            .unwrap();
+        let (jobs, queue) = channel::unbounded();
+        task::spawn_local(handle_jobs(queue, runtime));
+
+        // the runtime stays in this thread
+        let worker = thread::spawn(move || {
+            let handler = Handler::Remote(jobs);
+            let mut req = http::Request::new(http::Method::Get, "http://localhost/_foo");
+            req.insert_header("x-request-id", "123");
+            task::block_on(handler.handle(req)).status()
+        });
+        let status = task::spawn_blocking(move || worker.join()).await.unwrap();
+        assert_eq!(status, http::StatusCode::Ok);
+    }
+
+    // Lines logged by any test, only checked for the ones of the test
+    #[derive(Default)]
+    struct LoggedLines(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for LoggedLines {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[async_std::test]
+    async fn log_forwarded_clients_of_worker_threads() {
+        let logged: &'static LoggedLines = Box::leak(Box::default());
+        log::set_logger(logged).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+
+        let opt = Opt::from_iter(&[
+            "valor",
+            "--workers",
+            "2",
+            "--trusted-proxy",
+            "127.0.0.1",
+            "--access-log-format",
+            "{client_ip} {path}",
+        ]);
+        let runtime = Runtime::new(Loader::default())
+            .with_plugin("foo", ())
+            .unwrap();
+        let (jobs, queue) = channel::unbounded();
+        task::spawn_local(handle_jobs(queue, runtime.clone()));
+        let worker_server = opt
+            .server(runtime::Connections::default(), runtime)
+            .remote(jobs);
+
+        // the worker serves the connection and logs without the runtime
+        let worker = thread::spawn(move || {
+            task::block_on(async {
+                let listener = TcpListener::bind("127.0.0.1:0").await?;
+                let addr = listener.local_addr()?;
+                let (_stop, stopped) = channel::bounded(1);
+                let client = async {
+                    let mut stream = TcpStream::connect(addr).await?;
+                    stream
+                        .write_all(
+                            b"GET /_foo HTTP/1.1\r\nhost: localhost\r\nx-request-id: 1\r\n\
+                            x-forwarded-for: 203.0.113.9\r\n\r\n",
+                        )
+                        .await?;
+                    let mut buf = [0; 1024];
+                    let n = stream.read(&mut buf).await?;
+                    assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 200"));
+                    Ok::<_, Box<dyn std::error::Error>>(())
+                };
+                serve(listener.incoming(), None, worker_server(), stopped)
+                    .race(client)
+                    .await
+            })
+            .map_err(|e| e.to_string())
+        });
+        let served = task::spawn_blocking(move || worker.join()).await.unwrap();
+        assert_eq!(served, Ok(()));
+        let lines = logged.0.lock().unwrap();
+        assert!(
+            lines.iter().any(|line| line == "203.0.113.9 /_foo"),
+            "{:?}",
+            lines
+        );
+    }
+
+    #[async_std::test]
+    async fn close_idle_connections() {
+        let runtime = Runtime::new(Loader::default())
+            .with_plugin("foo", ())
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_stop, stopped) = channel::bounded(1);
+        let server = server(runtime, Some(Duration::from_millis(100)));
+        let connections = server.connections.clone();
+
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream
+                .write_all(b"GET /_foo HTTP/1.1\r\nhost: localhost\r\n\r\n")
+                .await?;
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await?;
+            assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 200"));
+            assert_eq!(connections.open(), 1);
+
+            // kept alive until it goes the timeout without a request
+            let started = Instant::now();
+            assert_eq!(stream.read(&mut buf).await?, 0);
+            assert!(started.elapsed() >= Duration::from_millis(90));
+            task::sleep(Duration::from_millis(10)).await;
+            assert_eq!(connections.open(), 0);
+            Ok::<_, Box<dyn std::error::Error>>(())
+        };
+        serve(listener.incoming(), None, server, stopped)
+            .race(client)
+            .await
+            .unwrap();
+    }
+
+    #[async_std::test]
+    async fn answer_slow_requests_with_timeout() {
+        let runtime = Runtime::new(Loader::default())
+            .with_plugin("foo", ())
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_stop, stopped) = channel::bounded(1);
+        let mut server = server(runtime, None);
+        server.read_timeouts.head = Some(Duration::from_millis(100));
+
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            // the head never ends
+            stream.write_all(b"GET /_foo HTTP/1.1\r\nhost: ").await?;
+            let started = Instant::now();
+            let mut res = String::new();
+            stream.read_to_string(&mut res).await?;
+            assert!(res.starts_with("HTTP/1.1 408"));
+            assert!(started.elapsed() >= Duration::from_millis(90));
+            Ok::<_, Box<dyn std::error::Error>>(())
+        };
+        serve(listener.incoming(), None, server, stopped)
+            .race(client)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+//! Middleware limiting the rate of requests of every client to every plugin
+
+use async_trait::async_trait;
+use kv_log_macro::warn;
+use redis::aio::MultiplexedConnection;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant},
+};
+use valor::http::{headers::RETRY_AFTER, Request, Response, StatusCode};
+use valor::runtime::{Middleware, Next};
+use valor::ClientIp;
+
+// how often buckets of clients that stopped making requests are removed
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+// time Redis has to count a request unless it's given
+const DEFAULT_REDIS_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// What's left of the quota of a client for a plugin after counting one of its requests
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Quota {
+    /// Requests the client can still make
+    pub remaining: u64,
+    /// Time until the whole quota is available again
+    pub reset: Duration,
+    /// Time the client has to wait to make the next request when it's over the limit
+    pub retry_after: Option<Duration>,
+}
+
+/// Where the requests of clients are counted, a store shared by all the
+/// instances of valor makes the limit apply to the whole cluster
+#[async_trait(?Send)]
+pub(crate) trait RateLimitStore {
+    /// Counts a request with the key(a client and a plugin) and checks it
+    /// against the limit at once, concurrent requests with the same key can't
+    /// both take the last one
+    async fn hit(&self, key: &str) -> Result<Quota, Box<dyn Error>>;
+}
+
+/// Limits the rate of requests per client IP and plugin, clients over the limit
+/// are answered with `429 Too Many Requests`. Plugins are told apart by the
+/// first segment of the path, the one they are mounted at by default(`/_name`).
+/// Requests without a peer address like the ones coming from a unix socket are
+/// not limited.
+pub(crate) struct RateLimit {
+    limit: u32,
+    store: Box<dyn RateLimitStore>,
+    fail_closed: bool,
+}
+
+impl RateLimit {
+    pub fn new(limit: u32, store: impl RateLimitStore + 'static) -> Self {
+        RateLimit {
+            limit,
+            store: Box::new(store),
+            fail_closed: false,
+        }
+    }
+
+    /// Answers `503 Service Unavailable` when the store can't be reached
+    /// instead of letting the requests through
+    pub fn fail_closed(self, fail_closed: bool) -> Self {
+        RateLimit {
+            fail_closed,
+            ..self
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Middleware for RateLimit {
+    async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response, valor::Error> {
+        let client = match req.client_ip() {
+            Some(ip) => ip.to_string(),
+            None => return next.run(req).await,
+        };
+        let key = format!("{}|{}", client, plugin_of(req.url().path()));
+        let quota = match self.store.hit(&key).await {
+            Ok(quota) => quota,
+            Err(err) if self.fail_closed => {
+                warn!("can't check the rate limit of {}: {}", client, err);
+                let mut res = Response::new(StatusCode::ServiceUnavailable);
+                res.insert_header(RETRY_AFTER, "1");
+                return Ok(res);
+            }
+            Err(err) => {
+                warn!(
+                    "can't check the rate limit of {}, letting it through: {}",
+                    client, err
+                );
+                return next.run(req).await;
+            }
+        };
+        let mut res = match quota.retry_after {
+            None => next.run(req).await?,
+            Some(wait) => {
+                let mut res = Response::new(StatusCode::TooManyRequests);
+                let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+                res.insert_header(RETRY_AFTER, secs.to_string());
+                res
+            }
+        };
+        let reset = quota.reset.as_secs_f64().ceil() as u64;
+        res.insert_header("x-ratelimit-limit", self.limit.to_string());
+        res.insert_header("x-ratelimit-remaining", quota.remaining.to_string());
+        res.insert_header("x-ratelimit-reset", reset.to_string());
+        Ok(res)
+    }
+}
+
+// The first segment of the path, where the plugin is mounted by default
+fn plugin_of(path: &str) -> &str {
+    path.trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default()
+}
+
+/// Token bucket per client and plugin kept in memory, clients can make `requests` per
+/// `window` with bursts of up to `burst` requests. Every instance has its own.
+pub(crate) struct MemoryStore {
+    burst: f64,
+    // tokens added per second
+    rate: f64,
+    buckets: RefCell<HashMap<String, Bucket>>,
+    last_cleanup: Cell<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl MemoryStore {
+    pub fn new(requests: u32, window: Duration, burst: u32) -> Self {
+        MemoryStore {
+            burst: burst.max(1) as f64,
+            rate: requests.max(1) as f64 / window.as_secs_f64().max(f64::EPSILON),
+            buckets: RefCell::default(),
+            last_cleanup: Cell::new(Instant::now()),
+        }
+    }
+
+    // Takes a token from the bucket of the key, without tokens left it returns
+    // the time until the next one is available
+    fn take(&self, key: &str, now: Instant) -> Result<f64, Duration> {
+        self.cleanup(now);
+        let mut buckets = self.buckets.borrow_mut();
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate));
+        }
+        bucket.tokens -= 1.0;
+        Ok(bucket.tokens)
+    }
+
+    // A bucket that would be full again is the same as having none
+    fn cleanup(&self, now: Instant) {
+        if now.saturating_duration_since(self.last_cleanup.get()) < CLEANUP_INTERVAL {
+            return;
+        }
+        self.last_cleanup.set(now);
+        let (burst, rate) = (self.burst, self.rate);
+        self.buckets.borrow_mut().retain(|_, b| {
+            let elapsed = now.saturating_duration_since(b.updated).as_secs_f64();
+            b.tokens + elapsed * rate < burst
+        });
+    }
+
+    // seconds until the bucket is full
+    fn reset(&self, tokens: f64) -> u64 {
+        ((self.burst - tokens) / self.rate).ceil() as u64
+    }
+}
+
+#[async_trait(?Send)]
+impl RateLimitStore for MemoryStore {
+    async fn hit(&self, key: &str) -> Result<Quota, Box<dyn Error>> {
+        let (tokens, retry_after) = match self.take(key, Instant::now()) {
+            Ok(tokens) => (tokens, None),
+            Err(wait) => (0.0, Some(wait)),
+        };
+        Ok(Quota {
+            remaining: tokens as u64,
+            reset: Duration::from_secs(self.reset(tokens)),
+            retry_after,
+        })
+    }
+}
+
+// Counts the request in the window of the client, the window starts with
+// its first request and its counter expires at the end of it
+const FIXED_WINDOW: &str = r"
+local count = redis.call('INCR', KEYS[1])
+if count == 1 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[1])
+end
+return {count, redis.call('PTTL', KEYS[1])}
+";
+
+/// Counters of fixed windows in Redis shared by all the instances, clients
+/// can make `requests` per `window`
+pub(crate) struct RedisStore {
+    client: redis::Client,
+    // opened on the first request and again after it fails
+    conn: RefCell<Option<MultiplexedConnection>>,
+    script: redis::Script,
+    requests: u32,
+    window: Duration,
+    timeout: Duration,
+}
+
+impl RedisStore {
+    pub fn new(url: &str, requests: u32, window: Duration) -> redis::RedisResult<Self> {
+        Ok(RedisStore {
+            client: redis::Client::open(url)?,
+            conn: RefCell::default(),
+            script: redis::Script::new(FIXED_WINDOW),
+            requests,
+            window,
+            timeout: DEFAULT_REDIS_TIMEOUT,
+        })
+    }
+
+    /// Time the server has to count a request, when it's slower it's the same
+    /// as being unreachable
+    pub fn timeout(self, timeout: Duration) -> Self {
+        RedisStore { timeout, ..self }
+    }
+
+    async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+        let conn = self.conn.borrow().clone();
+        match conn {
+            Some(conn) => Ok(conn),
+            None => {
+                let conn = self.client.get_multiplexed_async_std_connection().await?;
+                self.conn.replace(Some(conn.clone()));
+                Ok(conn)
+            }
+        }
+    }
+
+    fn quota(&self, count: u64, ttl_ms: i64) -> Quota {
+        let reset = match ttl_ms {
+            ms if ms > 0 => Duration::from_millis(ms as u64),
+            // the counter has no expiration yet or just expired
+            _ => self.window,
+        };
+        Quota {
+            remaining: u64::from(self.requests).saturating_sub(count),
+            reset,
+            retry_after: (count > u64::from(self.requests)).then(|| reset),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl RateLimitStore for RedisStore {
+    async fn hit(&self, key: &str) -> Result<Quota, Box<dyn Error>> {
+        let count = async {
+            let mut conn = self.connection().await?;
+            self.script
+                .key(format!("valor:ratelimit:{}", key))
+                .arg(self.window.as_millis() as u64)
+                .invoke_async::<_, (u64, i64)>(&mut conn)
+                .await
+        };
+        match async_std::future::timeout(self.timeout, count).await {
+            Ok(Ok((count, ttl_ms))) => Ok(self.quota(count, ttl_ms)),
+            Ok(Err(err)) => {
+                if err.is_io_error() || err.is_connection_dropped() {
+                    self.conn.replace(None);
+                }
+                Err(err.into())
+            }
+            // a server that stopped answering gets a new connection next time
+            Err(_) => {
+                self.conn.replace(None);
+                Err(format!("redis didn't answer within {:?}", self.timeout).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limit = MemoryStore::new(2, Duration::from_secs(1), 3);
+        let now = Instant::now();
+        assert_eq!(limit.take("a", now), Ok(2.0));
+        assert_eq!(limit.take("a", now), Ok(1.0));
+        assert_eq!(limit.take("a", now), Ok(0.0));
+        assert_eq!(limit.take("a", now), Err(Duration::from_millis(500)));
+        // other clients have their own bucket
+        assert_eq!(limit.take("b", now), Ok(2.0));
+        // two tokens per second
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limit.take("a", later), Ok(0.0));
+        assert_eq!(limit.reset(0.0), 2);
+    }
+
+    #[test]
+    fn redis_fixed_window() {
+        let store = RedisStore::new("redis://127.0.0.1/", 2, Duration::from_secs(60)).unwrap();
+        let quota = store.quota(1, 60_000);
+        assert_eq!(quota.remaining, 1);
+        assert_eq!(quota.reset, Duration::from_secs(60));
+        assert_eq!(quota.retry_after, None);
+        assert_eq!(store.quota(2, 30_000).remaining, 0);
+        assert_eq!(store.quota(2, 30_000).retry_after, None);
+        let quota = store.quota(3, 30_000);
+        assert_eq!(quota.retry_after, Some(Duration::from_secs(30)));
+        assert_eq!(store.quota(1, -1).reset, Duration::from_secs(60));
+    }
+
+    struct Unreachable;
+
+    #[async_trait(?Send)]
+    impl RateLimitStore for Unreachable {
+        async fn hit(&self, _key: &str) -> Result<Quota, Box<dyn Error>> {
+            Err("connection refused".into())
+        }
+    }
+
+    #[async_std::test]
+    async fn fail_open_or_closed() {
+        let status = |fail_closed| async move {
+            let runtime = valor::runtime::Runtime::new(())
+                .with_middleware(RateLimit::new(1, Unreachable).fail_closed(fail_closed))
+                .with_plugin("foo", ())
+                .unwrap();
+            let mut req = Request::new(valor::http::Method::Get, "http://example.com/_foo");
+            req.insert_header("x-request-id", "1");
+            req.set_peer_addr(Some("10.0.0.1:1234"));
+            runtime.handle(req).await.status()
+        };
+        assert_eq!(status(false).await, StatusCode::Ok);
+        assert_eq!(status(true).await, StatusCode::ServiceUnavailable);
+    }
+
+    #[async_std::test]
+    async fn limit_every_plugin_apart() {
+        let store = MemoryStore::new(1, Duration::from_secs(60), 1);
+        let runtime = valor::runtime::Runtime::new(())
+            .with_middleware(RateLimit::new(1, store))
+            .with_plugin("foo", ())
+            .unwrap()
+            .with_plugin("bar", ())
+            .unwrap();
+        let status = |path: &str, client: &str| {
+            let url = format!("http://example.com{}", path);
+            let mut req = Request::new(valor::http::Method::Get, url.as_str());
+            req.insert_header("x-request-id", "1");
+            req.set_peer_addr(Some(client));
+            let runtime = runtime.clone();
+            async move { runtime.handle(req).await.status() }
+        };
+        assert_eq!(status("/_foo", "10.0.0.1:1234").await, StatusCode::Ok);
+        assert_eq!(
+            status("/_foo/a", "10.0.0.1:1234").await,
+            StatusCode::TooManyRequests
+        );
+        assert_eq!(status("/_bar", "10.0.0.1:1234").await, StatusCode::Ok);
+        assert_eq!(status("/_foo", "10.0.0.2:1234").await, StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn redis_that_does_not_answer() {
+        // accepts the connection but never answers
+        let server = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("redis://{}/", server.local_addr().unwrap());
+        let accept = async_std::task::spawn(async move { server.accept().await });
+        let store = RedisStore::new(&url, 2, Duration::from_secs(60))
+            .unwrap()
+            .timeout(Duration::from_millis(50));
+        let started = Instant::now();
+        let err = store.hit("10.0.0.1").await.unwrap_err();
+        assert!(err.to_string().contains("didn't answer"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(store.conn.borrow().is_none());
+        drop(accept);
+    }
+
+    #[test]
+    fn remove_idle_buckets() {
+        let limit = MemoryStore::new(1, Duration::from_secs(1), 1);
+        let now = Instant::now();
+        limit.take("a", now).unwrap();
+        limit.take("b", now + CLEANUP_INTERVAL / 2).unwrap();
+        limit.take("c", now + CLEANUP_INTERVAL).unwrap();
+        let buckets = limit.buckets.borrow();
+        assert!(!buckets.contains_key("a"));
+        assert!(!buckets.contains_key("b"));
+        assert!(buckets.contains_key("c"));
+    }
+}
@@ -0,0 +1,99 @@
+//! Plugins listed by a manifest served over HTTP, it's fetched at startup and
+//! can be polled to apply its changes
+
+use async_std::{fs, task};
+use http_client::{h1::H1Client, HttpClient};
+use kv_log_macro::{info, warn};
+use std::{error::Error, path::PathBuf, time::Duration};
+use valor::http::{Request, Url};
+use valor::runtime::VluginDef;
+
+// attempts to fetch the manifest before giving up
+const ATTEMPTS: u32 = 4;
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+pub(crate) struct Manifest {
+    url: Url,
+    // copy of the last manifest fetched to use when the server is unreachable
+    cache: Option<PathBuf>,
+    client: H1Client,
+}
+
+impl Manifest {
+    pub fn new(url: Url, cache: Option<PathBuf>) -> Self {
+        Manifest {
+            url,
+            cache,
+            client: H1Client::new(),
+        }
+    }
+
+    /// Plugins of the manifest retrying with a growing backoff, when all the
+    /// attempts fail the cached copy is used if there's one
+    pub async fn load(&self) -> Result<Vec<VluginDef>, Box<dyn Error>> {
+        let err = match self.fetch_with_retries().await {
+            Ok(plugins) => return Ok(plugins),
+            Err(err) => err,
+        };
+        match &self.cache {
+            Some(path) if path.exists() => {
+                warn!("can't fetch {}: {}, using the cached copy", self.url, err);
+                crate::read_plugins(path)
+            }
+            _ => Err(format!("can't fetch {}: {}", self.url, err).into()),
+        }
+    }
+
+    async fn fetch_with_retries(&self) -> Result<Vec<VluginDef>, Box<dyn Error>> {
+        let mut backoff = FIRST_BACKOFF;
+        for _ in 1..ATTEMPTS {
+            match self.fetch().await {
+                Ok(plugins) => return Ok(plugins),
+                Err(err) => warn!("fetching {} failed: {}, retrying", self.url, err),
+            }
+            task::sleep(backoff).await;
+            backoff *= 2;
+        }
+        self.fetch().await
+    }
+
+    async fn fetch(&self) -> Result<Vec<VluginDef>, Box<dyn Error>> {
+        let mut res = self.client.send(Request::get(self.url.clone())).await?;
+        if !res.status().is_success() {
+            return Err(format!("server answered {}", res.status()).into());
+        }
+        let body = res.body_bytes().await?;
+        let plugins = crate::parse_plugins(&body)?;
+        if let Some(path) = &self.cache {
+            if let Err(err) = fs::write(path, &body).await {
+                warn!("can't cache the manifest in {}: {}", path.display(), err);
+            }
+        }
+        Ok(plugins)
+    }
+}
+
+/// Fetches the manifest periodically applying the changes to the runtime,
+/// the loaded plugins stay as they are when it can't be fetched
+pub(crate) async fn poll(
+    manifest: Manifest,
+    every: Duration,
+    mut loaded: Vec<VluginDef>,
+    runtime: crate::Runtime,
+) {
+    loop {
+        task::sleep(every).await;
+        let plugins = match manifest.fetch_with_retries().await {
+            Ok(plugins) => plugins,
+            Err(err) => {
+                warn!("can't fetch {}: {}", manifest.url, err);
+                continue;
+            }
+        };
+        if plugins != loaded {
+            info!("applying changes of {}", manifest.url);
+            crate::apply_changes(&runtime, &loaded, &plugins).await;
+            loaded = plugins;
+        }
+    }
+}
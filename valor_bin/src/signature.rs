@@ -0,0 +1,71 @@
+//! Verification of the ed25519 signatures of plugin modules
+
+use ed25519_dalek::{PublicKey, Signature};
+use std::{convert::TryFrom, str::FromStr};
+use valor::runtime;
+
+/// Public key plugin modules have to be signed with, the signature goes
+/// base64 encoded in the `signature` of the plugin definition
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TrustedKey(PublicKey);
+
+impl TrustedKey {
+    /// Checks the module is signed with the key, a module without a
+    /// signature is as untrusted as a tampered one
+    pub fn verify(&self, plugin: &runtime::VluginDef, module: &[u8]) -> Result<(), runtime::Error> {
+        let untrusted = || runtime::Error::UntrustedVlugin(plugin.name.clone());
+        let signature = plugin.signature.as_ref().ok_or_else(untrusted)?;
+        let signature = base64::decode(signature.trim()).map_err(|_| untrusted())?;
+        let signature = Signature::try_from(&signature[..]).map_err(|_| untrusted())?;
+        self.0
+            .verify_strict(module, &signature)
+            .map_err(|_| untrusted())
+    }
+}
+
+impl FromStr for TrustedKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = base64::decode(s.trim()).map_err(|e| format!("invalid key {}: {}", s, e))?;
+        PublicKey::from_bytes(&bytes)
+            .map(TrustedKey)
+            .map_err(|_| format!("{} is not an ed25519 public key", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{ExpandedSecretKey, SecretKey};
+
+    #[test]
+    fn verify_modules() {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let key: TrustedKey = base64::encode(public.as_bytes()).parse().unwrap();
+        let sign = |module: &[u8]| {
+            let signature = ExpandedSecretKey::from(&secret).sign(module, &public);
+            base64::encode(signature.to_bytes())
+        };
+        let plugin = |signature: Option<String>| runtime::VluginDef {
+            signature,
+            ..runtime::VluginDef::from("foo")
+        };
+
+        let module = b"\0asm plugin";
+        assert!(key.verify(&plugin(Some(sign(module))), module).is_ok());
+        for plugin in &[
+            plugin(None),
+            plugin(Some(sign(b"\0asm other"))),
+            plugin(Some("not base64!".into())),
+        ] {
+            let res = key.verify(plugin, module);
+            assert!(matches!(res, Err(runtime::Error::UntrustedVlugin(name)) if name == "foo"));
+        }
+        let tampered = b"\0asm plugin!";
+        assert!(key.verify(&plugin(Some(sign(module))), tampered).is_err());
+
+        assert!("AAAA".parse::<TrustedKey>().is_err());
+    }
+}
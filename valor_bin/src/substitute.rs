@@ -0,0 +1,124 @@
+//! Expansion of `${VAR}` placeholders in the configuration of plugins with
+//! the values of environment variables, e.g. to not write secrets in the
+//! plugin file. Only the configuration given to the plugin is expanded, the
+//! definition the registry lists or persists keeps the placeholders.
+
+use std::env;
+use valor::VluginConfig;
+
+/// Replaces the placeholders of the strings of the configuration,
+/// `${VAR:-fallback}` uses the fallback when the variable is unset or empty
+/// and `$${` is a literal `${`
+pub(crate) fn expand(config: VluginConfig) -> Result<VluginConfig, String> {
+    expand_with(config, &|name| env::var(name).ok())
+}
+
+fn expand_with(
+    config: VluginConfig,
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Result<VluginConfig, String> {
+    Ok(match config {
+        VluginConfig::String(s) => expand_str(&s, var)?.into(),
+        VluginConfig::Array(values) => values
+            .into_iter()
+            .map(|v| expand_with(v, var))
+            .collect::<Result<Vec<_>, _>>()?
+            .into(),
+        VluginConfig::Object(values) => values
+            .into_iter()
+            .map(|(k, v)| Ok((k, expand_with(v, var)?)))
+            .collect::<Result<serde_json::Map<_, _>, String>>()?
+            .into(),
+        value => value,
+    })
+}
+
+fn expand_str(s: &str, var: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("$${") {
+            expanded.push_str("${");
+            rest = &rest[3..];
+            continue;
+        }
+        if !rest.starts_with("${") {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        }
+        let end = rest
+            .find('}')
+            .ok_or_else(|| format!("Unclosed placeholder in {:?}", s))?;
+        let placeholder = &rest[2..end];
+        let (name, fallback) = match placeholder.split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (placeholder, None),
+        };
+        if !is_var_name(name) {
+            return Err(format!("Invalid placeholder ${{{}}}", placeholder));
+        }
+        let value = match (var(name), fallback) {
+            (Some(value), Some(fallback)) if value.is_empty() => fallback.to_owned(),
+            (Some(value), _) => value,
+            (None, Some(fallback)) => fallback.to_owned(),
+            (None, None) => return Err(format!("Environment variable {} is not set", name)),
+        };
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn var(name: &str) -> Option<String> {
+        match name {
+            "TOKEN" => Some("s3cret".into()),
+            "EMPTY" => Some("".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expand_placeholders() {
+        let config = json!({
+            "upstream": "http://${HOST:-localhost}:8080",
+            "auth": ["Bearer ${TOKEN}", "${EMPTY:-none}", "${EMPTY}"],
+            "literal": "$${TOKEN} costs $5",
+            "timeout_ms": 100,
+        });
+        assert_eq!(
+            expand_with(config, &var).unwrap(),
+            json!({
+                "upstream": "http://localhost:8080",
+                "auth": ["Bearer s3cret", "none", ""],
+                "literal": "${TOKEN} costs $5",
+                "timeout_ms": 100,
+            })
+        );
+    }
+
+    #[test]
+    fn fail_on_unset_or_malformed_placeholders() {
+        let err = |s: &str| expand_with(json!({ "a": [s] }), &var).unwrap_err();
+        assert_eq!(err("${HOST}"), "Environment variable HOST is not set");
+        assert_eq!(err("${TOKEN"), "Unclosed placeholder in \"${TOKEN\"");
+        assert_eq!(err("${1A}"), "Invalid placeholder ${1A}");
+        assert_eq!(err("${}"), "Invalid placeholder ${}");
+    }
+}
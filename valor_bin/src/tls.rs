@@ -0,0 +1,90 @@
+//! HTTPS support with rustls
+
+use async_std::io::{self, Read, Write};
+use async_tls::{server::TlsStream, TlsAcceptor};
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    NoClientAuth, PrivateKey, ServerConfig,
+};
+use std::{
+    error::Error,
+    fs::File,
+    io::BufReader,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// Creates an acceptor of TLS sessions from the PEM encoded
+/// certificate chain and private key(PKCS8 or RSA)
+pub(crate) fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("can't open {}: {}", path.display(), e))
+    };
+    let chain = certs(&mut open(cert)?)
+        .ok()
+        .filter(|c| !c.is_empty())
+        .ok_or_else(|| format!("no valid certificate in {}", cert.display()))?;
+    let key = read_key(&mut open(key)?, &mut open(key)?)
+        .ok_or_else(|| format!("no valid private key in {}", key.display()))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_key(pkcs8: &mut BufReader<File>, rsa: &mut BufReader<File>) -> Option<PrivateKey> {
+    let pkcs8 = pkcs8_private_keys(pkcs8).ok()?.into_iter().next();
+    pkcs8.or_else(|| rsa_private_keys(rsa).ok()?.into_iter().next())
+}
+
+/// TLS session that can be cloned as required by `async_h1`,
+/// the clones share the same session.
+pub(crate) struct Stream<S>(Arc<Mutex<TlsStream<S>>>);
+
+impl<S> Clone for Stream<S> {
+    fn clone(&self) -> Self {
+        Stream(self.0.clone())
+    }
+}
+
+impl<S> From<TlsStream<S>> for Stream<S> {
+    fn from(stream: TlsStream<S>) -> Self {
+        Stream(Arc::new(Mutex::new(stream)))
+    }
+}
+
+impl<S: Read + Write + Unpin> Read for Stream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut stream = self.0.lock().expect("TLS stream lock");
+        Pin::new(&mut *stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: Read + Write + Unpin> Write for Stream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut stream = self.0.lock().expect("TLS stream lock");
+        Pin::new(&mut *stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut stream = self.0.lock().expect("TLS stream lock");
+        Pin::new(&mut *stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut stream = self.0.lock().expect("TLS stream lock");
+        Pin::new(&mut *stream).poll_close(cx)
+    }
+}
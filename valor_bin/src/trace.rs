@@ -0,0 +1,319 @@
+//! Distributed tracing following the W3C trace context, a span is created for
+//! every request and exported to an OpenTelemetry collector with OTLP over HTTP.
+
+use async_std::{channel, task};
+use async_trait::async_trait;
+use http_client::{h1::H1Client, HttpClient};
+use kv_log_macro::warn;
+use serde_json::{json, Value};
+use std::{
+    env,
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+use valor::http::{self, Request, Response};
+use valor::runtime::{Middleware, Next};
+
+const TRACEPARENT: &str = "traceparent";
+// spans exported in a single request to the collector
+const MAX_BATCH: usize = 512;
+// spans waiting to be exported, new ones are dropped when it's full
+const MAX_QUEUE: usize = 4096;
+
+/// Identifies a span within a trace as sent in the `traceparent` header
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TraceContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    sampled: bool,
+}
+
+impl TraceContext {
+    // e.g. `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`
+    fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next().filter(|v| v.len() == 2 && *v != "ff")?;
+        let trace_id = parts.next().and_then(from_hex::<16>)?;
+        let span_id = parts.next().and_then(from_hex::<8>)?;
+        let [flags] = parts.next().and_then(from_hex::<1>)?;
+        // later versions can append fields
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    // a new span that continues the trace of the parent or starts a new one
+    fn child_of(parent: Option<&TraceContext>) -> Self {
+        let id = *Uuid::new_v4().as_bytes();
+        let mut span_id = [0; 8];
+        span_id.copy_from_slice(&id[..8]);
+        TraceContext {
+            trace_id: parent.map_or_else(|| *Uuid::new_v4().as_bytes(), |p| p.trace_id),
+            span_id,
+            sampled: parent.map_or(true, |p| p.sampled),
+        }
+    }
+
+    fn to_header(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            to_hex(&self.trace_id),
+            to_hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+struct Span {
+    context: TraceContext,
+    parent: Option<[u8; 8]>,
+    name: String,
+    start: SystemTime,
+    end: SystemTime,
+    plugin: Option<String>,
+    method: http::Method,
+    path: String,
+    status: u16,
+}
+
+/// Middleware tracing requests, the current `traceparent` is passed down to
+/// plugins so proxies can forward it and traces continue across services.
+pub(crate) struct Tracing {
+    spans: channel::Sender<Span>,
+}
+
+impl Tracing {
+    /// Tracing is enabled when there's a collector to export the spans to,
+    /// set with `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` or with
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT` that is appended the `/v1/traces` path.
+    /// The service is named after `OTEL_SERVICE_NAME` or `valor` by default.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let endpoint = match (
+            env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"),
+            env::var("OTEL_EXPORTER_OTLP_ENDPOINT"),
+        ) {
+            (Ok(endpoint), _) => endpoint,
+            (_, Ok(endpoint)) => format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+            _ => return Ok(None),
+        };
+        let endpoint = http::Url::parse(&endpoint)
+            .map_err(|e| format!("invalid OTLP endpoint {}: {}", endpoint, e))?;
+        let service = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "valor".into());
+
+        let (spans, queue) = channel::bounded(MAX_QUEUE);
+        task::spawn_local(export(endpoint, service, queue));
+        Ok(Some(Tracing { spans }))
+    }
+}
+
+#[async_trait(?Send)]
+impl Middleware for Tracing {
+    async fn handle(&self, mut req: Request, next: Next<'_>) -> Result<Response, valor::Error> {
+        let parent = req
+            .header(TRACEPARENT)
+            .and_then(|h| TraceContext::parse(h.last().as_str()));
+        let context = TraceContext::child_of(parent.as_ref());
+        req.insert_header(TRACEPARENT, context.to_header());
+        let method = req.method();
+        let path = req.url().path().to_owned();
+
+        let start = SystemTime::now();
+        let res = next.run(req).await;
+        if !context.sampled {
+            return res;
+        }
+        let (plugin, status) = match &res {
+            Ok(res) => (
+                res.header("x-valor-plugin").map(|p| p.as_str().to_owned()),
+                res.status() as u16,
+            ),
+            Err(valor::Error::Http(err)) => (None, err.status() as u16),
+            Err(_) => (None, 500),
+        };
+        let span = Span {
+            context,
+            parent: parent.map(|p| p.span_id),
+            name: format!("{} {}", method, plugin.as_deref().unwrap_or(""))
+                .trim_end()
+                .into(),
+            start,
+            end: SystemTime::now(),
+            plugin,
+            method,
+            path,
+            status,
+        };
+        // tracing shouldn't slow down requests when the collector can't keep up
+        let _ = self.spans.try_send(span);
+        res
+    }
+}
+
+// Sends the queued spans in batches until the middleware is dropped
+async fn export(endpoint: http::Url, service: String, queue: channel::Receiver<Span>) {
+    let client = H1Client::new();
+    while let Ok(span) = queue.recv().await {
+        let mut batch = vec![span];
+        while batch.len() < MAX_BATCH {
+            match queue.try_recv() {
+                Ok(span) => batch.push(span),
+                Err(_) => break,
+            }
+        }
+        let mut req = http::Request::post(endpoint.clone());
+        req.set_body(http::Body::from_json(&otlp_json(&service, &batch)).expect("valid JSON"));
+        match client.send(req).await {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => warn!("collector rejected {} spans: {}", batch.len(), res.status()),
+            Err(err) => warn!("can't export {} spans: {}", batch.len(), err),
+        }
+    }
+}
+
+// Spans in the JSON encoding of the OTLP protocol
+fn otlp_json(service: &str, spans: &[Span]) -> Value {
+    let nanos = |t: &SystemTime| {
+        let ns = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        ns.to_string()
+    };
+    let string = |key: &str, value: &str| json!({"key": key, "value": {"stringValue": value}});
+    let spans = spans
+        .iter()
+        .map(|span| {
+            let mut attributes = vec![
+                string("http.method", span.method.as_ref()),
+                string("http.target", &span.path),
+                json!({"key": "http.status_code", "value": {"intValue": span.status.to_string()}}),
+            ];
+            if let Some(plugin) = &span.plugin {
+                attributes.push(string("valor.plugin", plugin));
+            }
+            json!({
+                "traceId": to_hex(&span.context.trace_id),
+                "spanId": to_hex(&span.context.span_id),
+                "parentSpanId": span.parent.as_ref().map_or_else(String::new, |p| to_hex(p)),
+                "name": span.name,
+                // SPAN_KIND_SERVER
+                "kind": 2,
+                "startTimeUnixNano": nanos(&span.start),
+                "endTimeUnixNano": nanos(&span.end),
+                "attributes": attributes,
+                // STATUS_CODE_ERROR for server errors, unset otherwise
+                "status": {"code": if span.status >= 500 { 2 } else { 0 }},
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "resourceSpans": [{
+            "resource": {"attributes": [string("service.name", service)]},
+            "scopeSpans": [{"scope": {"name": "valor"}, "spans": spans}],
+        }]
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+fn from_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_traceparent() {
+        let header = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let cx = TraceContext::parse(header).unwrap();
+        assert!(cx.sampled);
+        assert_eq!(cx.to_header(), header);
+
+        let child = TraceContext::child_of(Some(&cx));
+        assert_eq!(child.trace_id, cx.trace_id);
+        assert_ne!(child.span_id, cx.span_id);
+
+        assert_eq!(
+            TraceContext::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00")
+                .map(|cx| cx.sampled),
+            Some(false)
+        );
+        for invalid in &[
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{}", invalid);
+        }
+        // future versions may add fields
+        assert!(TraceContext::parse(
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra"
+        )
+        .is_some());
+    }
+
+    #[async_std::test]
+    async fn trace_requests_of_plugins() {
+        let (spans, queue) = channel::bounded(MAX_QUEUE);
+        let runtime = valor::runtime::Runtime::new(())
+            .with_middleware(Tracing { spans })
+            .with_plugin(
+                "echo",
+                valor::h(|req: Request, _| async move {
+                    let traceparent = req.header(TRACEPARENT).unwrap().as_str();
+                    Ok(Response::from(traceparent))
+                }),
+            )
+            .unwrap();
+        let request = |traceparent: &str| {
+            let mut req = Request::new(http::Method::Get, "http://example.com/_echo");
+            req.insert_header("x-request-id", "1");
+            req.insert_header(TRACEPARENT, traceparent);
+            req
+        };
+
+        let parent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let mut res = runtime.handle(request(parent)).await;
+        let passed = TraceContext::parse(&res.body_string().await.unwrap()).unwrap();
+        let span = queue.try_recv().unwrap();
+        // the plugin gets the span of the request as parent of its own
+        assert_eq!(passed, span.context);
+        assert_eq!(span.parent, TraceContext::parse(parent).map(|p| p.span_id));
+        assert_eq!((span.name.as_str(), span.status), ("GET echo", 200));
+        assert_eq!(span.path, "/_echo");
+
+        let json = otlp_json("valor", &[span]);
+        let exported = &json["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(exported["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(exported["parentSpanId"], "b7ad6b7169203331");
+        assert_eq!(exported["status"]["code"], 0);
+
+        // spans the caller doesn't sample are not exported
+        let unsampled = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00";
+        runtime.handle(request(unsampled)).await;
+        assert!(queue.try_recv().is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Loader of WebAssembly plugins that run sandboxed with wasmtime.
+//!
+//! ## ABI
+//!
+//! A WASM plugin is a module exporting its linear `memory` and two functions:
+//!
+//! - `valor_alloc(len: u32) -> u32` reserves `len` bytes in the module's memory
+//!   and returns a pointer to them, the runtime writes the request there.
+//! - `valor_handle(ptr: u32, len: u32) -> u64` takes the request buffer and
+//!   returns the response buffer packed as `ptr << 32 | len`.
+//!
+//! Requests and responses are exchanged as HTTP/1.1 messages. Every request
+//! is handled by a fresh instance of the module that is limited in the memory
+//! it can grow and the fuel(roughly the instructions) it can consume so a
+//! runaway module traps instead of hanging the runtime.
+
+use async_std::io::{Cursor, ReadExt};
+use async_trait::async_trait;
+use kv_log_macro::{debug, warn};
+use std::error::Error as StdError;
+use valor::{http, runtime, Answer, Context, Message, Vlugin};
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+const ALLOC_FN: &str = "valor_alloc";
+const HANDLE_FN: &str = "valor_handle";
+
+type CallError = Box<dyn StdError + Send + Sync>;
+
+/// Resources a WASM plugin can use to handle a single request
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    /// Maximum size in bytes of the module's memory
+    pub memory: usize,
+    /// Fuel available to the module
+    pub fuel: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            memory: 64 << 20,
+            fuel: 100_000_000,
+        }
+    }
+}
+
+pub(crate) struct WasmLoader {
+    engine: Engine,
+    limits: Limits,
+}
+
+impl WasmLoader {
+    pub fn new(limits: Limits) -> Self {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).expect("valid engine configuration");
+        WasmLoader { engine, limits }
+    }
+
+    /// Compiles the plugin from the bytes of its module that were already read
+    pub fn load_module(
+        &self,
+        plugin: &runtime::VluginDef,
+        module: &[u8],
+    ) -> Result<runtime::VluginFactory, runtime::Error> {
+        let name = plugin.name.clone();
+        let module = Module::new(&self.engine, module).map_err(|e| {
+            warn!("{}", e);
+            runtime::Error::LoadVlugin(name.clone())
+        })?;
+        if let Some(missing) = ["memory", ALLOC_FN, HANDLE_FN]
+            .iter()
+            .find(|export| module.get_export(export).is_none())
+        {
+            warn!("wasm plugin {} doesn't export {}", name, missing);
+            return Err(runtime::Error::LoadVlugin(name));
+        }
+
+        let engine = self.engine.clone();
+        let limits = self.limits;
+        Ok(Box::new(move |_cfg| {
+            let plugin = WasmVlugin {
+                name: name.clone(),
+                engine: engine.clone(),
+                module: module.clone(),
+                limits,
+                cx: Context::default(),
+            };
+            Box::pin(async move { Ok(Box::new(plugin) as Box<dyn Vlugin>) })
+        }))
+    }
+}
+
+impl Default for WasmLoader {
+    fn default() -> Self {
+        WasmLoader::new(Limits::default())
+    }
+}
+
+#[async_trait(?Send)]
+impl runtime::Loader for WasmLoader {
+    async fn load(
+        &self,
+        plugin: &runtime::VluginDef,
+    ) -> Result<runtime::VluginFactory, runtime::Error> {
+        let path = match &plugin.r#type {
+            runtime::VluginType::Wasm { path } => path,
+            ty => return Err(runtime::Error::VluginNotSupported(ty.to_owned())),
+        };
+        debug!("loading wasm plugin {}({})", plugin.name, path);
+        let module = std::fs::read(path).map_err(|e| {
+            warn!("{}", e);
+            runtime::Error::LoadVlugin(plugin.name.clone())
+        })?;
+        self.load_module(plugin, &module)
+    }
+}
+
+struct WasmVlugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    limits: Limits,
+    cx: Context,
+}
+
+impl WasmVlugin {
+    // Instance of the module with the limits of a single request
+    fn instantiate(&self) -> Result<(Store<StoreLimits>, Instance), CallError> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.limits.memory)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits: &mut StoreLimits| limits);
+        store.add_fuel(self.limits.fuel)?;
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        Ok((store, instance))
+    }
+
+    fn call(&self, req: &[u8]) -> Result<Vec<u8>, CallError> {
+        let (mut store, instance) = self.instantiate()?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or("memory is not exported")?;
+        let alloc = instance.get_typed_func::<u32, u32, _>(&mut store, ALLOC_FN)?;
+        let handle = instance.get_typed_func::<(u32, u32), u64, _>(&mut store, HANDLE_FN)?;
+
+        let len = req.len() as u32;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as usize, req)?;
+        let packed = handle.call(&mut store, (ptr, len))?;
+
+        let (ptr, len) = ((packed >> 32) as usize, packed as u32 as usize);
+        let mut res = vec![0; len];
+        memory.read(&store, ptr, &mut res)?;
+        Ok(res)
+    }
+}
+
+#[async_trait(?Send)]
+impl Vlugin for WasmVlugin {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, valor::Error> {
+        let req = match msg {
+            Message::Http(req) => req,
+            Message::Ping => return Ok(Answer::Pong),
+        };
+        let mut buf = Vec::new();
+        async_h1::client::Encoder::new(req)
+            .read_to_end(&mut buf)
+            .await
+            .map_err(|e| http::Error::new(http::StatusCode::InternalServerError, e))?;
+
+        let res = self.call(&buf).map_err(|e| {
+            warn!("wasm plugin {} failed: {}", self.name, e);
+            http::Error::from_str(http::StatusCode::InternalServerError, "Plugin failed")
+        })?;
+        let res = async_h1::client::decode(Cursor::new(res)).await?;
+        Ok(res.into())
+    }
+
+    // the first instance pays for the initialization of the module
+    async fn warmup(&self) -> Result<(), valor::Error> {
+        self.instantiate().map(|_| ()).map_err(|e| {
+            let msg = format!("Can't instantiate the module: {}", e);
+            http::Error::from_str(http::StatusCode::InternalServerError, msg).into()
+        })
+    }
+
+    fn context(&self) -> &Context {
+        &self.cx
+    }
+    fn context_mut(&mut self) -> &mut Context {
+        &mut self.cx
+    }
+}
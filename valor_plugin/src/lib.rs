@@ -48,7 +48,12 @@ pub fn vlugin(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
             #[cfg(not(target_arch = "wasm32"))]
             #[no_mangle]
-            pub extern "Rust" fn instantiate_vlugin(cfg: Option<valor::VluginConfig>) ->
+            #[allow(non_upper_case_globals)]
+            pub static valor_abi_version: u32 = valor::VLUGIN_ABI_VERSION;
+
+            #[cfg(not(target_arch = "wasm32"))]
+            #[no_mangle]
+            pub extern "Rust" fn valor_plugin_entry(cfg: Option<valor::VluginConfig>) ->
                 core::pin::Pin<Box<dyn core::future::Future<
                     Output = core::result::Result<Box<dyn valor::Vlugin>, valor::Error>
                 >>>